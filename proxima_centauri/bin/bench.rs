@@ -0,0 +1,174 @@
+use anyhow::{Result, bail};
+use base64::{Engine as _, engine::general_purpose};
+use proxima_centauri::{Database, LimitValue, Limits, Server, UserRecord, build_config};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+struct BenchConfig {
+    tunnels: usize,
+    concurrency: usize,
+    payload: usize,
+}
+
+impl BenchConfig {
+    fn from_env() -> Self {
+        Self {
+            tunnels: var_or("BENCH_TUNNELS", 10_000),
+            concurrency: var_or("BENCH_CONCURRENCY", 1_000),
+            payload: var_or("BENCH_PAYLOAD", 16 * 1024),
+        }
+    }
+}
+
+fn var_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+async fn start_echo_target() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn start_proxy() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let mut config = build_config();
+    config.max_connections = 0;
+    config.max_connections_per_ip = 0;
+    let mut database = Database::new_persistence();
+    database.insert(UserRecord {
+        limits: Limits::new(LimitValue::Unrestricted, LimitValue::Unrestricted),
+        ..UserRecord::new("bench", "bench")
+    });
+    let server = Server::builder()
+        .config(config)
+        .auth_provider(database)
+        .listener(listener)
+        .build()
+        .await?;
+    tokio::spawn(server.run());
+    Ok(addr)
+}
+
+async fn run_tunnel(proxy: SocketAddr, target: SocketAddr, payload: &[u8]) -> Result<Duration> {
+    let started = Instant::now();
+    let auth = general_purpose::STANDARD.encode("bench:bench");
+    let mut socket = TcpStream::connect(proxy).await?;
+    socket
+        .write_all(
+            format!(
+                "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\nProxy-Authorization: Basic {auth}\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut response = [0u8; 128];
+    let size = socket.read(&mut response).await?;
+    if !response[..size].starts_with(b"HTTP/1.1 200") {
+        bail!(
+            "Proxy refused tunnel: {}",
+            String::from_utf8_lossy(&response[..size])
+        );
+    }
+    socket.write_all(payload).await?;
+    let mut echoed = vec![0u8; payload.len()];
+    socket.read_exact(&mut echoed).await?;
+    Ok(started.elapsed())
+}
+
+fn cpu_seconds() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) / CLOCK_TICKS_PER_SEC)
+}
+
+fn to_f64(value: usize) -> f64 {
+    f64::from(u32::try_from(value).unwrap_or(u32::MAX))
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted
+        .get((sorted.len() * percent / 100).min(sorted.len().saturating_sub(1)))
+        .copied()
+        .unwrap_or_default()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let bench = BenchConfig::from_env();
+    let target = start_echo_target().await?;
+    let proxy = start_proxy().await?;
+    let payload: Arc<[u8]> = vec![0x5a; bench.payload].into();
+    let permits = Arc::new(Semaphore::new(bench.concurrency.max(1)));
+
+    let cpu_before = cpu_seconds();
+    let started = Instant::now();
+    let mut tunnels = JoinSet::new();
+    for _ in 0..bench.tunnels {
+        let permit = permits.clone().acquire_owned().await?;
+        let payload = payload.clone();
+        tunnels.spawn(async move {
+            let result = run_tunnel(proxy, target, &payload).await;
+            drop(permit);
+            result
+        });
+    }
+    let mut latencies = Vec::with_capacity(bench.tunnels);
+    let mut failures = 0usize;
+    while let Some(result) = tunnels.join_next().await {
+        match result? {
+            Ok(latency) => latencies.push(latency),
+            Err(_) => failures += 1,
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    latencies.sort_unstable();
+
+    let bytes = to_f64(latencies.len()) * to_f64(bench.payload) * 2.0;
+    println!(
+        "tunnels: {} ok, {failures} failed, concurrency {}, payload {} B",
+        latencies.len(),
+        bench.concurrency,
+        bench.payload
+    );
+    println!(
+        "throughput: {:.0} tunnels/s, {:.2} MiB/s",
+        to_f64(latencies.len()) / elapsed,
+        bytes / elapsed / 1024.0 / 1024.0
+    );
+    println!(
+        "latency: p50 {:?}, p99 {:?}, max {:?}",
+        percentile(&latencies, 50),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    if let (Some(before), Some(after)) = (cpu_before, cpu_seconds()) {
+        println!(
+            "cpu: {:.2} s ({:.0}% of one core)",
+            after - before,
+            (after - before) / elapsed * 100.0
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use anyhow::Result;
+use proxima_centauri::{CancellationToken, QuotaCoordinator, init};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init();
+    let addr = std::env::var("COORDINATOR_ADDR").unwrap_or_else(|_| String::from("127.0.0.1:7070"));
+    let token = std::env::var("COORDINATOR_TOKEN").ok();
+    let listener = TcpListener::bind(&addr).await?;
+    let shutdown = CancellationToken::new();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let stop = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        stop.cancel();
+    });
+    Arc::new(QuotaCoordinator::new(token))
+        .serve(listener, shutdown)
+        .await;
+    Ok(())
+}
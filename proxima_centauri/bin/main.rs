@@ -1,8 +1,154 @@
-use anyhow::Result;
-use proxima_centauri::Server;
+use anyhow::{Context, Result, bail};
+use proxima_centauri::{
+    Server, UserRecord, UsersFile, build_config, encrypt_users, init, install_signal_handlers,
+    listen_fds,
+};
+use std::io::IsTerminal as _;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    Server::run().await?;
+    init();
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("encrypt-users") {
+        return encrypt_users_file(&args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("user") {
+        return manage_users(&args[2..]);
+    }
+    if std::env::args().any(|arg| arg == "--check") {
+        let report = build_config().check_backends().await;
+        print!("{report}");
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        println!("Configuration OK");
+        return Ok(());
+    }
+    let mut builder = Server::builder();
+    if let Some(listener) = listen_fds()?.into_iter().next() {
+        builder = builder.std_listener(listener);
+    }
+    #[cfg(feature = "ldap")]
+    let builder = match proxima_centauri::LdapConfig::from_env()? {
+        Some(config) => builder.auth_provider(proxima_centauri::LdapAuthProvider::new(config)?),
+        None => builder,
+    };
+    let server = builder.build().await?;
+    install_signal_handlers(&server)?;
+    server.run().await?;
     Ok(())
 }
+
+fn encrypt_users_file(args: &[String]) -> Result<()> {
+    let [input, output] = args else {
+        bail!("Usage: procent encrypt-users <input.csv> <output.enc>");
+    };
+    let Some(key) = build_config().users_key else {
+        bail!("PROXY_USERS_KEY must be set to encrypt the users file");
+    };
+    let plaintext = std::fs::read(input).with_context(|| format!("Cannot read `{input}`"))?;
+    std::fs::write(output, encrypt_users(&plaintext, &key)?)
+        .with_context(|| format!("Cannot write `{output}`"))?;
+    println!("Encrypted {input} -> {output}");
+    Ok(())
+}
+
+fn manage_users(args: &[String]) -> Result<()> {
+    const USAGE: &str = "Usage: procent user <list | add <name> [plan] | remove <name> | \
+                         set-plan <name> <plan|-> | set-limit <name> <concurrency> <traffic> | \
+                         set-status <name> <ok|banned> | migrate> [--reload <pid>]";
+    let (args, reload) = match args {
+        [args @ .., flag, pid] if flag == "--reload" => (args, Some(pid)),
+        args => (args, None),
+    };
+    if let Some(pid) = reload.filter(|pid| pid.parse::<u32>().is_err()) {
+        bail!("`{pid}` is not a process id");
+    }
+    let config = build_config();
+    let Some(path) = &config.users_file else {
+        bail!("PROXY_USERS_FILE must be set to manage users");
+    };
+    let known_plan = |plan: &str| {
+        if config.plans.contains_key(plan) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Unknown plan `{plan}`, see PROXY_PLANS"))
+        }
+    };
+    let mut users = UsersFile::open(path, config.users_key.as_deref())?;
+    match args {
+        [command] if command == "list" => {
+            for user in users.users()? {
+                println!(
+                    "{}\t{}\t{}",
+                    user.username,
+                    user.plan.as_deref().unwrap_or("-"),
+                    user.tenant.as_deref().unwrap_or("-")
+                );
+            }
+            return Ok(());
+        }
+        [command, name, plan @ ..] if command == "add" && plan.len() <= 1 => {
+            let mut record = UserRecord::new(name, read_password()?);
+            if let [plan] = plan {
+                known_plan(plan)?;
+                record.plan = Some(plan.clone());
+            }
+            users.add(&record)?;
+        }
+        [command, name] if command == "remove" => {
+            if !users.remove(name)? {
+                bail!("User `{name}` not found");
+            }
+        }
+        [command, name, plan] if command == "set-plan" => {
+            let plan = (plan != "-").then_some(plan.as_str());
+            if let Some(plan) = plan {
+                known_plan(plan)?;
+            }
+            if !users.set_plan(name, plan)? {
+                bail!("User `{name}` not found");
+            }
+        }
+        [command, name, concurrency, traffic] if command == "set-limit" => {
+            if !users.set_limits(name, concurrency.parse()?, traffic.parse()?)? {
+                bail!("User `{name}` not found");
+            }
+        }
+        [command] if command == "migrate" => {
+            let assigned = users.assign_ids()?;
+            println!("Assigned user IDs to {assigned} users");
+        }
+        [command, name, status] if command == "set-status" => {
+            if !users.set_status(name, &status.parse()?)? {
+                bail!("User `{name}` not found");
+            }
+        }
+        _ => bail!(USAGE),
+    }
+    users.save()?;
+    println!("Updated {}", path.display());
+    if let Some(pid) = reload {
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", pid])
+            .status()
+            .context("Cannot run `kill`")?;
+        if !status.success() {
+            bail!("Could not signal process {pid} to reload");
+        }
+        println!("Asked process {pid} to reload");
+    }
+    Ok(())
+}
+
+fn read_password() -> Result<String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprint!("Password: ");
+    }
+    let mut password = String::new();
+    stdin
+        .read_line(&mut password)
+        .context("Cannot read the password from stdin")?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let hash = std::env::var("PROCENT_GIT_HASH").unwrap_or_else(|_| git_hash());
+    let built = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    println!("cargo:rustc-env=PROCENT_GIT_HASH={hash}");
+    println!("cargo:rustc-env=PROCENT_BUILD_TIME={built}");
+    println!("cargo:rerun-if-env-changed=PROCENT_GIT_HASH");
+    watch_git_refs(Path::new("../.git"));
+}
+
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(|| String::from("unknown"), |hash| hash.trim().to_string())
+}
+
+fn watch_git_refs(git_dir: &Path) {
+    let head = git_dir.join("HEAD");
+    let branch = std::fs::read_to_string(&head)
+        .ok()
+        .and_then(|head| Some(head.strip_prefix("ref:")?.trim().to_string()));
+    let refs = [
+        Some(head),
+        branch.map(|branch| git_dir.join(branch)),
+        Some(git_dir.join("packed-refs")),
+    ];
+    for path in refs.into_iter().flatten().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
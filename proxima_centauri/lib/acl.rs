@@ -0,0 +1,338 @@
+use anyhow::{Context as _, Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Clone, Debug, Default)]
+pub struct AclConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    const ALL: Self = Self {
+        start: 0,
+        end: u16::MAX,
+    };
+
+    fn parse(value: &str) -> Result<Self> {
+        if value.is_empty() {
+            return Ok(Self::ALL);
+        }
+        let (start, end) = value.split_once('-').unwrap_or((value, value));
+        let range = Self {
+            start: start.parse().context("Invalid ACL port")?,
+            end: end.parse().context("Invalid ACL port")?,
+        };
+        if range.start > range.end {
+            bail!("Invalid ACL port range `{value}`");
+        }
+        Ok(range)
+    }
+
+    const fn contains(self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        let (addr, prefix) = value
+            .split_once('/')
+            .map_or((value, None), |(a, p)| (a, Some(p)));
+        let network: IpAddr = addr.parse().context("Invalid ACL address")?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .map_or(Ok(max), str::parse)
+            .context("Invalid ACL prefix")?;
+        if prefix > max {
+            bail!("Invalid ACL prefix length in `{value}`");
+        }
+        Ok(Self { network, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct DomainTrie {
+    children: HashMap<String, Self>,
+    exact: Vec<PortRange>,
+    wildcard: Vec<PortRange>,
+}
+
+impl DomainTrie {
+    fn node<'a>(&mut self, labels: impl Iterator<Item = &'a str>) -> &mut Self {
+        labels.fold(self, |node, label| {
+            node.children.entry(label.to_string()).or_default()
+        })
+    }
+
+    fn matches<'a>(&self, mut labels: impl Iterator<Item = &'a str>, port: u16) -> bool {
+        let mut node = self;
+        let mut label = labels.next();
+        loop {
+            let Some(current) = label else {
+                return node.exact.iter().any(|range| range.contains(port));
+            };
+            if node.wildcard.iter().any(|range| range.contains(port)) {
+                return true;
+            }
+            let Some(child) = node.children.get(current) else {
+                return false;
+            };
+            node = child;
+            label = labels.next();
+        }
+    }
+}
+
+#[derive(Default)]
+struct RuleSet {
+    suffixes: DomainTrie,
+    prefixes: DomainTrie,
+    networks: Vec<(Cidr, PortRange)>,
+    categories: HashSet<String>,
+    len: usize,
+}
+
+impl RuleSet {
+    fn compile(rules: &[String]) -> Result<Self> {
+        let mut set = Self::default();
+        for rule in rules {
+            set.insert(rule)
+                .with_context(|| format!("Invalid ACL rule `{rule}`"))?;
+        }
+        Ok(set)
+    }
+
+    fn insert(&mut self, rule: &str) -> Result<()> {
+        self.len += 1;
+        if let Some(category) = rule.strip_prefix("category:") {
+            let category = category.trim().to_ascii_lowercase();
+            if category.is_empty() {
+                bail!("Missing category name");
+            }
+            self.categories.insert(category);
+            return Ok(());
+        }
+        let (host, ports) = split_rule(rule)?;
+        let ports = PortRange::parse(ports)?;
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if host == "*" {
+            self.suffixes.wildcard.push(ports);
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            self.suffixes.node(domain.rsplit('.')).wildcard.push(ports);
+        } else if let Some(domain) = host.strip_suffix(".*") {
+            self.prefixes.node(domain.split('.')).wildcard.push(ports);
+        } else if host.contains('/') || host.parse::<IpAddr>().is_ok() {
+            self.networks.push((Cidr::parse(&host)?, ports));
+        } else if host.is_empty() || host.contains('*') {
+            bail!("Unsupported wildcard position");
+        } else {
+            self.suffixes.node(host.rsplit('.')).exact.push(ports);
+        }
+        Ok(())
+    }
+
+    fn has_category(&self, categories: &[String]) -> bool {
+        categories
+            .iter()
+            .any(|category| self.categories.contains(category))
+    }
+
+    fn matches(&self, host: &str, port: u16, addrs: &[SocketAddr]) -> bool {
+        let in_networks = |ip: IpAddr| {
+            self.networks
+                .iter()
+                .any(|(cidr, ports)| ports.contains(port) && cidr.contains(ip))
+        };
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return in_networks(ip);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.suffixes.matches(host.rsplit('.'), port)
+            || self.prefixes.matches(host.split('.'), port)
+            || addrs.iter().any(|addr| in_networks(addr.ip()))
+    }
+}
+
+fn split_rule(rule: &str) -> Result<(&str, &str)> {
+    if let Some(rest) = rule.strip_prefix('[') {
+        let Some((host, ports)) = rest.split_once(']') else {
+            bail!("Unterminated `[` in ACL rule");
+        };
+        return Ok((host, ports.strip_prefix(':').unwrap_or(ports)));
+    }
+    if rule.matches(':').count() == 1 {
+        return Ok(rule.split_once(':').unwrap_or((rule, "")));
+    }
+    Ok((rule, ""))
+}
+
+pub(crate) struct Acl {
+    allow: RuleSet,
+    deny: RuleSet,
+}
+
+impl Acl {
+    pub(crate) fn compile(config: &AclConfig) -> Result<Self> {
+        Ok(Self {
+            allow: RuleSet::compile(&config.allow)?,
+            deny: RuleSet::compile(&config.deny)?,
+        })
+    }
+
+    pub(crate) fn uses_categories(&self) -> bool {
+        !self.allow.categories.is_empty() || !self.deny.categories.is_empty()
+    }
+
+    pub(crate) fn denies_category(&self, categories: &[String]) -> bool {
+        self.deny.has_category(categories)
+    }
+
+    pub(crate) fn is_allowed(
+        &self,
+        authority: &str,
+        addrs: &[SocketAddr],
+        categories: &[String],
+    ) -> bool {
+        let (host, port) = authority
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .unwrap_or((authority, 0));
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if self.deny.matches(host, port, addrs) || self.deny.has_category(categories) {
+            return false;
+        }
+        self.allow.len == 0
+            || self.allow.matches(host, port, addrs)
+            || self.allow.has_category(categories)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allow: &[&str], deny: &[&str]) -> Acl {
+        Acl::compile(&AclConfig {
+            allow: allow.iter().map(ToString::to_string).collect(),
+            deny: deny.iter().map(ToString::to_string).collect(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn empty_acl_allows_everything() {
+        assert!(acl(&[], &[]).is_allowed("example.com:443", &[], &[]));
+    }
+
+    #[test]
+    fn matches_subdomain_and_suffix_wildcards() {
+        let acl = acl(&["*.example.com", "example.*", "exact.org"], &[]);
+
+        assert!(acl.is_allowed("api.example.com:443", &[], &[]));
+        assert!(acl.is_allowed("a.b.EXAMPLE.com:443", &[], &[]));
+        assert!(!acl.is_allowed("badexample.com:443", &[], &[]));
+        assert!(acl.is_allowed("example.net:80", &[], &[]));
+        assert!(acl.is_allowed("example.co.uk:80", &[], &[]));
+        assert!(acl.is_allowed("exact.org:80", &[], &[]));
+        assert!(!acl.is_allowed("www.exact.org:80", &[], &[]));
+    }
+
+    #[test]
+    fn matches_cidr_blocks_and_port_ranges() {
+        let acl = acl(
+            &["10.0.0.0/8:8000-8999", "[2001:db8::/32]:443", "*:443"],
+            &[],
+        );
+
+        assert!(acl.is_allowed("10.1.2.3:8080", &[], &[]));
+        assert!(!acl.is_allowed("10.1.2.3:22", &[], &[]));
+        assert!(!acl.is_allowed("11.1.2.3:8080", &[], &[]));
+        assert!(acl.is_allowed("[2001:db8::1]:443", &[], &[]));
+        assert!(acl.is_allowed(
+            "internal.test:8080",
+            &["10.0.0.7:8080".parse().unwrap()],
+            &[]
+        ));
+        assert!(acl.is_allowed("anything.test:443", &[], &[]));
+        assert!(!acl.is_allowed("anything.test:80", &[], &[]));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let acl = acl(
+            &["*.example.com", "10.0.0.0/8", "*.test"],
+            &["admin.example.com", "10.0.0.0/24:22", "192.168.0.0/16"],
+        );
+
+        assert!(acl.is_allowed("www.example.com:443", &[], &[]));
+        assert!(!acl.is_allowed("admin.example.com:443", &[], &[]));
+        assert!(!acl.is_allowed("10.0.0.5:22", &[], &[]));
+        assert!(acl.is_allowed("10.0.0.5:80", &[], &[]));
+        assert!(acl.is_allowed("fine.test:80", &["10.0.0.5:80".parse().unwrap()], &[]));
+        assert!(!acl.is_allowed("evil.test:80", &["192.168.1.1:80".parse().unwrap()], &[]));
+    }
+
+    #[test]
+    fn category_rules_match_the_target_categories() {
+        let acl = acl(&["category:news", "*.example.com"], &["category:Adult"]);
+        let adult = ["adult".to_string()];
+        let news = ["news".to_string()];
+
+        assert!(acl.uses_categories());
+        assert!(acl.is_allowed("paper.test:443", &[], &news));
+        assert!(!acl.is_allowed("paper.test:443", &[], &[]));
+        assert!(!acl.is_allowed("www.example.com:443", &[], &adult));
+        assert!(acl.denies_category(&adult));
+        assert!(!acl.denies_category(&news));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for rule in [
+            "ex*ample.com",
+            "10.0.0.0/33",
+            "host:90-80",
+            "[::1:443",
+            "category:",
+        ] {
+            assert!(
+                Acl::compile(&AclConfig {
+                    allow: vec![rule.to_string()],
+                    deny: Vec::new(),
+                })
+                .is_err()
+            );
+        }
+    }
+}
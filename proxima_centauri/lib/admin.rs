@@ -0,0 +1,558 @@
+use crate::auth::{Credential, UserFilter, UserRecord, UsernameTaken, UsersFile};
+use crate::chaos::ChaosConfig;
+use crate::clock::unix_now;
+use crate::context::{Context, SharedContext};
+use crate::http_utils::headers;
+use crate::ledger::QuotaGrant;
+use crate::logging;
+use anyhow::Result;
+use httparse::{EMPTY_HEADER, Request, Status};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, info, warn};
+
+const CONNECTIONS_PAGE: usize = 100;
+const USERS_PAGE: usize = 100;
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+const USER_SECTIONS: [&str; 4] = ["credentials", "username", "destinations", "topup"];
+
+struct AdminResponse {
+    status: u16,
+    body: Value,
+}
+
+impl AdminResponse {
+    const fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            501 => "Not Implemented",
+            _ => "Internal Server Error",
+        };
+        let body = self.body.to_string();
+        format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.status,
+            body.len()
+        )
+        .into_bytes()
+    }
+}
+
+pub(crate) async fn serve(listener: TcpListener, live: SharedContext, shutdown: CancellationToken) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Admin API started on {addr}");
+    }
+    loop {
+        let (socket, peer) = tokio::select! {
+            () = shutdown.cancelled() => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(error = format!("{err}"), "Admin API accept failed");
+                    continue;
+                }
+            },
+        };
+        let ctx_copy = Context::clone(&live.load());
+        tokio::spawn(
+            async move {
+                if let Err(err) = handle_admin_connection(socket, peer, ctx_copy).await {
+                    debug!(error = format!("{err}"), "Admin API request failed");
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+async fn handle_admin_connection(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    ctx: Context,
+) -> Result<()> {
+    let Some(buff) = read_admin_request(&mut socket).await? else {
+        socket
+            .write_all(&AdminResponse::error(413, "request too large").into_bytes())
+            .await?;
+        return Ok(());
+    };
+    if buff.is_empty() {
+        return Ok(());
+    }
+
+    let mut headers = [EMPTY_HEADER; 32];
+    let mut request = Request::new(&mut headers);
+    let response = match request.parse(&buff) {
+        Ok(Status::Complete(offset)) => match (request.method, request.path) {
+            (Some(method), Some(path)) => {
+                if is_authorized(&request, peer, &ctx) {
+                    route(method, path, &buff[offset..], &ctx).await
+                } else {
+                    AdminResponse::error(401, "unauthorized")
+                }
+            }
+            _ => AdminResponse::error(400, "malformed request"),
+        },
+        Ok(Status::Partial) | Err(_) => AdminResponse::error(400, "malformed request"),
+    };
+
+    socket.write_all(&response.into_bytes()).await?;
+    Ok(())
+}
+
+pub(crate) async fn read_admin_request(socket: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut buff = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let size = socket.read(&mut chunk).await?;
+        if size == 0 {
+            return Ok(Some(buff));
+        }
+        buff.extend_from_slice(&chunk[..size]);
+        if buff.len() > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+
+        let mut headers = [EMPTY_HEADER; 32];
+        let mut request = Request::new(&mut headers);
+        match request.parse(&buff) {
+            Ok(Status::Complete(offset)) => {
+                let length = headers::find_str(request.headers, "Content-Length")
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                let total = offset.saturating_add(length);
+                if total > MAX_REQUEST_BYTES {
+                    return Ok(None);
+                }
+                if buff.len() >= total {
+                    buff.truncate(total);
+                    return Ok(Some(buff));
+                }
+            }
+            Ok(Status::Partial) => {}
+            Err(_) => return Ok(Some(buff)),
+        }
+    }
+}
+
+fn is_authorized(request: &Request<'_, '_>, peer: SocketAddr, ctx: &Context) -> bool {
+    let Some(token) = ctx.config.admin_token.as_deref() else {
+        return peer.ip().is_loopback();
+    };
+    headers::find_str(request.headers, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| token_matches(value, token))
+}
+
+pub(crate) fn token_matches(value: &str, token: &str) -> bool {
+    value.len() == token.len()
+        && value
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn route(method: &str, path: &str, body: &[u8], ctx: &Context) -> AdminResponse {
+    match (method, path) {
+        ("GET", "/sessions") => {
+            let registry = ctx.registry.lock().await;
+            AdminResponse::ok(json!({ "sessions": registry.sessions() }))
+        }
+        ("GET", "/info") => AdminResponse::ok(ctx.runtime.to_json(&ctx.config)),
+        ("GET", "/metrics") => {
+            let mut counters: serde_json::Map<String, Value> = ctx
+                .metrics
+                .counters()
+                .into_iter()
+                .chain(ctx.auth.counters())
+                .chain(ctx.pool.iter().flat_map(|pool| pool.counters()))
+                .chain(ctx.cache.iter().flat_map(|cache| cache.counters()))
+                .map(|(name, value)| (name.to_string(), json!(value)))
+                .collect();
+            let close_reasons: serde_json::Map<String, Value> = ctx
+                .metrics
+                .close_reasons()
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), json!(value)))
+                .collect();
+            counters.insert("tunnels_closed".to_string(), Value::Object(close_reasons));
+            let throttled = ctx.bandwidth.throttled();
+            counters.insert(
+                "bandwidth_throttled_ms_total".to_string(),
+                json!(throttled.iter().map(|(_, ms)| ms).sum::<u64>()),
+            );
+            let per_user: serde_json::Map<String, Value> = throttled
+                .into_iter()
+                .map(|(user, ms)| (user, json!(ms)))
+                .collect();
+            counters.insert("bandwidth_throttled_ms".to_string(), Value::Object(per_user));
+            counters.insert("dial_ms".to_string(), ctx.metrics.dial_latency.to_json());
+            counters.insert(
+                "first_byte_ms".to_string(),
+                ctx.metrics.first_byte_latency.to_json(),
+            );
+            let (tunnel_bytes, tunnel_duration_ms) = ctx.registry.lock().await.tunnel_histograms();
+            counters.insert("tunnel_bytes".to_string(), json!(tunnel_bytes));
+            counters.insert("tunnel_duration_ms".to_string(), json!(tunnel_duration_ms));
+            AdminResponse::ok(Value::Object(counters))
+        }
+        ("GET", "/tenants") => {
+            let registry = ctx.registry.lock().await;
+            AdminResponse::ok(json!({ "tenants": registry.tenant_stats() }))
+        }
+        ("GET", "/egress") => {
+            let usage = ctx.egress.as_ref().map(|pool| pool.usage()).unwrap_or_default();
+            AdminResponse::ok(json!({ "egress": usage }))
+        }
+        (_, "/log") => log_route(method, body),
+        (_, "/maintenance") => maintenance_route(method, body, ctx),
+        (_, "/chaos") => chaos_route(method, body, ctx),
+        (_, "/sessions" | "/metrics" | "/tenants" | "/egress" | "/info") => {
+            AdminResponse::error(405, "method not allowed")
+        }
+        _ if path == "/users" || path.starts_with("/users?") => {
+            users_route(method, path, ctx).await
+        }
+        _ if path.starts_with("/users/") => user_route(method, path, body, ctx).await,
+        _ if path.starts_with("/connections") => connections_route(method, path, ctx),
+        _ => AdminResponse::error(404, "not found"),
+    }
+}
+
+#[derive(Deserialize)]
+struct LogFilter {
+    filter: String,
+}
+
+fn log_route(method: &str, body: &[u8]) -> AdminResponse {
+    let Some(current) = logging::current_filter() else {
+        return AdminResponse::error(501, "log filter is not managed by procent");
+    };
+    match method {
+        "GET" => AdminResponse::ok(json!({ "filter": current })),
+        "PUT" => {
+            let Ok(request) = serde_json::from_slice::<LogFilter>(body) else {
+                return AdminResponse::error(400, "expected {\"filter\": ...}");
+            };
+            match logging::set_filter(&request.filter) {
+                Ok(filter) => {
+                    info!(filter = filter, "Log filter changed");
+                    AdminResponse::ok(json!({ "filter": filter }))
+                }
+                Err(err) => AdminResponse::error(400, &err.to_string()),
+            }
+        }
+        _ => AdminResponse::error(405, "method not allowed"),
+    }
+}
+
+fn connections_route(method: &str, path: &str, ctx: &Context) -> AdminResponse {
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["connections"]) => {
+            let (mut user, mut offset, mut limit) = (None, 0, CONNECTIONS_PAGE);
+            for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+                let parsed = match key {
+                    "user" => {
+                        user = Some(value);
+                        Ok(())
+                    }
+                    "offset" => value.parse().map(|value| offset = value),
+                    "limit" => value.parse().map(|value| limit = value),
+                    _ => Ok(()),
+                };
+                if parsed.is_err() {
+                    return AdminResponse::error(400, "offset and limit must be numbers");
+                }
+            }
+            let limit = limit.min(CONNECTIONS_PAGE);
+            let (total, connections) = ctx.connections.list(user, offset, limit);
+            AdminResponse::ok(json!({
+                "connections": connections,
+                "total": total,
+                "offset": offset,
+                "limit": limit,
+            }))
+        }
+        ("DELETE", ["connections", id]) => match id.parse() {
+            Ok(id) if ctx.connections.kill(id) => {
+                warn!(connection_id = id, "Connection killed over the admin API");
+                AdminResponse::ok(json!({ "killed": id }))
+            }
+            Ok(_) => AdminResponse::error(404, "not found"),
+            Err(_) => AdminResponse::error(400, "connection id must be a number"),
+        },
+        (_, ["connections"] | ["connections", _]) => {
+            AdminResponse::error(405, "method not allowed")
+        }
+        _ => AdminResponse::error(404, "not found"),
+    }
+}
+
+async fn users_route(method: &str, path: &str, ctx: &Context) -> AdminResponse {
+    if method != "GET" {
+        return AdminResponse::error(405, "method not allowed");
+    }
+    let query = path.split_once('?').map_or("", |(_, query)| query);
+    let (mut filter, mut offset, mut limit) = (UserFilter::default(), 0, USERS_PAGE);
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let parsed = match key {
+            "plan" => {
+                filter.plan = Some(value.to_string());
+                Ok(())
+            }
+            "tenant" => {
+                filter.tenant = Some(value.to_string());
+                Ok(())
+            }
+            "q" => {
+                filter.search = Some(value.to_string());
+                Ok(())
+            }
+            "offset" => value.parse().map(|value| offset = value),
+            "limit" => value.parse().map(|value| limit = value),
+            _ => Ok(()),
+        };
+        if parsed.is_err() {
+            return AdminResponse::error(400, "offset and limit must be numbers");
+        }
+    }
+    let limit = limit.min(USERS_PAGE);
+    match ctx.auth.fetch_page(&filter, offset, limit).await {
+        Ok(page) => AdminResponse::ok(json!({
+            "users": page.users.iter().map(user_summary).collect::<Vec<Value>>(),
+            "total": page.total,
+            "offset": offset,
+            "limit": limit,
+        })),
+        Err(err) => AdminResponse::error(501, &err.to_string()),
+    }
+}
+
+fn user_summary(record: &UserRecord) -> Value {
+    let credentials: Vec<&str> = record
+        .credentials
+        .iter()
+        .map(|credential| credential.id.as_str())
+        .collect();
+    json!({
+        "user_id": record.user_id,
+        "username": record.username,
+        "plan": record.plan,
+        "tenant": record.tenant,
+        "private": record.private,
+        "credentials": credentials,
+    })
+}
+
+#[derive(Deserialize)]
+struct MaintenanceToggle {
+    enabled: bool,
+    retry_after: Option<u64>,
+}
+
+fn maintenance_route(method: &str, body: &[u8], ctx: &Context) -> AdminResponse {
+    let retry_after = match method {
+        "GET" => ctx.maintenance.retry_after(),
+        "PUT" => {
+            let Ok(request) = serde_json::from_slice::<MaintenanceToggle>(body) else {
+                return AdminResponse::error(400, "expected {\"enabled\": ...}");
+            };
+            let retry_after = if request.enabled {
+                Some(ctx.maintenance.enable(request.retry_after))
+            } else {
+                ctx.maintenance.set(None);
+                None
+            };
+            warn!(enabled = request.enabled, "Maintenance mode changed");
+            retry_after
+        }
+        _ => return AdminResponse::error(405, "method not allowed"),
+    };
+    AdminResponse::ok(json!({ "enabled": retry_after.is_some(), "retry_after": retry_after }))
+}
+
+fn chaos_route(method: &str, body: &[u8], ctx: &Context) -> AdminResponse {
+    match method {
+        "GET" => {}
+        "PUT" => {
+            let Ok(config) = serde_json::from_slice::<ChaosConfig>(body) else {
+                return AdminResponse::error(400, "expected {\"dial_delay_ms\": ...}");
+            };
+            if let Err(err) = ctx.chaos.set(config) {
+                return AdminResponse::error(400, &err.to_string());
+            }
+            warn!(enabled = config.is_enabled(), "Chaos faults changed");
+        }
+        _ => return AdminResponse::error(405, "method not allowed"),
+    }
+    AdminResponse::ok(json!(ctx.chaos.config()))
+}
+
+#[derive(Deserialize)]
+struct NewCredential {
+    id: Option<String>,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct TopUp {
+    bytes: u64,
+}
+
+async fn user_route(method: &str, path: &str, body: &[u8], ctx: &Context) -> AdminResponse {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let result = match (method, segments.as_slice()) {
+        ("POST", ["users", user, "credentials"]) => {
+            let Ok(request) = serde_json::from_slice::<NewCredential>(body) else {
+                return AdminResponse::error(400, "expected {\"password\": ...}");
+            };
+            let id = request.id.unwrap_or_else(credential_id);
+            let credential = Credential {
+                id: id.clone(),
+                password: request.password,
+            };
+            ctx.auth
+                .add_credential(user, credential)
+                .await
+                .map(|added| added.then(|| json!({ "user": user, "id": id })))
+        }
+        ("DELETE", ["users", user, "credentials", id]) => ctx
+            .auth
+            .revoke_credential(user, id)
+            .await
+            .map(|revoked| revoked.then(|| json!({ "user": user, "revoked": id }))),
+        ("PUT", ["users", user, "username"]) => {
+            let Ok(request) = serde_json::from_slice::<Rename>(body) else {
+                return AdminResponse::error(400, "expected {\"username\": ...}");
+            };
+            match rename_user(ctx, user, &request.username).await {
+                Err(err) if err.is::<UsernameTaken>() => {
+                    return AdminResponse::error(409, &err.to_string());
+                }
+                result => result.map(|renamed| {
+                    renamed.then(|| json!({ "user": user, "username": request.username }))
+                }),
+            }
+        }
+        ("POST", ["users", user, "topup"]) => {
+            return match serde_json::from_slice::<TopUp>(body) {
+                Ok(request) if request.bytes > 0 => top_up(ctx, user, request.bytes).await,
+                _ => AdminResponse::error(400, "expected {\"bytes\": ...}"),
+            };
+        }
+        ("GET", ["users", user, "destinations"]) => Ok(ctx
+            .registry
+            .lock()
+            .await
+            .stats_of(user)
+            .map(|stats| json!({ "user": user, "destinations": stats.destinations }))),
+        (_, ["users", _, section, ..]) if USER_SECTIONS.contains(section) => {
+            return AdminResponse::error(405, "method not allowed");
+        }
+        _ => return AdminResponse::error(404, "not found"),
+    };
+    match result {
+        Ok(Some(body)) => AdminResponse::ok(body),
+        Ok(None) => AdminResponse::error(404, "not found"),
+        Err(err) => AdminResponse::error(501, &err.to_string()),
+    }
+}
+
+async fn rename_user(ctx: &Context, user: &str, username: &str) -> Result<bool> {
+    let previous = persist_rename(ctx, user, username).await?;
+    let renamed = ctx.auth.rename_user(user, username).await;
+    if !matches!(renamed, Ok(true))
+        && let Some(previous) = previous
+        && let Err(err) = persist_rename(ctx, user, &previous).await
+    {
+        warn!(
+            user = user,
+            error = format!("{err:#}"),
+            "Cannot undo the rename in the users file"
+        );
+    }
+    renamed
+}
+
+async fn persist_rename(ctx: &Context, user: &str, username: &str) -> Result<Option<String>> {
+    let Some(path) = ctx.config.users_file.clone() else {
+        return Ok(None);
+    };
+    let key = ctx.config.users_key.clone();
+    let (user, username) = (user.to_string(), username.to_string());
+    tokio::task::spawn_blocking(move || {
+        let mut users = UsersFile::open(&path, key.as_deref())?;
+        let previous = users.rename(&user, &username)?;
+        if previous.is_some() {
+            users.save()?;
+        }
+        Ok(previous)
+    })
+    .await?
+}
+
+async fn top_up(ctx: &Context, user: &str, bytes: u64) -> AdminResponse {
+    let grant = QuotaGrant {
+        user: user.to_string(),
+        granted_bytes: bytes,
+        granted_at: unix_now(),
+    };
+    if let Some(ledger) = &ctx.ledger
+        && let Err(err) = ledger.append_grant(&grant).await
+    {
+        return AdminResponse::error(500, &format!("Cannot record the grant: {err}"));
+    }
+    let granted = ctx.registry.lock().await.top_up(user, u128::from(bytes));
+    info!(user = user, bytes = bytes, "Traffic quota topped up");
+    match ctx.auth.limits(user).await {
+        Ok(limits) => AdminResponse::ok(json!({
+            "user": user,
+            "bytes": bytes,
+            "granted": granted,
+            "traffic_limit": limits.topped_up(granted).traffic().restricted(),
+        })),
+        Err(err) => AdminResponse::error(501, &err.to_string()),
+    }
+}
+
+fn credential_id() -> String {
+    let mut bytes = [0u8; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return unix_now().to_string();
+    }
+    bytes.iter().fold(String::new(), |mut id, byte| {
+        let _ = write!(id, "{byte:02x}");
+        id
+    })
+}
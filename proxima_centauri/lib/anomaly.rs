@@ -0,0 +1,191 @@
+use crate::context::Context;
+use crate::events::Event;
+use crate::metrics::Metrics;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnomalyConfig {
+    pub interval: Duration,
+    pub window: usize,
+    pub multiplier: f64,
+    pub min_rate: u64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_mins(1),
+            window: 10,
+            multiplier: 5.0,
+            min_rate: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Anomaly {
+    pub(crate) user: String,
+    pub(crate) rate: u64,
+    pub(crate) average: u64,
+}
+
+#[derive(Default)]
+struct History {
+    total: u128,
+    rates: VecDeque<u64>,
+    alerting: bool,
+}
+
+pub(crate) struct AnomalyDetector {
+    config: AnomalyConfig,
+    users: HashMap<String, History>,
+}
+
+impl AnomalyDetector {
+    pub(crate) fn new(config: AnomalyConfig) -> Self {
+        Self {
+            config,
+            users: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, traffic: Vec<(String, u128, u128)>) -> Vec<Anomaly> {
+        let seconds = self.config.interval.as_secs().max(1);
+        let mut anomalies = Vec::new();
+        self.users
+            .retain(|user, _| traffic.iter().any(|(name, _, _)| name == user));
+        for (user, ingress, egress) in traffic {
+            let total = ingress + egress;
+            let Some(history) = self.users.get_mut(&user) else {
+                self.users.insert(
+                    user,
+                    History {
+                        total,
+                        ..History::default()
+                    },
+                );
+                continue;
+            };
+            let delta = total.saturating_sub(history.total);
+            history.total = total;
+            let rate = u64::try_from(delta / u128::from(seconds)).unwrap_or(u64::MAX);
+            let average = average(&history.rates);
+            let anomalous = history.rates.len() >= self.config.window
+                && rate >= self.config.min_rate
+                && exceeds(rate, average, self.config.multiplier);
+            if anomalous && !history.alerting {
+                anomalies.push(Anomaly {
+                    user,
+                    rate,
+                    average,
+                });
+            }
+            history.alerting = anomalous;
+            if !anomalous {
+                history.rates.push_back(rate);
+                if history.rates.len() > self.config.window {
+                    history.rates.pop_front();
+                }
+            }
+        }
+        anomalies.sort_by(|a, b| a.user.cmp(&b.user));
+        anomalies
+    }
+}
+
+fn average(rates: &VecDeque<u64>) -> u64 {
+    let count = u64::try_from(rates.len()).unwrap_or(u64::MAX).max(1);
+    rates.iter().sum::<u64>() / count
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn exceeds(rate: u64, average: u64, multiplier: f64) -> bool {
+    rate as f64 > average as f64 * multiplier
+}
+
+pub(crate) async fn watch(ctx: Context, config: AnomalyConfig, shutdown: CancellationToken) {
+    let mut detector = AnomalyDetector::new(config);
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => break,
+            () = sleep(config.interval) => {}
+        }
+        let traffic = ctx.registry.lock().await.traffic();
+        for anomaly in detector.observe(traffic) {
+            warn!(
+                user = anomaly.user,
+                rate = anomaly.rate,
+                average = anomaly.average,
+                "Traffic rate anomaly"
+            );
+            Metrics::inc(&ctx.metrics.traffic_anomalies);
+            ctx.events.emit(Event::TrafficAnomaly {
+                user: anomaly.user,
+                rate: anomaly.rate,
+                average: anomaly.average,
+                multiplier: config.multiplier,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(user: &str, total: u128) -> Vec<(String, u128, u128)> {
+        vec![(user.to_string(), total, 0)]
+    }
+
+    fn detector() -> AnomalyDetector {
+        AnomalyDetector::new(AnomalyConfig {
+            interval: Duration::from_secs(1),
+            window: 3,
+            multiplier: 4.0,
+            min_rate: 100,
+        })
+    }
+
+    #[test]
+    fn flags_spike_once_after_warm_up() {
+        let mut detector = detector();
+        let mut total = 0;
+        for _ in 0..4 {
+            total += 1_000;
+            assert!(detector.observe(sample("alice", total)).is_empty());
+        }
+
+        total += 10_000;
+        let anomalies = detector.observe(sample("alice", total));
+        assert_eq!(
+            anomalies,
+            vec![Anomaly {
+                user: "alice".to_string(),
+                rate: 10_000,
+                average: 1_000,
+            }]
+        );
+
+        total += 10_000;
+        assert!(detector.observe(sample("alice", total)).is_empty());
+        total += 1_000;
+        assert!(detector.observe(sample("alice", total)).is_empty());
+        total += 10_000;
+        assert_eq!(detector.observe(sample("alice", total)).len(), 1);
+    }
+
+    #[test]
+    fn ignores_rates_below_floor_and_short_history() {
+        let mut detector = detector();
+        assert!(detector.observe(sample("bob", 0)).is_empty());
+        assert!(detector.observe(sample("bob", 10)).is_empty());
+        assert!(detector.observe(sample("bob", 10_000)).is_empty());
+        for total in [10_010, 10_020, 10_030, 10_080] {
+            assert!(detector.observe(sample("bob", total)).is_empty());
+        }
+    }
+}
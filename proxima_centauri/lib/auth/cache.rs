@@ -0,0 +1,319 @@
+use crate::auth::{AuthProvider, Credential, UserFilter, UserPage, UserRecord};
+use crate::clock::{self, Clock};
+use crate::config::Config;
+use crate::registry::Limits;
+use crate::routing::Route;
+use crate::schedule::Schedule;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+enum Cached {
+    Accepted {
+        password: String,
+        expires_at: Instant,
+    },
+    Unknown {
+        expires_at: Instant,
+    },
+}
+
+impl Cached {
+    const fn expires_at(&self) -> Instant {
+        match self {
+            Self::Accepted { expires_at, .. } | Self::Unknown { expires_at } => *expires_at,
+        }
+    }
+}
+
+pub struct CachedAuthProvider {
+    inner: Arc<dyn AuthProvider>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    capacity: usize,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<HashMap<String, Cached>>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CachedAuthProvider {
+    pub fn new(inner: Arc<dyn AuthProvider>) -> Self {
+        Self {
+            inner,
+            ttl: Duration::from_mins(1),
+            negative_ttl: Duration::from_secs(30),
+            capacity: 10_000,
+            clock: clock::system(),
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    #[must_use]
+    pub const fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    #[must_use]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn cached(&self, login: &str, password: &str) -> Option<bool> {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        match entries.get(login)? {
+            Cached::Accepted {
+                password: accepted,
+                expires_at,
+            } if *expires_at > now && accepted == password => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(true)
+            }
+            Cached::Unknown { expires_at } if *expires_at > now => {
+                self.negative_hits.fetch_add(1, Ordering::Relaxed);
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+
+    fn store(&self, login: &str, entry: Cached) {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= self.capacity && !entries.contains_key(login) {
+            entries.retain(|_, cached| cached.expires_at() > now);
+            if entries.len() >= self.capacity {
+                return;
+            }
+        }
+        entries.insert(login.to_string(), entry);
+    }
+
+    fn invalidate(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+}
+
+#[async_trait]
+impl AuthProvider for CachedAuthProvider {
+    async fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        if let Some(accepted) = self.cached(user, password) {
+            return Ok(accepted);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if self.inner.authenticate(user, password).await? {
+            let expires_at = self.clock.now() + self.ttl;
+            self.store(
+                user,
+                Cached::Accepted {
+                    password: password.to_string(),
+                    expires_at,
+                },
+            );
+            return Ok(true);
+        }
+        if !self.inner.user_exists(user).await? {
+            let expires_at = self.clock.now() + self.negative_ttl;
+            self.store(user, Cached::Unknown { expires_at });
+        }
+        Ok(false)
+    }
+
+    async fn user_exists(&self, login: &str) -> Result<bool> {
+        self.inner.user_exists(login).await
+    }
+
+    async fn account(&self, login: &str) -> Result<String> {
+        self.inner.account(login).await
+    }
+
+    async fn limits(&self, user: &str) -> Result<Limits> {
+        self.inner.limits(user).await
+    }
+
+    async fn is_private(&self, user: &str) -> Result<bool> {
+        self.inner.is_private(user).await
+    }
+
+    async fn is_intercepted(&self, user: &str) -> Result<bool> {
+        self.inner.is_intercepted(user).await
+    }
+
+    async fn routes(&self, user: &str) -> Result<Vec<Route>> {
+        self.inner.routes(user).await
+    }
+
+    async fn tenant(&self, user: &str) -> Result<Option<String>> {
+        self.inner.tenant(user).await
+    }
+
+    async fn schedule(&self, user: &str) -> Result<Option<Schedule>> {
+        self.inner.schedule(user).await
+    }
+
+    async fn add_credential(&self, user: &str, credential: Credential) -> Result<bool> {
+        let added = self.inner.add_credential(user, credential).await;
+        self.invalidate();
+        added
+    }
+
+    async fn revoke_credential(&self, user: &str, id: &str) -> Result<bool> {
+        let revoked = self.inner.revoke_credential(user, id).await;
+        self.invalidate();
+        revoked
+    }
+
+    async fn rename_user(&self, user: &str, username: &str) -> Result<bool> {
+        let renamed = self.inner.rename_user(user, username).await;
+        self.invalidate();
+        renamed
+    }
+
+    async fn fetch_all(&self, filter: &UserFilter) -> Result<Vec<UserRecord>> {
+        self.inner.fetch_all(filter).await
+    }
+
+    async fn fetch_page(
+        &self,
+        filter: &UserFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<UserPage> {
+        self.inner.fetch_page(filter, offset, limit).await
+    }
+
+    async fn reload(&self, config: &Config) -> Result<()> {
+        let reloaded = self.inner.reload(config).await;
+        self.invalidate();
+        reloaded
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.inner.health_check().await
+    }
+
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        let mut counters = vec![
+            ("auth_cache_hits_total", self.hits.load(Ordering::Relaxed)),
+            (
+                "auth_cache_negative_hits_total",
+                self.negative_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "auth_cache_misses_total",
+                self.misses.load(Ordering::Relaxed),
+            ),
+        ];
+        counters.extend(self.inner.counters());
+        counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Database, UserRecord};
+    use crate::clock::MockClock;
+
+    struct Counting {
+        database: Database,
+        calls: AtomicU64,
+    }
+
+    #[async_trait]
+    impl AuthProvider for Counting {
+        async fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            self.database.authenticate(user, password).await
+        }
+
+        async fn user_exists(&self, login: &str) -> Result<bool> {
+            self.database.user_exists(login).await
+        }
+    }
+
+    fn counting() -> Arc<Counting> {
+        Arc::new(Counting {
+            database: Database::with_users([UserRecord::new("alice", "secret")]),
+            calls: AtomicU64::new(0),
+        })
+    }
+
+    #[tokio::test]
+    async fn caches_logins_until_the_ttl_expires() -> Result<()> {
+        let backend = counting();
+        let clock = MockClock::new();
+        let cache = CachedAuthProvider::new(backend.clone())
+            .with_ttl(Duration::from_mins(1))
+            .with_clock(clock.clone());
+
+        assert!(cache.authenticate("alice", "secret").await?);
+        assert!(cache.authenticate("alice", "secret").await?);
+        assert!(!cache.authenticate("alice", "guess").await?);
+        assert!(!cache.authenticate("alice", "guess").await?);
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 3);
+
+        clock.advance(Duration::from_secs(61));
+        assert!(cache.authenticate("alice", "secret").await?);
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 4);
+        assert_eq!(cache.counters()[0], ("auth_cache_hits_total", 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_logins_are_rejected_from_the_cache() -> Result<()> {
+        let backend = counting();
+        let clock = MockClock::new();
+        let cache = CachedAuthProvider::new(backend.clone())
+            .with_negative_ttl(Duration::from_secs(30))
+            .with_clock(clock.clone());
+
+        for password in ["a", "b", "c"] {
+            assert!(!cache.authenticate("mallory", password).await?);
+        }
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.counters()[1], ("auth_cache_negative_hits_total", 2));
+
+        clock.advance(Duration::from_secs(31));
+        assert!(!cache.authenticate("mallory", "d").await?);
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_cache_keeps_working_without_storing() -> Result<()> {
+        let backend = counting();
+        let cache = CachedAuthProvider::new(backend.clone()).with_capacity(1);
+
+        assert!(!cache.authenticate("mallory", "x").await?);
+        assert!(cache.authenticate("alice", "secret").await?);
+        assert!(cache.authenticate("alice", "secret").await?);
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::auth::{AuthProvider, Credential, UserFilter, UserPage, UserRecord};
+use crate::config::Config;
+use crate::registry::Limits;
+use crate::routing::Route;
+use crate::schedule::Schedule;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::{Instant, timeout};
+use tracing::{info, warn};
+
+type BackendCall<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+pub struct FailoverAuthProvider {
+    primary: Arc<dyn AuthProvider>,
+    fallback: Arc<dyn AuthProvider>,
+    timeout: Duration,
+    probe_interval: Duration,
+    degraded: AtomicBool,
+    last_probe: Mutex<Instant>,
+    failovers: AtomicU64,
+    recoveries: AtomicU64,
+}
+
+impl FailoverAuthProvider {
+    pub fn new(primary: Arc<dyn AuthProvider>, fallback: Arc<dyn AuthProvider>) -> Self {
+        Self {
+            primary,
+            fallback,
+            timeout: Duration::from_secs(2),
+            probe_interval: Duration::from_secs(10),
+            degraded: AtomicBool::new(false),
+            last_probe: Mutex::new(Instant::now()),
+            failovers: AtomicU64::new(0),
+            recoveries: AtomicU64::new(0),
+        }
+    }
+
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    async fn dispatch<'a, T>(
+        &'a self,
+        call: impl Fn(&'a dyn AuthProvider) -> BackendCall<'a, T> + Send,
+    ) -> Result<T> {
+        if self.primary_available().await {
+            match timeout(self.timeout, call(self.primary.as_ref())).await {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(err)) => self.fail_over(&err),
+                Err(_) => self.fail_over(&anyhow!("no answer within {:?}", self.timeout)),
+            }
+        }
+        call(self.fallback.as_ref()).await
+    }
+
+    async fn primary_available(&self) -> bool {
+        if !self.is_degraded() {
+            return true;
+        }
+        {
+            let mut last_probe = self
+                .last_probe
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if last_probe.elapsed() < self.probe_interval {
+                return false;
+            }
+            *last_probe = Instant::now();
+        }
+        match timeout(self.timeout, self.primary.health_check()).await {
+            Ok(Ok(())) => {
+                if self.degraded.swap(false, Ordering::Relaxed) {
+                    self.recoveries.fetch_add(1, Ordering::Relaxed);
+                    info!("Primary auth backend recovered, leaving fallback");
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn fail_over(&self, err: &anyhow::Error) {
+        *self
+            .last_probe
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+        if !self.degraded.swap(true, Ordering::Relaxed) {
+            self.failovers.fetch_add(1, Ordering::Relaxed);
+            warn!(error = %err, "Primary auth backend failed, switching to fallback");
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for FailoverAuthProvider {
+    async fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        self.dispatch(|backend| backend.authenticate(user, password))
+            .await
+    }
+
+    async fn user_exists(&self, login: &str) -> Result<bool> {
+        self.dispatch(|backend| backend.user_exists(login)).await
+    }
+
+    async fn account(&self, login: &str) -> Result<String> {
+        self.dispatch(|backend| backend.account(login)).await
+    }
+
+    async fn limits(&self, user: &str) -> Result<Limits> {
+        self.dispatch(|backend| backend.limits(user)).await
+    }
+
+    async fn is_private(&self, user: &str) -> Result<bool> {
+        self.dispatch(|backend| backend.is_private(user)).await
+    }
+
+    async fn is_intercepted(&self, user: &str) -> Result<bool> {
+        self.dispatch(|backend| backend.is_intercepted(user)).await
+    }
+
+    async fn routes(&self, user: &str) -> Result<Vec<Route>> {
+        self.dispatch(|backend| backend.routes(user)).await
+    }
+
+    async fn tenant(&self, user: &str) -> Result<Option<String>> {
+        self.dispatch(|backend| backend.tenant(user)).await
+    }
+
+    async fn schedule(&self, user: &str) -> Result<Option<Schedule>> {
+        self.dispatch(|backend| backend.schedule(user)).await
+    }
+
+    async fn add_credential(&self, user: &str, credential: Credential) -> Result<bool> {
+        self.primary.add_credential(user, credential).await
+    }
+
+    async fn revoke_credential(&self, user: &str, id: &str) -> Result<bool> {
+        self.primary.revoke_credential(user, id).await
+    }
+
+    async fn rename_user(&self, user: &str, username: &str) -> Result<bool> {
+        self.primary.rename_user(user, username).await
+    }
+
+    async fn fetch_all(&self, filter: &UserFilter) -> Result<Vec<UserRecord>> {
+        self.dispatch(|backend| backend.fetch_all(filter)).await
+    }
+
+    async fn fetch_page(
+        &self,
+        filter: &UserFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<UserPage> {
+        self.dispatch(|backend| backend.fetch_page(filter, offset, limit))
+            .await
+    }
+
+    async fn reload(&self, config: &Config) -> Result<()> {
+        self.fallback.reload(config).await?;
+        self.primary.reload(config).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.fallback.health_check().await
+    }
+
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            (
+                "auth_failovers_total",
+                self.failovers.load(Ordering::Relaxed),
+            ),
+            (
+                "auth_recoveries_total",
+                self.recoveries.load(Ordering::Relaxed),
+            ),
+            ("auth_degraded", u64::from(self.is_degraded())),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Database;
+    use anyhow::bail;
+
+    struct Flaky {
+        down: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl AuthProvider for Flaky {
+        async fn authenticate(&self, user: &str, _password: &str) -> Result<bool> {
+            if self.down.load(Ordering::Relaxed) {
+                bail!("connection refused");
+            }
+            Ok(user == "primary")
+        }
+
+        async fn health_check(&self) -> Result<()> {
+            if self.down.load(Ordering::Relaxed) {
+                bail!("connection refused");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_recovers() -> Result<()> {
+        let down = Arc::new(AtomicBool::new(false));
+        let primary = Arc::new(Flaky { down: down.clone() });
+        let provider = FailoverAuthProvider::new(primary, Arc::new(Database::new_persistence()))
+            .with_probe_interval(Duration::ZERO);
+
+        assert!(provider.authenticate("primary", "").await?);
+        assert!(!provider.authenticate("procent", "o953zY7lnkYMEl5D").await?);
+
+        down.store(true, Ordering::Relaxed);
+        assert!(provider.authenticate("procent", "o953zY7lnkYMEl5D").await?);
+        assert!(provider.is_degraded());
+        assert!(!provider.authenticate("primary", "").await?);
+
+        down.store(false, Ordering::Relaxed);
+        assert!(provider.authenticate("primary", "").await?);
+        assert_eq!(
+            provider.counters(),
+            vec![
+                ("auth_failovers_total", 1),
+                ("auth_recoveries_total", 1),
+                ("auth_degraded", 0),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_primary_until_probe_is_due() -> Result<()> {
+        let down = Arc::new(AtomicBool::new(true));
+        let primary = Arc::new(Flaky { down: down.clone() });
+        let provider = FailoverAuthProvider::new(primary, Arc::new(Database::new_persistence()))
+            .with_probe_interval(Duration::from_mins(1));
+
+        assert!(provider.authenticate("procent", "o953zY7lnkYMEl5D").await?);
+        down.store(false, Ordering::Relaxed);
+        assert!(!provider.authenticate("primary", "").await?);
+        assert!(provider.is_degraded());
+        Ok(())
+    }
+}
@@ -0,0 +1,265 @@
+use crate::auth::AuthProvider;
+use crate::registry::Limits;
+use anyhow::{Context as _, Result, anyhow};
+use async_trait::async_trait;
+use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry, dn_escape};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+
+const INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Clone, Debug)]
+pub struct LdapConfig {
+    pub url: String,
+    pub bind_dn_template: String,
+    pub group_attribute: String,
+    pub starttls: bool,
+    pub connect_timeout: Duration,
+    pub cache_ttl: Duration,
+    pub group_limits: Vec<(String, Limits)>,
+    pub default_limits: Limits,
+}
+
+impl LdapConfig {
+    pub fn new(url: impl Into<String>, bind_dn_template: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            bind_dn_template: bind_dn_template.into(),
+            group_attribute: String::from("memberOf"),
+            starttls: false,
+            connect_timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_mins(1),
+            group_limits: Vec::new(),
+            default_limits: Limits::with_low_limits(),
+        }
+    }
+
+    pub fn from_env() -> Result<Option<Self>> {
+        let (Ok(url), Ok(template)) = (
+            dotenv::var("LDAP_URL"),
+            dotenv::var("LDAP_BIND_DN_TEMPLATE"),
+        ) else {
+            return Ok(None);
+        };
+        let mut config = Self::new(url, template);
+        config.starttls = dotenv::var("LDAP_STARTTLS").is_ok_and(|value| value == "true");
+        if let Ok(attribute) = dotenv::var("LDAP_GROUP_ATTRIBUTE") {
+            config.group_attribute = attribute;
+        }
+        if let Ok(groups) = dotenv::var("LDAP_GROUP_LIMITS") {
+            config.group_limits = parse_group_limits(&groups)?;
+        }
+        Ok(Some(config))
+    }
+
+    #[must_use]
+    pub fn with_group_limits(mut self, group: impl Into<String>, limits: Limits) -> Self {
+        self.group_limits.push((group.into(), limits));
+        self
+    }
+
+    fn bind_dn(&self, user: &str) -> String {
+        self.bind_dn_template.replace("%u", &dn_escape(user))
+    }
+
+    fn limits_for_groups(&self, groups: &[String]) -> Limits {
+        self.group_limits
+            .iter()
+            .find(|(group, _)| groups.iter().any(|g| g.eq_ignore_ascii_case(group)))
+            .map_or(self.default_limits, |(_, limits)| *limits)
+    }
+}
+
+fn parse_group_limits(value: &str) -> Result<Vec<(String, Limits)>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (group, limits) = entry
+                .rsplit_once('=')
+                .filter(|(group, _)| !group.trim().is_empty())
+                .ok_or_else(|| {
+                    anyhow!("LDAP_GROUP_LIMITS entry `{entry}` must be `group=limits`")
+                })?;
+            let limits = limits
+                .trim()
+                .parse()
+                .with_context(|| format!("LDAP_GROUP_LIMITS entry `{entry}`"))?;
+            Ok((group.trim().to_string(), limits))
+        })
+        .collect()
+}
+
+struct CachedBind {
+    tag: hmac::Tag,
+    limits: Limits,
+    expires_at: Instant,
+}
+
+pub struct LdapAuthProvider {
+    config: LdapConfig,
+    key: hmac::Key,
+    cache: Mutex<HashMap<String, CachedBind>>,
+}
+
+impl LdapAuthProvider {
+    pub fn new(config: LdapConfig) -> Result<Self> {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate the LDAP cache key"))?;
+        Ok(Self {
+            config,
+            key,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn connect(&self) -> Result<ldap3::Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_starttls(self.config.starttls)
+            .set_conn_timeout(self.config.connect_timeout);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    async fn bind(&self, user: &str, password: &str) -> Result<Option<Limits>> {
+        let mut ldap = self.connect().await?;
+        let dn = self.config.bind_dn(user);
+        let bind = ldap.simple_bind(&dn, password).await?;
+        if bind.rc == INVALID_CREDENTIALS {
+            ldap.unbind().await.ok();
+            return Ok(None);
+        }
+        bind.success()?;
+
+        let (entries, _) = ldap
+            .search(
+                &dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec![self.config.group_attribute.as_str()],
+            )
+            .await?
+            .success()?;
+        ldap.unbind().await.ok();
+
+        let groups: Vec<String> = entries
+            .into_iter()
+            .map(SearchEntry::construct)
+            .filter_map(|mut entry| entry.attrs.remove(&self.config.group_attribute))
+            .flatten()
+            .collect();
+        debug!(user = user, groups = format!("{:?}", groups));
+
+        Ok(Some(self.config.limits_for_groups(&groups)))
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LdapAuthProvider {
+    async fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        if password.is_empty() {
+            return Ok(false);
+        }
+        {
+            let cache = self.cache.lock().await;
+            if let Some(cached) = cache.get(user)
+                && cached.expires_at > Instant::now()
+                && hmac::verify(&self.key, password.as_bytes(), cached.tag.as_ref()).is_ok()
+            {
+                return Ok(true);
+            }
+        }
+
+        let Some(limits) = self.bind(user, password).await? else {
+            self.cache.lock().await.remove(user);
+            return Ok(false);
+        };
+
+        self.cache.lock().await.insert(
+            user.to_string(),
+            CachedBind {
+                tag: hmac::sign(&self.key, password.as_bytes()),
+                limits,
+                expires_at: Instant::now() + self.config.cache_ttl,
+            },
+        );
+        Ok(true)
+    }
+
+    async fn limits(&self, user: &str) -> Result<Limits> {
+        Ok(self
+            .cache
+            .lock()
+            .await
+            .get(user)
+            .map_or(self.config.default_limits, |cached| cached.limits))
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.connect().await?.unbind().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::LimitValue;
+
+    #[test]
+    fn bind_dn_escapes_user() {
+        let config = LdapConfig::new("ldap://localhost", "uid=%u,ou=people,dc=example");
+        assert_eq!(config.bind_dn("a,b"), "uid=a\\2cb,ou=people,dc=example");
+    }
+
+    #[test]
+    fn first_matching_group_wins() {
+        let pro = Limits::new(LimitValue::Restricted(10), LimitValue::Unrestricted);
+        let free = Limits::with_low_limits();
+        let config = LdapConfig::new("ldap://localhost", "uid=%u")
+            .with_group_limits("cn=pro,dc=example", pro)
+            .with_group_limits("cn=free,dc=example", free);
+
+        let groups = vec![
+            String::from("CN=free,dc=example"),
+            String::from("cn=pro,dc=example"),
+        ];
+        assert_eq!(config.limits_for_groups(&groups), pro);
+        assert_eq!(config.limits_for_groups(&[]), Limits::with_low_limits());
+    }
+
+    #[test]
+    fn parses_group_limits_from_env_format() -> Result<()> {
+        let groups = parse_group_limits("cn=pro,dc=example=10:*; cn=free,dc=example=2:10000;")?;
+        assert_eq!(
+            groups,
+            [
+                (
+                    String::from("cn=pro,dc=example"),
+                    Limits::new(LimitValue::Restricted(10), LimitValue::Unrestricted)
+                ),
+                (
+                    String::from("cn=free,dc=example"),
+                    Limits::new(LimitValue::Restricted(2), LimitValue::Restricted(10_000))
+                ),
+            ]
+        );
+        assert!(parse_group_limits("cn=pro,dc=example").is_err());
+        assert!(parse_group_limits("=2:100").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_password_is_rejected_without_binding() -> Result<()> {
+        let provider = LdapAuthProvider::new(LdapConfig::new("ldap://127.0.0.1:1", "uid=%u"))?;
+        assert!(!provider.authenticate("alice", "").await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,666 @@
+mod cache;
+mod failover;
+#[cfg(feature = "ldap")]
+mod ldap;
+mod users_edit;
+mod users_file;
+
+use crate::config::Config;
+use crate::registry::Limits;
+use crate::routing::Route;
+use crate::schedule::Schedule;
+use anyhow::{Result, anyhow, bail};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use base64::Engine as _;
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use thiserror::Error;
+
+pub use cache::CachedAuthProvider;
+pub use failover::FailoverAuthProvider;
+#[cfg(feature = "ldap")]
+pub use ldap::{LdapAuthProvider, LdapConfig};
+pub(crate) use users_file::{cipher, load_rows, screen_users, user_problems};
+pub use users_edit::UsersFile;
+pub use users_file::{encrypt_users, load_users, parse_users};
+
+const BASIC_TOKEN: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    async fn authenticate(&self, user: &str, password: &str) -> Result<bool>;
+
+    async fn user_exists(&self, _login: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    async fn account(&self, login: &str) -> Result<String> {
+        Ok(login.to_string())
+    }
+
+    async fn limits(&self, _user: &str) -> Result<Limits> {
+        Ok(Limits::with_low_limits())
+    }
+
+    async fn is_private(&self, _user: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn is_intercepted(&self, _user: &str) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn routes(&self, _user: &str) -> Result<Vec<Route>> {
+        Ok(Vec::new())
+    }
+
+    async fn tenant(&self, _user: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn schedule(&self, _user: &str) -> Result<Option<Schedule>> {
+        Ok(None)
+    }
+
+    async fn add_credential(&self, _user: &str, _credential: Credential) -> Result<bool> {
+        bail!("Credential rotation is not supported by this auth provider")
+    }
+
+    async fn revoke_credential(&self, _user: &str, _id: &str) -> Result<bool> {
+        bail!("Credential rotation is not supported by this auth provider")
+    }
+
+    async fn rename_user(&self, _user: &str, _username: &str) -> Result<bool> {
+        bail!("Renaming users is not supported by this auth provider")
+    }
+
+    async fn fetch_all(&self, _filter: &UserFilter) -> Result<Vec<UserRecord>> {
+        bail!("Listing users is not supported by this auth provider")
+    }
+
+    async fn fetch_page(
+        &self,
+        filter: &UserFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<UserPage> {
+        let users = self.fetch_all(filter).await?;
+        Ok(UserPage {
+            total: users.len(),
+            users: users.into_iter().skip(offset).take(limit).collect(),
+        })
+    }
+
+    async fn reload(&self, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn counters(&self) -> Vec<(&'static str, u64)> {
+        Vec::new()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Username `{0}` is already taken")]
+pub struct UsernameTaken(pub String);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credential {
+    pub id: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserRecord {
+    pub user_id: String,
+    pub username: String,
+    pub password: String,
+    pub limits: Limits,
+    pub plan: Option<String>,
+    pub private: bool,
+    pub intercept: bool,
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+    pub credentials: Vec<Credential>,
+    pub routes: Vec<Route>,
+    pub tenant: Option<String>,
+    pub schedule: Option<Schedule>,
+    pub status: UserStatus,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum UserStatus {
+    #[default]
+    Active,
+    Banned,
+    Unknown(String),
+}
+
+impl FromStr for UserStatus {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "ok" => Self::Active,
+            "banned" => Self::Banned,
+            other => Self::Unknown(other.to_string()),
+        })
+    }
+}
+
+impl Display for UserStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Active => f.write_str("ok"),
+            Self::Banned => f.write_str("banned"),
+            Self::Unknown(status) => f.write_str(status),
+        }
+    }
+}
+
+impl UserRecord {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        let username = username.into();
+        Self {
+            user_id: username.clone(),
+            username,
+            password: password.into(),
+            limits: Limits::with_low_limits(),
+            plan: None,
+            private: false,
+            intercept: false,
+            proxy_username: None,
+            proxy_password: None,
+            credentials: Vec::new(),
+            routes: Vec::new(),
+            tenant: None,
+            schedule: None,
+            status: UserStatus::Active,
+        }
+    }
+
+    pub fn proxy_login(&self) -> &str {
+        self.proxy_username.as_deref().unwrap_or(&self.username)
+    }
+
+    pub fn accepts(&self, password: &str) -> bool {
+        self.status == UserStatus::Active
+            && (self.proxy_password.as_deref().unwrap_or(&self.password) == password
+                || self
+                    .credentials
+                    .iter()
+                    .any(|credential| credential.password == password))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserFilter {
+    pub plan: Option<String>,
+    pub tenant: Option<String>,
+    pub search: Option<String>,
+}
+
+impl UserFilter {
+    pub fn matches(&self, record: &UserRecord) -> bool {
+        self.plan
+            .as_ref()
+            .is_none_or(|plan| record.plan.as_ref() == Some(plan))
+            && self
+                .tenant
+                .as_ref()
+                .is_none_or(|tenant| record.tenant.as_ref() == Some(tenant))
+            && self.search.as_ref().is_none_or(|search| {
+                record
+                    .username
+                    .to_lowercase()
+                    .contains(&search.to_lowercase())
+            })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserPage {
+    pub users: Vec<UserRecord>,
+    pub total: usize,
+}
+
+type Records = HashMap<String, UserRecord>;
+
+#[derive(Default)]
+struct Users {
+    records: Records,
+    logins: HashMap<String, String>,
+}
+
+impl From<Records> for Users {
+    fn from(records: Records) -> Self {
+        let logins = records
+            .values()
+            .map(|record| (record.proxy_login().to_string(), record.user_id.clone()))
+            .collect();
+        Self { records, logins }
+    }
+}
+
+pub struct Database {
+    users: ArcSwap<Users>,
+    writer: Mutex<()>,
+    plans: RwLock<HashMap<String, Limits>>,
+}
+
+impl Database {
+    pub fn new_persistence() -> Self {
+        Self::with_users([
+            UserRecord::new("procent", "o953zY7lnkYMEl5D"),
+            UserRecord::new("admin", "12345"),
+        ])
+    }
+
+    pub fn with_users(records: impl IntoIterator<Item = UserRecord>) -> Self {
+        Self {
+            users: ArcSwap::from_pointee(Users::from(index(records))),
+            writer: Mutex::new(()),
+            plans: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&mut self, record: UserRecord) {
+        self.update(|users| users.insert(record.user_id.clone(), record));
+    }
+
+    pub fn replace_users(&self, records: impl IntoIterator<Item = UserRecord>) {
+        let users = Arc::new(Users::from(index(records)));
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        self.users.store(users);
+    }
+
+    fn update<T>(&self, apply: impl FnOnce(&mut Records) -> T) -> T {
+        let _writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut records = self.users.load().records.clone();
+        let result = apply(&mut records);
+        self.users.store(Arc::new(Users::from(records)));
+        result
+    }
+
+    pub fn set_plans(&self, plans: HashMap<String, Limits>) {
+        *self.plans.write().unwrap_or_else(PoisonError::into_inner) = plans;
+    }
+
+    fn record_limits(&self, record: &UserRecord) -> Limits {
+        record
+            .plan
+            .as_ref()
+            .and_then(|plan| {
+                self.plans
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(plan)
+                    .copied()
+            })
+            .unwrap_or(record.limits)
+    }
+
+    pub fn is_authenticated(&self, login: &str, password: &str) -> bool {
+        self.login(login)
+            .is_some_and(|record| record.accepts(password))
+    }
+
+    fn page(&self, filter: &UserFilter, offset: usize, limit: usize) -> UserPage {
+        let users = self.users.load();
+        let mut matching: Vec<&UserRecord> = users
+            .records
+            .values()
+            .filter(|record| filter.matches(record))
+            .collect();
+        matching.sort_by(|a, b| a.username.cmp(&b.username));
+        UserPage {
+            total: matching.len(),
+            users: matching
+                .into_iter()
+                .skip(offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn record(&self, user: &str) -> Option<UserRecord> {
+        self.users.load().records.get(user).cloned()
+    }
+
+    fn login(&self, login: &str) -> Option<UserRecord> {
+        let users = self.users.load();
+        users
+            .logins
+            .get(login)
+            .and_then(|user| users.records.get(user))
+            .cloned()
+    }
+}
+
+fn index(records: impl IntoIterator<Item = UserRecord>) -> Records {
+    records
+        .into_iter()
+        .map(|record| (record.user_id.clone(), record))
+        .collect()
+}
+
+#[async_trait]
+impl AuthProvider for Database {
+    async fn authenticate(&self, user: &str, password: &str) -> Result<bool> {
+        Ok(self.is_authenticated(user, password))
+    }
+
+    async fn user_exists(&self, login: &str) -> Result<bool> {
+        Ok(self.login(login).is_some())
+    }
+
+    async fn account(&self, login: &str) -> Result<String> {
+        Ok(self
+            .login(login)
+            .map_or_else(|| login.to_string(), |record| record.user_id))
+    }
+
+    async fn limits(&self, user: &str) -> Result<Limits> {
+        Ok(self
+            .record(user)
+            .map_or_else(Limits::with_low_limits, |record| {
+                self.record_limits(&record)
+            }))
+    }
+
+    async fn is_private(&self, user: &str) -> Result<bool> {
+        Ok(self.record(user).is_some_and(|record| record.private))
+    }
+
+    async fn is_intercepted(&self, user: &str) -> Result<bool> {
+        Ok(self.record(user).is_some_and(|record| record.intercept))
+    }
+
+    async fn routes(&self, user: &str) -> Result<Vec<Route>> {
+        Ok(self.record(user).map(|record| record.routes).unwrap_or_default())
+    }
+
+    async fn tenant(&self, user: &str) -> Result<Option<String>> {
+        Ok(self.record(user).and_then(|record| record.tenant))
+    }
+
+    async fn schedule(&self, user: &str) -> Result<Option<Schedule>> {
+        Ok(self.record(user).and_then(|record| record.schedule))
+    }
+
+    async fn reload(&self, config: &Config) -> Result<()> {
+        if let Some(path) = config.users_file.clone() {
+            let key = config.users_key.clone();
+            let rows = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || load_rows(&path, key.as_deref())).await??
+            };
+            self.replace_users(screen_users(&path, rows, config)?);
+        }
+        self.set_plans(config.plans.clone());
+        Ok(())
+    }
+
+    async fn add_credential(&self, user: &str, credential: Credential) -> Result<bool> {
+        Ok(self.update(|users| {
+            let Some(record) = users.get_mut(user) else {
+                return false;
+            };
+            record
+                .credentials
+                .retain(|existing| existing.id != credential.id);
+            record.credentials.push(credential);
+            true
+        }))
+    }
+
+    async fn revoke_credential(&self, user: &str, id: &str) -> Result<bool> {
+        Ok(self.update(|users| {
+            let Some(record) = users.get_mut(user) else {
+                return false;
+            };
+            let before = record.credentials.len();
+            record.credentials.retain(|credential| credential.id != id);
+            record.credentials.len() != before
+        }))
+    }
+
+    async fn rename_user(&self, user: &str, username: &str) -> Result<bool> {
+        self.update(|users| {
+            if users.values().any(|record| {
+                record.user_id != user
+                    && (record.username == username || record.proxy_login() == username)
+            }) {
+                return Err(UsernameTaken(username.to_string()).into());
+            }
+            let Some(record) = users.get_mut(user) else {
+                return Ok(false);
+            };
+            record.username = username.to_string();
+            Ok(true)
+        })
+    }
+
+    async fn fetch_all(&self, filter: &UserFilter) -> Result<Vec<UserRecord>> {
+        Ok(self.page(filter, 0, usize::MAX).users)
+    }
+
+    async fn fetch_page(
+        &self,
+        filter: &UserFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<UserPage> {
+        Ok(self.page(filter, offset, limit))
+    }
+}
+
+pub fn parse_proxy_auth_token(token: &[u8]) -> Result<(String, String)> {
+    let token_str = std::str::from_utf8(token)?.trim();
+
+    let (_, encoded_cred) = token_str
+        .split_once(char::is_whitespace)
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic"))
+        .ok_or_else(|| anyhow!("Invalid auth format: expected 'Basic ...'"))?;
+
+    let decoded = BASIC_TOKEN.decode(encoded_cred.trim())?;
+    let credentials = String::from_utf8(decoded)
+        .unwrap_or_else(|err| err.into_bytes().into_iter().map(char::from).collect());
+
+    credentials
+        .split_once(':')
+        .map(|(u, p)| (u.to_string(), p.to_string()))
+        .ok_or_else(|| anyhow!("Invalid credentials format: expected 'user:password'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::LimitValue;
+
+    #[tokio::test]
+    async fn database_serves_record_limits_and_privacy() -> Result<()> {
+        let mut database = Database::new_persistence();
+        let limits = Limits::new(LimitValue::Unrestricted, LimitValue::Restricted(1));
+        database.insert(UserRecord {
+            limits,
+            private: true,
+            ..UserRecord::new("ghost", "secret")
+        });
+
+        assert!(database.authenticate("ghost", "secret").await?);
+        assert_eq!(database.limits("ghost").await?, limits);
+        assert!(database.is_private("ghost").await?);
+        assert!(!database.is_private("procent").await?);
+        assert_eq!(database.limits("nobody").await?, Limits::with_low_limits());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn database_pages_users_matching_a_filter() -> Result<()> {
+        let database = Database::with_users([
+            UserRecord {
+                plan: Some("pro".to_string()),
+                tenant: Some("acme".to_string()),
+                ..UserRecord::new("carol", "secret")
+            },
+            UserRecord {
+                plan: Some("pro".to_string()),
+                ..UserRecord::new("Alice", "secret")
+            },
+            UserRecord::new("bob", "secret"),
+        ]);
+        let pro = UserFilter {
+            plan: Some("pro".to_string()),
+            ..UserFilter::default()
+        };
+
+        let page = database.fetch_page(&pro, 1, 10).await?;
+        assert_eq!(page.total, 2);
+        assert_eq!(page.users[0].username, "carol");
+        let all = database.fetch_all(&UserFilter::default()).await?;
+        let names: Vec<&str> = all.iter().map(|record| record.username.as_str()).collect();
+        assert_eq!(names, ["Alice", "bob", "carol"]);
+        let search = UserFilter {
+            tenant: Some("acme".to_string()),
+            search: Some("AR".to_string()),
+            ..UserFilter::default()
+        };
+        assert_eq!(database.fetch_all(&search).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn database_rotates_additional_credentials() -> Result<()> {
+        let database = Database::new_persistence();
+        let credential = Credential {
+            id: "ci".to_string(),
+            password: "rotated".to_string(),
+        };
+
+        assert!(database.add_credential("procent", credential).await?);
+        assert!(database.authenticate("procent", "rotated").await?);
+        assert!(database.authenticate("procent", "o953zY7lnkYMEl5D").await?);
+
+        assert!(database.revoke_credential("procent", "ci").await?);
+        assert!(!database.revoke_credential("procent", "ci").await?);
+        assert!(!database.authenticate("procent", "rotated").await?);
+        assert!(
+            !database
+                .add_credential(
+                    "nobody",
+                    Credential {
+                        id: "x".to_string(),
+                        password: "x".to_string(),
+                    }
+                )
+                .await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxy_credentials_map_to_account() -> Result<()> {
+        let mut database = Database::new_persistence();
+        database.insert(UserRecord {
+            proxy_username: Some("edge-1".to_string()),
+            proxy_password: Some("proxy-secret".to_string()),
+            ..UserRecord::new("acme", "account-secret")
+        });
+        database.insert(UserRecord {
+            proxy_password: Some("proxy-only".to_string()),
+            ..UserRecord::new("solo", "account-secret")
+        });
+
+        assert!(database.authenticate("edge-1", "proxy-secret").await?);
+        assert!(!database.authenticate("edge-1", "account-secret").await?);
+        assert!(!database.authenticate("acme", "proxy-secret").await?);
+        assert_eq!(database.account("edge-1").await?, "acme");
+        assert!(database.authenticate("solo", "proxy-only").await?);
+        assert!(!database.authenticate("solo", "account-secret").await?);
+        assert_eq!(database.account("solo").await?, "solo");
+        assert!(database.authenticate("admin", "12345").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn renamed_user_keeps_stable_id() -> Result<()> {
+        let mut database = Database::new_persistence();
+        database.insert(UserRecord {
+            user_id: "u-42".to_string(),
+            plan: Some("pro".to_string()),
+            ..UserRecord::new("alice", "secret")
+        });
+        let pro = Limits::new(LimitValue::Restricted(10), LimitValue::Unrestricted);
+        database.set_plans(HashMap::from([("pro".to_string(), pro)]));
+        assert_eq!(database.account("alice").await?, "u-42");
+
+        assert!(database.rename_user("u-42", "alice.smith").await?);
+        assert!(database.authenticate("alice.smith", "secret").await?);
+        assert!(!database.authenticate("alice", "secret").await?);
+        assert_eq!(database.account("alice.smith").await?, "u-42");
+        assert_eq!(database.limits("u-42").await?, pro);
+
+        let taken = database.rename_user("u-42", "admin").await.unwrap_err();
+        assert!(taken.is::<UsernameTaken>());
+        assert!(!database.rename_user("nobody", "fresh").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_in_the_users_file_atomically() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-swap-{}.csv", std::process::id()));
+        std::fs::write(&path, "alice,secret\n")?;
+        let database = Database::new_persistence();
+        let before = database.users.load_full();
+        let mut config = crate::config::build_config();
+        config.users_file = Some(path.clone());
+
+        let reloaded = database.reload(&config).await;
+        std::fs::remove_file(&path)?;
+        reloaded?;
+
+        assert!(database.authenticate("alice", "secret").await?);
+        assert!(!database.authenticate("procent", "o953zY7lnkYMEl5D").await?);
+        assert!(before.records.contains_key("procent"));
+        assert!(!before.records.contains_key("alice"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_basic_tokens_tolerantly() -> Result<()> {
+        let expected = ("procent".to_string(), "o953zY7lnkYMEl5D".to_string());
+        for token in [
+            "Basic cHJvY2VudDpvOTUzelk3bG5rWU1FbDVE",
+            "  basic \t cHJvY2VudDpvOTUzelk3bG5rWU1FbDVE  ",
+            "BASIC cHJvY2VudDpvOTUzelk3bG5rWU1FbDVE",
+        ] {
+            assert_eq!(parse_proxy_auth_token(token.as_bytes())?, expected);
+        }
+
+        let colons = parse_proxy_auth_token(b"Basic dXNlcjpwYTpzczp3b3Jk")?;
+        assert_eq!(colons, ("user".to_string(), "pa:ss:word".to_string()));
+        let utf8 = parse_proxy_auth_token(b"Basic dGVzdDoxMjPCow")?;
+        assert_eq!(utf8, ("test".to_string(), "123\u{a3}".to_string()));
+        let latin1 = parse_proxy_auth_token(b"Basic dGVzdDoxMjOj")?;
+        assert_eq!(latin1, ("test".to_string(), "123\u{a3}".to_string()));
+
+        for token in ["Bearer abc", "Basic", "Basic !!!", "Basic dXNlcg=="] {
+            assert!(parse_proxy_auth_token(token.as_bytes()).is_err(), "{token}");
+        }
+        Ok(())
+    }
+}
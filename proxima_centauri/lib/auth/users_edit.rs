@@ -0,0 +1,441 @@
+use crate::auth::users_file::{
+    COLUMNS, MAGIC, Schema, decode_users, encrypt_users, parse_rows, parse_table,
+};
+use crate::auth::{UserRecord, UserStatus, UsernameTaken};
+use crate::registry::LimitValue;
+use anyhow::{Context as _, Result, bail};
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write as _};
+use std::os::unix::fs::{MetadataExt as _, OpenOptionsExt as _};
+use std::path::{Path, PathBuf};
+
+struct Lock(PathBuf);
+
+impl Lock {
+    fn acquire(path: &Path) -> Result<Self> {
+        let lock = sibling(path, "lock");
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&lock) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => bail!(
+                "Users file `{}` is being edited by another process (remove `{}` if it is stale)",
+                path.display(),
+                lock.display()
+            ),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Cannot create `{}`", lock.display()));
+            }
+        };
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self(lock))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+pub struct UsersFile {
+    path: PathBuf,
+    key: Option<String>,
+    encrypted: bool,
+    lines: Vec<String>,
+    schema: Schema,
+    _lock: Lock,
+}
+
+impl UsersFile {
+    pub fn open(path: &Path, key: Option<&str>) -> Result<Self> {
+        let lock = Lock::acquire(path)?;
+        let (encrypted, csv) = match std::fs::read(path) {
+            Ok(data) => (data.starts_with(MAGIC), decode_users(path, data, key)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => (key.is_some(), String::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Cannot read users file `{}`", path.display()));
+            }
+        };
+        let (mut schema, _) = parse_table(&csv)?;
+        let mut lines: Vec<String> = csv.lines().map(str::to_string).collect();
+        if lines.iter().all(|line| line.trim().is_empty()) {
+            lines = vec![COLUMNS.join(",")];
+            schema.header = Some(0);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            key: key.map(str::to_string),
+            encrypted,
+            lines,
+            schema,
+            _lock: lock,
+        })
+    }
+
+    pub fn users(&self) -> Result<Vec<UserRecord>> {
+        Ok(parse_rows(&self.lines.join("\n"))?
+            .records
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect())
+    }
+
+    pub fn add(&mut self, record: &UserRecord) -> Result<()> {
+        check_username(&record.username)?;
+        if record.password.trim().is_empty()
+            || record.password.trim() != record.password
+            || record.password.contains([',', '\n', '\r'])
+        {
+            bail!("Password must be non-empty without commas, line breaks or surrounding spaces");
+        }
+        if self.position(&record.username)?.is_some() {
+            bail!("User `{}` already exists", record.username);
+        }
+        if self.row(|row| row.user_id == record.user_id)?.is_some() {
+            bail!("User ID `{}` is already taken", record.user_id);
+        }
+        let mut values = vec![
+            ("username", record.username.as_str()),
+            ("password", record.password.as_str()),
+        ];
+        if record.user_id != record.username {
+            values.push(("user_id", record.user_id.as_str()));
+        }
+        values.extend(record.plan.as_deref().map(|plan| ("plan", plan)));
+        self.lines.push(String::new());
+        self.write_fields(self.lines.len() - 1, &values)
+    }
+
+    pub fn remove(&mut self, username: &str) -> Result<bool> {
+        let Some(index) = self.position(username)? else {
+            return Ok(false);
+        };
+        self.lines.remove(index);
+        Ok(true)
+    }
+
+    pub fn rename(&mut self, user_id: &str, username: &str) -> Result<Option<String>> {
+        check_username(username)?;
+        let rows = parse_rows(&self.lines.join("\n"))?.records;
+        let Some((line, record)) = rows.iter().find(|(_, row)| row.user_id == user_id) else {
+            return Ok(None);
+        };
+        if rows.iter().any(|(_, row)| {
+            row.user_id != user_id && (row.username == username || row.proxy_login() == username)
+        }) {
+            return Err(UsernameTaken(username.to_string()).into());
+        }
+        self.write_fields(line - 1, &[("username", username), ("user_id", user_id)])?;
+        Ok(Some(record.username.clone()))
+    }
+
+    pub fn assign_ids(&mut self) -> Result<usize> {
+        let rows = parse_rows(&self.lines.join("\n"))?.records;
+        let mut assigned = 0;
+        for (line, record) in rows {
+            let explicit = self.schema.position("user_id").is_some_and(|position| {
+                self.lines[line - 1]
+                    .split(',')
+                    .nth(position)
+                    .map(str::trim)
+                    .is_some_and(|field| !field.is_empty() && field != "-")
+            });
+            if !explicit {
+                self.write_fields(line - 1, &[("user_id", &record.user_id)])?;
+                assigned += 1;
+            }
+        }
+        Ok(assigned)
+    }
+
+    pub fn set_plan(&mut self, username: &str, plan: Option<&str>) -> Result<bool> {
+        if plan.is_some_and(|plan| plan.contains([',', '\n', '\r'])) {
+            bail!("Plan names must not contain commas or line breaks");
+        }
+        self.set_field(username, "plan", plan.unwrap_or("-"))
+    }
+
+    pub fn set_limits(
+        &mut self,
+        username: &str,
+        concurrency: LimitValue<u16>,
+        traffic: LimitValue<u128>,
+    ) -> Result<bool> {
+        if concurrency == LimitValue::Restricted(0) || traffic == LimitValue::Restricted(0) {
+            bail!("Limits must be positive numbers or `*`");
+        }
+        let Some(index) = self.position(username)? else {
+            return Ok(false);
+        };
+        self.write_fields(
+            index,
+            &[
+                ("concurrency_limit", &limit_field(concurrency)),
+                ("traffic_limit", &limit_field(traffic)),
+            ],
+        )?;
+        Ok(true)
+    }
+
+    pub fn set_status(&mut self, username: &str, status: &UserStatus) -> Result<bool> {
+        if let UserStatus::Unknown(status) = status {
+            bail!("Unknown status `{status}`, expected ok or banned");
+        }
+        self.set_field(username, "status", &status.to_string())
+    }
+
+    fn set_field(&mut self, username: &str, column: &'static str, value: &str) -> Result<bool> {
+        let Some(index) = self.position(username)? else {
+            return Ok(false);
+        };
+        self.write_fields(index, &[(column, value)])?;
+        Ok(true)
+    }
+
+    fn write_fields(&mut self, index: usize, values: &[(&'static str, &str)]) -> Result<()> {
+        let mut fields: Vec<String> = if self.lines[index].is_empty() {
+            Vec::new()
+        } else {
+            self.lines[index].split(',').map(str::to_string).collect()
+        };
+        for (column, value) in values {
+            let position = self.column(column)?;
+            if fields.len() <= position {
+                fields.resize(position + 1, "-".to_string());
+            }
+            fields[position] = (*value).to_string();
+        }
+        self.lines[index] = fields.join(",");
+        Ok(())
+    }
+
+    fn column(&mut self, column: &'static str) -> Result<usize> {
+        if let Some(position) = self.schema.position(column) {
+            return Ok(position);
+        }
+        let header = self
+            .schema
+            .header
+            .with_context(|| format!("Users file has no `{column}` column"))?;
+        self.lines[header].push(',');
+        self.lines[header].push_str(column);
+        self.schema.columns.push(column);
+        Ok(self.schema.columns.len() - 1)
+    }
+
+    pub fn save(self) -> Result<()> {
+        let mut csv = self.lines.join("\n");
+        csv.push('\n');
+        let data = match (&self.key, self.encrypted) {
+            (Some(key), true) => encrypt_users(csv.as_bytes(), key)?,
+            _ => csv.into_bytes(),
+        };
+        let temp = sibling(&self.path, "tmp");
+        let mode = std::fs::metadata(&self.path).map_or(0o600, |metadata| metadata.mode() & 0o777);
+        let _ = std::fs::remove_file(&temp);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&temp)
+            .and_then(|mut file| {
+                file.write_all(&data)?;
+                file.sync_all()
+            })
+            .with_context(|| format!("Cannot write `{}`", temp.display()))?;
+        std::fs::rename(&temp, &self.path)
+            .with_context(|| format!("Cannot replace `{}`", self.path.display()))
+    }
+
+    fn position(&self, username: &str) -> Result<Option<usize>> {
+        self.row(|record| record.username == username)
+    }
+
+    fn row(&self, matches: impl Fn(&UserRecord) -> bool) -> Result<Option<usize>> {
+        Ok(parse_rows(&self.lines.join("\n"))?
+            .records
+            .into_iter()
+            .find(|(_, record)| matches(record))
+            .map(|(line, _)| line - 1))
+    }
+}
+
+fn check_username(username: &str) -> Result<()> {
+    if username.is_empty() || username.contains(|c: char| c == ',' || c.is_whitespace()) {
+        bail!("Username must be non-empty without commas or whitespace");
+    }
+    Ok(())
+}
+
+fn limit_field<T: Copy + Display>(limit: LimitValue<T>) -> String {
+    limit
+        .restricted()
+        .map_or_else(|| "*".to_string(), |limit| limit.to_string())
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn edits_keep_comments_and_encryption() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-edit-{}.enc", std::process::id()));
+        let sealed = encrypt_users(
+            b"# staff\nusername,password,plan,routes,tenant\nalice,secret,,*.corp=corp,acme\nbob,hunter2\n",
+            KEY,
+        )?;
+        std::fs::write(&path, sealed)?;
+
+        let mut users = UsersFile::open(&path, Some(KEY))?;
+        assert!(UsersFile::open(&path, Some(KEY)).is_err());
+        users.add(&UserRecord::new("carol", "pw"))?;
+        assert!(users.add(&UserRecord::new("bob", "other")).is_err());
+        assert!(users.add(&UserRecord::new("dan ny", "pw")).is_err());
+        assert!(users.set_plan("alice", Some("pro"))?);
+        assert!(users.remove("bob")?);
+        assert!(!users.remove("bob")?);
+        users.save()?;
+
+        let data = std::fs::read(&path)?;
+        let csv = decode_users(&path, data.clone(), Some(KEY));
+        let relocked = UsersFile::open(&path, Some(KEY)).map(|file| file.lines.len());
+        std::fs::remove_file(&path)?;
+
+        assert!(data.starts_with(MAGIC));
+        assert_eq!(
+            csv?,
+            "# staff\nusername,password,plan,routes,tenant\nalice,secret,pro,*.corp=corp,acme\ncarol,pw\n"
+        );
+        assert_eq!(relocked?, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn saved_files_are_private_by_default() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-edit-{}.csv", std::process::id()));
+        let mut users = UsersFile::open(&path, None)?;
+        users.add(&UserRecord::new("erin", "pw"))?;
+        users.save()?;
+        let created = std::fs::metadata(&path)?.mode() & 0o777;
+
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o640))?;
+        UsersFile::open(&path, None)?.save()?;
+        let kept = std::fs::metadata(&path)?.mode() & 0o777;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!((created, kept), (0o600, 0o640));
+        Ok(())
+    }
+
+    #[test]
+    fn edits_add_missing_columns_to_the_header() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-edit-{}.db.csv", std::process::id()));
+        std::fs::write(&path, include_str!("../../files/db.csv"))?;
+
+        let mut users = UsersFile::open(&path, None)?;
+        assert!(users.set_plan("admin", Some("pro"))?);
+        let mut carol = UserRecord::new("carol", "pw");
+        carol.plan = Some("pro".to_string());
+        users.add(&carol)?;
+        let records = users.users()?;
+        users.save()?;
+        let csv = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(
+            csv,
+            "username,password,proxy_username,proxy_password,concurrency_limit,traffic_limit,status,plan\n\
+             procent,o953zY7lnkYMEl5D,-,-,-,-,ok\n\
+             admin,12345,-,-,2,10000,ok,pro\n\
+             banned,dqdwqd1231_*qWTd,-,-,-,-,banned\n\
+             carol,pw,-,-,-,-,-,pro\n"
+        );
+        assert_eq!(records[3].plan.as_deref(), Some("pro"));
+        Ok(())
+    }
+
+    #[test]
+    fn edits_limits_and_status_in_place() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("procent-edit-{}.limits.csv", std::process::id()));
+        std::fs::write(&path, include_str!("../../files/db.csv"))?;
+
+        let mut users = UsersFile::open(&path, None)?;
+        assert!(users.set_limits(
+            "procent",
+            LimitValue::Restricted(8),
+            LimitValue::Unrestricted
+        )?);
+        assert!(users.set_status("banned", &UserStatus::Active)?);
+        assert!(users.set_status("admin", &UserStatus::Banned)?);
+        assert!(!users.set_status("nobody", &UserStatus::Banned)?);
+        assert!(
+            users
+                .set_limits("admin", LimitValue::Restricted(0), LimitValue::Unrestricted)
+                .is_err()
+        );
+        assert!(
+            users
+                .set_status("admin", &UserStatus::Unknown("paused".to_string()))
+                .is_err()
+        );
+        users.save()?;
+        let csv = std::fs::read_to_string(&path)?;
+        let reopened = UsersFile::open(&path, None).and_then(|file| file.users());
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(
+            csv,
+            "username,password,proxy_username,proxy_password,concurrency_limit,traffic_limit,status\n\
+             procent,o953zY7lnkYMEl5D,-,-,8,*,ok\n\
+             admin,12345,-,-,2,10000,banned\n\
+             banned,dqdwqd1231_*qWTd,-,-,-,-,ok\n"
+        );
+        let reopened = reopened?;
+        assert_eq!(reopened[0].limits.concurrency(), LimitValue::Restricted(8));
+        assert_eq!(reopened[1].status, UserStatus::Banned);
+        Ok(())
+    }
+
+    #[test]
+    fn renames_keep_the_user_id() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("procent-edit-{}.ids.csv", std::process::id()));
+        std::fs::write(&path, include_str!("../../files/db.csv"))?;
+
+        let mut users = UsersFile::open(&path, None)?;
+        assert_eq!(users.rename("admin", "root")?.as_deref(), Some("admin"));
+        assert_eq!(users.rename("nobody", "root")?, None);
+        assert!(
+            users
+                .rename("procent", "root")
+                .unwrap_err()
+                .is::<UsernameTaken>()
+        );
+        assert!(users.add(&UserRecord::new("admin", "pw")).is_err());
+        assert_eq!(users.assign_ids()?, 2);
+        assert_eq!(users.assign_ids()?, 0);
+        users.save()?;
+        let csv = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(
+            csv,
+            "username,password,proxy_username,proxy_password,concurrency_limit,traffic_limit,status,user_id\n\
+             procent,o953zY7lnkYMEl5D,-,-,-,-,ok,procent\n\
+             root,12345,-,-,2,10000,ok,admin\n\
+             banned,dqdwqd1231_*qWTd,-,-,-,-,banned,banned\n"
+        );
+        Ok(())
+    }
+}
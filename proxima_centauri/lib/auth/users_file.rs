@@ -0,0 +1,489 @@
+use crate::auth::{UserRecord, UserStatus};
+use crate::config::{Config, UsersValidation};
+use crate::registry::{LimitValue, Limits};
+use crate::routing::{DIRECT, TLS, parse_routes};
+use anyhow::{Context as _, Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose};
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::warn;
+
+pub(super) const MAGIC: &[u8] = b"PROCENT-ENC1";
+
+pub fn load_users(path: &Path, key: Option<&str>) -> Result<Vec<UserRecord>> {
+    parse_users(&read_users(path, key)?)
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Rows {
+    pub(crate) records: Vec<(usize, UserRecord)>,
+    pub(crate) malformed: Vec<(usize, String)>,
+}
+
+pub(crate) fn load_rows(path: &Path, key: Option<&str>) -> Result<Rows> {
+    parse_rows(&read_users(path, key)?)
+}
+
+pub(crate) fn screen_users(path: &Path, rows: Rows, config: &Config) -> Result<Vec<UserRecord>> {
+    let problems = user_problems(&rows, config);
+    if config.users_validation == UsersValidation::Strict && !problems.is_empty() {
+        let problems: Vec<&str> = problems
+            .iter()
+            .map(|(_, problem)| problem.as_str())
+            .collect();
+        bail!(
+            "Users file `{}` is invalid: {}",
+            path.display(),
+            problems.join("; ")
+        );
+    }
+    for (_, problem) in &problems {
+        warn!(
+            path = format!("{}", path.display()),
+            "{problem}, row skipped"
+        );
+    }
+    Ok(rows
+        .records
+        .into_iter()
+        .filter(|(line, _)| !problems.iter().any(|(skipped, _)| skipped == line))
+        .map(|(_, record)| record)
+        .collect())
+}
+
+pub(crate) fn user_problems(rows: &Rows, config: &Config) -> Vec<(usize, String)> {
+    let mut first_seen = HashMap::new();
+    let mut first_ids = HashMap::new();
+    let mut problems = rows.malformed.clone();
+    for (line, record) in &rows.records {
+        if let Some(first) = first_seen.get(record.username.as_str()) {
+            problems.push((
+                *line,
+                format!(
+                    "Users file line {line}: duplicate username `{}`, first defined on line {first}",
+                    record.username
+                ),
+            ));
+            continue;
+        }
+        if let Some(first) = first_ids.get(record.user_id.as_str()) {
+            problems.push((
+                *line,
+                format!(
+                    "Users file line {line}: duplicate user_id `{}`, first defined on line {first}",
+                    record.user_id
+                ),
+            ));
+            continue;
+        }
+        first_seen.insert(record.username.as_str(), *line);
+        first_ids.insert(record.user_id.as_str(), *line);
+        if let UserStatus::Unknown(status) = &record.status {
+            problems.push((
+                *line,
+                format!("Users file line {line}: unknown status `{status}`, expected ok or banned"),
+            ));
+        }
+        if record.limits.concurrency() == LimitValue::Restricted(0) {
+            problems.push((
+                *line,
+                format!("Users file line {line}: concurrency_limit must not be zero"),
+            ));
+        }
+        if record.limits.traffic() == LimitValue::Restricted(0) {
+            problems.push((
+                *line,
+                format!("Users file line {line}: traffic_limit must not be zero"),
+            ));
+        }
+        if let Some(plan) = record
+            .plan
+            .as_deref()
+            .filter(|plan| !config.plans.contains_key(*plan))
+        {
+            problems.push((
+                *line,
+                format!("Users file line {line}: unknown plan `{plan}`"),
+            ));
+        }
+        for route in &record.routes {
+            let upstream = route.upstream.as_str();
+            if upstream != DIRECT && upstream != TLS && !config.upstreams.contains_key(upstream) {
+                problems.push((
+                    *line,
+                    format!(
+                        "Users file line {line}: route `{}` names unknown upstream `{upstream}`",
+                        route.hosts
+                    ),
+                ));
+            }
+        }
+    }
+    problems.sort_by_key(|(line, _)| *line);
+    problems
+}
+
+fn read_users(path: &Path, key: Option<&str>) -> Result<String> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Cannot read users file `{}`", path.display()))?;
+    decode_users(path, data, key)
+}
+
+pub(super) fn decode_users(path: &Path, data: Vec<u8>, key: Option<&str>) -> Result<String> {
+    let plaintext = if data.starts_with(MAGIC) {
+        let Some(key) = key else {
+            bail!(
+                "Users file `{}` is encrypted but PROXY_USERS_KEY is not set",
+                path.display()
+            );
+        };
+        decrypt(&data, key)?
+    } else {
+        data
+    };
+    Ok(String::from_utf8(plaintext)?)
+}
+
+pub(super) const COLUMNS: [&str; 12] = [
+    "username",
+    "password",
+    "proxy_username",
+    "proxy_password",
+    "concurrency_limit",
+    "traffic_limit",
+    "status",
+    "plan",
+    "routes",
+    "tenant",
+    "schedule",
+    "user_id",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Schema {
+    pub(super) header: Option<usize>,
+    pub(super) columns: Vec<&'static str>,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Self {
+            header: None,
+            columns: COLUMNS.to_vec(),
+        }
+    }
+}
+
+impl Schema {
+    fn from_header(number: usize, line: &str) -> Result<Self> {
+        let mut columns = Vec::new();
+        for name in line.split(',').map(str::trim) {
+            let Some(column) = COLUMNS.iter().find(|column| **column == name) else {
+                bail!("Users file line {}: unknown column `{name}`", number + 1);
+            };
+            if columns.contains(column) {
+                bail!("Users file line {}: duplicate column `{name}`", number + 1);
+            }
+            columns.push(*column);
+        }
+        if !columns.contains(&"password") {
+            bail!(
+                "Users file line {}: the header has no `password` column",
+                number + 1
+            );
+        }
+        Ok(Self {
+            header: Some(number),
+            columns,
+        })
+    }
+
+    pub(super) fn position(&self, column: &str) -> Option<usize> {
+        self.columns.iter().position(|name| *name == column)
+    }
+
+    fn field<'a>(&self, fields: &[&'a str], column: &str) -> Option<&'a str> {
+        self.position(column)
+            .and_then(|position| fields.get(position))
+            .copied()
+            .filter(|field| !field.is_empty() && *field != "-")
+    }
+
+    fn record(&self, fields: &[&str]) -> Result<UserRecord> {
+        if fields.len() > self.columns.len() {
+            bail!(
+                "expected at most {} columns: {}",
+                self.columns.len(),
+                self.columns.join(",")
+            );
+        }
+        let (Some(username), Some(password)) = (
+            self.field(fields, "username"),
+            self.field(fields, "password"),
+        ) else {
+            bail!("username and password must not be empty");
+        };
+        let mut record = UserRecord::new(username, password);
+        let text = |column| self.field(fields, column).map(str::to_string);
+        record.proxy_username = text("proxy_username");
+        record.proxy_password = text("proxy_password");
+        let defaults = record.limits;
+        record.limits = Limits::new(
+            self.field(fields, "concurrency_limit")
+                .map_or(Ok(defaults.concurrency()), str::parse)
+                .context("concurrency_limit")?,
+            self.field(fields, "traffic_limit")
+                .map_or(Ok(defaults.traffic()), str::parse)
+                .context("traffic_limit")?,
+        );
+        record.status = self
+            .field(fields, "status")
+            .map(UserStatus::from_str)
+            .transpose()?
+            .unwrap_or_default();
+        record.plan = text("plan");
+        record.routes = parse_routes(self.field(fields, "routes").unwrap_or_default())?;
+        record.tenant = text("tenant");
+        record.schedule = self.field(fields, "schedule").map(str::parse).transpose()?;
+        if let Some(user_id) = text("user_id") {
+            record.user_id = user_id;
+        }
+        Ok(record)
+    }
+}
+
+pub fn parse_users(csv: &str) -> Result<Vec<UserRecord>> {
+    let rows = parse_rows(csv)?;
+    if let Some((_, problem)) = rows.malformed.first() {
+        bail!("{problem}");
+    }
+    Ok(rows.records.into_iter().map(|(_, record)| record).collect())
+}
+
+pub(super) fn parse_rows(csv: &str) -> Result<Rows> {
+    Ok(parse_table(csv)?.1)
+}
+
+pub(super) fn parse_table(csv: &str) -> Result<(Schema, Rows)> {
+    let mut schema = None;
+    let mut rows = Rows::default();
+    for (number, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let schema = match &schema {
+            Some(schema) => schema,
+            None if fields[0] == "username" => {
+                schema = Some(Schema::from_header(number, line)?);
+                continue;
+            }
+            None => schema.insert(Schema::default()),
+        };
+        match schema.record(&fields) {
+            Ok(record) => rows.records.push((number + 1, record)),
+            Err(err) => rows.malformed.push((
+                number + 1,
+                format!("Users file line {}: {err:#}", number + 1),
+            )),
+        }
+    }
+    Ok((schema.unwrap_or_default(), rows))
+}
+
+pub fn encrypt_users(plaintext: &[u8], key: &str) -> Result<Vec<u8>> {
+    let key = cipher(key)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("Cannot generate a nonce"))?;
+    let mut sealed = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(MAGIC),
+        &mut sealed,
+    )
+    .map_err(|_| anyhow!("Cannot encrypt users file"))?;
+    Ok([MAGIC, &nonce, &sealed].concat())
+}
+
+fn decrypt(data: &[u8], key: &str) -> Result<Vec<u8>> {
+    let key = cipher(key)?;
+    let body = &data[MAGIC.len()..];
+    if body.len() < NONCE_LEN {
+        bail!("Users file is truncated");
+    }
+    let (nonce, sealed) = body.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow!("Users file has an invalid nonce"))?;
+    let mut sealed = sealed.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(MAGIC), &mut sealed)
+        .map_err(|_| anyhow!("Cannot decrypt users file: wrong key or corrupted data"))?;
+    Ok(plaintext.to_vec())
+}
+
+pub(crate) fn cipher(key: &str) -> Result<LessSafeKey> {
+    let bytes = general_purpose::STANDARD
+        .decode(key.trim())
+        .context("PROXY_USERS_KEY is not valid base64")?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes)
+        .map_err(|_| anyhow!("PROXY_USERS_KEY must decode to 32 bytes"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::build_config;
+    use crate::registry::LimitValue;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn parses_the_users_schema() -> Result<()> {
+        let users = parse_users("# comment\nalice,secret,-,-,5,*,ok,pro\n\nbob, pa:ss\n")?;
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].username, "alice");
+        assert_eq!(users[0].plan.as_deref(), Some("pro"));
+        assert_eq!(
+            users[0].limits,
+            Limits::new(LimitValue::Restricted(5), LimitValue::Unrestricted)
+        );
+        assert_eq!(users[1].password, "pa:ss");
+        assert_eq!(users[1].plan, None);
+        assert_eq!(users[1].limits, Limits::with_low_limits());
+        assert!(parse_users("carol\n").is_err());
+        assert!(parse_users("carol,secret,-,-,many\n").is_err());
+
+        let users = parse_users(
+            "username,password,routes,tenant,schedule\ndave,secret,*.internal=corp;*=direct\n",
+        )?;
+        assert_eq!(users[0].plan, None);
+        assert_eq!(users[0].routes[0].upstream, "corp");
+        assert!(parse_users("username,password,routes\nerin,secret,*.internal\n").is_err());
+
+        let users = parse_users("username,password,tenant\nfrank,secret,acme\n")?;
+        assert!(users[0].routes.is_empty());
+        assert_eq!(users[0].tenant.as_deref(), Some("acme"));
+
+        let users = parse_users("username,schedule,password\ngrace,mon-fri 08:00-20:00,secret\n")?;
+        let schedule = users[0].schedule.as_ref().map(ToString::to_string);
+        assert_eq!(schedule.as_deref(), Some("mon-fri 08:00-20:00"));
+        assert!(parse_users("username,password,schedule\nheidi,secret,weekdays\n").is_err());
+        assert!(parse_users("username,password,colour\nivan,secret,red\n").is_err());
+        assert!(parse_users("username,password\njudy,secret,extra\n").is_err());
+
+        let users = parse_users("username,password,user_id\nkim,secret,u-7\nleo,secret,-\n")?;
+        assert_eq!(users[0].user_id, "u-7");
+        assert_eq!(users[1].user_id, "leo");
+        Ok(())
+    }
+
+    #[test]
+    fn loads_the_bundled_users_file() -> Result<()> {
+        let users = parse_users(include_str!("../../files/db.csv"))?;
+        let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(names, ["procent", "admin", "banned"]);
+        assert!(users[0].accepts("o953zY7lnkYMEl5D"));
+        assert_eq!(users[0].proxy_username, None);
+        assert_eq!(
+            users[1].limits,
+            Limits::new(LimitValue::Restricted(2), LimitValue::Restricted(10_000))
+        );
+        assert_eq!(users[2].status, UserStatus::Banned);
+        assert!(!users[2].accepts("dqdwqd1231_*qWTd"));
+        Ok(())
+    }
+
+    #[test]
+    fn screens_duplicates_and_unknown_references() -> Result<()> {
+        let mut config = build_config();
+        config
+            .plans
+            .insert("pro".to_string(), Limits::with_low_limits());
+        let rows = parse_rows(
+            "username,password,plan,routes,user_id\nalice,secret,pro\nbob,secret,gold\nalice,other\ncarol,secret,,*=nowhere\ndave,secret,,*=tls\nerin,secret,,,dave\n",
+        )?;
+        let problems = user_problems(&rows, &config);
+        let lines: Vec<usize> = problems.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [3, 4, 5, 7]);
+        assert!(
+            problems[3]
+                .1
+                .contains("duplicate user_id `dave`, first defined on line 6")
+        );
+        assert!(
+            problems[1]
+                .1
+                .contains("duplicate username `alice`, first defined on line 2")
+        );
+
+        let path = Path::new("users.csv");
+        let users = screen_users(path, rows.clone(), &config)?;
+        let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(names, ["alice", "dave"]);
+
+        config.users_validation = UsersValidation::Strict;
+        let err = screen_users(path, rows, &config).unwrap_err();
+        assert!(format!("{err}").contains("line 3: unknown plan `gold`"));
+        Ok(())
+    }
+
+    #[test]
+    fn screens_bad_statuses_and_limits() -> Result<()> {
+        let mut config = build_config();
+        let rows = parse_rows(
+            "username,password,concurrency_limit,traffic_limit,status\nalice,secret,2,100,ok\nbob,secret,2,100,paused\ncarol,secret,0,100\ndave,secret,2,0\nerin,secret,lots,100\n",
+        )?;
+        let problems = user_problems(&rows, &config);
+        let problems: Vec<&str> = problems
+            .iter()
+            .map(|(_, problem)| problem.as_str())
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "Users file line 3: unknown status `paused`, expected ok or banned",
+                "Users file line 4: concurrency_limit must not be zero",
+                "Users file line 5: traffic_limit must not be zero",
+                "Users file line 6: concurrency_limit: Invalid limit `lots`",
+            ]
+        );
+
+        let path = Path::new("users.csv");
+        let users = screen_users(path, rows.clone(), &config)?;
+        let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(names, ["alice"]);
+
+        config.users_validation = UsersValidation::Strict;
+        let err = format!("{}", screen_users(path, rows, &config).unwrap_err());
+        assert!(err.contains("line 3: unknown status `paused`"));
+        assert!(err.contains("line 4: concurrency_limit must not be zero"));
+        assert!(err.contains("line 5: traffic_limit must not be zero"));
+        assert!(err.contains("line 6: concurrency_limit: Invalid limit `lots`"));
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_users_round_trip() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-users-{}.enc", std::process::id()));
+        let sealed = encrypt_users(b"alice,secret\n", KEY)?;
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+        std::fs::write(&path, &sealed)?;
+
+        let users = load_users(&path, Some(KEY));
+        let missing_key = load_users(&path, None);
+        let wrong_key = load_users(&path, Some("QUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUFBQUE="));
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(users?[0].password, "secret");
+        assert!(missing_key.is_err());
+        assert!(wrong_key.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use crate::clock::{self, Clock};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Subject {
+    User(String),
+    Ip(IpAddr),
+}
+
+#[derive(Default)]
+struct Failures {
+    recent: VecDeque<Instant>,
+    lockouts: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn is_active(&self, now: Instant, window: Duration) -> bool {
+        self.recent
+            .back()
+            .is_some_and(|last| now.duration_since(*last) < window)
+            || self.locked_until.is_some_and(|until| now < until + window)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Lockout {
+    pub(crate) failures: usize,
+    pub(crate) remaining: Duration,
+}
+
+pub(crate) struct AuthAudit {
+    max_failures: usize,
+    window: Duration,
+    lockout: Duration,
+    tarpit: Option<(usize, Duration)>,
+    subjects: Mutex<HashMap<Subject, Failures>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuthAudit {
+    pub(crate) fn new(max_failures: usize, window: Duration, lockout: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            lockout,
+            tarpit: None,
+            subjects: Mutex::new(HashMap::new()),
+            clock: clock::system(),
+        }
+    }
+
+    #[must_use]
+    pub(crate) fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    #[must_use]
+    pub(crate) fn with_tarpit(self, after: usize, delay: Duration) -> Self {
+        Self {
+            tarpit: (after != 0 && !delay.is_zero()).then_some((after, delay)),
+            ..self
+        }
+    }
+
+    pub(crate) const fn max_failures(&self) -> usize {
+        self.max_failures
+    }
+
+    pub(crate) fn locked(&self, user: &str, ip: IpAddr) -> Option<Lockout> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        let now = self.clock.now();
+        let subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+        [Subject::User(user.to_string()), Subject::Ip(ip)]
+            .iter()
+            .filter_map(|subject| subjects.get(subject))
+            .filter_map(|failures| {
+                Some(Lockout {
+                    failures: self.max_failures,
+                    remaining: failures.locked_until?.checked_duration_since(now)?,
+                })
+            })
+            .max_by_key(|lockout| lockout.remaining)
+    }
+
+    pub(crate) fn tarpit(&self, ip: IpAddr) -> Option<Duration> {
+        let (after, delay) = self.tarpit?;
+        let now = self.clock.now();
+        let subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+        let failures = subjects.get(&Subject::Ip(ip))?;
+        let recent = failures
+            .recent
+            .iter()
+            .filter(|at| now.duration_since(**at) < self.window)
+            .count();
+        let relapsed = failures.lockouts > 0 && failures.is_active(now, self.window);
+        (recent >= after || relapsed).then_some(delay)
+    }
+
+    pub(crate) fn record_failure(&self, user: &str, ip: IpAddr) -> Option<Lockout> {
+        if self.max_failures == 0 && self.tarpit.is_none() {
+            return None;
+        }
+        let now = self.clock.now();
+        let mut subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut triggered = None;
+        for subject in [Subject::User(user.to_string()), Subject::Ip(ip)] {
+            let failures = subjects.entry(subject).or_default();
+            while failures
+                .recent
+                .front()
+                .is_some_and(|first| now.duration_since(*first) >= self.window)
+            {
+                failures.recent.pop_front();
+            }
+            failures.recent.push_back(now);
+            if self.max_failures == 0 || failures.recent.len() < self.max_failures {
+                continue;
+            }
+            let remaining = self.lockout * 2u32.pow(failures.lockouts.min(MAX_BACKOFF_SHIFT));
+            failures.locked_until = Some(now + remaining);
+            failures.lockouts += 1;
+            failures.recent.clear();
+            triggered = triggered.max(Some(Lockout {
+                failures: self.max_failures,
+                remaining,
+            }));
+        }
+        drop(subjects);
+        if let Some(lockout) = triggered {
+            warn!(
+                user = user,
+                client_ip = format!("{ip}"),
+                lockout = format!("{:?}", lockout.remaining),
+                "Authentication locked out"
+            );
+        }
+        triggered
+    }
+
+    pub(crate) fn record_success(&self, user: &str) {
+        self.subjects
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&Subject::User(user.to_string()));
+    }
+
+    pub(crate) fn evict_expired(&self) {
+        let now = self.clock.now();
+        self.subjects
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, failures| failures.is_active(now, self.window));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn locks_out_after_max_failures_with_backoff() {
+        let audit = AuthAudit::new(3, Duration::from_mins(1), Duration::from_secs(10));
+
+        assert!(audit.record_failure("alice", IP).is_none());
+        assert!(audit.record_failure("alice", IP).is_none());
+        let first = audit.record_failure("alice", IP).unwrap();
+        assert_eq!(first.remaining, Duration::from_secs(10));
+        assert!(audit.locked("alice", IpAddr::from([10, 0, 0, 2])).is_some());
+        assert!(audit.locked("bob", IP).is_some());
+        assert!(audit.locked("bob", IpAddr::from([10, 0, 0, 2])).is_none());
+
+        for _ in 0..2 {
+            audit.record_failure("alice", IP);
+        }
+        let second = audit.record_failure("alice", IP).unwrap();
+        assert_eq!(second.remaining, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn success_clears_user_failures_and_zero_disables() {
+        let audit = AuthAudit::new(2, Duration::from_mins(1), Duration::from_secs(10));
+        audit.record_failure("alice", IP);
+        audit.record_success("alice");
+        assert!(
+            audit
+                .record_failure("alice", IpAddr::from([10, 0, 0, 2]))
+                .is_none()
+        );
+
+        let disabled = AuthAudit::new(0, Duration::from_mins(1), Duration::from_secs(10));
+        for _ in 0..10 {
+            assert!(disabled.record_failure("alice", IP).is_none());
+        }
+        assert!(disabled.locked("alice", IP).is_none());
+    }
+
+    #[test]
+    fn tarpits_ips_with_repeated_failures() {
+        let audit = AuthAudit::new(0, Duration::from_mins(1), Duration::from_secs(10))
+            .with_tarpit(2, Duration::from_secs(3));
+        let other = IpAddr::from([10, 0, 0, 2]);
+        audit.record_failure("alice", IP);
+        assert_eq!(audit.tarpit(IP), None);
+        audit.record_failure("bob", IP);
+        assert_eq!(audit.tarpit(IP), Some(Duration::from_secs(3)));
+        assert_eq!(audit.tarpit(other), None);
+        assert!(audit.locked("alice", IP).is_none());
+
+        let locking = AuthAudit::new(2, Duration::from_mins(1), Duration::from_secs(10))
+            .with_tarpit(5, Duration::from_secs(3));
+        locking.record_failure("alice", IP);
+        locking.record_failure("alice", IP);
+        assert_eq!(locking.tarpit(IP), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn evicts_idle_subjects() {
+        let audit = AuthAudit::new(5, Duration::ZERO, Duration::ZERO);
+        audit.record_failure("alice", IP);
+        audit.evict_expired();
+
+        assert!(audit.subjects.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failure_window_rolls_over_and_lockout_expires_with_the_clock() {
+        let clock = MockClock::new();
+        let audit = AuthAudit::new(2, Duration::from_mins(1), Duration::from_secs(10))
+            .with_clock(clock.clone());
+
+        audit.record_failure("alice", IP);
+        clock.advance(Duration::from_mins(1));
+        assert!(audit.record_failure("alice", IP).is_none());
+
+        assert!(audit.record_failure("alice", IP).is_some());
+        clock.advance(Duration::from_secs(4));
+        let lockout = audit.locked("alice", IP).unwrap();
+        assert_eq!(lockout.remaining, Duration::from_secs(6));
+
+        clock.advance(Duration::from_secs(7));
+        assert!(audit.locked("alice", IP).is_none());
+        clock.advance(Duration::from_mins(1));
+        audit.evict_expired();
+        assert!(audit.subjects.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+const BURST_DIVISOR: u64 = 10;
+
+#[derive(Default)]
+pub(crate) struct Bandwidth {
+    buckets: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl Bandwidth {
+    pub(crate) fn bucket(&self, user: &str, rate: u64) -> Arc<TokenBucket> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets
+            .entry(user.to_string())
+            .or_insert_with(|| Arc::new(TokenBucket::new(rate)));
+        bucket.set_rate(rate);
+        Arc::clone(bucket)
+    }
+
+    pub(crate) fn throttled(&self) -> Vec<(String, u64)> {
+        let buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut throttled: Vec<_> = buckets
+            .iter()
+            .map(|(user, bucket)| (user.clone(), bucket.throttled_ms()))
+            .collect();
+        drop(buckets);
+        throttled.sort();
+        throttled
+    }
+}
+
+pub(crate) struct TokenBucket {
+    rate: AtomicU64,
+    refill: tokio::sync::Mutex<Refill>,
+    throttled_us: AtomicU64,
+}
+
+struct Refill {
+    tokens: u64,
+    at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate: AtomicU64::new(rate),
+            refill: tokio::sync::Mutex::new(Refill {
+                tokens: burst(rate),
+                at: Instant::now(),
+            }),
+            throttled_us: AtomicU64::new(0),
+        }
+    }
+
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate.max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn throttled_ms(&self) -> u64 {
+        self.throttled_us.load(Ordering::Relaxed) / 1000
+    }
+
+    pub(crate) async fn take(&self, size: u64, progress: impl Fn()) -> Duration {
+        let started = Instant::now();
+        let mut throttled = false;
+        let mut remaining = size;
+        while remaining > 0 {
+            let mut refill = if let Ok(refill) = self.refill.try_lock() {
+                refill
+            } else {
+                throttled = true;
+                self.refill.lock().await
+            };
+            let rate = self.rate.load(Ordering::Relaxed);
+            let wanted = remaining.min(burst(rate));
+            refill.top_up(rate);
+            if refill.tokens < wanted {
+                throttled = true;
+                sleep(time_for(wanted - refill.tokens, rate)).await;
+                refill.top_up(rate);
+            }
+            refill.tokens = refill.tokens.saturating_sub(wanted);
+            remaining -= wanted;
+            drop(refill);
+            progress();
+        }
+        if !throttled {
+            return Duration::ZERO;
+        }
+        let waited = started.elapsed();
+        let micros = u64::try_from(waited.as_micros()).unwrap_or(u64::MAX);
+        self.throttled_us.fetch_add(micros, Ordering::Relaxed);
+        waited
+    }
+}
+
+impl Refill {
+    fn top_up(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.at).as_nanos();
+        let earned = u64::try_from(elapsed * u128::from(rate) / 1_000_000_000).unwrap_or(u64::MAX);
+        let tokens = self.tokens.saturating_add(earned);
+        if tokens >= burst(rate) {
+            self.tokens = burst(rate);
+            self.at = now;
+        } else {
+            self.tokens = tokens;
+            self.at += time_for(earned, rate).min(now.duration_since(self.at));
+        }
+    }
+}
+
+fn burst(rate: u64) -> u64 {
+    (rate / BURST_DIVISOR).max(1)
+}
+
+fn time_for(tokens: u64, rate: u64) -> Duration {
+    let nanos = (u128::from(tokens) * 1_000_000_000).div_ceil(u128::from(rate.max(1)));
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn caps_the_rate_and_records_throttle_time() {
+        let bucket = TokenBucket::new(20_000);
+        let started = Instant::now();
+        assert_eq!(bucket.take(2_000, || {}).await, Duration::ZERO);
+        let waited = bucket.take(4_000, || {}).await;
+
+        assert!(started.elapsed() >= Duration::from_millis(190));
+        assert!(waited >= Duration::from_millis(190));
+        assert!(bucket.throttled_ms() >= 190);
+    }
+
+    #[tokio::test]
+    async fn concurrent_takers_share_the_rate() {
+        let bandwidth = Bandwidth::default();
+        let bucket = bandwidth.bucket("alice", 40_000);
+        bucket.take(4_000, || {}).await;
+        let started = Instant::now();
+        let bulk = bandwidth.bucket("alice", 40_000);
+        let bulk = tokio::spawn(async move { bulk.take(16_000, || {}).await });
+        sleep(Duration::from_millis(10)).await;
+        bucket.take(4_000, || {}).await;
+        let small = started.elapsed();
+        bulk.await.ok();
+
+        assert!(small < Duration::from_millis(300));
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!(bandwidth.throttled().len(), 1);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::http_utils::request::parse_forward_target;
+use anyhow::{Context as _, Result, anyhow, bail};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::warn;
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_CACHED_HOSTS: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CategoryConfig {
+    pub file: Option<PathBuf>,
+    pub url: Option<String>,
+    pub cache_ttl: Duration,
+}
+
+impl Default for CategoryConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            url: None,
+            cache_ttl: Duration::from_hours(1),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Categorizer: Send + Sync {
+    async fn categories(&self, host: &str) -> Result<Vec<String>>;
+}
+
+pub(crate) struct CategoryList {
+    domains: HashMap<String, Vec<String>>,
+}
+
+impl CategoryList {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read category list `{}`", path.display()))?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut domains: HashMap<String, Vec<String>> = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(',').map(str::trim);
+            let domain = normalize(fields.next().unwrap_or_default());
+            let categories: Vec<String> = fields
+                .filter(|category| !category.is_empty())
+                .map(str::to_ascii_lowercase)
+                .collect();
+            if domain.is_empty() || categories.is_empty() {
+                bail!(
+                    "Category list line {}: expected `domain,category`",
+                    index + 1
+                );
+            }
+            domains.entry(domain).or_default().extend(categories);
+        }
+        Ok(Self { domains })
+    }
+
+    fn lookup(&self, host: &str) -> Vec<String> {
+        let host = normalize(host);
+        let mut domain = host.as_str();
+        let mut found = Vec::new();
+        loop {
+            if let Some(categories) = self.domains.get(domain) {
+                found.extend(categories.iter().cloned());
+            }
+            let Some((_, parent)) = domain.split_once('.') else {
+                return found;
+            };
+            domain = parent;
+        }
+    }
+}
+
+#[async_trait]
+impl Categorizer for CategoryList {
+    async fn categories(&self, host: &str) -> Result<Vec<String>> {
+        Ok(self.lookup(host))
+    }
+}
+
+pub(crate) struct CategoryApi {
+    url: String,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+}
+
+impl CategoryApi {
+    pub(crate) fn new(url: &str, ttl: Duration) -> Result<Self> {
+        if parse_forward_target(url).is_none() {
+            bail!("PROXY_CATEGORY_URL `{url}` must be an http:// URL");
+        }
+        Ok(Self {
+            url: url.to_string(),
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<String>> {
+        let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let (fetched, categories) = cache.get(host)?;
+        (fetched.elapsed() < self.ttl).then(|| categories.clone())
+    }
+
+    async fn fetch(&self, host: &str) -> Result<Vec<String>> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{separator}host={}", self.url, encode(host));
+        let target = parse_forward_target(&url)
+            .ok_or_else(|| anyhow!("Unsupported category API URL `{}`", self.url))?;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+            target.origin_path, target.authority
+        );
+        let response = timeout(LOOKUP_TIMEOUT, async {
+            let mut stream = TcpStream::connect(&target.authority).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, anyhow::Error>(response)
+        })
+        .await??;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("Category API sent a malformed response"))?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            bail!("Category API answered `{status}` for `{host}`");
+        }
+        let body: Value = serde_json::from_str(body)?;
+        Ok(body["categories"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(str::to_ascii_lowercase)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Categorizer for CategoryApi {
+    async fn categories(&self, host: &str) -> Result<Vec<String>> {
+        let host = normalize(host);
+        if let Some(categories) = self.cached(&host) {
+            return Ok(categories);
+        }
+        let categories = self.fetch(&host).await?;
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if cache.len() >= MAX_CACHED_HOSTS {
+            cache.retain(|_, (fetched, _)| fetched.elapsed() < self.ttl);
+        }
+        if cache.len() >= MAX_CACHED_HOSTS {
+            cache.clear();
+        }
+        cache.insert(host, (Instant::now(), categories.clone()));
+        Ok(categories)
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Categories {
+    custom: Option<Arc<dyn Categorizer>>,
+    active: Option<Arc<dyn Categorizer>>,
+}
+
+impl Categories {
+    pub(crate) fn new(
+        config: &CategoryConfig,
+        custom: Option<Arc<dyn Categorizer>>,
+    ) -> Result<Self> {
+        let active: Option<Arc<dyn Categorizer>> = match (&custom, &config.file, &config.url) {
+            (Some(custom), _, _) => Some(custom.clone()),
+            (None, Some(path), _) => Some(Arc::new(CategoryList::load(path)?)),
+            (None, None, Some(url)) => Some(Arc::new(CategoryApi::new(url, config.cache_ttl)?)),
+            (None, None, None) => None,
+        };
+        Ok(Self { custom, active })
+    }
+
+    pub(crate) fn reloaded(
+        &self,
+        config: &CategoryConfig,
+        previous: &CategoryConfig,
+    ) -> Result<Self> {
+        if config == previous && config.file.is_none() {
+            return Ok(self.clone());
+        }
+        Self::new(config, self.custom.clone())
+    }
+
+    pub(crate) async fn lookup(&self, host: &str) -> Vec<String> {
+        let Some(categorizer) = &self.active else {
+            return Vec::new();
+        };
+        match categorizer.categories(host).await {
+            Ok(categories) => categories
+                .iter()
+                .map(|category| category.to_ascii_lowercase())
+                .collect(),
+            Err(err) => {
+                warn!(error = format!("{err}"), "Category lookup failed");
+                Vec::new()
+            }
+        }
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn encode(value: &str) -> String {
+    value.bytes().fold(String::new(), |mut encoded, byte| {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_') {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+        encoded
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn list_matches_domains_and_their_subdomains() -> Result<()> {
+        let list = CategoryList::parse(
+            "# managed list\nexample.com,adult\n\ncdn.example.com, Ads ,tracking\nmalware.test,malware\n",
+        )?;
+
+        assert_eq!(list.lookup("Example.COM."), ["adult"]);
+        assert_eq!(
+            list.lookup("img.cdn.example.com"),
+            ["ads", "tracking", "adult"]
+        );
+        assert!(list.lookup("example.org").is_empty());
+        assert!(CategoryList::parse("no-category.test\n").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_answers_are_cached() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/v1/categorize", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = vec![0u8; 1024];
+            let size = socket.read(&mut request).await?;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\n\r\n{\"categories\":[\"Malware\"]}")
+                .await?;
+            Ok::<_, anyhow::Error>(String::from_utf8_lossy(&request[..size]).into_owned())
+        });
+        let api = CategoryApi::new(&url, Duration::from_mins(1))?;
+
+        assert_eq!(api.categories("Bad.Test").await?, ["malware"]);
+        assert_eq!(api.categories("bad.test").await?, ["malware"]);
+        assert!(
+            server
+                .await??
+                .starts_with("GET /v1/categorize?host=bad.test HTTP/1.1\r\n")
+        );
+        Ok(())
+    }
+}
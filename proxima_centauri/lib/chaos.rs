@@ -0,0 +1,137 @@
+use crate::tunnel::CloseReason;
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::{Mutex, PoisonError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosConfig {
+    pub dial_delay_ms: u64,
+    pub dial_error_percent: u8,
+    pub reset_percent: u8,
+    pub reset_after_ms: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            dial_delay_ms: 0,
+            dial_error_percent: 0,
+            reset_percent: 0,
+            reset_after_ms: 5_000,
+        }
+    }
+}
+
+impl ChaosConfig {
+    pub const fn is_enabled(&self) -> bool {
+        self.dial_delay_ms > 0 || self.dial_error_percent > 0 || self.reset_percent > 0
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.dial_error_percent > 100 || self.reset_percent > 100 {
+            bail!("Chaos fault percentages must be between 0 and 100");
+        }
+        if self.is_enabled() && !cfg!(feature = "chaos") {
+            bail!("Chaos faults configured but the `chaos` feature is disabled");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Chaos {
+    config: Mutex<ChaosConfig>,
+}
+
+impl Chaos {
+    pub(crate) const fn new(config: ChaosConfig) -> Self {
+        Self {
+            config: Mutex::new(config),
+        }
+    }
+
+    pub(crate) fn config(&self) -> ChaosConfig {
+        *self.config.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn set(&self, config: ChaosConfig) -> Result<()> {
+        config.check()?;
+        *self.config.lock().unwrap_or_else(PoisonError::into_inner) = config;
+        Ok(())
+    }
+
+    #[cfg(feature = "chaos")]
+    pub(crate) async fn before_dial(&self) -> io::Result<()> {
+        let config = self.config();
+        if config.dial_delay_ms > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(config.dial_delay_ms)).await;
+        }
+        if roll(config.dial_error_percent) {
+            return Err(io::Error::other("injected chaos dial failure"));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    #[allow(clippy::unused_async)]
+    pub(crate) async fn before_dial(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(feature = "chaos")]
+    pub(crate) async fn tunnel_reset(&self) -> CloseReason {
+        let config = self.config();
+        if !roll(config.reset_percent) {
+            return std::future::pending().await;
+        }
+        let after = random_below(config.reset_after_ms.saturating_add(1));
+        tokio::time::sleep(std::time::Duration::from_millis(after)).await;
+        CloseReason::ChaosReset
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    pub(crate) async fn tunnel_reset(&self) -> CloseReason {
+        std::future::pending().await
+    }
+}
+
+#[cfg(feature = "chaos")]
+fn roll(percent: u8) -> bool {
+    percent > 0 && random_below(100) < u64::from(percent)
+}
+
+#[cfg(feature = "chaos")]
+fn random_below(bound: u64) -> u64 {
+    use ring::rand::{SecureRandom, SystemRandom};
+
+    let mut bytes = [0u8; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return bound;
+    }
+    u64::from_le_bytes(bytes) % bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_out_of_range_and_disabled_faults() {
+        let chaos = Chaos::default();
+        let invalid = ChaosConfig {
+            reset_percent: 101,
+            ..ChaosConfig::default()
+        };
+        assert!(chaos.set(invalid).is_err());
+        assert_eq!(chaos.config(), ChaosConfig::default());
+
+        let delayed = ChaosConfig {
+            dial_delay_ms: 10,
+            ..ChaosConfig::default()
+        };
+        assert_eq!(chaos.set(delayed).is_ok(), cfg!(feature = "chaos"));
+        assert!(chaos.set(ChaosConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Mutex, PoisonError};
+#[cfg(any(test, feature = "test-util"))]
+use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn unix_now(&self) -> u64 {
+        unix_now()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub(crate) fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct MockClock {
+    origin: Instant,
+    origin_unix: u64,
+    offset: Mutex<Duration>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        Self::starting_at(unix_now())
+    }
+
+    pub fn starting_at(unix_seconds: u64) -> Arc<Self> {
+        Arc::new(Self {
+            origin: Instant::now(),
+            origin_unix: unix_seconds,
+            offset: Mutex::new(Duration::ZERO),
+        })
+    }
+
+    fn offset(&self) -> Duration {
+        *self.offset.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap_or_else(PoisonError::into_inner) += by;
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.origin + self.offset()
+    }
+
+    fn unix_now(&self) -> u64 {
+        self.origin_unix + self.offset().as_secs()
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
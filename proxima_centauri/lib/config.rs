@@ -1,30 +1,559 @@
+use crate::acl::AclConfig;
+use crate::anomaly::AnomalyConfig;
+use crate::category::CategoryConfig;
+use crate::chaos::ChaosConfig;
+use crate::coordinator::CoordinatorConfig;
+use crate::dial::{KeepaliveConfig, OutboundBinding, OutboundConfig, SocketOptions};
+use crate::egress::EgressPoolConfig;
+use crate::events::WebhookConfig;
+use crate::geoip::GeoPolicy;
+use crate::http_utils::request::HeaderPolicy;
+use crate::http_utils::response::ConnectHeaders;
+use crate::intercept::InterceptConfig;
+use crate::ledger::LedgerRotation;
+use crate::logging;
+use crate::pool::ForwardPoolConfig;
+use crate::registry::{LimitValue, Limits};
+use crate::reporter::StatsReportConfig;
+use crate::response_cache::ForwardCacheConfig;
+use crate::routing::UpstreamProxy;
+use crate::secrets::Secrets;
+use crate::sni::SniPolicy;
+use crate::store::StoreConfig;
+use crate::transparent::TransparentConfig;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Once;
+use std::time::Duration;
 
 static INIT: Once = Once::new();
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StealthMode {
+    #[default]
+    Off,
+    Close,
+    Delay(Duration),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Enforcement {
+    #[default]
+    Enforce,
+    Shadow,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UsersValidation {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScheduleEnforcement {
+    #[default]
+    Connect,
+    Tunnel,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub reuse_port: bool,
+    pub v6_only: bool,
+    pub drain_timeout: u64,
+}
+
 pub struct Config {
     pub port: String,
     pub host: String,
     pub connection_timeout: u64,
+    pub stall_timeout: u64,
+    pub tunnel_buffer: usize,
+    pub header_timeout: u64,
+    pub max_request_line: usize,
+    pub max_header_bytes: usize,
+    pub max_connections_per_ip: usize,
+    pub max_connections: usize,
+    pub accept_wait: u64,
+    pub listener: ListenerConfig,
+    pub happy_eyeballs_delay: u64,
+    pub connect_attempt_timeout: u64,
+    pub connect_max_attempts: usize,
+    pub auth_max_failures: usize,
+    pub auth_failure_window: u64,
+    pub auth_lockout: u64,
+    pub auth_tarpit_after: usize,
+    pub auth_tarpit_delay: u64,
+    pub forward_http: bool,
+    pub forward_headers: HeaderPolicy,
+    pub forward_pool: ForwardPoolConfig,
+    pub forward_cache: ForwardCacheConfig,
+    pub tls_origin_ca: Option<String>,
+    pub intercept: Option<InterceptConfig>,
+    pub connect_headers: ConnectHeaders,
+    pub usage_host: String,
+    pub stealth: StealthMode,
+    pub enforcement: Enforcement,
+    pub maintenance: Option<u64>,
+    pub chaos: ChaosConfig,
+    pub keepalive: Option<KeepaliveConfig>,
+    pub socket: SocketOptions,
+    pub sni: SniPolicy,
+    pub session_ttl: u64,
+    pub stats_report: StatsReportConfig,
+    pub plans: HashMap<String, Limits>,
+    pub plan_errors: Vec<String>,
+    pub tenant_limits: HashMap<String, Limits>,
+    pub users_file: Option<PathBuf>,
+    pub users_key: Option<String>,
+    pub users_validation: UsersValidation,
+    pub schedule_enforcement: ScheduleEnforcement,
+    pub store: StoreConfig,
+    pub ledger_path: Option<PathBuf>,
+    pub ledger_rollup: u64,
+    pub ledger_rotation: LedgerRotation,
+    pub traffic_warn_percent: Option<u8>,
+    pub traffic_window: u64,
+    pub anomaly: Option<AnomalyConfig>,
+    pub warn_webhook: Option<String>,
+    pub webhooks: WebhookConfig,
+    pub admin_addr: Option<String>,
+    pub admin_token: Option<String>,
+    pub acl: AclConfig,
+    pub categories: CategoryConfig,
+    pub allow_ip_targets: bool,
+    pub reverse_dns: bool,
+    pub geoip_db: Option<String>,
+    pub geoip_policy: GeoPolicy,
+    pub outbound: OutboundConfig,
+    pub egress_pool: EgressPoolConfig,
+    pub upstreams: HashMap<String, UpstreamProxy>,
+    pub transparent: Option<TransparentConfig>,
+    pub secret_lease: Option<Duration>,
+    pub secret_errors: Vec<String>,
 }
 
 impl Config {
     pub fn addr(&self) -> String {
-        format!("{}:{}", self.host, self.port)
+        if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
     }
 }
 
 pub fn init() {
     INIT.call_once(|| {
-        tracing_subscriber::fmt::init();
+        logging::install();
         dotenv::dotenv().ok();
     });
 }
 
 pub fn build_config() -> Config {
+    let secrets = Secrets::from_env();
     Config {
         port: dotenv::var("PROXY_PORT").unwrap_or_else(|_| String::from("9090")),
         host: dotenv::var("PROXY_HOST").unwrap_or_else(|_| String::from("127.0.0.1")),
         connection_timeout: 60,
+        stall_timeout: var_or("PROXY_STALL_TIMEOUT", 30),
+        tunnel_buffer: var_or::<usize>("PROXY_TUNNEL_BUFFER", 8192).clamp(1024, 1 << 20),
+        header_timeout: var_or("PROXY_HEADER_TIMEOUT", 10),
+        max_request_line: var_or("PROXY_MAX_REQUEST_LINE", 8192),
+        max_header_bytes: var_or("PROXY_MAX_HEADER_BYTES", 16384),
+        max_connections_per_ip: var_or("PROXY_MAX_CONNECTIONS_PER_IP", 64),
+        max_connections: var_or("PROXY_MAX_CONNECTIONS", 4096),
+        accept_wait: var_or("PROXY_ACCEPT_WAIT_MS", 100),
+        listener: ListenerConfig {
+            reuse_port: dotenv::var("PROXY_REUSE_PORT").is_ok_and(|value| value == "true"),
+            v6_only: dotenv::var("PROXY_V6_ONLY").is_ok_and(|value| value == "true"),
+            drain_timeout: var_or("PROXY_DRAIN_TIMEOUT", 300),
+        },
+        happy_eyeballs_delay: var_or("PROXY_HAPPY_EYEBALLS_DELAY_MS", 250),
+        connect_attempt_timeout: var_or("PROXY_CONNECT_ATTEMPT_TIMEOUT", 5),
+        connect_max_attempts: var_or("PROXY_CONNECT_MAX_ATTEMPTS", 4),
+        auth_max_failures: var_or("PROXY_AUTH_MAX_FAILURES", 5),
+        auth_failure_window: var_or("PROXY_AUTH_FAILURE_WINDOW", 300),
+        auth_lockout: var_or("PROXY_AUTH_LOCKOUT", 60),
+        auth_tarpit_after: var_or("PROXY_AUTH_TARPIT_AFTER", 0),
+        auth_tarpit_delay: var_or("PROXY_AUTH_TARPIT_DELAY_MS", 2000),
+        forward_http: dotenv::var("PROXY_FORWARD_HTTP").is_ok_and(|value| value == "true"),
+        forward_headers: forward_headers(),
+        forward_pool: forward_pool_config(),
+        forward_cache: forward_cache_config(),
+        tls_origin_ca: dotenv::var("PROXY_TLS_ORIGIN_CA").ok(),
+        intercept: intercept_config(),
+        connect_headers: connect_headers(),
+        usage_host: dotenv::var("PROXY_USAGE_HOST").unwrap_or_else(|_| String::from("proxy.local")),
+        stealth: stealth_mode(),
+        enforcement: enforcement(),
+        maintenance: maintenance(),
+        chaos: chaos_config(),
+        keepalive: keepalive(),
+        socket: socket_options(),
+        sni: sni_policy(),
+        session_ttl: var_or("PROXY_SESSION_TTL", 300),
+        stats_report: stats_report(),
+        plans: plans(),
+        plan_errors: plan_errors(),
+        tenant_limits: tenant_limits(),
+        users_file: dotenv::var("PROXY_USERS_FILE").ok().map(PathBuf::from),
+        users_key: secrets.var("PROXY_USERS_KEY"),
+        users_validation: users_validation(),
+        schedule_enforcement: schedule_enforcement(),
+        store: store_config(&secrets),
+        ledger_path: dotenv::var("PROXY_LEDGER_PATH").ok().map(PathBuf::from),
+        ledger_rollup: var_or("PROXY_LEDGER_ROLLUP", 3600),
+        ledger_rotation: ledger_rotation(),
+        traffic_warn_percent: dotenv::var("PROXY_TRAFFIC_WARN_PERCENT")
+            .ok()
+            .and_then(|value| value.parse().ok()),
+        traffic_window: var_or("PROXY_TRAFFIC_WINDOW", 0),
+        anomaly: anomaly_config(),
+        warn_webhook: dotenv::var("PROXY_WARN_WEBHOOK").ok(),
+        webhooks: webhook_config(&secrets),
+        admin_addr: dotenv::var("PROXY_ADMIN_ADDR").ok(),
+        admin_token: secrets.var("PROXY_ADMIN_TOKEN"),
+        acl: acl_config(),
+        categories: category_config(),
+        allow_ip_targets: dotenv::var("PROXY_ALLOW_IP_TARGETS")
+            .map_or(true, |value| value != "false"),
+        reverse_dns: dotenv::var("PROXY_REVERSE_DNS").is_ok_and(|value| value == "true"),
+        geoip_db: dotenv::var("PROXY_GEOIP_DB").ok(),
+        geoip_policy: GeoPolicy {
+            allow: list_var("PROXY_GEOIP_ALLOW"),
+            deny: list_var("PROXY_GEOIP_DENY"),
+        },
+        outbound: OutboundConfig {
+            default: OutboundBinding {
+                addr: dotenv::var("PROXY_OUTBOUND_ADDR")
+                    .ok()
+                    .and_then(|value| value.parse().ok()),
+                interface: dotenv::var("PROXY_OUTBOUND_INTERFACE").ok(),
+            },
+            users: list_var("PROXY_OUTBOUND_USERS")
+                .iter()
+                .filter_map(|item| item.split_once('='))
+                .filter_map(|(user, addr)| {
+                    let binding = OutboundBinding {
+                        addr: Some(addr.trim().parse().ok()?),
+                        interface: None,
+                    };
+                    Some((user.trim().to_string(), binding))
+                })
+                .collect(),
+        },
+        egress_pool: egress_pool_config(),
+        upstreams: upstreams(),
+        transparent: transparent_config(),
+        secret_lease: secrets.lease(),
+        secret_errors: secrets.errors(),
+    }
+}
+
+fn var_or<T: FromStr>(name: &str, default: T) -> T {
+    dotenv::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn forward_headers() -> HeaderPolicy {
+    HeaderPolicy {
+        forwarded_for: dotenv::var("PROXY_FORWARDED_FOR").is_ok_and(|value| value == "true"),
+        via: dotenv::var("PROXY_VIA").is_ok_and(|value| value == "true"),
+        remove: list_var("PROXY_HEADERS_REMOVE"),
+        inject: list_var("PROXY_HEADERS_ADD")
+            .iter()
+            .filter_map(|item| item.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect(),
+    }
+}
+
+fn forward_cache_config() -> ForwardCacheConfig {
+    let defaults = ForwardCacheConfig::default();
+    ForwardCacheConfig {
+        max_bytes: var_or("PROXY_FORWARD_CACHE_BYTES", defaults.max_bytes),
+        max_entry: var_or("PROXY_FORWARD_CACHE_MAX_ENTRY", defaults.max_entry),
+    }
+}
+
+fn forward_pool_config() -> ForwardPoolConfig {
+    let defaults = ForwardPoolConfig::default();
+    ForwardPoolConfig {
+        per_origin: var_or("PROXY_POOL_PER_ORIGIN", defaults.per_origin),
+        max_idle: var_or("PROXY_POOL_MAX_IDLE", defaults.max_idle),
+        idle_timeout: Duration::from_secs(var_or(
+            "PROXY_POOL_IDLE_TIMEOUT",
+            defaults.idle_timeout.as_secs(),
+        )),
+    }
+}
+
+fn egress_pool_config() -> EgressPoolConfig {
+    EgressPoolConfig {
+        addrs: list_var("PROXY_EGRESS_POOL")
+            .iter()
+            .filter_map(|addr| addr.parse().ok())
+            .collect(),
+        strategy: dotenv::var("PROXY_EGRESS_STRATEGY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default(),
+        per_session: dotenv::var("PROXY_EGRESS_ROTATE").is_ok_and(|value| value == "session"),
+    }
+}
+
+fn upstreams() -> HashMap<String, UpstreamProxy> {
+    list_var("PROXY_UPSTREAMS")
+        .iter()
+        .filter_map(|item| item.split_once('='))
+        .filter_map(|(name, proxy)| Some((name.trim().to_string(), proxy.trim().parse().ok()?)))
+        .collect()
+}
+
+fn plans() -> HashMap<String, Limits> {
+    let mut plans = HashMap::from([
+        (String::from("free"), Limits::with_low_limits()),
+        (
+            String::from("pro"),
+            Limits::new(
+                LimitValue::Restricted(32),
+                LimitValue::Restricted(100 * 1024 * 1024 * 1024),
+            ),
+        ),
+        (String::from("unlimited"), Limits::default()),
+    ]);
+    plans.extend(
+        list_var("PROXY_PLANS")
+            .iter()
+            .filter_map(|item| parse_plan(item).ok()),
+    );
+    plans
+}
+
+fn plan_errors() -> Vec<String> {
+    list_var("PROXY_PLANS")
+        .iter()
+        .filter_map(|item| {
+            let err = parse_plan(item).err()?;
+            Some(format!("PROXY_PLANS entry `{item}` is invalid: {err}"))
+        })
+        .collect()
+}
+
+fn parse_plan(item: &str) -> anyhow::Result<(String, Limits)> {
+    let (name, limits) = item
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected `name=limits`"))?;
+    Ok((name.trim().to_string(), limits.parse()?))
+}
+
+fn tenant_limits() -> HashMap<String, Limits> {
+    list_var("PROXY_TENANT_LIMITS")
+        .iter()
+        .filter_map(|item| item.split_once('='))
+        .filter_map(|(name, limits)| Some((name.trim().to_string(), limits.parse().ok()?)))
+        .collect()
+}
+
+fn anomaly_config() -> Option<AnomalyConfig> {
+    let multiplier = dotenv::var("PROXY_ANOMALY_MULTIPLIER").ok()?.parse().ok()?;
+    let defaults = AnomalyConfig::default();
+    Some(AnomalyConfig {
+        interval: Duration::from_secs(var_or(
+            "PROXY_ANOMALY_INTERVAL",
+            defaults.interval.as_secs(),
+        )),
+        window: var_or("PROXY_ANOMALY_WINDOW", defaults.window),
+        multiplier,
+        min_rate: var_or("PROXY_ANOMALY_MIN_RATE", defaults.min_rate),
+    })
+}
+
+fn acl_config() -> AclConfig {
+    AclConfig {
+        allow: list_var("PROXY_ACL_ALLOW"),
+        deny: list_var("PROXY_ACL_DENY"),
+    }
+}
+
+fn category_config() -> CategoryConfig {
+    let defaults = CategoryConfig::default();
+    CategoryConfig {
+        file: dotenv::var("PROXY_CATEGORY_FILE").ok().map(PathBuf::from),
+        url: dotenv::var("PROXY_CATEGORY_URL").ok(),
+        cache_ttl: Duration::from_secs(var_or(
+            "PROXY_CATEGORY_CACHE_TTL",
+            defaults.cache_ttl.as_secs(),
+        )),
+    }
+}
+
+fn intercept_config() -> Option<InterceptConfig> {
+    Some(InterceptConfig {
+        ca_cert: dotenv::var("PROXY_INTERCEPT_CA_CERT").ok()?.into(),
+        ca_key: dotenv::var("PROXY_INTERCEPT_CA_KEY").ok()?.into(),
+    })
+}
+
+fn transparent_config() -> Option<TransparentConfig> {
+    Some(TransparentConfig {
+        addr: dotenv::var("PROXY_TRANSPARENT_ADDR").ok()?,
+        mode: dotenv::var("PROXY_TRANSPARENT_MODE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default(),
+        clients: list_var("PROXY_TRANSPARENT_CLIENTS"),
+    })
+}
+
+fn connect_headers() -> ConnectHeaders {
+    let non_empty = |name| dotenv::var(name).ok().filter(|value| !value.trim().is_empty());
+    ConnectHeaders {
+        anonymous: dotenv::var("PROXY_CONNECT_ANONYMOUS").is_ok_and(|value| value == "true"),
+        via: dotenv::var("PROXY_CONNECT_VIA").is_ok_and(|value| value == "true"),
+        server: non_empty("PROXY_CONNECT_SERVER"),
+        request_id: non_empty("PROXY_CONNECT_REQUEST_ID"),
+    }
+}
+
+fn sni_policy() -> SniPolicy {
+    SniPolicy {
+        mode: dotenv::var("PROXY_SNI")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default(),
+        timeout: Duration::from_millis(var_or("PROXY_SNI_TIMEOUT_MS", 500)),
+    }
+}
+
+fn webhook_config(secrets: &Secrets) -> WebhookConfig {
+    WebhookConfig {
+        urls: list_var("PROXY_WEBHOOK_URLS"),
+        secret: secrets.var("PROXY_WEBHOOK_SECRET"),
+        retries: var_or("PROXY_WEBHOOK_RETRIES", 3),
+        spill_path: dotenv::var("PROXY_WEBHOOK_SPILL_PATH")
+            .ok()
+            .map(PathBuf::from),
+        spill_max_bytes: var_or("PROXY_WEBHOOK_SPILL_MAX_BYTES", 16 * 1024 * 1024),
+    }
+}
+
+fn stats_report() -> StatsReportConfig {
+    StatsReportConfig {
+        sink: dotenv::var("PROXY_STATS_PUSH")
+            .ok()
+            .and_then(|value| value.parse().ok()),
+        prefix: dotenv::var("PROXY_STATS_PREFIX").unwrap_or_else(|_| String::from("procent")),
+        dump: dotenv::var("PROXY_STATS_DUMP").is_ok_and(|value| value == "true"),
+    }
+}
+
+fn store_config(secrets: &Secrets) -> StoreConfig {
+    match dotenv::var("PROXY_STORE").as_deref() {
+        Ok("file") => StoreConfig::File(
+            dotenv::var("PROXY_STORE_PATH")
+                .unwrap_or_else(|_| String::from("registry.json"))
+                .into(),
+        ),
+        Ok("redis") => StoreConfig::Redis(
+            secrets
+                .var("PROXY_REDIS_URL")
+                .unwrap_or_else(|| String::from("redis://127.0.0.1:6379")),
+        ),
+        Ok("coordinator") => StoreConfig::Coordinator(CoordinatorConfig {
+            url: dotenv::var("PROXY_COORDINATOR_URL")
+                .unwrap_or_else(|_| String::from("http://127.0.0.1:7070")),
+            token: secrets.var("PROXY_COORDINATOR_TOKEN"),
+            chunk: var_or("PROXY_COORDINATOR_CHUNK_MB", 64) << 20,
+        }),
+        _ => StoreConfig::Memory,
+    }
+}
+
+fn ledger_rotation() -> LedgerRotation {
+    LedgerRotation {
+        daily: dotenv::var("PROXY_LEDGER_ROTATE").is_ok_and(|value| value == "daily"),
+        retention_days: var_or("PROXY_LEDGER_RETENTION_DAYS", 0),
+    }
+}
+
+fn maintenance() -> Option<u64> {
+    dotenv::var("PROXY_MAINTENANCE")
+        .is_ok_and(|value| value == "true")
+        .then(|| var_or("PROXY_MAINTENANCE_RETRY_AFTER", 300))
+}
+
+fn chaos_config() -> ChaosConfig {
+    ChaosConfig {
+        dial_delay_ms: var_or("PROXY_CHAOS_DIAL_DELAY_MS", 0),
+        dial_error_percent: var_or("PROXY_CHAOS_DIAL_ERROR_PERCENT", 0),
+        reset_percent: var_or("PROXY_CHAOS_RESET_PERCENT", 0),
+        reset_after_ms: var_or("PROXY_CHAOS_RESET_AFTER_MS", 5_000),
+    }
+}
+
+fn keepalive() -> Option<KeepaliveConfig> {
+    let idle: u64 = var_or("PROXY_KEEPALIVE_IDLE", 30);
+    (idle > 0).then(|| KeepaliveConfig {
+        idle: Duration::from_secs(idle),
+        interval: Duration::from_secs(var_or("PROXY_KEEPALIVE_INTERVAL", 10)),
+        retries: var_or("PROXY_KEEPALIVE_RETRIES", 3),
+    })
+}
+
+fn socket_options() -> SocketOptions {
+    let linger: u64 = var_or("PROXY_SO_LINGER", 0);
+    SocketOptions {
+        nodelay: var_or("PROXY_TCP_NODELAY", true),
+        linger: (linger > 0).then(|| Duration::from_secs(linger)),
+    }
+}
+
+fn enforcement() -> Enforcement {
+    match dotenv::var("PROXY_ENFORCEMENT").as_deref() {
+        Ok("shadow") => Enforcement::Shadow,
+        _ => Enforcement::Enforce,
     }
 }
+
+fn users_validation() -> UsersValidation {
+    match dotenv::var("PROXY_USERS_VALIDATION").as_deref() {
+        Ok("strict") => UsersValidation::Strict,
+        _ => UsersValidation::Lenient,
+    }
+}
+
+fn schedule_enforcement() -> ScheduleEnforcement {
+    match dotenv::var("PROXY_SCHEDULE_ENFORCEMENT").as_deref() {
+        Ok("tunnel") => ScheduleEnforcement::Tunnel,
+        _ => ScheduleEnforcement::Connect,
+    }
+}
+
+fn stealth_mode() -> StealthMode {
+    match dotenv::var("PROXY_STEALTH").as_deref() {
+        Ok("close") => StealthMode::Close,
+        Ok("delay") => StealthMode::Delay(Duration::from_secs(
+            var_or("PROXY_STEALTH_DELAY", 5),
+        )),
+        _ => StealthMode::Off,
+    }
+}
+
+fn list_var(name: &str) -> Vec<String> {
+    dotenv::var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,184 @@
+use crate::clock::unix_now;
+use crate::hooks::TunnelOpenContext;
+use crate::registry::TrafficCounters;
+use crate::tunnel::CloseReason;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: u64,
+    pub(crate) user: String,
+    pub(crate) client_ip: IpAddr,
+    pub(crate) target: Option<String>,
+    pub(crate) started_at: u64,
+    pub(crate) ingress: u128,
+    pub(crate) egress: u128,
+}
+
+pub(crate) struct Connection {
+    id: u64,
+    user: String,
+    client_ip: IpAddr,
+    target: Option<String>,
+    started_at: u64,
+    pub(crate) counters: TrafficCounters,
+    kill: CancellationToken,
+    reason: OnceLock<CloseReason>,
+}
+
+impl Connection {
+    pub(crate) async fn killed(&self) -> CloseReason {
+        self.kill.cancelled().await;
+        self.reason.get().copied().unwrap_or(CloseReason::AdminKick)
+    }
+
+    fn stop(&self, reason: CloseReason) {
+        let _ = self.reason.set(reason);
+        self.kill.cancel();
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            user: self.user.clone(),
+            client_ip: self.client_ip,
+            target: self.target.clone(),
+            started_at: self.started_at,
+            ingress: self.counters.ingress(),
+            egress: self.counters.egress(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Connections {
+    active: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+impl Connections {
+    pub(crate) fn open(
+        &self,
+        opening: &TunnelOpenContext,
+        live: Option<Arc<TrafficCounters>>,
+    ) -> Arc<Connection> {
+        let id = opening.connection_id;
+        let connection = Arc::new(Connection {
+            id,
+            user: opening.user.clone(),
+            client_ip: opening.client_ip,
+            target: opening.target.clone(),
+            started_at: unix_now(),
+            counters: TrafficCounters::within(live),
+            kill: CancellationToken::new(),
+            reason: OnceLock::new(),
+        });
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, connection.clone());
+        connection
+    }
+
+    pub(crate) fn close(&self, id: u64) {
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id);
+    }
+
+    pub(crate) fn kill(&self, id: u64) -> bool {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        active
+            .get(&id)
+            .map(|connection| connection.stop(CloseReason::AdminKick))
+            .is_some()
+    }
+
+    pub(crate) fn users(&self) -> BTreeSet<String> {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        active
+            .values()
+            .map(|connection| connection.user.clone())
+            .collect()
+    }
+
+    pub(crate) fn kill_user(&self, user: &str, reason: CloseReason) -> usize {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        active
+            .values()
+            .filter(|connection| connection.user == user)
+            .map(|connection| connection.stop(reason))
+            .count()
+    }
+
+    pub(crate) fn list(
+        &self,
+        user: Option<&str>,
+        offset: usize,
+        limit: usize,
+    ) -> (usize, Vec<ConnectionInfo>) {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        let matching = active
+            .values()
+            .filter(|connection| user.is_none_or(|user| connection.user == user));
+        let total = matching.clone().count();
+        let page = matching
+            .skip(offset)
+            .take(limit)
+            .map(|connection| connection.info())
+            .collect();
+        (total, page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn opening(connection_id: u64, user: &str, target: Option<&str>) -> TunnelOpenContext {
+        TunnelOpenContext {
+            user: user.to_string(),
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            session_id: 1,
+            connection_id,
+            host: String::new(),
+            target: target.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_pages_and_kills_connections() {
+        let connections = Connections::default();
+        let user_live = Arc::new(TrafficCounters::default());
+        let first = connections.open(&opening(1, "alice", None), Some(user_live.clone()));
+        connections.open(&opening(2, "bob", Some("b:443")), None);
+        let third = connections.open(&opening(3, "alice", Some("c:443")), None);
+        first.counters.add_ingress(10);
+        first.counters.add_egress(5);
+
+        let (total, page) = connections.list(Some("alice"), 0, 1);
+        assert_eq!(total, 2);
+        assert_eq!((page[0].id, page[0].ingress, page[0].egress), (1, 10, 5));
+        assert_eq!(user_live.total(), 15);
+        let (total, page) = connections.list(None, 1, 10);
+        assert_eq!(total, 3);
+        assert_eq!(page.len(), 2);
+
+        assert!(connections.kill(1));
+        assert_eq!(first.killed().await, CloseReason::AdminKick);
+        connections.close(1);
+        assert!(!connections.kill(1));
+        assert_eq!(connections.list(None, 0, 10).0, 2);
+
+        let users: Vec<String> = connections.users().into_iter().collect();
+        assert_eq!(users, ["alice", "bob"]);
+        let killed = connections.kill_user("alice", CloseReason::OutsideSchedule);
+        assert_eq!(killed, 1);
+        assert_eq!(third.killed().await, CloseReason::OutsideSchedule);
+    }
+}
@@ -1,22 +1,245 @@
-use crate::auth::Database;
+use crate::acl::Acl;
+use crate::auth::AuthProvider;
+use crate::auth_audit::AuthAudit;
+use crate::bandwidth::Bandwidth;
+use crate::category::{Categories, Categorizer};
+use crate::chaos::Chaos;
+use crate::clock::Clock;
 use crate::config::Config;
+use crate::connections::Connections;
+use crate::egress::EgressPool;
+use crate::events::EventBus;
+use crate::geoip::GeoIp;
+use crate::hooks::HookChain;
+use crate::info::RuntimeInfo;
+use crate::intercept::{Inspector, Interceptor};
+use crate::ip_limit::IpLimiter;
+use crate::ledger::Ledger;
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::origin::OriginTls;
+use crate::pool::OriginPool;
 use crate::registry::Registry;
+use crate::response_cache::ResponseCache;
+use crate::store::RegistryStore;
+use anyhow::Result;
+use arc_swap::ArcSwap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tracing::warn;
+
+pub(crate) type SharedContext = Arc<ArcSwap<Context>>;
 
 #[derive(Clone)]
 pub(crate) struct Context {
     pub(crate) config: Arc<Config>,
-    pub(crate) database: Arc<Database>,
+    pub(crate) auth: Arc<dyn AuthProvider>,
     pub(crate) registry: Arc<Mutex<Registry>>,
+    pub(crate) store: Arc<dyn RegistryStore>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) connections: Arc<Connections>,
+    pub(crate) acl: Arc<Acl>,
+    pub(crate) categories: Categories,
+    pub(crate) geoip: Option<Arc<GeoIp>>,
+    pub(crate) egress: Option<Arc<EgressPool>>,
+    pub(crate) pool: Option<Arc<OriginPool>>,
+    pub(crate) cache: Option<Arc<ResponseCache>>,
+    pub(crate) origin_tls: Arc<OriginTls>,
+    pub(crate) events: EventBus,
+    pub(crate) ledger: Option<Arc<Ledger>>,
+    pub(crate) ip_limiter: Arc<IpLimiter>,
+    pub(crate) auth_audit: Arc<AuthAudit>,
+    pub(crate) bandwidth: Arc<Bandwidth>,
+    pub(crate) maintenance: Arc<Maintenance>,
+    pub(crate) chaos: Arc<Chaos>,
+    pub(crate) hooks: HookChain,
+    pub(crate) interceptor: Option<Arc<Interceptor>>,
+    inspector: Arc<dyn Inspector>,
+    pub(crate) runtime: Arc<RuntimeInfo>,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 impl Context {
-    pub(crate) fn new(config: Config, database: Database, registry: Registry) -> Self {
-        Self {
-            config:Arc::new(config),
-            database:Arc::new(database),
-            registry:Arc::new(Mutex::new(registry)),
+    pub(crate) async fn new(
+        config: Config,
+        auth: Arc<dyn AuthProvider>,
+        registry: Arc<Mutex<Registry>>,
+        store: Arc<dyn RegistryStore>,
+        clock: Arc<dyn Clock>,
+        (hooks, inspector, categorizer): (
+            HookChain,
+            Arc<dyn Inspector>,
+            Option<Arc<dyn Categorizer>>,
+        ),
+        runtime: RuntimeInfo,
+    ) -> Result<Self> {
+        let geoip = open_geoip(&config)?;
+        let ledger = match &config.ledger_path {
+            Some(path) => Some(Arc::new(
+                Ledger::open(path.clone(), config.ledger_rotation).await?,
+            )),
+            None => None,
+        };
+        let interceptor = open_interceptor(&config, &inspector)?;
+        registry
+            .lock()
+            .await
+            .set_traffic_window(config.traffic_window);
+        Ok(Self {
+            acl: Arc::new(Acl::compile(&config.acl)?),
+            categories: Categories::new(&config.categories, categorizer)?,
+            egress: EgressPool::new(config.egress_pool.clone()).map(Arc::new),
+            pool: OriginPool::new(config.forward_pool).map(Arc::new),
+            cache: ResponseCache::new(config.forward_cache).map(Arc::new),
+            origin_tls: Arc::new(OriginTls::new(config.tls_origin_ca.as_deref())?),
+            events: EventBus::start(config.webhooks.clone()),
+            ip_limiter: Arc::new(IpLimiter::new(config.max_connections_per_ip)),
+            auth_audit: Arc::new(auth_audit(&config, clock.clone())),
+            bandwidth: Arc::new(Bandwidth::default()),
+            maintenance: Arc::new(Maintenance::new(config.maintenance)),
+            chaos: Arc::new(Chaos::new(config.chaos)),
+            config: Arc::new(config),
+            auth,
+            registry,
+            store,
+            metrics: Arc::new(Metrics::default()),
+            connections: Arc::new(Connections::default()),
+            geoip,
+            ledger,
+            hooks,
+            interceptor,
+            inspector,
+            runtime: Arc::new(runtime),
+            clock,
+        })
+    }
+
+    pub(crate) async fn reloaded(&self, config: Config) -> Result<Self> {
+        let report = config.validate();
+        if !report.is_ok() {
+            anyhow::bail!("\n{report}");
+        }
+        config.chaos.check()?;
+        let acl = Arc::new(Acl::compile(&config.acl)?);
+        let categories = self
+            .categories
+            .reloaded(&config.categories, &self.config.categories)?;
+        let geoip = open_geoip(&config)?;
+        let origin_tls = if config.tls_origin_ca == self.config.tls_origin_ca {
+            self.origin_tls.clone()
+        } else {
+            Arc::new(OriginTls::new(config.tls_origin_ca.as_deref())?)
+        };
+        let interceptor = if config.intercept == self.config.intercept {
+            self.interceptor.clone()
+        } else {
+            open_interceptor(&config, &self.inspector)?
+        };
+        self.store.reload(&config.store).await?;
+        if let Err(err) = self.auth.reload(&config).await {
+            self.store.reload(&self.config.store).await?;
+            return Err(err);
+        }
+        if config.maintenance != self.config.maintenance {
+            self.maintenance.set(config.maintenance);
+        }
+        if config.chaos != self.config.chaos {
+            self.chaos.set(config.chaos)?;
+        }
+        if (&config.ledger_path, config.ledger_rotation)
+            != (&self.config.ledger_path, self.config.ledger_rotation)
+        {
+            warn!("Usage ledger settings changed; they take effect after a restart");
+        }
+        if config.traffic_window != self.config.traffic_window {
+            self.registry
+                .lock()
+                .await
+                .set_traffic_window(config.traffic_window);
         }
+        if config.max_connections_per_ip != self.config.max_connections_per_ip {
+            self.ip_limiter.set_max(config.max_connections_per_ip);
+        }
+        let audit_policy = |config: &Config| {
+            (
+                config.auth_max_failures,
+                config.auth_failure_window,
+                config.auth_lockout,
+                config.auth_tarpit_after,
+                config.auth_tarpit_delay,
+            )
+        };
+        let auth_audit = if audit_policy(&config) == audit_policy(&self.config) {
+            self.auth_audit.clone()
+        } else {
+            Arc::new(auth_audit(&config, self.clock.clone()))
+        };
+        let egress = if config.egress_pool == self.config.egress_pool {
+            self.egress.clone()
+        } else {
+            EgressPool::new(config.egress_pool.clone()).map(Arc::new)
+        };
+        let pool = if config.forward_pool == self.config.forward_pool {
+            self.pool.clone()
+        } else {
+            OriginPool::new(config.forward_pool).map(Arc::new)
+        };
+        let cache = if config.forward_cache == self.config.forward_cache {
+            self.cache.clone()
+        } else {
+            ResponseCache::new(config.forward_cache).map(Arc::new)
+        };
+        let events = if config.webhooks == self.config.webhooks {
+            self.events.clone()
+        } else {
+            EventBus::start(config.webhooks.clone())
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            acl,
+            categories,
+            geoip,
+            egress,
+            pool,
+            cache,
+            origin_tls,
+            events,
+            auth_audit,
+            interceptor,
+            ..self.clone()
+        })
     }
 }
+
+fn auth_audit(config: &Config, clock: Arc<dyn Clock>) -> AuthAudit {
+    AuthAudit::new(
+        config.auth_max_failures,
+        Duration::from_secs(config.auth_failure_window),
+        Duration::from_secs(config.auth_lockout),
+    )
+    .with_tarpit(
+        config.auth_tarpit_after,
+        Duration::from_millis(config.auth_tarpit_delay),
+    )
+    .with_clock(clock)
+}
+
+fn open_interceptor(
+    config: &Config,
+    inspector: &Arc<dyn Inspector>,
+) -> Result<Option<Arc<Interceptor>>> {
+    Ok(match &config.intercept {
+        Some(intercept) => Some(Arc::new(Interceptor::new(intercept, inspector.clone())?)),
+        None => None,
+    })
+}
+
+fn open_geoip(config: &Config) -> Result<Option<Arc<GeoIp>>> {
+    Ok(config
+        .geoip_db
+        .as_deref()
+        .map(|path| GeoIp::open(path, config.geoip_policy.clone()))
+        .transpose()?
+        .map(Arc::new))
+}
@@ -0,0 +1,600 @@
+use crate::admin::{read_admin_request, token_matches};
+use crate::clock::{self, Clock};
+use crate::http_utils::headers;
+use crate::http_utils::request::parse_forward_target;
+use crate::http_utils::response::json_response;
+use crate::registry::{LimitError, Limits};
+use crate::store::{MemoryStore, RegistryStore, StoreConfig};
+use anyhow::{Result, anyhow, bail};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use httparse::{EMPTY_HEADER, Request, Status};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+const COORDINATOR_TIMEOUT: Duration = Duration::from_secs(2);
+const LEASE_TTL: Duration = Duration::from_mins(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoordinatorConfig {
+    pub url: String,
+    pub token: Option<String>,
+    pub chunk: u128,
+}
+
+#[derive(Default, Serialize)]
+struct Account {
+    used: u128,
+    leased: u128,
+    #[serde(skip)]
+    grants: VecDeque<(u128, Instant)>,
+}
+
+impl Account {
+    fn expire(&mut self, now: Instant) {
+        self.grants.retain(|(_, expires)| *expires > now);
+        self.leased = self.grants.iter().map(|(granted, _)| granted).sum();
+    }
+
+    fn settle(&mut self, mut bytes: u128) {
+        while bytes > 0
+            && let Some((granted, _)) = self.grants.front_mut()
+        {
+            let settled = bytes.min(*granted);
+            *granted -= settled;
+            bytes -= settled;
+            if *granted == 0 {
+                self.grants.pop_front();
+            }
+        }
+        self.leased = self.grants.iter().map(|(granted, _)| granted).sum();
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseRequest {
+    user: String,
+    limit: u128,
+    chunk: u128,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsageReport {
+    user: String,
+    used: u128,
+    #[serde(default)]
+    returned: u128,
+}
+
+#[derive(Deserialize)]
+struct Grant {
+    granted: u128,
+    used: u128,
+}
+
+pub struct QuotaCoordinator {
+    token: Option<String>,
+    accounts: Mutex<HashMap<String, Account>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for QuotaCoordinator {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl QuotaCoordinator {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token,
+            accounts: Mutex::default(),
+            clock: clock::system(),
+        }
+    }
+
+    fn lease(&self, request: &LeaseRequest) -> Value {
+        let now = self.clock.now();
+        let mut accounts = self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        let account = accounts.entry(request.user.clone()).or_default();
+        account.expire(now);
+        let available = request
+            .limit
+            .saturating_sub(account.used)
+            .saturating_sub(account.leased);
+        let granted = request.chunk.min(available);
+        if granted > 0 {
+            account.grants.push_back((granted, now + LEASE_TTL * 2));
+            account.leased += granted;
+        }
+        json!({ "granted": granted, "used": account.used })
+    }
+
+    fn report(&self, report: &UsageReport) -> Value {
+        let mut accounts = self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        let account = accounts.entry(report.user.clone()).or_default();
+        account.used += report.used;
+        account.settle(report.used + report.returned);
+        json!({ "used": account.used })
+    }
+
+    fn usage(&self) -> Value {
+        let now = self.clock.now();
+        let mut accounts = self.accounts.lock().unwrap_or_else(PoisonError::into_inner);
+        for account in accounts.values_mut() {
+            account.expire(now);
+        }
+        json!({ "users": &*accounts })
+    }
+
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, shutdown: CancellationToken) {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Quota coordinator started on {addr}");
+        }
+        loop {
+            let socket = tokio::select! {
+                () = shutdown.cancelled() => return,
+                accepted = listener.accept() => match accepted {
+                    Ok((socket, _)) => socket,
+                    Err(err) => {
+                        warn!(error = format!("{err}"), "Quota coordinator accept failed");
+                        continue;
+                    }
+                },
+            };
+            let coordinator = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = coordinator.handle(socket).await {
+                    debug!(error = format!("{err}"), "Quota coordinator request failed");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut socket: TcpStream) -> Result<()> {
+        let Some(buff) = read_admin_request(&mut socket).await? else {
+            let body = json!({ "error": "request too large" });
+            socket
+                .write_all(&json_response("413 Payload Too Large", &body))
+                .await?;
+            return Ok(());
+        };
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut request = Request::new(&mut headers);
+        let (status, body) = match request.parse(&buff) {
+            Ok(Status::Complete(_)) if !self.is_authorized(&request) => {
+                ("401 Unauthorized", json!({ "error": "unauthorized" }))
+            }
+            Ok(Status::Complete(offset)) => {
+                self.route(request.method, request.path, &buff[offset..])
+            }
+            Ok(Status::Partial) | Err(_) => {
+                ("400 Bad Request", json!({ "error": "malformed request" }))
+            }
+        };
+        socket.write_all(&json_response(status, &body)).await?;
+        Ok(())
+    }
+
+    fn route(&self, method: Option<&str>, path: Option<&str>, body: &[u8]) -> (&str, Value) {
+        let reply = match (method, path) {
+            (Some("POST"), Some("/lease")) => {
+                serde_json::from_slice(body).map(|lease| self.lease(&lease))
+            }
+            (Some("POST"), Some("/report")) => {
+                serde_json::from_slice(body).map(|report| self.report(&report))
+            }
+            (Some("GET"), Some("/usage")) => Ok(self.usage()),
+            _ => return ("404 Not Found", json!({ "error": "not found" })),
+        };
+        match reply {
+            Ok(body) => ("200 OK", body),
+            Err(err) => ("400 Bad Request", json!({ "error": err.to_string() })),
+        }
+    }
+
+    fn is_authorized(&self, request: &Request<'_, '_>) -> bool {
+        let Some(token) = self.token.as_deref() else {
+            return true;
+        };
+        headers::find_str(request.headers, "Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| token_matches(value, token))
+    }
+}
+
+struct Lease {
+    ceiling: Option<u128>,
+    reported: u128,
+    returned: u128,
+    expires: Instant,
+}
+
+impl Lease {
+    fn expire(&mut self, total: u128, now: Instant) {
+        if self.expires > now {
+            return;
+        }
+        if let Some(ceiling) = self.ceiling {
+            self.returned += ceiling.saturating_sub(total);
+        }
+        self.ceiling = Some(total);
+    }
+}
+
+pub(crate) struct CoordinatorStore {
+    memory: MemoryStore,
+    config: ArcSwap<CoordinatorConfig>,
+    leases: Mutex<HashMap<String, Lease>>,
+    leasing: AsyncMutex<()>,
+    clock: Arc<dyn Clock>,
+}
+
+impl CoordinatorStore {
+    pub(crate) fn new(config: CoordinatorConfig, memory: MemoryStore) -> Self {
+        Self {
+            memory,
+            config: ArcSwap::from_pointee(config),
+            leases: Mutex::default(),
+            leasing: AsyncMutex::default(),
+            clock: clock::system(),
+        }
+    }
+
+    fn leases(&self) -> MutexGuard<'_, HashMap<String, Lease>> {
+        self.leases.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn renew(&self, user: &str, limit: u128) -> Result<(), u128> {
+        let _leasing = self.leasing.lock().await;
+        let Some(counters) = self.memory.traffic_counters(user).await else {
+            return Ok(());
+        };
+        let total = counters.total();
+        let now = self.clock.now();
+        let exhausted = {
+            let mut leases = self.leases();
+            let lease = leases.entry(user.to_string()).or_insert(Lease {
+                ceiling: Some(total),
+                reported: 0,
+                returned: 0,
+                expires: now,
+            });
+            lease.expire(total, now);
+            counters.set_ceiling(lease.ceiling);
+            lease.ceiling.is_some_and(|ceiling| total >= ceiling)
+        };
+        if !exhausted {
+            return Ok(());
+        }
+        let request = LeaseRequest {
+            user: user.to_string(),
+            limit,
+            chunk: self.config.load().chunk,
+        };
+        let grant = self
+            .call("/lease", &request)
+            .await
+            .and_then(|reply| Ok(serde_json::from_value::<Grant>(reply)?));
+        let mut leases = self.leases();
+        let Some(lease) = leases.get_mut(user) else {
+            return Ok(());
+        };
+        let renewed = match grant {
+            Ok(grant) if grant.granted == 0 => return Err(grant.used),
+            Ok(grant) => Some(total + grant.granted),
+            Err(err) => {
+                warn!(
+                    user = user,
+                    error = format!("{err:#}"),
+                    "Quota coordinator unavailable, enforcing limits locally"
+                );
+                None
+            }
+        };
+        lease.ceiling = renewed;
+        lease.expires = now + LEASE_TTL;
+        counters.set_ceiling(renewed);
+        Ok(())
+    }
+
+    async fn call<T: Serialize + Sync>(&self, path: &str, body: &T) -> Result<Value> {
+        let config = self.config.load_full();
+        let url = format!("{}{path}", config.url.trim_end_matches('/'));
+        let target = parse_forward_target(&url)
+            .ok_or_else(|| anyhow!("Unsupported quota coordinator URL `{url}`"))?;
+        let body = serde_json::to_vec(body)?;
+        let authorization = config
+            .token
+            .as_deref()
+            .map(|token| format!("Authorization: Bearer {token}\r\n"))
+            .unwrap_or_default();
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n{authorization}Connection: close\r\n\r\n",
+            target.origin_path,
+            target.authority,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+        let response = timeout(COORDINATOR_TIMEOUT, async {
+            let mut stream = TcpStream::connect(&target.authority).await?;
+            stream.write_all(&request).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, anyhow::Error>(response)
+        })
+        .await??;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("Quota coordinator sent a malformed response"))?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            bail!("Quota coordinator answered `{status}` for `{path}`");
+        }
+        Ok(serde_json::from_str(body)?)
+    }
+}
+
+#[async_trait]
+impl RegistryStore for CoordinatorStore {
+    async fn try_acquire(&self, user: &str, limits: Limits) -> Result<()> {
+        self.memory.try_acquire(user, limits).await?;
+        let Some(limit) = limits.traffic().restricted() else {
+            return Ok(());
+        };
+        if let Err(used) = self.renew(user, limit).await {
+            self.memory.release(user).await?;
+            return Err(LimitError::TrafficLimitExceed(used).into());
+        }
+        Ok(())
+    }
+
+    async fn release(&self, user: &str) -> Result<()> {
+        self.memory.release(user).await
+    }
+
+    async fn add_traffic(&self, user: &str, ingress: u128, egress: u128) -> Result<()> {
+        self.memory.add_traffic(user, ingress, egress).await
+    }
+
+    async fn extend(&self, user: &str, limits: Limits) -> Result<bool> {
+        let Some(limit) = limits.traffic().restricted() else {
+            return Ok(false);
+        };
+        Ok(self.renew(user, limit).await.is_ok())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let now = self.clock.now();
+        let users: Vec<String> = self.leases().keys().cloned().collect();
+        let mut reports = Vec::new();
+        for user in users {
+            let Some(counters) = self.memory.traffic_counters(&user).await else {
+                continue;
+            };
+            let total = counters.total();
+            let mut leases = self.leases();
+            let Some(lease) = leases.get_mut(&user) else {
+                continue;
+            };
+            lease.expire(total, now);
+            counters.set_ceiling(lease.ceiling);
+            let used = total.saturating_sub(lease.reported);
+            if used == 0 && lease.returned == 0 {
+                continue;
+            }
+            lease.reported = total;
+            reports.push(UsageReport {
+                user,
+                used,
+                returned: std::mem::take(&mut lease.returned),
+            });
+        }
+        let mut failed = None;
+        for report in reports {
+            if let Err(err) = self.call("/report", &report).await {
+                if let Some(lease) = self.leases().get_mut(&report.user) {
+                    lease.reported -= report.used;
+                    lease.returned += report.returned;
+                }
+                failed = Some(err);
+            }
+        }
+        failed.map_or(Ok(()), Err)
+    }
+
+    async fn reload(&self, config: &StoreConfig) -> Result<()> {
+        if let StoreConfig::Coordinator(coordinator) = config {
+            self.config.store(Arc::new(coordinator.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::registry::{LimitValue, QuotaLease, Registry};
+    use tokio::sync::Mutex as AsyncMutex;
+
+    fn limits(traffic: u128) -> Limits {
+        Limits::new(LimitValue::Unrestricted, LimitValue::Restricted(traffic))
+    }
+
+    fn store(url: String, chunk: u128) -> CoordinatorStore {
+        let config = CoordinatorConfig {
+            url,
+            token: Some("fleet".to_string()),
+            chunk,
+        };
+        let memory = MemoryStore::new(Arc::new(AsyncMutex::new(Registry::new())));
+        CoordinatorStore::new(config, memory)
+    }
+
+    #[tokio::test]
+    async fn proxies_share_a_global_quota_through_leases() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let coordinator = Arc::new(QuotaCoordinator::new(Some("fleet".to_string())));
+        let shutdown = CancellationToken::new();
+        tokio::spawn(coordinator.clone().serve(listener, shutdown.clone()));
+        let (first, second) = (store(url.clone(), 60), store(url.clone(), 60));
+
+        first.try_acquire("alice", limits(100)).await?;
+        first.add_traffic("alice", 50, 20).await?;
+        first.release("alice").await?;
+        second.try_acquire("alice", limits(100)).await?;
+        second.release("alice").await?;
+        first.flush().await?;
+        second.flush().await?;
+
+        let err = first.try_acquire("alice", limits(100)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast::<LimitError>()?,
+            LimitError::TrafficLimitExceed(70)
+        ));
+        assert_eq!(coordinator.usage()["users"]["alice"]["used"], 70);
+        assert_eq!(coordinator.usage()["users"]["alice"]["leased"], 30);
+
+        let intruder = CoordinatorStore::new(
+            CoordinatorConfig {
+                token: None,
+                ..CoordinatorConfig::clone(&first.config.load())
+            },
+            MemoryStore::new(Arc::new(AsyncMutex::new(Registry::new()))),
+        );
+        let report = UsageReport {
+            user: "alice".to_string(),
+            used: 1,
+            returned: 0,
+        };
+        assert!(intruder.call("/report", &report).await.is_err());
+
+        let rotated = StoreConfig::Coordinator(CoordinatorConfig::clone(&first.config.load()));
+        intruder.reload(&rotated).await?;
+        intruder.call("/report", &report).await?;
+        shutdown.cancel();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreachable_coordinator_falls_back_to_local_limits() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        drop(listener);
+        let store = store(url, 60);
+
+        store.try_acquire("bob", limits(100)).await?;
+        store.add_traffic("bob", 100, 0).await?;
+        store.release("bob").await?;
+        assert!(store.flush().await.is_err());
+        assert_eq!(store.leases()["bob"].reported, 0);
+        assert!(store.try_acquire("bob", limits(100)).await.is_err());
+        Ok(())
+    }
+
+    async fn coordinator(clock: Arc<dyn Clock>) -> Result<(String, Arc<QuotaCoordinator>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let coordinator = Arc::new(QuotaCoordinator {
+            clock,
+            ..QuotaCoordinator::new(Some("fleet".to_string()))
+        });
+        tokio::spawn(
+            coordinator
+                .clone()
+                .serve(listener, CancellationToken::new()),
+        );
+        Ok((url, coordinator))
+    }
+
+    #[tokio::test]
+    async fn tunnels_renew_their_lease_when_it_runs_out() -> Result<()> {
+        let (url, coordinator) = coordinator(clock::system()).await?;
+        let store = Arc::new(store(url, 60));
+        store.try_acquire("carol", limits(150)).await?;
+        let counters = store.memory.traffic_counters("carol").await.unwrap();
+        let quota = QuotaLease::new(counters.clone(), 150, 40).renewed_from(
+            store.clone(),
+            "carol",
+            limits(150),
+        );
+
+        assert_eq!(quota.reserve(100), 60);
+        counters.add_ingress(60);
+        quota.settle(60);
+        assert!(quota.renew().await);
+        assert_eq!(quota.reserve(100), 60);
+        counters.add_ingress(60);
+        quota.settle(60);
+        assert!(quota.renew().await);
+        assert_eq!(quota.reserve(100), 30);
+        counters.add_ingress(30);
+        quota.settle(30);
+        assert!(!quota.renew().await);
+        assert_eq!(coordinator.usage()["users"]["carol"]["leased"], 150);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unused_leases_expire() -> Result<()> {
+        let clock = MockClock::new();
+        let (url, coordinator) = coordinator(clock.clone()).await?;
+        let proxy = CoordinatorStore {
+            clock: clock.clone(),
+            ..store(url.clone(), 60)
+        };
+        let crashed = store(url, 30);
+
+        proxy.try_acquire("dave", limits(100)).await?;
+        crashed.try_acquire("dave", limits(100)).await?;
+        assert_eq!(coordinator.usage()["users"]["dave"]["leased"], 90);
+
+        clock.advance(LEASE_TTL);
+        proxy.flush().await?;
+        assert_eq!(coordinator.usage()["users"]["dave"]["leased"], 30);
+        assert_eq!(proxy.leases()["dave"].ceiling, Some(0));
+
+        clock.advance(LEASE_TTL);
+        assert_eq!(coordinator.usage()["users"]["dave"]["leased"], 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_split_across_packets() -> Result<()> {
+        let (url, coordinator) = coordinator(clock::system()).await?;
+        let body = r#"{"user":"erin","limit":100,"chunk":40}"#;
+        let mut stream = TcpStream::connect(url.trim_start_matches("http://")).await?;
+        stream
+            .write_all(
+                format!(
+                    "POST /lease HTTP/1.1\r\nAuthorization: Bearer fleet\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream.write_all(body.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert_eq!(coordinator.usage()["users"]["erin"]["leased"], 40);
+        Ok(())
+    }
+}
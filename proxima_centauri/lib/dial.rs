@@ -0,0 +1,283 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::debug;
+
+#[derive(Clone, Debug, Default)]
+pub struct OutboundBinding {
+    pub addr: Option<IpAddr>,
+    pub interface: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OutboundConfig {
+    pub default: OutboundBinding,
+    pub users: HashMap<String, OutboundBinding>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub linger: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct DialPolicy {
+    pub(crate) stagger: Duration,
+    pub(crate) attempt_timeout: Duration,
+    pub(crate) max_attempts: usize,
+}
+
+pub(crate) async fn dial(
+    addrs: &[SocketAddr],
+    binding: &OutboundBinding,
+    policy: DialPolicy,
+) -> io::Result<TcpStream> {
+    let mut ordered = interleave_families(
+        addrs
+            .iter()
+            .filter(|addr| {
+                binding
+                    .addr
+                    .is_none_or(|local| local.is_ipv4() == addr.is_ipv4())
+            })
+            .copied()
+            .collect(),
+    );
+    if policy.max_attempts != 0 {
+        ordered.truncate(policy.max_attempts);
+    }
+    let mut pending = ordered.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            let binding = binding.clone();
+            attempts.spawn(async move {
+                timeout(policy.attempt_timeout, connect_from(addr, &binding))
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            });
+        } else if attempts.is_empty() {
+            break;
+        }
+        let finished = if pending.as_slice().is_empty() {
+            attempts.join_next().await
+        } else {
+            match timeout(policy.stagger, attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        };
+        match finished {
+            Some(Ok(Ok(stream))) => return Ok(stream),
+            Some(Ok(Err(err))) => last_error = Some(err),
+            Some(Err(err)) => last_error = Some(io::Error::other(err)),
+            None => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("No target address matches the outbound binding {binding:?}"),
+        )
+    }))
+}
+
+pub(crate) fn set_keepalive(stream: &TcpStream, config: Option<KeepaliveConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    let keepalive = TcpKeepalive::new()
+        .with_time(config.idle)
+        .with_interval(config.interval)
+        .with_retries(config.retries);
+    if let Err(err) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        debug!(error = format!("{err}"), "Cannot enable TCP keepalive");
+    }
+}
+
+pub(crate) fn set_socket_options(stream: &TcpStream, options: SocketOptions) {
+    if let Err(err) = stream.set_nodelay(options.nodelay) {
+        debug!(error = format!("{err}"), "Cannot set TCP_NODELAY");
+    }
+    if let Some(linger) = options.linger
+        && let Err(err) = SockRef::from(stream).set_linger(Some(linger))
+    {
+        debug!(error = format!("{err}"), "Cannot set SO_LINGER");
+    }
+}
+
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first().copied() else {
+        return addrs;
+    };
+    let (preferred, fallback): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    let mut preferred = preferred.into_iter();
+    let mut fallback = fallback.into_iter();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), fallback.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+async fn connect_from(addr: SocketAddr, binding: &OutboundBinding) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    if let Some(local) = binding.addr {
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(interface) = &binding.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    socket.connect(addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::net::Ipv6Addr;
+    use tokio::net::TcpListener;
+
+    const POLICY: DialPolicy = DialPolicy {
+        stagger: Duration::from_millis(250),
+        attempt_timeout: Duration::from_secs(5),
+        max_attempts: 4,
+    };
+
+    #[tokio::test]
+    async fn keepalive_probes_use_configured_timings() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let stream = TcpStream::connect(listener.local_addr()?).await?;
+        set_keepalive(
+            &stream,
+            Some(KeepaliveConfig {
+                idle: Duration::from_secs(30),
+                interval: Duration::from_secs(5),
+                retries: 4,
+            }),
+        );
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive()?);
+        assert_eq!(socket.tcp_keepalive_time()?, Duration::from_secs(30));
+        assert_eq!(socket.tcp_keepalive_interval()?, Duration::from_secs(5));
+        assert_eq!(socket.tcp_keepalive_retries()?, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dial_binds_configured_source_address() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let binding = OutboundBinding {
+            addr: Some(IpAddr::from([127, 0, 0, 1])),
+            interface: None,
+        };
+
+        let stream = dial(&[listener.local_addr()?], &binding, POLICY).await?;
+        let (_, peer) = listener.accept().await?;
+
+        assert_eq!(peer, stream.local_addr()?);
+        assert_eq!(peer.ip(), IpAddr::from([127, 0, 0, 1]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dial_skips_addresses_of_other_family() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let binding = OutboundBinding {
+            addr: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            interface: None,
+        };
+
+        assert!(
+            dial(&[listener.local_addr()?], &binding, POLICY)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn interleaves_address_families_starting_with_first() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave_families(addrs)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            ordered,
+            ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn dial_moves_on_immediately_after_failure() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let addrs = [closed, listener.local_addr()?];
+
+        let stream = tokio::time::timeout(
+            Duration::from_secs(2),
+            dial(
+                &addrs,
+                &OutboundBinding::default(),
+                DialPolicy {
+                    stagger: Duration::from_secs(30),
+                    ..POLICY
+                },
+            ),
+        )
+        .await??;
+
+        assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dial_caps_total_attempts() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let policy = DialPolicy {
+            max_attempts: 1,
+            ..POLICY
+        };
+
+        assert!(
+            dial(
+                &[closed, listener.local_addr()?],
+                &OutboundBinding::default(),
+                policy
+            )
+            .await
+            .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RotationStrategy {
+    #[default]
+    RoundRobin,
+    StickyPerUser,
+    Random,
+}
+
+impl FromStr for RotationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "round-robin" => Ok(Self::RoundRobin),
+            "sticky" => Ok(Self::StickyPerUser),
+            "random" => Ok(Self::Random),
+            _ => anyhow::bail!("Unknown egress rotation strategy `{value}`"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EgressPoolConfig {
+    pub addrs: Vec<IpAddr>,
+    pub strategy: RotationStrategy,
+    pub per_session: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EgressUsage {
+    pub addr: IpAddr,
+    pub connections: u64,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct AddrCounters {
+    connections: AtomicU64,
+    bytes: AtomicU64,
+}
+
+pub(crate) struct EgressPool {
+    config: EgressPoolConfig,
+    next: AtomicUsize,
+    hasher: RandomState,
+    sessions: Mutex<HashMap<u64, usize>>,
+    usage: Vec<AddrCounters>,
+}
+
+impl EgressPool {
+    pub(crate) fn new(config: EgressPoolConfig) -> Option<Self> {
+        if config.addrs.is_empty() {
+            return None;
+        }
+        let usage = config
+            .addrs
+            .iter()
+            .map(|_| AddrCounters::default())
+            .collect();
+        Some(Self {
+            config,
+            next: AtomicUsize::new(0),
+            hasher: RandomState::new(),
+            sessions: Mutex::new(HashMap::new()),
+            usage,
+        })
+    }
+
+    pub(crate) fn pick(&self, user: &str, session_id: u64) -> IpAddr {
+        let index = if self.config.per_session {
+            let mut sessions = self
+                .sessions
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            *sessions
+                .entry(session_id)
+                .or_insert_with(|| self.next_index(user))
+        } else {
+            self.next_index(user)
+        };
+        self.usage[index]
+            .connections
+            .fetch_add(1, Ordering::Relaxed);
+        self.config.addrs[index]
+    }
+
+    fn next_index(&self, user: &str) -> usize {
+        match self.config.strategy {
+            RotationStrategy::RoundRobin => {
+                self.next.fetch_add(1, Ordering::Relaxed) % self.config.addrs.len()
+            }
+            RotationStrategy::StickyPerUser => self.hashed_index(user),
+            RotationStrategy::Random => {
+                self.hashed_index(self.next.fetch_add(1, Ordering::Relaxed))
+            }
+        }
+    }
+
+    fn hashed_index(&self, value: impl Hash) -> usize {
+        let len = self.config.addrs.len() as u64;
+        usize::try_from(self.hasher.hash_one(value) % len).unwrap_or_default()
+    }
+
+    pub(crate) fn record(&self, addr: IpAddr, bytes: u64) {
+        if let Some(index) = self.config.addrs.iter().position(|a| *a == addr) {
+            self.usage[index].bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn forget_session(&self, session_id: u64) {
+        if self.config.per_session {
+            self.sessions
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .remove(&session_id);
+        }
+    }
+
+    pub(crate) fn usage(&self) -> Vec<EgressUsage> {
+        self.config
+            .addrs
+            .iter()
+            .zip(&self.usage)
+            .map(|(addr, counters)| EgressUsage {
+                addr: *addr,
+                connections: counters.connections.load(Ordering::Relaxed),
+                bytes: counters.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: RotationStrategy, per_session: bool) -> EgressPool {
+        EgressPool::new(EgressPoolConfig {
+            addrs: vec![
+                IpAddr::from([10, 0, 0, 1]),
+                IpAddr::from([10, 0, 0, 2]),
+                IpAddr::from([10, 0, 0, 3]),
+            ],
+            strategy,
+            per_session,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn empty_pool_is_disabled() {
+        assert!(EgressPool::new(EgressPoolConfig::default()).is_none());
+    }
+
+    #[test]
+    fn round_robin_cycles_through_addresses() {
+        let pool = pool(RotationStrategy::RoundRobin, false);
+        let picked: Vec<IpAddr> = (0..4).map(|id| pool.pick("alice", id)).collect();
+
+        assert_eq!(picked[0], IpAddr::from([10, 0, 0, 1]));
+        assert_eq!(picked[1], IpAddr::from([10, 0, 0, 2]));
+        assert_eq!(picked[2], IpAddr::from([10, 0, 0, 3]));
+        assert_eq!(picked[3], IpAddr::from([10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn sticky_strategy_keeps_user_on_one_address() {
+        let pool = pool(RotationStrategy::StickyPerUser, false);
+        let first = pool.pick("alice", 1);
+
+        assert!((2..10).all(|id| pool.pick("alice", id) == first));
+    }
+
+    #[test]
+    fn per_session_rotation_reuses_address_within_session() {
+        let pool = pool(RotationStrategy::RoundRobin, true);
+
+        let first = pool.pick("alice", 7);
+        assert_eq!(pool.pick("alice", 7), first);
+        assert_ne!(pool.pick("alice", 8), first);
+    }
+
+    #[test]
+    fn usage_counts_connections_and_bytes() {
+        let pool = pool(RotationStrategy::RoundRobin, false);
+        let addr = pool.pick("alice", 1);
+        pool.record(addr, 1500);
+
+        let usage = pool.usage();
+        assert_eq!(usage[0].connections, 1);
+        assert_eq!(usage[0].bytes, 1500);
+        assert_eq!(usage[1].connections, 0);
+    }
+}
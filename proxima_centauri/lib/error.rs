@@ -0,0 +1,85 @@
+use crate::registry::LimitError;
+use std::io;
+use thiserror::Error;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T, E = ProxyError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("Cannot bind {addr}: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Auth provider failed: {0}")]
+    Auth(#[source] BoxError),
+    #[error("Backend failed: {0}")]
+    Backend(#[source] BoxError),
+    #[error("Cannot connect to {target}: {source}")]
+    Dial {
+        target: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Tunnel I/O failed: {0}")]
+    Tunnel(#[from] io::Error),
+    #[error(transparent)]
+    LimitExceeded(#[from] LimitError),
+}
+
+impl ProxyError {
+    pub(crate) fn config(err: impl std::fmt::Display) -> Self {
+        Self::Config(err.to_string())
+    }
+
+    pub(crate) fn auth(err: impl Into<BoxError>) -> Self {
+        Self::Auth(err.into())
+    }
+
+    pub(crate) fn backend(err: impl Into<BoxError>) -> Self {
+        Self::Backend(err.into())
+    }
+
+    pub(crate) fn bind(addr: impl std::fmt::Display) -> impl FnOnce(io::Error) -> Self {
+        let addr = addr.to_string();
+        move |source| Self::Bind { addr, source }
+    }
+
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::Bind { .. } => "bind",
+            Self::Config(_) => "config",
+            Self::Auth(_) => "auth",
+            Self::Backend(_) => "backend",
+            Self::Dial { .. } => "dial",
+            Self::Tunnel(_) => "tunnel",
+            Self::LimitExceeded(_) => "limit_exceeded",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_keep_their_kind_and_source() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let err = ProxyError::bind("127.0.0.1:8080")(refused);
+        assert_eq!(err.code(), "bind");
+        assert!(err.to_string().starts_with("Cannot bind 127.0.0.1:8080"));
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = ProxyError::from(LimitError::ConcurrencyLimitExceed(1));
+        assert_eq!(err.code(), "limit_exceeded");
+        assert_eq!(err.to_string(), "Concurrency limit exceed");
+
+        let err = ProxyError::backend(anyhow::anyhow!("redis is down"));
+        assert_eq!(err.to_string(), "Backend failed: redis is down");
+    }
+}
@@ -0,0 +1,273 @@
+use crate::clock::unix_now;
+use crate::spill::{SpillQueue, SpilledEvent};
+use crate::tunnel::CloseReason;
+use crate::webhook::post_json;
+use anyhow::Result;
+use ring::hmac;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep};
+use tracing::warn;
+
+const EVENT_QUEUE: usize = 1024;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+const SPILL_REPLAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub secret: Option<String>,
+    pub retries: u32,
+    pub spill_path: Option<PathBuf>,
+    pub spill_max_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    UserAuthenticated {
+        user: String,
+        client_ip: IpAddr,
+    },
+    AuthFailed {
+        user: String,
+        client_ip: IpAddr,
+    },
+    TunnelOpened {
+        user: String,
+        session_id: u64,
+        target: Option<String>,
+    },
+    TunnelClosed {
+        user: String,
+        session_id: u64,
+        ingress: u64,
+        egress: u64,
+        reason: CloseReason,
+    },
+    LimitExceeded {
+        user: String,
+        error: &'static str,
+    },
+    TrafficAnomaly {
+        user: String,
+        rate: u64,
+        average: u64,
+        multiplier: f64,
+    },
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    sender: Option<mpsc::Sender<Event>>,
+}
+
+impl EventBus {
+    pub(crate) fn start(config: WebhookConfig) -> Self {
+        if config.urls.is_empty() {
+            return Self::default();
+        }
+        let (sender, receiver) = mpsc::channel(EVENT_QUEUE);
+        tokio::spawn(dispatch(receiver, config));
+        Self {
+            sender: Some(sender),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        if let Some(sender) = &self.sender
+            && sender.try_send(event).is_err()
+        {
+            warn!("Webhook event queue is full, event dropped");
+        }
+    }
+}
+
+async fn dispatch(mut receiver: mpsc::Receiver<Event>, config: WebhookConfig) {
+    let key = config
+        .secret
+        .as_ref()
+        .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()));
+    let spill = config
+        .spill_path
+        .clone()
+        .map(|path| SpillQueue::new(path, config.spill_max_bytes));
+    let mut replay = interval(SPILL_REPLAY);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                let Ok(payload) = envelope(&event) else {
+                    continue;
+                };
+                let signature = key.as_ref().map(|key| sign(key, &payload));
+                for url in &config.urls {
+                    if let Err(err) =
+                        deliver(url, &payload, signature.as_deref(), config.retries).await
+                    {
+                        warn!(
+                            url = url,
+                            error = format!("{err}"),
+                            "Webhook delivery failed"
+                        );
+                        spill_event(spill.as_ref(), url, &payload).await;
+                    }
+                }
+            }
+            _ = replay.tick(), if spill.is_some() => {
+                if let Some(spill) = &spill {
+                    replay_spilled(spill, key.as_ref()).await;
+                }
+            }
+        }
+    }
+}
+
+async fn spill_event(spill: Option<&SpillQueue>, url: &str, payload: &[u8]) {
+    let Some(spill) = spill else {
+        return;
+    };
+    let Ok(payload) = serde_json::from_slice(payload) else {
+        return;
+    };
+    let event = SpilledEvent {
+        url: url.to_string(),
+        payload,
+    };
+    match spill.push(&event).await {
+        Ok(true) => {}
+        Ok(false) => warn!(url = url, "Webhook spill queue is full, event dropped"),
+        Err(err) => warn!(
+            url = url,
+            error = format!("{err}"),
+            "Cannot spill webhook event"
+        ),
+    }
+}
+
+async fn replay_spilled(spill: &SpillQueue, key: Option<&hmac::Key>) {
+    let events = match spill.drain().await {
+        Ok(events) => events,
+        Err(err) => {
+            warn!(error = format!("{err}"), "Cannot read webhook spill queue");
+            return;
+        }
+    };
+    let mut sink_down = false;
+    for event in events {
+        let Ok(payload) = serde_json::to_vec(&event.payload) else {
+            continue;
+        };
+        if !sink_down {
+            let signature = key.map(|key| sign(key, &payload));
+            sink_down = deliver(&event.url, &payload, signature.as_deref(), 0)
+                .await
+                .is_err();
+            if !sink_down {
+                continue;
+            }
+        }
+        spill_event(Some(spill), &event.url, &payload).await;
+    }
+}
+
+fn envelope(event: &Event) -> Result<Vec<u8>> {
+    let mut payload = serde_json::to_value(event)?;
+    if let Value::Object(fields) = &mut payload {
+        fields.insert("timestamp".to_string(), unix_now().into());
+    }
+    Ok(serde_json::to_vec(&payload)?)
+}
+
+fn sign(key: &hmac::Key, payload: &[u8]) -> String {
+    hmac::sign(key, payload)
+        .as_ref()
+        .iter()
+        .fold(String::from("sha256="), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+async fn deliver(url: &str, payload: &[u8], signature: Option<&str>, retries: u32) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match post_json(url, payload, signature).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= retries => return Err(err),
+            Err(_) => {
+                sleep(RETRY_BACKOFF * 2u32.pow(attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn signs_payload_with_hmac_sha256() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"key");
+        assert_eq!(
+            sign(&key, b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn envelope_tags_event_and_adds_timestamp() -> Result<()> {
+        let payload = envelope(&Event::LimitExceeded {
+            user: "alice".to_string(),
+            error: "traffic_quota_exceeded",
+        })?;
+        let value: Value = serde_json::from_slice(&payload)?;
+
+        assert_eq!(value["event"], "limit_exceeded");
+        assert_eq!(value["user"], "alice");
+        assert!(value["timestamp"].as_u64().is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events_with_retry() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/events", listener.local_addr()?);
+        let server = tokio::spawn(async move {
+            let (mut first, _) = listener.accept().await?;
+            first.write_all(b"HTTP/1.1 503 Busy\r\n\r\n").await?;
+            drop(first);
+            let (mut second, _) = listener.accept().await?;
+            let mut request = vec![0u8; 2048];
+            let size = second.read(&mut request).await?;
+            second.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await?;
+            Ok::<_, anyhow::Error>(String::from_utf8_lossy(&request[..size]).into_owned())
+        });
+
+        let bus = EventBus::start(WebhookConfig {
+            urls: vec![url],
+            secret: Some("secret".to_string()),
+            retries: 2,
+            ..WebhookConfig::default()
+        });
+        bus.emit(Event::UserAuthenticated {
+            user: "alice".to_string(),
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+        });
+
+        let request = tokio::time::timeout(Duration::from_secs(5), server).await???;
+        assert!(request.contains("X-Procent-Signature: sha256="));
+        assert!(request.contains(r#""event":"user_authenticated""#));
+        Ok(())
+    }
+}
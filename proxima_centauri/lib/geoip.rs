@@ -0,0 +1,98 @@
+use anyhow::Result;
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, Default)]
+pub struct GeoPolicy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl GeoPolicy {
+    pub(crate) fn is_allowed(&self, country: Option<&str>) -> bool {
+        let Some(country) = country else {
+            return self.allow.is_empty();
+        };
+        if self.deny.iter().any(|c| c.eq_ignore_ascii_case(country)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.eq_ignore_ascii_case(country))
+    }
+}
+
+pub(crate) struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+    pub(crate) policy: GeoPolicy,
+}
+
+impl GeoIp {
+    #[cfg(feature = "geoip")]
+    pub(crate) fn open(path: &str, policy: GeoPolicy) -> Result<Self> {
+        Ok(Self {
+            reader: maxminddb::Reader::open_readfile(path)?,
+            policy,
+        })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub(crate) fn open(_path: &str, _policy: GeoPolicy) -> Result<Self> {
+        anyhow::bail!("GeoIP database configured but the `geoip` feature is disabled")
+    }
+
+    #[cfg(feature = "geoip")]
+    pub(crate) fn country(&self, ip: IpAddr) -> Option<String> {
+        self.reader
+            .lookup(ip)
+            .ok()?
+            .decode::<maxminddb::geoip2::Country>()
+            .ok()
+            .flatten()?
+            .country
+            .iso_code
+            .map(str::to_string)
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    #[allow(clippy::unused_self)]
+    pub(crate) const fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+
+    pub(crate) fn is_allowed(&self, ip: IpAddr) -> (bool, Option<String>) {
+        let country = self.country(ip);
+        (self.policy.is_allowed(country.as_deref()), country)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> GeoPolicy {
+        GeoPolicy {
+            allow: allow.iter().map(ToString::to_string).collect(),
+            deny: deny.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = GeoPolicy::default();
+        assert!(policy.is_allowed(Some("US")));
+        assert!(policy.is_allowed(None));
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let policy = policy(&["US", "DE"], &["de"]);
+        assert!(policy.is_allowed(Some("US")));
+        assert!(!policy.is_allowed(Some("DE")));
+        assert!(!policy.is_allowed(Some("FR")));
+    }
+
+    #[test]
+    fn unknown_country_denied_only_with_allow_list() {
+        assert!(!policy(&["US"], &[]).is_allowed(None));
+        assert!(policy(&[], &["RU"]).is_allowed(None));
+    }
+}
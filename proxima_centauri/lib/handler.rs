@@ -1,102 +1,1449 @@
-use crate::auth::{authenticate, parse_proxy_auth_token};
+use crate::auth::parse_proxy_auth_token;
+use crate::auth_audit::Lockout;
+use crate::bandwidth::TokenBucket;
+use crate::config::{Config, Enforcement, StealthMode};
+use crate::clock::unix_now;
 use crate::context::Context;
-use crate::http_utils::response::ProxyResponse;
-use crate::registry::{LimitError, Limits};
-use crate::tunnel::connect_target;
-use anyhow::{bail, Result};
-use httparse::{Request, EMPTY_HEADER};
-use std::time::Duration;
+use crate::dial::{DialPolicy, OutboundBinding, dial, set_keepalive, set_socket_options};
+use crate::error::{ProxyError, Result};
+use crate::events::Event;
+use crate::hooks::{AcceptContext, AuthContext, TunnelCloseContext, TunnelOpenContext, Verdict};
+use crate::http_utils::framing::ResponseFraming;
+use crate::http_utils::headers;
+use crate::http_utils::request::{
+    ForwardTarget, parse_forward_target, request_body, rewrite_request_head,
+};
+use crate::http_utils::response::{LimitUsage, ProxyResponse};
+use crate::intercept::{Direction, Interceptor, Replay};
+use crate::ledger::UsageRecord;
+use crate::metrics::Metrics;
+use crate::origin::OriginStream;
+use crate::rdns::reverse_lookup;
+use crate::sni::{ClientHello, SniMode, matches_host, read_client_hello};
+use crate::response_cache::CacheLookup;
+use crate::routing::{self, DIRECT, Route, TLS, UpstreamProxy};
+use crate::registry::{
+    LimitError, Limits, QUOTA_CHUNK, QuotaLease, SoftLimitWarning, TrafficCounters,
+};
+use crate::stats::TrafficStats;
+use crate::store::ConcurrencyGuard;
+use crate::tunnel::{CloseReason, RelayOutcome, RelayPolicy, exchange, forward_request};
+use crate::webhook::post_json;
+use httparse::{EMPTY_HEADER, Request, Status};
+use serde_json::json;
+use socket2::SockRef;
+use std::io::{self, Cursor};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tracing::{debug, error, warn};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::{sleep, timeout};
+use tracing::{Instrument, Span, debug, error, info, info_span, instrument, warn};
 
-pub async fn handle_connection(mut source: TcpStream, ctx: Context) -> Result<()> {
-    let mut buff = [0u8; 1024];
+const READ_CHUNK: usize = 1024;
+const SLOT_RETRY_AFTER: u64 = 1;
+
+enum TunnelMode {
+    Connect(Vec<u8>),
+    Forward(Vec<u8>),
+    Exchange {
+        head: Vec<u8>,
+        head_request: bool,
+        upload: ResponseFraming,
+        lookup: Option<CacheLookup>,
+    },
+    Transparent,
+    Intercept(Vec<u8>, Arc<Interceptor>),
+}
+
+impl TunnelMode {
+    fn cache_lookup(&self) -> Option<CacheLookup> {
+        match self {
+            Self::Exchange { lookup, .. } => lookup.clone(),
+            _ => None,
+        }
+    }
+
+    async fn relay(
+        &self,
+        source: &mut TcpStream,
+        target: &mut OriginStream,
+        policy: RelayPolicy,
+        live: Option<&TrafficCounters>,
+        (quota, bandwidth): (Option<&QuotaLease>, Option<&TokenBucket>),
+        opening: &TunnelOpenContext,
+    ) -> Result<RelayOutcome> {
+        match self {
+            Self::Connect(head) | Self::Forward(head) => {
+                forward_request(source, target, head, policy, live, quota, bandwidth).await
+            }
+            Self::Exchange {
+                head,
+                head_request,
+                upload,
+                ..
+            } => {
+                let request = (head.as_slice(), *head_request, upload.clone());
+                exchange(source, target, request, policy, live, quota, bandwidth).await
+            }
+            Self::Transparent => {
+                forward_request(source, target, &[], policy, live, quota, bandwidth).await
+            }
+            Self::Intercept(early, interceptor) => {
+                let tunnel = Arc::new(opening.clone());
+                let client = Replay::new(early.clone(), source);
+                let accepting = timeout(policy.idle, interceptor.accept(client, &tunnel));
+                let handshake = accepting
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+                let mut tls = match handshake {
+                    Ok(tls) => tls,
+                    Err(err) => {
+                        warn!(error = format!("{err}"), "Intercepted TLS handshake failed");
+                        return Ok(RelayOutcome::empty(CloseReason::IoError));
+                    }
+                };
+                let mut origin = interceptor.inspect(target, &tunnel, Direction::Egress);
+                forward_request(&mut tls, &mut origin, &[], policy, live, quota, bandwidth).await
+            }
+        }
+    }
+}
+
+enum Intent {
+    Tunnel(String, TunnelMode),
+    Usage,
+}
+
+struct TunnelTarget {
+    addrs: Vec<SocketAddr>,
+    host: String,
+    authority: Option<String>,
+    country: Option<String>,
+    upstream: Option<(UpstreamProxy, String)>,
+    tls: bool,
+}
+
+async fn read_request_head(
+    source: &mut TcpStream,
+    config: &Config,
+) -> Result<std::result::Result<Vec<u8>, ProxyResponse>> {
+    let mut buff = Vec::with_capacity(READ_CHUNK);
+    let mut chunk = [0u8; READ_CHUNK];
+
+    loop {
+        let size = match source.read(&mut chunk).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!(error = format!("{}", e));
+                return Err(e.into());
+            }
+        };
+        if size == 0 {
+            return Ok(Ok(buff));
+        }
+        buff.extend_from_slice(&chunk[..size]);
+        if let Some(response) = oversized_head(&buff, config) {
+            return Ok(Err(response));
+        }
+
+        let mut headers = [EMPTY_HEADER; 16];
+        let parsed = Request::new(&mut headers).parse(&buff);
+        if !matches!(parsed, Ok(Status::Partial)) {
+            return Ok(Ok(buff));
+        }
+    }
+}
+
+fn oversized_head(buff: &[u8], config: &Config) -> Option<ProxyResponse> {
+    let line = buff
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(buff.len());
+    if line > config.max_request_line {
+        return Some(ProxyResponse::UriTooLong);
+    }
+    let head = buff
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(buff.len(), |end| end + 4);
+    (head.saturating_sub(line + 2) > config.max_header_bytes)
+        .then_some(ProxyResponse::HeadersTooLarge)
+}
+
+fn is_geo_allowed(ctx: &Context, ip: IpAddr, direction: &str) -> (bool, Option<String>) {
+    let Some(geoip) = &ctx.geoip else {
+        return (true, None);
+    };
+    let (allowed, country) = geoip.is_allowed(ip);
+    if !allowed {
+        Metrics::inc(&ctx.metrics.geoip_denied);
+        warn!(
+            country = format!("{country:?}"),
+            "{direction} country denied"
+        );
+    }
+    (allowed, country)
+}
+
+pub(crate) fn shadowed(ctx: &Context, violation: &str) -> bool {
+    if ctx.config.enforcement == Enforcement::Enforce {
+        return false;
+    }
+    Metrics::inc(&ctx.metrics.shadow_violations);
+    warn!(violation = violation, "Shadow mode, violation not enforced");
+    true
+}
 
-    let size = match source.read(&mut buff).await {
-        Ok(0) => return Ok(()),
-        Ok(n) => n,
-        Err(e) => {
-            error!(error = format!("{}", e));
-            bail!(e);
+enum AuthDecision {
+    Granted(String),
+    Missing,
+    Malformed,
+    Denied,
+    Locked(Lockout),
+}
+
+async fn reject_unauthenticated(
+    source: &mut TcpStream,
+    ctx: &Context,
+    (response, tarpit): (&ProxyResponse, Option<Duration>),
+) -> Result<()> {
+    if let Some(delay) = tarpit {
+        Metrics::inc(&ctx.metrics.auth_tarpitted);
+        debug!("Tarpitting rejected client for {delay:?}");
+        sleep(delay).await;
+    }
+    match ctx.config.stealth {
+        StealthMode::Off => source.write_all(&response.to_bytes()).await?,
+        StealthMode::Close => {}
+        StealthMode::Delay(delay) => {
+            sleep(delay).await;
+            source
+                .write_all(&ProxyResponse::NotFound.to_bytes())
+                .await?;
         }
+    }
+    Ok(())
+}
+
+async fn reject_by_hook(source: &mut TcpStream, ctx: &Context, code: &'static str) -> Result<()> {
+    Metrics::inc(&ctx.metrics.hook_rejections);
+    debug!(code = code, "Connection rejected by hook");
+    source
+        .write_all(&ProxyResponse::Forbidden(code).to_bytes())
+        .await?;
+    Ok(())
+}
+
+async fn reject_locked(source: &mut TcpStream, ctx: &Context, lockout: Lockout) -> Result<()> {
+    Metrics::inc(&ctx.metrics.auth_locked_rejections);
+    let response = ProxyResponse::AuthLocked(LimitUsage {
+        limit: Some(ctx.auth_audit.max_failures() as u128),
+        used: lockout.failures as u128,
+        reset_at: Some(unix_now() + lockout.remaining.as_secs()),
+        retry_after: Some(lockout.remaining.as_secs().max(1)),
+    });
+    source.write_all(&response.to_bytes()).await?;
+    Ok(())
+}
+
+async fn reject_ip_limited(source: &mut TcpStream, ctx: &Context, active: usize) -> Result<()> {
+    Metrics::inc(&ctx.metrics.ip_limit_rejections);
+    let response = ProxyResponse::TooManyConnections(LimitUsage {
+        limit: Some(ctx.ip_limiter.max() as u128),
+        used: active as u128,
+        reset_at: None,
+        retry_after: Some(SLOT_RETRY_AFTER),
+    });
+    source.write_all(&response.to_bytes()).await?;
+    Ok(())
+}
+
+#[instrument(name = "auth", skip_all)]
+async fn authorize(
+    ctx: &Context,
+    request: &Request<'_, '_>,
+    client_ip: IpAddr,
+) -> Result<AuthDecision> {
+    let Ok(auth_header) = headers::single(request.headers, "Proxy-Authorization") else {
+        warn!("Rejecting request with conflicting Proxy-Authorization headers");
+        return Ok(AuthDecision::Malformed);
+    };
+    let Some(auth_header) = auth_header else {
+        return Ok(AuthDecision::Missing);
+    };
+    let Ok((login, password)) = parse_proxy_auth_token(auth_header) else {
+        debug!("Malformed Proxy-Authorization header");
+        return Ok(AuthDecision::Malformed);
+    };
+    let user = ctx.auth.account(&login).await.map_err(ProxyError::auth)?;
+    if let Some(lockout) = ctx.auth_audit.locked(&user, client_ip) {
+        return Ok(AuthDecision::Locked(lockout));
+    }
+    if !check_credentials(ctx, &login, &user, &password, client_ip).await? {
+        return Ok(AuthDecision::Denied);
+    }
+    Ok(AuthDecision::Granted(user))
+}
+
+async fn check_credentials(
+    ctx: &Context,
+    login: &str,
+    user: &str,
+    password: &str,
+    client_ip: IpAddr,
+) -> Result<bool> {
+    let authenticated = ctx
+        .auth
+        .authenticate(login, password)
+        .await
+        .map_err(ProxyError::auth)?;
+    if authenticated {
+        ctx.auth_audit.record_success(user);
+    } else {
+        Metrics::inc(&ctx.metrics.auth_failures);
+        warn!(
+            user = user,
+            client_ip = format!("{client_ip}"),
+            "Authentication failed"
+        );
+        if ctx.auth_audit.record_failure(user, client_ip).is_some() {
+            Metrics::inc(&ctx.metrics.auth_lockouts);
+        }
+    }
+    let user = user.to_string();
+    ctx.events.emit(if authenticated {
+        Event::UserAuthenticated { user, client_ip }
+    } else {
+        Event::AuthFailed { user, client_ip }
+    });
+    Ok(authenticated)
+}
+
+fn authority_host(authority: &str) -> &str {
+    authority
+        .rsplit_once(':')
+        .map_or(authority, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+}
+
+fn target_ip(authority: &str) -> Option<IpAddr> {
+    authority
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| authority.parse())
+        .ok()
+}
+
+fn log_reverse_dns(authority: String, ip: IpAddr) {
+    tokio::spawn(
+        async move {
+            match reverse_lookup(ip).await {
+                Ok(Some(name)) => info!(target = authority, rdns = name, "Target reverse DNS"),
+                Ok(None) => info!(target = authority, "Target has no reverse DNS"),
+                Err(e) => debug!(target = authority, "Reverse DNS lookup failed: {e}"),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+fn route_upstream(
+    ctx: &Context,
+    routes: &[Route],
+    host: &str,
+) -> Result<Option<UpstreamProxy>, String> {
+    let Some(route) = routing::select(routes, host) else {
+        return Ok(None);
+    };
+    if route.upstream == DIRECT || route.upstream == TLS {
+        return Ok(None);
+    }
+    ctx.config
+        .upstreams
+        .get(&route.upstream)
+        .cloned()
+        .map(Some)
+        .ok_or_else(|| route.upstream.clone())
+}
+
+async fn resolve_target(
+    source: &mut TcpStream,
+    ctx: &Context,
+    (user, private): (&str, bool),
+    (authority, forwarded): (String, bool),
+) -> Result<Option<TunnelTarget>> {
+    let literal = target_ip(&authority);
+    if literal.is_some() && !ctx.config.allow_ip_targets {
+        Metrics::inc(&ctx.metrics.ip_target_denied);
+        if !private {
+            warn!(target = authority, "IP literal target denied");
+        }
+        if !shadowed(ctx, "ip_target_denied") {
+            source
+                .write_all(&ProxyResponse::Forbidden("ip_target_denied").to_bytes())
+                .await?;
+            return Ok(None);
+        }
+    }
+    let routes = ctx.auth.routes(user).await.map_err(ProxyError::auth)?;
+    let tls = forwarded
+        && routing::select(&routes, authority_host(&authority))
+            .is_some_and(|route| route.upstream == TLS);
+    let authority = match authority.strip_suffix(":80") {
+        Some(host) if tls => format!("{host}:443"),
+        _ => authority,
+    };
+    let upstream = match route_upstream(ctx, &routes, authority_host(&authority)) {
+        Ok(upstream) => upstream.map(|proxy| (proxy, authority.clone())),
+        Err(name) => {
+            warn!(user = user, upstream = name, "Route names an unknown upstream");
+            source
+                .write_all(&ProxyResponse::BadGateway.to_bytes())
+                .await?;
+            return Ok(None);
+        }
+    };
+    let addrs: Vec<SocketAddr> = match upstream {
+        Some(_) => Vec::new(),
+        None => lookup_host(authority.as_str()).await?.collect(),
+    };
+    let Some(mut target) = admit_target(source, ctx, authority, addrs, private).await? else {
+        return Ok(None);
+    };
+    target.upstream = upstream;
+    target.tls = tls;
+    if let Some(ip) = literal.filter(|_| ctx.config.reverse_dns && !private) {
+        log_reverse_dns(target.authority.clone().unwrap_or_default(), ip);
+    }
+    Ok(Some(target))
+}
+
+async fn admit_target(
+    source: &mut TcpStream,
+    ctx: &Context,
+    authority: String,
+    addrs: Vec<SocketAddr>,
+    private: bool,
+) -> Result<Option<TunnelTarget>> {
+    let categories = target_categories(ctx, authority_host(&authority)).await;
+    if !ctx.acl.is_allowed(&authority, &addrs, &categories) {
+        Metrics::inc(&ctx.metrics.acl_denied);
+        if !private {
+            let categories = categories.join(",");
+            warn!(target = authority, categories, "Target denied by ACL");
+        }
+        if !shadowed(ctx, "acl_denied") {
+            source
+                .write_all(&ProxyResponse::Forbidden("acl_denied").to_bytes())
+                .await?;
+            return Ok(None);
+        }
+    }
+    let (allowed, country) = addrs.first().map_or((true, None), |addr| {
+        is_geo_allowed(ctx, addr.ip(), "Target")
+    });
+    if !allowed && !shadowed(ctx, "geoip_denied") {
+        source
+            .write_all(&ProxyResponse::Forbidden("geoip_denied").to_bytes())
+            .await?;
+        return Ok(None);
+    }
+    Ok(Some(TunnelTarget {
+        addrs,
+        host: authority_host(&authority).to_string(),
+        authority: (!private).then_some(authority),
+        country: country.filter(|_| !private),
+        upstream: None,
+        tls: false,
+    }))
+}
+
+async fn target_categories(ctx: &Context, host: &str) -> Vec<String> {
+    if !ctx.acl.uses_categories() {
+        return Vec::new();
+    }
+    ctx.categories.lookup(host).await
+}
+
+fn request_intent(
+    request: &Request<'_, '_>,
+    body: &[u8],
+    config: &Config,
+    client_ip: IpAddr,
+) -> Result<Intent, ProxyResponse> {
+    let method = request.method.unwrap_or_default();
+    let path = request.path.unwrap_or_default();
+    if method == "CONNECT" {
+        return Ok(Intent::Tunnel(
+            path.to_string(),
+            TunnelMode::Connect(body.to_vec()),
+        ));
+    }
+    let target = parse_forward_target(path);
+    if method == "GET"
+        && target
+            .as_ref()
+            .is_some_and(|target| is_usage_endpoint(target, &config.usage_host))
+    {
+        return Ok(Intent::Usage);
+    }
+    if !config.forward_http {
+        return Err(ProxyResponse::MethodNotAllowed);
+    }
+    let target = target.ok_or(ProxyResponse::BadRequest)?;
+    let upload = request_body(request, body);
+    let pooled = config.forward_pool.per_origin > 0 && upload.is_some();
+    let lookup = (config.forward_cache.max_bytes > 0
+        && upload.as_ref().is_some_and(ResponseFraming::is_complete))
+    .then(|| CacheLookup::of(request, &target.authority, &target.origin_path))
+    .flatten();
+    let mut head = rewrite_request_head(
+        request,
+        &target.origin_path,
+        &config.forward_headers,
+        client_ip,
+        pooled,
+    );
+    head.extend_from_slice(body);
+    let mode = match upload.filter(|_| pooled || lookup.is_some()) {
+        Some(upload) => TunnelMode::Exchange {
+            head,
+            head_request: method.eq_ignore_ascii_case("HEAD"),
+            upload,
+            lookup,
+        },
+        None => TunnelMode::Forward(head),
+    };
+    Ok(Intent::Tunnel(target.authority, mode))
+}
+
+async fn effective_limits(ctx: &Context, user: &str) -> Result<Limits> {
+    let limits = ctx.auth.limits(user).await.map_err(ProxyError::auth)?;
+    Ok(limits.topped_up(ctx.registry.lock().await.granted(user)))
+}
+
+fn is_usage_endpoint(target: &ForwardTarget, usage_host: &str) -> bool {
+    !usage_host.is_empty()
+        && target
+            .authority
+            .strip_suffix(":80")
+            .is_some_and(|host| host.eq_ignore_ascii_case(usage_host))
+        && target.origin_path.split('?').next() == Some("/usage")
+}
+
+async fn report_usage(source: &mut TcpStream, ctx: &Context, user: &str) -> Result<()> {
+    let limits = effective_limits(ctx, user).await?;
+    let stats = ctx.registry.lock().await.stats_of(user);
+    let (traffic, concurrency, destinations) = stats.map_or_else(
+        || (TrafficStats::default(), 0, Vec::new()),
+        |stats| (stats.traffic, stats.concurrency, stats.destinations),
+    );
+    let total = traffic.ingress + traffic.egress;
+    let traffic_limit = limits.traffic().restricted();
+    let usage = json!({
+        "user": user,
+        "ingress": traffic.ingress,
+        "egress": traffic.egress,
+        "used": total,
+        "traffic_limit": traffic_limit,
+        "remaining": traffic_limit.map(|limit| limit.saturating_sub(total)),
+        "concurrency": concurrency,
+        "concurrency_limit": limits.concurrency().restricted(),
+        "destinations": destinations,
+    });
+    source
+        .write_all(&ProxyResponse::Usage(usage).to_bytes())
+        .await?;
+    Ok(())
+}
+
+async fn receive_head(source: &mut TcpStream, ctx: &Context) -> Result<Option<Vec<u8>>> {
+    let header_timeout = Duration::from_secs(ctx.config.header_timeout);
+    let Ok(head) = timeout(header_timeout, read_request_head(source, &ctx.config)).await else {
+        Metrics::inc(&ctx.metrics.header_timeouts);
+        warn!("Request header was not received in {header_timeout:?}");
+        source
+            .write_all(&ProxyResponse::RequestTimeout.to_bytes())
+            .await?;
+        return Ok(None);
+    };
+    let buff = match head? {
+        Ok(buff) => buff,
+        Err(response) => {
+            let (counter, limit) = match response {
+                ProxyResponse::UriTooLong => (&ctx.metrics.uri_too_long, "request line"),
+                _ => (&ctx.metrics.headers_too_large, "header"),
+            };
+            Metrics::inc(counter);
+            warn!("Request {limit} exceeds the configured size limit");
+            source.write_all(&response.to_bytes()).await?;
+            return Ok(None);
+        }
+    };
+    Ok(Some(buff).filter(|buff| !buff.is_empty()))
+}
+
+pub async fn handle_connection(mut source: TcpStream, ctx: Context) -> Result<()> {
+    let _ip_guard = match ctx.ip_limiter.try_acquire(source.peer_addr()?.ip().to_canonical()) {
+        Ok(guard) => guard,
+        Err(active) => return reject_ip_limited(&mut source, &ctx, active).await,
+    };
+    let accepted = AcceptContext {
+        client_addr: source.peer_addr()?,
+    };
+    if let Verdict::Reject(code) = ctx.hooks.on_accept(&accepted).await {
+        return reject_by_hook(&mut source, &ctx, code).await;
+    }
+    let Some(buff) = receive_head(&mut source, &ctx).await? else {
+        return Ok(());
     };
 
     let mut headers = [EMPTY_HEADER; 16];
     let mut request = Request::new(&mut headers);
-    request.parse(&buff[..size])?;
+    let parsed = request
+        .parse(&buff)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let head_len = match parsed {
+        Status::Complete(len) => len,
+        Status::Partial => buff.len(),
+    };
 
-    debug!(request = format!("{:?}", request));
-    let request_method = request.method.unwrap();
-    let request_path = request.path.unwrap();
+    Metrics::inc(&ctx.metrics.requests);
+    Metrics::add(&ctx.metrics.request_headers, request.headers.len() as u64);
+    Metrics::add(&ctx.metrics.request_header_bytes, buff.len() as u64);
 
-    if request_method != "CONNECT" {
+    debug!(method = request.method);
+    if let Some(retry_after) = ctx.maintenance.retry_after() {
+        Metrics::inc(&ctx.metrics.maintenance_rejections);
         source
-            .write_all(ProxyResponse::MethodNotAllowed.as_bytes())
+            .write_all(&ProxyResponse::Maintenance(retry_after).to_bytes())
+            .await?;
+        return Ok(());
+    }
+    let client_ip = source.peer_addr()?.ip().to_canonical();
+    if !is_geo_allowed(&ctx, client_ip, "Client").0 && !shadowed(&ctx, "geoip_denied") {
+        source
+            .write_all(&ProxyResponse::Forbidden("geoip_denied").to_bytes())
             .await?;
         return Ok(());
     }
-    let auth_header = request
-        .headers
-        .iter()
-        .find(|header| header.name == "Proxy-Authorization");
 
-    match auth_header {
-        None => {
+    let intent = match request_intent(&request, &buff[head_len..], &ctx.config, client_ip) {
+        Ok(intent) => intent,
+        Err(response) => {
+            source.write_all(&response.to_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let tarpit = ctx.auth_audit.tarpit(client_ip);
+    let user = match authorize(&ctx, &request, client_ip).await? {
+        AuthDecision::Granted(user) => user,
+        AuthDecision::Missing => {
+            let response = (&ProxyResponse::ProxyAuthRequired, tarpit);
+            return reject_unauthenticated(&mut source, &ctx, response).await;
+        }
+        AuthDecision::Denied => {
+            let response = (&ProxyResponse::Unauthorized, tarpit);
+            return reject_unauthenticated(&mut source, &ctx, response).await;
+        }
+        AuthDecision::Malformed => {
             source
-                .write_all(ProxyResponse::ProxyAuthRequired.as_bytes())
+                .write_all(&ProxyResponse::BadRequest.to_bytes())
                 .await?;
+            return Ok(());
         }
-        Some(proxy_auth_header) => {
-            let (user, password) = parse_proxy_auth_token(proxy_auth_header.value)?;
+        AuthDecision::Locked(lockout) => return reject_locked(&mut source, &ctx, lockout).await,
+    };
+    Span::current().record("user", user.as_str());
+    let authenticated = AuthContext {
+        user: user.clone(),
+        client_ip,
+    };
+    if let Verdict::Reject(code) = ctx.hooks.on_auth(&authenticated).await {
+        return reject_by_hook(&mut source, &ctx, code).await;
+    }
 
-            if !authenticate(&user, &password, &ctx.database) {
-                source
-                    .write_all(ProxyResponse::Unauthorized.as_bytes())
-                    .await?;
-            }
+    let (target_authority, mode) = match intent {
+        Intent::Tunnel(authority, mode) => (authority, mode),
+        Intent::Usage => return report_usage(&mut source, &ctx, &user).await,
+    };
+    let private = ctx.auth.is_private(&user).await.map_err(ProxyError::auth)?;
+    if private {
+        debug!(user = user, "Destination of private user is not logged");
+    } else {
+        debug!(user = user, target = target_authority);
+    }
 
-            let mut registry = ctx.registry.lock().await;
-            registry.create_user(&user, Limits::with_low_limits());
-            registry.inc_concurrency(&user);
-
-            match registry.check_limits(&user) {
-                Ok(()) => {
-                    drop(registry);
-
-                    let mut target = TcpStream::connect(request_path).await?;
-                    let (ingress, egress) = connect_target(
-                        &mut source,
-                        &mut target,
-                        Duration::from_secs(ctx.config.connection_timeout),
-                    )
-                    .await?;
-
-                    let mut registry = ctx.registry.lock().await;
-                    registry.add_ingress_traffic(&user, u128::from(ingress));
-                    registry.add_egress_traffic(&user, u128::from(egress));
-                    registry.dec_concurrency(&user);
-                }
-                Err(err) => {
-                    registry.dec_concurrency(&user);
-
-                    warn!(message = format!("{:?}", err));
-                    match err {
-                        LimitError::ConcurrencyLimitExceed(_) => {
-                            source
-                                .write_all(ProxyResponse::TooManyRequests.as_bytes())
-                                .await?;
-                        }
-                        LimitError::TrafficLimitExceed(_) => {
-                            source
-                                .write_all(ProxyResponse::QuotaExceeded.as_bytes())
-                                .await?;
-                        }
-                    }
-                }
+    let requested = (target_authority, !matches!(mode, TunnelMode::Connect(_)));
+    let Some(mut target) = resolve_target(&mut source, &ctx, (&user, private), requested).await?
+    else {
+        return Ok(());
+    };
+    let mode = intercept_mode(&ctx, (&user, private), &mut target, mode).await?;
+    tunnel(source, &ctx, &user, target, mode).await
+}
+
+async fn intercept_mode(
+    ctx: &Context,
+    (user, private): (&str, bool),
+    target: &mut TunnelTarget,
+    mode: TunnelMode,
+) -> Result<TunnelMode> {
+    let (TunnelMode::Connect(early), Some(interceptor)) = (&mode, &ctx.interceptor) else {
+        return Ok(mode);
+    };
+    let intercepted = ctx.auth.is_intercepted(user).await;
+    if !intercepted.map_err(ProxyError::auth)? {
+        return Ok(mode);
+    }
+    let host = (!private).then_some(target.host.as_str());
+    warn!(
+        user = user,
+        target = host,
+        "Decrypting intercepted TLS tunnel"
+    );
+    target.tls = true;
+    Ok(TunnelMode::Intercept(early.clone(), interceptor.clone()))
+}
+
+pub(crate) async fn handle_transparent(
+    mut source: TcpStream,
+    ctx: Context,
+    user: String,
+    target: SocketAddr,
+) -> Result<()> {
+    let client_ip = source.peer_addr()?.ip().to_canonical();
+    let Ok(_ip_guard) = ctx.ip_limiter.try_acquire(client_ip) else {
+        Metrics::inc(&ctx.metrics.ip_limit_rejections);
+        return Ok(());
+    };
+    let accepted = AcceptContext {
+        client_addr: source.peer_addr()?,
+    };
+    if let Verdict::Reject(code) = ctx.hooks.on_accept(&accepted).await {
+        Metrics::inc(&ctx.metrics.hook_rejections);
+        debug!(code = code, "Transparent connection rejected by hook");
+        return Ok(());
+    }
+    Metrics::inc(&ctx.metrics.requests);
+    if !is_geo_allowed(&ctx, client_ip, "Client").0 && !shadowed(&ctx, "geoip_denied") {
+        return Ok(());
+    }
+    let private = ctx.auth.is_private(&user).await.map_err(ProxyError::auth)?;
+    if !private {
+        debug!(user = user, target = format!("{target}"), "Transparent connection");
+    }
+    let Some(target) =
+        admit_target(&mut source, &ctx, target.to_string(), vec![target], private).await?
+    else {
+        return Ok(());
+    };
+    tunnel(source, &ctx, &user, target, TunnelMode::Transparent).await
+}
+
+async fn establish(
+    source: &mut TcpStream,
+    ctx: &Context,
+    (user, host, logged_target): (&str, &str, Option<&str>),
+    (mode, connection_id): (TunnelMode, u64),
+) -> Result<(Result<TunnelMode, CloseReason>, Option<String>)> {
+    let policy = ctx.config.sni;
+    let established = ctx.config.connect_headers.established(connection_id);
+    if matches!(mode, TunnelMode::Intercept(..)) {
+        source.write_all(&established).await?;
+        return Ok((Ok(mode), None));
+    }
+    let TunnelMode::Connect(early_data) = mode else {
+        return Ok((Ok(mode), None));
+    };
+    source.write_all(&established).await?;
+    if policy.mode == SniMode::Off {
+        return Ok((Ok(TunnelMode::Forward(early_data)), None));
+    }
+    let (first_flight, hello) = read_client_hello(source, early_data, policy.timeout).await?;
+    let ClientHello::Tls(Some(sni)) = hello else {
+        return Ok((Ok(TunnelMode::Forward(first_flight)), None));
+    };
+    let categories = target_categories(ctx, &sni).await;
+    if ctx.acl.denies_category(&categories) {
+        Metrics::inc(&ctx.metrics.acl_denied);
+        if logged_target.is_some() {
+            let categories = categories.join(",");
+            warn!(
+                user = user,
+                sni = sni,
+                categories,
+                "TLS server name denied by ACL"
+            );
+        }
+        if !shadowed(ctx, CloseReason::CategoryDenied.as_str()) {
+            return Ok((Err(CloseReason::CategoryDenied), Some(sni)));
+        }
+    }
+    if matches_host(host, &sni) {
+        if logged_target.is_some() {
+            info!(user = user, sni = sni, "TLS server name");
+        }
+        return Ok((Ok(TunnelMode::Forward(first_flight)), Some(sni)));
+    }
+    Metrics::inc(&ctx.metrics.sni_mismatches);
+    if let Some(authority) = logged_target {
+        warn!(
+            user = user,
+            target = authority,
+            sni = sni,
+            "TLS server name does not match CONNECT host"
+        );
+    }
+    let mode = (policy.mode != SniMode::Enforce || shadowed(ctx, "sni_mismatch"))
+        .then_some(TunnelMode::Forward(first_flight))
+        .ok_or(CloseReason::SniMismatch);
+    Ok((mode, Some(sni)))
+}
+
+async fn relay_with_limits(
+    (source, stream): (&mut TcpStream, &mut OriginStream),
+    ctx: &Context,
+    (user, limits): (&str, Limits),
+    (live, opening): (Option<&Arc<TrafficCounters>>, &TunnelOpenContext),
+    mode: TunnelMode,
+) -> Result<RelayOutcome> {
+    let enforced = if ctx.config.enforcement == Enforcement::Shadow {
+        Limits::default()
+    } else {
+        limits
+    };
+    let quota = live
+        .cloned()
+        .zip(enforced.traffic().restricted())
+        .map(|(live, limit)| {
+            QuotaLease::new(live, limit, QUOTA_CHUNK).renewed_from(
+                ctx.store.clone(),
+                user,
+                enforced,
+            )
+        });
+    let bandwidth = limits
+        .bandwidth()
+        .restricted()
+        .map(|rate| ctx.bandwidth.bucket(user, rate));
+    let policy = relay_policy(ctx, enforced);
+    let started = Instant::now();
+    let tracked = ctx.connections.open(opening, live.cloned());
+    let relay = mode.relay(
+        source,
+        stream,
+        policy,
+        Some(&tracked.counters),
+        (quota.as_ref(), bandwidth.as_deref()),
+        opening,
+    );
+    let stopped = async {
+        tokio::select! {
+            reason = tracked.killed() => reason,
+            reason = ctx.chaos.tunnel_reset() => reason,
+        }
+    };
+    let outcome = tokio::select! {
+        outcome = relay => outcome,
+        reason = stopped => Ok(RelayOutcome {
+            ingress: u64::try_from(tracked.counters.ingress()).unwrap_or(u64::MAX),
+            egress: u64::try_from(tracked.counters.egress()).unwrap_or(u64::MAX),
+            reason,
+            first_byte: None,
+            duration: started.elapsed(),
+            reusable: false,
+        }),
+    };
+    ctx.connections.close(opening.connection_id);
+    let outcome = outcome?;
+    if outcome.reason == CloseReason::ChaosReset {
+        SockRef::from(&*source).set_linger(Some(Duration::ZERO))?;
+    }
+    if outcome.reason == CloseReason::ClientReset
+        && let Some(target) = stream.tcp()
+    {
+        SockRef::from(target).set_linger(Some(Duration::ZERO))?;
+    }
+    if limits
+        .traffic()
+        .restricted()
+        .zip(live)
+        .is_some_and(|(limit, live)| live.total() >= limit)
+    {
+        shadowed(ctx, CloseReason::QuotaExceeded.as_str());
+    }
+    if limits
+        .lifetime()
+        .restricted()
+        .is_some_and(|lifetime| started.elapsed() >= lifetime)
+    {
+        shadowed(ctx, CloseReason::MaxLifetime.as_str());
+    }
+    Ok(outcome)
+}
+
+fn relay_policy(ctx: &Context, limits: Limits) -> RelayPolicy {
+    RelayPolicy {
+        idle: Duration::from_secs(ctx.config.connection_timeout),
+        lifetime: limits.lifetime().restricted(),
+        stall: (ctx.config.stall_timeout > 0)
+            .then(|| Duration::from_secs(ctx.config.stall_timeout)),
+        buffer: ctx.config.tunnel_buffer,
+    }
+}
+
+fn outbound_binding(ctx: &Context, user: &str, session_id: u64) -> OutboundBinding {
+    let outbound = &ctx.config.outbound;
+    if let Some(binding) = outbound.users.get(user) {
+        return binding.clone();
+    }
+    ctx.egress.as_ref().map_or_else(
+        || outbound.default.clone(),
+        |pool| OutboundBinding {
+            addr: Some(pool.pick(user, session_id)),
+            interface: outbound.default.interface.clone(),
+        },
+    )
+}
+
+fn notify_soft_limit(ctx: &Context, user: &str, warning: SoftLimitWarning) {
+    Metrics::inc(&ctx.metrics.soft_limit_warnings);
+    warn!(
+        user = user,
+        used = format!("{}", warning.used),
+        limit = format!("{}", warning.limit),
+        percent = warning.percent,
+        "Traffic soft limit crossed"
+    );
+    let Some(url) = ctx.config.warn_webhook.clone() else {
+        return;
+    };
+    let event = json!({
+        "event": "soft_limit_crossed",
+        "user": user,
+        "used": warning.used,
+        "limit": warning.limit,
+        "percent": warning.percent,
+    });
+    tokio::spawn(
+        async move {
+            if let Err(err) = post_json(&url, event.to_string().as_bytes(), None).await {
+                warn!(error = format!("{err}"), "Soft limit webhook failed");
             }
         }
+        .in_current_span(),
+    );
+}
+
+async fn retry_hints(
+    ctx: &Context,
+    user: &str,
+    err: &LimitError,
+    limits: Limits,
+) -> (u64, Option<(u64, u64)>) {
+    let registry = ctx.registry.lock().await;
+    let oldest = match err {
+        LimitError::ConcurrencyLimitExceed(_) => registry.oldest_tunnel(user),
+        LimitError::TenantConcurrencyLimitExceed(..) => registry.oldest_tenant_tunnel(user),
+        _ => None,
+    };
+    let reset_at = registry.traffic_reset_at();
+    drop(registry);
+    let lifetime = limits.lifetime().restricted();
+    let slot_free_in = oldest.zip(lifetime).map_or(SLOT_RETRY_AFTER, |(started, lifetime)| {
+        let remaining = (started + lifetime).saturating_duration_since(ctx.clock.now());
+        let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        seconds.max(SLOT_RETRY_AFTER)
+    });
+    let quota_reset = reset_at.map(|reset_at| {
+        let reset_in = reset_at.saturating_sub(ctx.clock.unix_now()).max(1);
+        (reset_at, reset_in)
+    });
+    (slot_free_in, quota_reset)
+}
+
+fn limit_response(
+    err: &LimitError,
+    limits: Limits,
+    (slot_free_in, quota_reset): (u64, Option<(u64, u64)>),
+) -> ProxyResponse {
+    let (reset_at, reset_in) = quota_reset.unzip();
+    match *err {
+        LimitError::ConcurrencyLimitExceed(active) => ProxyResponse::TooManyRequests(LimitUsage {
+            limit: limits.concurrency().restricted().map(u128::from),
+            used: u128::from(active.saturating_sub(1)),
+            reset_at: None,
+            retry_after: Some(slot_free_in),
+        }),
+        LimitError::TrafficLimitExceed(traffic) => ProxyResponse::QuotaExceeded(LimitUsage {
+            limit: limits.traffic().restricted(),
+            used: traffic,
+            reset_at,
+            retry_after: reset_in,
+        }),
+        LimitError::TenantConcurrencyLimitExceed(active, limit) => {
+            ProxyResponse::TooManyRequests(LimitUsage {
+                limit: Some(u128::from(limit)),
+                used: u128::from(active.saturating_sub(1)),
+                reset_at: None,
+                retry_after: Some(slot_free_in),
+            })
+        }
+        LimitError::TenantTrafficLimitExceed(traffic, limit) => {
+            ProxyResponse::QuotaExceeded(LimitUsage {
+                limit: Some(limit),
+                used: traffic,
+                reset_at,
+                retry_after: reset_in,
+            })
+        }
+        LimitError::OutsideSchedule => ProxyResponse::Forbidden("outside_schedule"),
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+async fn append_usage(ctx: &Context, record: UsageRecord) {
+    if let Some(ledger) = &ctx.ledger
+        && let Err(err) = ledger.append(&record).await
+    {
+        error!(error = format!("{err}"), "Usage ledger append failed");
+    }
+}
+
+async fn close_in_registry(
+    ctx: &Context,
+    user: &str,
+    (session_id, connection_id): (u64, u64),
+    (host, country): (Option<&str>, Option<&str>),
+    outcome: &RelayOutcome,
+) -> Option<SoftLimitWarning> {
+    ctx.metrics.tunnel_closed(outcome.reason);
+    if let Some(first_byte) = outcome.first_byte {
+        ctx.metrics.first_byte_latency.observe(first_byte);
+    }
+    info!(
+        user = user,
+        reason = outcome.reason.as_str(),
+        ingress = outcome.ingress,
+        egress = outcome.egress,
+        first_byte_ms = outcome.first_byte.map(millis),
+        "Tunnel closed"
+    );
+    let (ingress, egress) = (u128::from(outcome.ingress), u128::from(outcome.egress));
+    let mut registry = ctx.registry.lock().await;
+    registry.close_session(session_id, ingress, egress);
+    registry.close_tunnel(user, connection_id);
+    registry.record_close(user, outcome.reason);
+    registry.record_tunnel(user, outcome.ingress + outcome.egress, outcome.duration);
+    if let Some(country) = country {
+        registry.add_country_traffic(user, country, ingress, egress);
+    }
+    if let Some(host) = host {
+        registry.add_destination_traffic(user, host, ingress, egress);
+    }
+    ctx.config
+        .traffic_warn_percent
+        .and_then(|percent| registry.cross_traffic_threshold(user, percent))
+}
+
+const fn dial_policy(config: &Config) -> DialPolicy {
+    DialPolicy {
+        stagger: Duration::from_millis(config.happy_eyeballs_delay),
+        attempt_timeout: Duration::from_secs(config.connect_attempt_timeout),
+        max_attempts: config.connect_max_attempts,
+    }
+}
+
+async fn acquire_slot(
+    source: &mut TcpStream,
+    ctx: &Context,
+    user: &str,
+    limits: Limits,
+) -> Result<Option<ConcurrencyGuard>> {
+    let schedule = ctx.auth.schedule(user).await.map_err(ProxyError::auth)?;
+    if schedule.is_some_and(|schedule| !schedule.allows(ctx.clock.unix_now()))
+        && !shadowed(ctx, LimitError::OutsideSchedule.code())
+    {
+        reject_over_limit(source, ctx, user, &LimitError::OutsideSchedule, limits).await?;
+        return Ok(None);
+    }
+    let slot = match ConcurrencyGuard::acquire(ctx.store.clone(), user, limits).await {
+        Ok(slot) => slot,
+        Err(ProxyError::LimitExceeded(err)) if shadowed(ctx, err.code()) => {
+            ConcurrencyGuard::acquire(ctx.store.clone(), user, Limits::default()).await?
+        }
+        Err(ProxyError::LimitExceeded(err)) => {
+            reject_over_limit(source, ctx, user, &err, limits).await?;
+            return Ok(None);
+        }
+        Err(ProxyError::Backend(err)) => {
+            warn!(
+                user = user,
+                error = format!("{err}"),
+                "Cannot reserve a tunnel slot"
+            );
+            source
+                .write_all(&ProxyResponse::ServiceUnavailable.to_bytes())
+                .await?;
+            return Ok(None);
+        }
+        Err(err) => return Err(err),
+    };
+    match check_tenant(ctx, user).await? {
+        Err(err) if !shadowed(ctx, err.code()) => {
+            slot.release().await?;
+            reject_over_limit(source, ctx, user, &err, limits).await?;
+            Ok(None)
+        }
+        _ => Ok(Some(slot)),
     }
+}
+
+async fn check_tenant(ctx: &Context, user: &str) -> Result<Result<(), LimitError>> {
+    let Some(tenant) = ctx.auth.tenant(user).await.map_err(ProxyError::auth)? else {
+        return Ok(Ok(()));
+    };
+    let limits = ctx
+        .config
+        .tenant_limits
+        .get(&tenant)
+        .copied()
+        .unwrap_or_default();
+    let mut registry = ctx.registry.lock().await;
+    registry.join_tenant(user, &tenant, limits);
+    Ok(registry.check_tenant_limits(user))
+}
 
+async fn reject_over_limit(
+    source: &mut TcpStream,
+    ctx: &Context,
+    user: &str,
+    err: &LimitError,
+    limits: Limits,
+) -> Result<()> {
+    warn!(message = format!("{:?}", err));
+    ctx.events.emit(Event::LimitExceeded {
+        user: user.to_string(),
+        error: err.code(),
+    });
+    let hints = retry_hints(ctx, user, err, limits).await;
+    let response = limit_response(err, limits, hints);
+    source.write_all(&response.to_bytes()).await?;
     Ok(())
 }
+
+async fn connect_upstream(
+    ctx: &Context,
+    target: &TunnelTarget,
+    binding: &OutboundBinding,
+    (user, logged_target): (&str, Option<&str>),
+) -> Result<(TcpStream, Duration)> {
+    let started = Instant::now();
+    let policy = dial_policy(&ctx.config);
+    let stream = match (ctx.chaos.before_dial().await, &target.upstream) {
+        (Err(err), _) => Err(err),
+        (Ok(()), Some((proxy, authority))) => {
+            timeout(policy.attempt_timeout, proxy.connect(authority))
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+        }
+        (Ok(()), None) => dial(&target.addrs, binding, policy).await,
+    }
+    .map_err(|source| ProxyError::Dial {
+        target: target.host.clone(),
+        source,
+    })?;
+    let elapsed = started.elapsed();
+    ctx.metrics.dial_latency.observe(elapsed);
+    set_keepalive(&stream, ctx.config.keepalive);
+    set_socket_options(&stream, ctx.config.socket);
+    if let Some(authority) = logged_target {
+        info!(
+            user = user,
+            target = authority,
+            upstream = format!("{}", stream.peer_addr()?),
+            dial_ms = millis(elapsed),
+            "Tunnel connected"
+        );
+    }
+    Ok((stream, elapsed))
+}
+
+fn pool_key(
+    ctx: &Context,
+    target: &TunnelTarget,
+    binding: &OutboundBinding,
+    mode: &TunnelMode,
+) -> Option<String> {
+    if ctx.pool.is_none()
+        || target.upstream.is_some()
+        || target.tls
+        || !matches!(mode, TunnelMode::Exchange { .. })
+    {
+        return None;
+    }
+    let port = target.addrs.first()?.port();
+    let source = binding
+        .addr
+        .map(|addr| format!("@{addr}"))
+        .unwrap_or_default();
+    Some(format!("http://{}:{port}{source}", target.host))
+}
+
+async fn open_origin(
+    ctx: &Context,
+    target: &TunnelTarget,
+    binding: &OutboundBinding,
+    (pool_key, lookup): (Option<&str>, Option<&CacheLookup>),
+    logged: (&str, Option<&str>),
+) -> Result<(OriginStream, Duration)> {
+    let Some((cache, lookup)) = ctx.cache.as_deref().zip(lookup) else {
+        return connect_target(ctx, target, binding, pool_key, logged).await;
+    };
+    if let Some(response) = cache.fetch(lookup) {
+        return Ok((OriginStream::Cached(Cursor::new(response)), Duration::ZERO));
+    }
+    let (stream, elapsed) = connect_target(ctx, target, binding, pool_key, logged).await?;
+    let recording = OriginStream::Recording(Box::new(stream), Vec::new(), cache.max_entry());
+    Ok((recording, elapsed))
+}
+
+async fn connect_target(
+    ctx: &Context,
+    target: &TunnelTarget,
+    binding: &OutboundBinding,
+    pool_key: Option<&str>,
+    logged: (&str, Option<&str>),
+) -> Result<(OriginStream, Duration)> {
+    let pooled = pool_key
+        .zip(ctx.pool.as_deref())
+        .and_then(|(key, pool)| pool.take(key));
+    if let Some(stream) = pooled {
+        return Ok((OriginStream::Plain(stream), Duration::ZERO));
+    }
+    let (stream, elapsed) = connect_upstream(ctx, target, binding, logged).await?;
+    if !target.tls {
+        return Ok((OriginStream::Plain(stream), elapsed));
+    }
+    let handshake = ctx.origin_tls.connect(&target.host, stream);
+    let stream = timeout(dial_policy(&ctx.config).attempt_timeout, handshake)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+        .map_err(|source| ProxyError::Dial {
+            target: target.host.clone(),
+            source,
+        })?;
+    Ok((stream, elapsed))
+}
+
+async fn open_session(
+    ctx: &Context,
+    user: &str,
+    target: &TunnelTarget,
+    client_ip: IpAddr,
+) -> (Option<Arc<TrafficCounters>>, TunnelOpenContext) {
+    let connection_id = ctx.metrics.next_tunnel_id();
+    let mut registry = ctx.registry.lock().await;
+    let live = registry.traffic_counters(user);
+    let session_id = registry.open_session(
+        user,
+        client_ip,
+        Duration::from_secs(ctx.config.session_ttl),
+    );
+    registry.open_tunnel(user, connection_id);
+    drop(registry);
+    let opening = TunnelOpenContext {
+        user: user.to_string(),
+        client_ip,
+        session_id,
+        connection_id,
+        host: target.host.clone(),
+        target: target.authority.clone(),
+    };
+    (live, opening)
+}
+
+async fn abandon_session(ctx: &Context, user: &str, (session_id, connection_id): (u64, u64)) {
+    let mut registry = ctx.registry.lock().await;
+    registry.close_session(session_id, 0, 0);
+    registry.close_tunnel(user, connection_id);
+}
+
+async fn announce_closed(ctx: &Context, opening: TunnelOpenContext, outcome: &RelayOutcome) {
+    ctx.events.emit(Event::TunnelClosed {
+        user: opening.user.clone(),
+        session_id: opening.session_id,
+        ingress: outcome.ingress,
+        egress: outcome.egress,
+        reason: outcome.reason,
+    });
+    let closed = TunnelCloseContext {
+        user: opening.user,
+        session_id: opening.session_id,
+        connection_id: opening.connection_id,
+        target: opening.target,
+        ingress: outcome.ingress,
+        egress: outcome.egress,
+        reason: outcome.reason,
+    };
+    ctx.hooks.on_tunnel_close(&closed).await;
+}
+
+async fn tunnel(
+    mut source: TcpStream,
+    ctx: &Context,
+    user: &str,
+    target: TunnelTarget,
+    mode: TunnelMode,
+) -> Result<()> {
+    let limits = effective_limits(ctx, user).await?;
+    let Some(slot) = acquire_slot(&mut source, ctx, user, limits).await? else {
+        return Ok(());
+    };
+
+    let client_ip = source.peer_addr()?.ip().to_canonical();
+    let (live, opening) = open_session(ctx, user, &target, client_ip).await;
+    let (session_id, connection_id) = (opening.session_id, opening.connection_id);
+    if let Verdict::Reject(code) = ctx.hooks.on_tunnel_open(&opening).await {
+        abandon_session(ctx, user, (session_id, connection_id)).await;
+        slot.release().await?;
+        return reject_by_hook(&mut source, ctx, code).await;
+    }
+    let logged_target = target.authority.clone();
+    ctx.events.emit(Event::TunnelOpened {
+        user: user.to_string(),
+        session_id,
+        target: logged_target.clone(),
+    });
+
+    let started_at = unix_now();
+    let binding = outbound_binding(ctx, user, session_id);
+    let logged = logged_target.as_deref();
+    let (pool_key, lookup) = (pool_key(ctx, &target, &binding, &mode), mode.cache_lookup());
+    let reuse = (pool_key.as_deref(), lookup.as_ref());
+    let dialing = open_origin(ctx, &target, &binding, reuse, (user, logged));
+    let (mut stream, dial_time) = match dialing.instrument(info_span!("dial")).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            warn!(error = format!("{err}"), "Target connect failed");
+            abandon_session(ctx, user, (session_id, connection_id)).await;
+            slot.release().await?;
+            source
+                .write_all(&ProxyResponse::BadGateway.to_bytes())
+                .await?;
+            return Ok(());
+        }
+    };
+    let screened = (user, target.host.as_str(), logged);
+    let channel = (&mut source, &mut stream);
+    let tracked = (live.as_ref(), &opening);
+    let relayed = establish_and_relay(channel, ctx, (screened, limits), tracked, mode).await;
+    let (outcome, sni, failure) = match relayed {
+        Ok((outcome, sni)) => (outcome, sni, None),
+        Err(err) => (RelayOutcome::empty(CloseReason::IoError), None, Some(err)),
+    };
+    let RelayOutcome {
+        ingress,
+        egress,
+        reason,
+        first_byte,
+        ..
+    } = outcome;
+
+    let counted = match live {
+        None => ctx
+            .store
+            .add_traffic(user, u128::from(ingress), u128::from(egress))
+            .await
+            .map_err(ProxyError::backend),
+        Some(_) => Ok(()),
+    };
+    let released = slot.release().await;
+    append_usage(
+        ctx,
+        UsageRecord {
+            connection_id,
+            session_id,
+            user: user.to_string(),
+            ingress,
+            egress,
+            started_at,
+            ended_at: unix_now(),
+            close_reason: Some(reason),
+            sni: sni.filter(|_| logged_target.is_some()),
+            dial_ms: Some(millis(dial_time)),
+            first_byte_ms: first_byte.map(millis),
+        },
+    )
+    .await;
+    let place = (logged.map(|_| target.host.as_str()), target.country.as_deref());
+    let ids = (session_id, connection_id);
+    let warning = close_in_registry(ctx, user, ids, place, &outcome).await;
+
+    announce_closed(ctx, opening, &outcome).await;
+    if let Some(warning) = warning {
+        notify_soft_limit(ctx, user, warning);
+    }
+
+    if let (Some(pool), Some(addr)) = (&ctx.egress, binding.addr) {
+        pool.record(addr, ingress + egress);
+    }
+    release_origin(ctx, stream, &outcome, (pool_key, lookup.as_ref()));
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    counted?;
+    released
+}
+
+async fn establish_and_relay(
+    (source, stream): (&mut TcpStream, &mut OriginStream),
+    ctx: &Context,
+    (screened, limits): ((&str, &str, Option<&str>), Limits),
+    (live, opening): (Option<&Arc<TrafficCounters>>, &TunnelOpenContext),
+    mode: TunnelMode,
+) -> Result<(RelayOutcome, Option<String>)> {
+    let (session_id, connection_id) = (opening.session_id, opening.connection_id);
+    let (mode, sni) = establish(source, ctx, screened, (mode, connection_id)).await?;
+    let outcome = match mode {
+        Ok(mode) => {
+            let (channel, limited) = ((source, stream), (screened.0, limits));
+            relay_with_limits(channel, ctx, limited, (live, opening), mode)
+                .instrument(info_span!("tunnel", session_id, connection_id))
+                .await?
+        }
+        Err(reason) => RelayOutcome::empty(reason),
+    };
+    Ok((outcome, sni))
+}
+
+fn release_origin(
+    ctx: &Context,
+    stream: OriginStream,
+    outcome: &RelayOutcome,
+    (pool_key, lookup): (Option<String>, Option<&CacheLookup>),
+) {
+    let stream = match stream {
+        OriginStream::Recording(stream, recorded, _) => {
+            if let (CloseReason::TargetClosed, Some(cache), Some(lookup)) =
+                (outcome.reason, &ctx.cache, lookup)
+            {
+                cache.store(lookup, &recorded);
+            }
+            *stream
+        }
+        stream => stream,
+    };
+    if let (true, Some(pool), Some(key), OriginStream::Plain(stream)) =
+        (outcome.reusable, &ctx.pool, pool_key, stream)
+    {
+        pool.put(&key, stream);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::tunnel::CloseReason;
+use async_trait::async_trait;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Reject(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub struct AcceptContext {
+    pub client_addr: SocketAddr,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthContext {
+    pub user: String,
+    pub client_ip: IpAddr,
+}
+
+#[derive(Clone, Debug)]
+pub struct TunnelOpenContext {
+    pub user: String,
+    pub client_ip: IpAddr,
+    pub session_id: u64,
+    pub connection_id: u64,
+    pub host: String,
+    pub target: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TunnelCloseContext {
+    pub user: String,
+    pub session_id: u64,
+    pub connection_id: u64,
+    pub target: Option<String>,
+    pub ingress: u64,
+    pub egress: u64,
+    pub reason: CloseReason,
+}
+
+#[async_trait]
+pub trait Hooks: Send + Sync {
+    async fn on_accept(&self, _ctx: &AcceptContext) -> Verdict {
+        Verdict::Allow
+    }
+
+    async fn on_auth(&self, _ctx: &AuthContext) -> Verdict {
+        Verdict::Allow
+    }
+
+    async fn on_tunnel_open(&self, _ctx: &TunnelOpenContext) -> Verdict {
+        Verdict::Allow
+    }
+
+    async fn on_tunnel_close(&self, _ctx: &TunnelCloseContext) {}
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct HookChain {
+    hooks: Arc<[Arc<dyn Hooks>]>,
+}
+
+impl HookChain {
+    pub(crate) fn new(hooks: Vec<Arc<dyn Hooks>>) -> Self {
+        Self {
+            hooks: hooks.into(),
+        }
+    }
+
+    pub(crate) async fn on_accept(&self, ctx: &AcceptContext) -> Verdict {
+        for hook in self.hooks.iter() {
+            if let Verdict::Reject(code) = hook.on_accept(ctx).await {
+                return Verdict::Reject(code);
+            }
+        }
+        Verdict::Allow
+    }
+
+    pub(crate) async fn on_auth(&self, ctx: &AuthContext) -> Verdict {
+        for hook in self.hooks.iter() {
+            if let Verdict::Reject(code) = hook.on_auth(ctx).await {
+                return Verdict::Reject(code);
+            }
+        }
+        Verdict::Allow
+    }
+
+    pub(crate) async fn on_tunnel_open(&self, ctx: &TunnelOpenContext) -> Verdict {
+        for hook in self.hooks.iter() {
+            if let Verdict::Reject(code) = hook.on_tunnel_open(ctx).await {
+                return Verdict::Reject(code);
+            }
+        }
+        Verdict::Allow
+    }
+
+    pub(crate) async fn on_tunnel_close(&self, ctx: &TunnelCloseContext) {
+        for hook in self.hooks.iter() {
+            hook.on_tunnel_close(ctx).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counting(Arc<AtomicUsize>, Verdict);
+
+    #[async_trait]
+    impl Hooks for Counting {
+        async fn on_auth(&self, _ctx: &AuthContext) -> Verdict {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            self.1
+        }
+    }
+
+    #[tokio::test]
+    async fn first_rejecting_hook_short_circuits_the_chain() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let chain = HookChain::new(vec![
+            Arc::new(Counting(calls.clone(), Verdict::Allow)),
+            Arc::new(Counting(calls.clone(), Verdict::Reject("billing_suspended"))),
+            Arc::new(Counting(calls.clone(), Verdict::Allow)),
+        ]);
+        let ctx = AuthContext {
+            user: "alice".to_string(),
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+
+        assert_eq!(chain.on_auth(&ctx).await, Verdict::Reject("billing_suspended"));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(HookChain::default().on_auth(&ctx).await, Verdict::Allow);
+    }
+}
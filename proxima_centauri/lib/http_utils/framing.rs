@@ -0,0 +1,275 @@
+use crate::http_utils::headers;
+use httparse::{EMPTY_HEADER, Response, Status};
+
+const MAX_HEAD: usize = 65_536;
+const MAX_LINE: usize = 4_096;
+
+#[derive(Clone)]
+enum State {
+    Head(Vec<u8>),
+    Length(u64),
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    ChunkEnd(u8),
+    Trailer(Vec<u8>),
+    UntilClose,
+    Done,
+}
+
+#[derive(Clone)]
+pub(crate) struct ResponseFraming {
+    state: State,
+    head_request: bool,
+    reusable: bool,
+}
+
+impl ResponseFraming {
+    pub(crate) const fn new(head_request: bool) -> Self {
+        Self {
+            state: State::Head(Vec::new()),
+            head_request,
+            reusable: true,
+        }
+    }
+
+    pub(crate) fn body(
+        content_length: Option<&str>,
+        transfer_encoding: Option<&str>,
+    ) -> Option<Self> {
+        let state = match (transfer_encoding, content_length) {
+            (Some(encoding), _) if is_chunked(encoding) => State::ChunkSize(Vec::new()),
+            (Some(_), _) => return None,
+            (None, None) => State::Done,
+            (None, Some(length)) => match length.trim().parse().ok()? {
+                0 => State::Done,
+                length => State::Length(length),
+            },
+        };
+        Some(Self {
+            state,
+            head_request: false,
+            reusable: true,
+        })
+    }
+
+    pub(crate) const fn is_complete(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    pub(crate) const fn is_reusable(&self) -> bool {
+        self.reusable && self.is_complete()
+    }
+
+    pub(crate) fn closed(&mut self) {
+        self.reusable = false;
+        if matches!(self.state, State::UntilClose) {
+            self.state = State::Done;
+        }
+    }
+
+    pub(crate) const fn overrun(&mut self) {
+        self.reusable = false;
+    }
+
+    pub(crate) fn feed(&mut self, data: &[u8]) -> usize {
+        let mut used = 0;
+        while used < data.len() {
+            let available = (data.len() - used) as u64;
+            match &mut self.state {
+                State::Done => break,
+                State::UntilClose => used = data.len(),
+                State::Length(remaining) => {
+                    let taken = (*remaining).min(available);
+                    used += usize::try_from(taken).unwrap_or(usize::MAX);
+                    *remaining -= taken;
+                    if *remaining == 0 {
+                        self.state = State::Done;
+                    }
+                }
+                State::ChunkData(remaining) => {
+                    let taken = (*remaining).min(available);
+                    used += usize::try_from(taken).unwrap_or(usize::MAX);
+                    *remaining -= taken;
+                    if *remaining == 0 {
+                        self.state = State::ChunkEnd(2);
+                    }
+                }
+                State::ChunkEnd(remaining) => {
+                    used += 1;
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        self.state = State::ChunkSize(Vec::new());
+                    }
+                }
+                State::Head(head) => {
+                    head.push(data[used]);
+                    used += 1;
+                    if head.ends_with(b"\r\n\r\n") {
+                        let head = std::mem::take(head);
+                        self.state = self.body_after(&head);
+                    } else if head.len() > MAX_HEAD {
+                        self.give_up();
+                    }
+                }
+                State::ChunkSize(line) => {
+                    line.push(data[used]);
+                    used += 1;
+                    if line.ends_with(b"\n") {
+                        self.state = match chunk_size(line) {
+                            Some(0) => State::Trailer(Vec::new()),
+                            Some(size) => State::ChunkData(size),
+                            None => {
+                                self.reusable = false;
+                                State::UntilClose
+                            }
+                        };
+                    } else if line.len() > MAX_LINE {
+                        self.give_up();
+                    }
+                }
+                State::Trailer(line) => {
+                    line.push(data[used]);
+                    used += 1;
+                    if line.ends_with(b"\n") {
+                        if line.as_slice() == b"\r\n" || line.as_slice() == b"\n" {
+                            self.state = State::Done;
+                        } else {
+                            line.clear();
+                        }
+                    } else if line.len() > MAX_LINE {
+                        self.give_up();
+                    }
+                }
+            }
+        }
+        used
+    }
+
+    fn give_up(&mut self) {
+        self.reusable = false;
+        self.state = State::UntilClose;
+    }
+
+    fn body_after(&mut self, head: &[u8]) -> State {
+        let mut headers = [EMPTY_HEADER; 64];
+        let mut response = Response::new(&mut headers);
+        if !matches!(response.parse(head), Ok(Status::Complete(_))) {
+            self.reusable = false;
+            return State::UntilClose;
+        }
+        let code = response.code.unwrap_or_default();
+        if (100..200).contains(&code) && code != 101 {
+            return State::Head(Vec::new());
+        }
+        let connection = headers::find_str(response.headers, "Connection").unwrap_or_default();
+        let has_token = |token: &str| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        if code == 101
+            || has_token("close")
+            || (response.version == Some(0) && !has_token("keep-alive"))
+        {
+            self.reusable = false;
+        }
+        if code == 101 {
+            return State::UntilClose;
+        }
+        if self.head_request || code == 204 || code == 304 {
+            return State::Done;
+        }
+        if let Some(encoding) = headers::find_str(response.headers, "Transfer-Encoding") {
+            if is_chunked(encoding) {
+                return State::ChunkSize(Vec::new());
+            }
+            self.reusable = false;
+            return State::UntilClose;
+        }
+        match headers::find_str(response.headers, "Content-Length")
+            .map(|value| value.trim().parse())
+        {
+            Some(Ok(0)) => State::Done,
+            Some(Ok(length)) => State::Length(length),
+            _ => {
+                self.reusable = false;
+                State::UntilClose
+            }
+        }
+    }
+}
+
+fn is_chunked(encoding: &str) -> bool {
+    encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn chunk_size(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    u64::from_str_radix(size, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(response: &[u8], head_request: bool) -> (ResponseFraming, usize) {
+        let mut framing = ResponseFraming::new(head_request);
+        let used = response.chunks(7).map(|chunk| framing.feed(chunk)).sum();
+        (framing, used)
+    }
+
+    #[test]
+    fn content_length_responses_end_after_the_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloEXTRA";
+        let (framing, used) = framed(response, false);
+        assert!(framing.is_reusable());
+        assert_eq!(used, response.len() - 5);
+    }
+
+    #[test]
+    fn chunked_responses_end_after_the_trailer() {
+        let response = b"HTTP/1.1 100 Continue\r\n\r\n\
+                         HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                         5;ext=1\r\nhello\r\n0\r\nX-Sum: 1\r\n\r\n";
+        let (framing, used) = framed(response, false);
+        assert!(framing.is_reusable());
+        assert_eq!(used, response.len());
+    }
+
+    #[test]
+    fn bodiless_and_closing_responses() {
+        let (head, _) = framed(b"HTTP/1.1 200 OK\r\nContent-Length: 99\r\n\r\n", true);
+        assert!(head.is_reusable());
+
+        let (closing, _) = framed(
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok",
+            false,
+        );
+        assert!(closing.is_complete() && !closing.is_reusable());
+
+        let (mut legacy, _) = framed(b"HTTP/1.0 200 OK\r\n\r\nuntil close", false);
+        assert!(!legacy.is_complete());
+        legacy.closed();
+        assert!(legacy.is_complete() && !legacy.is_reusable());
+    }
+
+    #[test]
+    fn request_bodies_follow_their_framing() {
+        let mut chunked = ResponseFraming::body(None, Some("chunked")).unwrap();
+        assert_eq!(chunked.feed(b"4\r\nwiki\r\n"), 9);
+        assert!(!chunked.is_complete());
+        assert_eq!(chunked.feed(b"0\r\n\r\nGET /next"), 5);
+        assert!(chunked.is_complete());
+
+        let mut sized = ResponseFraming::body(Some("3"), None).unwrap();
+        assert_eq!(sized.feed(b"abcd"), 3);
+        assert!(sized.is_complete());
+        assert!(ResponseFraming::body(None, None).unwrap().is_complete());
+        assert!(ResponseFraming::body(Some("x"), None).is_none());
+        assert!(ResponseFraming::body(None, Some("gzip")).is_none());
+    }
+}
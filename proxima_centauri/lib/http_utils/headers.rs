@@ -0,0 +1,83 @@
+use httparse::Header;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("Conflicting `{0}` headers")]
+pub(crate) struct ConflictingHeader(pub(crate) String);
+
+pub(crate) fn find_all<'h, 'a>(
+    headers: &'h [Header<'a>],
+    name: &'h str,
+) -> impl Iterator<Item = &'a [u8]> + 'h {
+    headers
+        .iter()
+        .filter(move |header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value)
+}
+
+pub(crate) fn find<'a>(headers: &[Header<'a>], name: &str) -> Option<&'a [u8]> {
+    find_all(headers, name).next()
+}
+
+pub(crate) fn find_str<'a>(headers: &[Header<'a>], name: &str) -> Option<&'a str> {
+    find(headers, name).and_then(|value| std::str::from_utf8(value).ok())
+}
+
+pub(crate) fn single<'a>(
+    headers: &[Header<'a>],
+    name: &str,
+) -> Result<Option<&'a [u8]>, ConflictingHeader> {
+    let mut values = find_all(headers, name);
+    let Some(first) = values.next() else {
+        return Ok(None);
+    };
+    if values.any(|value| value.trim_ascii() != first.trim_ascii()) {
+        return Err(ConflictingHeader(name.to_string()));
+    }
+    Ok(Some(first))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(name: &'a str, value: &'a str) -> Header<'a> {
+        Header {
+            name,
+            value: value.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn lookup_ignores_name_case() {
+        let headers = [
+            header("host", "a"),
+            header("PROXY-AUTHORIZATION", "Basic x"),
+        ];
+        assert_eq!(find(&headers, "Host"), Some(&b"a"[..]));
+        assert_eq!(find_str(&headers, "Proxy-Authorization"), Some("Basic x"));
+        assert_eq!(find(&headers, "Via"), None);
+    }
+
+    #[test]
+    fn single_tolerates_repeats_but_rejects_conflicts() {
+        let repeated = [
+            header("Proxy-Authorization", "Basic x"),
+            header("proxy-authorization", "Basic x "),
+        ];
+        assert_eq!(
+            single(&repeated, "Proxy-Authorization"),
+            Ok(Some(&b"Basic x"[..]))
+        );
+
+        let conflicting = [
+            header("Proxy-Authorization", "Basic x"),
+            header("Proxy-Authorization", "Basic y"),
+        ];
+        assert_eq!(
+            single(&conflicting, "Proxy-Authorization"),
+            Err(ConflictingHeader("Proxy-Authorization".to_string()))
+        );
+        assert_eq!(single(&[], "Proxy-Authorization"), Ok(None));
+    }
+}
@@ -1 +1,4 @@
+pub(crate) mod framing;
+pub(crate) mod headers;
+pub(crate) mod request;
 pub(crate) mod response;
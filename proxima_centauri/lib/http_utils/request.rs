@@ -0,0 +1,294 @@
+use crate::http_utils::framing::ResponseFraming;
+use crate::http_utils::headers;
+use httparse::Request;
+use std::net::IpAddr;
+
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+pub(crate) const VIA: &str = "1.1 procent";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderPolicy {
+    pub forwarded_for: bool,
+    pub via: bool,
+    pub remove: Vec<String>,
+    pub inject: Vec<(String, String)>,
+}
+
+impl HeaderPolicy {
+    fn drops(&self, name: &str, connection: &[&str]) -> bool {
+        let matches = |dropped: &str| name.eq_ignore_ascii_case(dropped);
+        HOP_BY_HOP_HEADERS
+            .iter()
+            .chain(connection)
+            .any(|dropped| matches(dropped))
+            || self.remove.iter().any(|dropped| matches(dropped))
+            || self.inject.iter().any(|(dropped, _)| matches(dropped))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ForwardTarget {
+    pub(crate) authority: String,
+    pub(crate) origin_path: String,
+}
+
+pub(crate) fn parse_forward_target(uri: &str) -> Option<ForwardTarget> {
+    let rest = uri
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &uri[7..])?;
+    let (authority, path) = rest
+        .find(['/', '?'])
+        .map_or((rest, "/"), |index| rest.split_at(index));
+    if authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    let origin_path = if path.starts_with('?') {
+        format!("/{path}")
+    } else {
+        path.to_string()
+    };
+    let authority = if has_port(authority) {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    };
+    Some(ForwardTarget {
+        authority,
+        origin_path,
+    })
+}
+
+fn has_port(authority: &str) -> bool {
+    authority.rsplit_once(':').is_some_and(|(host, port)| {
+        !host.is_empty()
+            && !port.is_empty()
+            && port.bytes().all(|b| b.is_ascii_digit())
+            && (!host.contains(':') || host.ends_with(']'))
+    })
+}
+
+pub(crate) fn rewrite_request_head(
+    request: &Request<'_, '_>,
+    origin_path: &str,
+    policy: &HeaderPolicy,
+    client_ip: IpAddr,
+    keep_alive: bool,
+) -> Vec<u8> {
+    let mut head = format!(
+        "{} {origin_path} HTTP/1.{}\r\n",
+        request.method.unwrap_or("GET"),
+        request.version.unwrap_or(1)
+    )
+    .into_bytes();
+    let connection = headers::find_str(request.headers, "Connection").unwrap_or_default();
+    let connection: Vec<&str> = connection.split(',').map(str::trim).collect();
+    let upgrade = headers::find_str(request.headers, "Upgrade").filter(|_| {
+        connection
+            .iter()
+            .any(|token| token.eq_ignore_ascii_case("upgrade"))
+    });
+    let mut forwarded_for = None;
+    let mut via = None;
+    for header in request.headers.iter() {
+        if policy.forwarded_for && header.name.eq_ignore_ascii_case("X-Forwarded-For") {
+            forwarded_for = std::str::from_utf8(header.value).ok();
+            continue;
+        }
+        if policy.via && header.name.eq_ignore_ascii_case("Via") {
+            via = std::str::from_utf8(header.value).ok();
+            continue;
+        }
+        if !policy.drops(header.name, &connection) {
+            push_header(&mut head, header.name, header.value);
+        }
+    }
+    match upgrade {
+        Some(protocol) => {
+            push_header(&mut head, "Connection", b"Upgrade");
+            push_header(&mut head, "Upgrade", protocol.as_bytes());
+        }
+        None if keep_alive => push_header(&mut head, "Connection", b"keep-alive"),
+        None => push_header(&mut head, "Connection", b"close"),
+    }
+    if policy.forwarded_for {
+        let value = forwarded_for.map_or_else(
+            || client_ip.to_string(),
+            |previous| format!("{previous}, {client_ip}"),
+        );
+        push_header(&mut head, "X-Forwarded-For", value.as_bytes());
+    }
+    if policy.via {
+        let value = via.map_or_else(|| VIA.to_string(), |previous| format!("{previous}, {VIA}"));
+        push_header(&mut head, "Via", value.as_bytes());
+    }
+    for (name, value) in &policy.inject {
+        push_header(&mut head, name, value.as_bytes());
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+pub(crate) fn request_body(request: &Request<'_, '_>, body: &[u8]) -> Option<ResponseFraming> {
+    if headers::find(request.headers, "Upgrade").is_some() {
+        return None;
+    }
+    let mut framing = ResponseFraming::body(
+        headers::find_str(request.headers, "Content-Length"),
+        headers::find_str(request.headers, "Transfer-Encoding"),
+    )?;
+    (framing.feed(body) == body.len()).then_some(framing)
+}
+
+fn push_header(head: &mut Vec<u8>, name: &str, value: &[u8]) {
+    head.extend_from_slice(name.as_bytes());
+    head.extend_from_slice(b": ");
+    head.extend_from_slice(value);
+    head.extend_from_slice(b"\r\n");
+}
+
+pub(crate) fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httparse::EMPTY_HEADER;
+
+    #[test]
+    fn parses_absolute_uri_with_default_port() {
+        let target = parse_forward_target("http://example.com/index.html?q=1").unwrap();
+        assert_eq!(target.authority, "example.com:80");
+        assert_eq!(target.origin_path, "/index.html?q=1");
+    }
+
+    #[test]
+    fn keeps_explicit_port_and_ipv6_hosts() {
+        let target = parse_forward_target("http://[::1]:8080").unwrap();
+        assert_eq!(target.authority, "[::1]:8080");
+        assert_eq!(target.origin_path, "/");
+
+        let target = parse_forward_target("HTTP://[::1]?a=b").unwrap();
+        assert_eq!(target.authority, "[::1]:80");
+        assert_eq!(target.origin_path, "/?a=b");
+    }
+
+    #[test]
+    fn rejects_non_http_and_origin_form() {
+        assert!(parse_forward_target("https://example.com/").is_none());
+        assert!(parse_forward_target("/index.html").is_none());
+        assert!(parse_forward_target("http:///path").is_none());
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 7));
+
+    fn rewrite(raw: &[u8], policy: &HeaderPolicy) -> String {
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut request = Request::new(&mut headers);
+        request.parse(raw).unwrap();
+        String::from_utf8(rewrite_request_head(
+            &request, "/chat", policy, CLIENT, false,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn rewrite_strips_proxy_headers() {
+        let head = rewrite(
+            b"GET http://example.com/chat HTTP/1.1\r\n\
+              Host: example.com\r\n\
+              Proxy-Authorization: Basic abc\r\n\
+              proxy-connection: keep-alive\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\r\n",
+            &HeaderPolicy::default(),
+        );
+        assert_eq!(
+            head,
+            "GET /chat HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_strips_hop_by_hop_headers() {
+        let head = rewrite(
+            b"GET http://example.com/chat HTTP/1.1\r\n\
+              Host: example.com\r\n\
+              Connection: keep-alive, X-Trace\r\n\
+              Keep-Alive: timeout=5\r\n\
+              TE: trailers\r\n\
+              X-Trace: 1\r\n\
+              Upgrade: h2c\r\n\
+              Accept: */*\r\n\r\n",
+            &HeaderPolicy::default(),
+        );
+        assert_eq!(
+            head,
+            "GET /chat HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_appends_forwarded_for_and_via() {
+        let policy = HeaderPolicy {
+            forwarded_for: true,
+            via: true,
+            ..HeaderPolicy::default()
+        };
+        let head = rewrite(
+            b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            &policy,
+        );
+        assert!(head.ends_with("X-Forwarded-For: 192.0.2.7\r\nVia: 1.1 procent\r\n\r\n"));
+
+        let head = rewrite(
+            b"GET http://example.com/ HTTP/1.1\r\n\
+              X-Forwarded-For: 198.51.100.1\r\n\
+              Via: 1.0 edge\r\n\r\n",
+            &policy,
+        );
+        assert!(head.contains("X-Forwarded-For: 198.51.100.1, 192.0.2.7\r\n"));
+        assert!(head.contains("Via: 1.0 edge, 1.1 procent\r\n"));
+        assert_eq!(head.matches("Via:").count(), 1);
+    }
+
+    #[test]
+    fn rewrite_removes_and_injects_configured_headers() {
+        let policy = HeaderPolicy {
+            remove: vec!["X-Api-Key".to_string()],
+            inject: vec![("X-Team".to_string(), "core".to_string())],
+            ..HeaderPolicy::default()
+        };
+        let head = rewrite(
+            b"GET http://example.com/ HTTP/1.1\r\n\
+              x-api-key: secret\r\n\
+              X-Team: spoofed\r\n\
+              Accept: */*\r\n\r\n",
+            &policy,
+        );
+        assert_eq!(
+            head,
+            "GET /chat HTTP/1.1\r\nAccept: */*\r\nConnection: close\r\nX-Team: core\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn validates_header_names() {
+        assert!(is_header_name("X-Api-Key"));
+        assert!(!is_header_name("X Api"));
+        assert!(!is_header_name(""));
+    }
+}
@@ -1,23 +1,213 @@
+use crate::http_utils::request::VIA;
+use serde_json::{Value, json};
+use std::fmt::Write as _;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectHeaders {
+    pub anonymous: bool,
+    pub via: bool,
+    pub server: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl ConnectHeaders {
+    pub(crate) fn established(&self, request_id: u64) -> Vec<u8> {
+        let mut response = ProxyResponse::ConnectionEstablished.to_bytes();
+        if self.anonymous {
+            return response;
+        }
+        let mut headers = String::new();
+        if self.via {
+            let _ = write!(headers, "Via: {VIA}\r\n");
+        }
+        if let Some(server) = &self.server {
+            let _ = write!(headers, "Server: {server}\r\n");
+        }
+        if let Some(name) = &self.request_id {
+            let _ = write!(headers, "{name}: {request_id}\r\n");
+        }
+        let end = response.len() - 2;
+        response.splice(end..end, headers.into_bytes());
+        response
+    }
+}
+
+pub struct LimitUsage {
+    pub limit: Option<u128>,
+    pub used: u128,
+    pub reset_at: Option<u64>,
+    pub retry_after: Option<u64>,
+}
+
+impl LimitUsage {
+    fn body(&self, error: &str) -> Value {
+        json!({
+            "error": error,
+            "limit": self.limit,
+            "used": self.used,
+            "reset_at": self.reset_at,
+        })
+    }
+
+    fn headers(&self) -> String {
+        let mut headers = String::new();
+        if let Some(retry_after) = self.retry_after {
+            let _ = write!(headers, "Retry-After: {retry_after}\r\n");
+        }
+        if let Some(limit) = self.limit {
+            let _ = write!(
+                headers,
+                "X-RateLimit-Limit: {limit}\r\nX-RateLimit-Remaining: {}\r\n",
+                limit.saturating_sub(self.used)
+            );
+        }
+        if let Some(reset_at) = self.reset_at {
+            let _ = write!(headers, "X-RateLimit-Reset: {reset_at}\r\n");
+        }
+        headers
+    }
+
+    fn response(&self, status: &str, error: &str) -> Vec<u8> {
+        json_response_with(status, &self.headers(), &self.body(error))
+    }
+}
+
 pub enum ProxyResponse {
     ConnectionEstablished,
+    BadRequest,
     Unauthorized,
     ProxyAuthRequired,
+    NotFound,
     MethodNotAllowed,
-    TooManyRequests,
-    QuotaExceeded,
+    RequestTimeout,
+    UriTooLong,
+    HeadersTooLarge,
+    TooManyRequests(LimitUsage),
+    TooManyConnections(LimitUsage),
+    QuotaExceeded(LimitUsage),
+    AuthLocked(LimitUsage),
+    Forbidden(&'static str),
+    BadGateway,
+    ServiceUnavailable,
+    Maintenance(u64),
+    Usage(Value),
 }
 
 impl ProxyResponse {
-    pub const fn as_bytes(&self) -> &'static [u8] {
+    pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            Self::ConnectionEstablished => b"HTTP/1.1 200 Connection Established\r\n\r\n",
-            Self::Unauthorized => b"HTTP/1.1 401 Unauthorized\r\n\r\n",
+            Self::ConnectionEstablished => b"HTTP/1.1 200 Connection Established\r\n\r\n".to_vec(),
+            Self::BadRequest => b"HTTP/1.1 400 Bad Request\r\n\r\n".to_vec(),
+            Self::Unauthorized => b"HTTP/1.1 401 Unauthorized\r\n\r\n".to_vec(),
             Self::ProxyAuthRequired => {
-                b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                  Proxy-Authenticate: Basic realm=\"procent\", charset=\"UTF-8\"\r\n\r\n"
+                    .to_vec()
+            }
+            Self::NotFound => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            Self::MethodNotAllowed => b"HTTP/1.1 405 Method Not Allowed\r\n\r\n".to_vec(),
+            Self::RequestTimeout => b"HTTP/1.1 408 Request Timeout\r\n\r\n".to_vec(),
+            Self::UriTooLong => b"HTTP/1.1 414 URI Too Long\r\nConnection: close\r\n\r\n".to_vec(),
+            Self::HeadersTooLarge => {
+                b"HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n"
+                    .to_vec()
+            }
+            Self::TooManyRequests(usage) => {
+                usage.response("429 Too Many Requests", "concurrency_limit_exceeded")
             }
-            Self::MethodNotAllowed => b"HTTP/1.1 405 Method Not Allowed\r\n\r\n",
-            Self::TooManyRequests =>  b"HTTP/1.1 429 Too Many Requests\r\n\r\n",
-            Self::QuotaExceeded =>  b"HTTP/1.1 403 Forbidden\r\n\r\n",
+            Self::TooManyConnections(usage) => {
+                usage.response("429 Too Many Requests", "client_connection_limit_exceeded")
+            }
+            Self::QuotaExceeded(usage) => usage.response("403 Forbidden", "traffic_quota_exceeded"),
+            Self::AuthLocked(usage) => usage.response("429 Too Many Requests", "auth_locked"),
+            Self::Forbidden(reason) => json_response("403 Forbidden", &json!({ "error": reason })),
+            Self::BadGateway => b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            Self::ServiceUnavailable => {
+                b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nContent-Length: 0\r\n\r\n"
+                    .to_vec()
+            }
+            Self::Maintenance(retry_after) => format!(
+                "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {retry_after}\r\nContent-Length: 0\r\n\r\n"
+            )
+            .into_bytes(),
+            Self::Usage(usage) => json_response("200 OK", usage),
         }
     }
 }
+
+pub(crate) fn json_response(status: &str, body: &Value) -> Vec<u8> {
+    json_response_with(status, "", body)
+}
+
+fn json_response_with(status: &str, headers: &str, body: &Value) -> Vec<u8> {
+    let body = body.to_string();
+    format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_responses_carry_retry_and_rate_limit_headers() {
+        let response = ProxyResponse::TooManyRequests(LimitUsage {
+            limit: Some(2),
+            used: 2,
+            reset_at: Some(1_700_000_030),
+            retry_after: Some(30),
+        })
+        .to_bytes();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with(
+            "HTTP/1.1 429 Too Many Requests\r\n\
+             Retry-After: 30\r\n\
+             X-RateLimit-Limit: 2\r\n\
+             X-RateLimit-Remaining: 0\r\n\
+             X-RateLimit-Reset: 1700000030\r\n\
+             Content-Type: application/json\r\n"
+        ));
+
+        let response = ProxyResponse::QuotaExceeded(LimitUsage {
+            limit: None,
+            used: 10,
+            reset_at: None,
+            retry_after: None,
+        })
+        .to_bytes();
+        assert!(!String::from_utf8_lossy(&response).contains("X-RateLimit"));
+    }
+
+    #[test]
+    fn connect_response_carries_configured_headers() {
+        let headers = ConnectHeaders {
+            via: true,
+            server: Some("procent".to_string()),
+            request_id: Some("X-Request-Id".to_string()),
+            ..ConnectHeaders::default()
+        };
+        assert_eq!(
+            String::from_utf8_lossy(&headers.established(42)),
+            "HTTP/1.1 200 Connection Established\r\n\
+             Via: 1.1 procent\r\n\
+             Server: procent\r\n\
+             X-Request-Id: 42\r\n\r\n"
+        );
+
+        let anonymous = ConnectHeaders {
+            anonymous: true,
+            ..headers
+        };
+        assert_eq!(
+            anonymous.established(42),
+            ProxyResponse::ConnectionEstablished.to_bytes()
+        );
+        assert_eq!(
+            ConnectHeaders::default().established(42),
+            ProxyResponse::ConnectionEstablished.to_bytes()
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::clock::unix_now;
+use crate::config::Config;
+use crate::registry::Limits;
+use crate::store::StoreConfig;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{info, warn};
+
+const REDACTED: &str = "[redacted]";
+
+pub(crate) struct RuntimeInfo {
+    started: Instant,
+    started_at: u64,
+    listeners: BTreeMap<&'static str, SocketAddr>,
+    auth: &'static str,
+}
+
+impl RuntimeInfo {
+    pub(crate) fn new(
+        listeners: impl IntoIterator<Item = (&'static str, SocketAddr)>,
+        auth: &'static str,
+    ) -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: unix_now(),
+            listeners: listeners.into_iter().collect(),
+            auth,
+        }
+    }
+
+    pub(crate) fn log_banner(&self, config: &Config) {
+        let listeners: Vec<String> = self
+            .listeners
+            .iter()
+            .map(|(name, addr)| format!("{name}={addr}"))
+            .collect();
+        let defaults = Limits::with_low_limits();
+        info!(
+            version = env!("CARGO_PKG_VERSION"),
+            listeners = listeners.join(" "),
+            auth = self.auth,
+            store = store_kind(&config.store),
+            default_concurrency = defaults.concurrency().restricted(),
+            default_traffic = defaults
+                .traffic()
+                .restricted()
+                .map(|traffic| traffic.to_string()),
+            plans = config.plans.len(),
+            enforcement = format!("{:?}", config.enforcement),
+            tls_origin = cfg!(feature = "tls-origin"),
+            tls_intercept = config.intercept.is_some(),
+            "Effective configuration"
+        );
+        for warning in self.security_warnings(config) {
+            warn!("Insecure configuration: {warning}");
+        }
+    }
+
+    fn security_warnings(&self, config: &Config) -> Vec<String> {
+        let mut warnings = Vec::new();
+        match self.auth {
+            "built-in" => warnings.push(
+                "the built-in users `procent` and `admin` with their default passwords are active, \
+                 set PROXY_USERS_FILE"
+                    .to_string(),
+            ),
+            "users_file" if config.users_key.is_none() => warnings.push(
+                "the users file keeps plaintext passwords, set PROXY_USERS_KEY to encrypt it"
+                    .to_string(),
+            ),
+            _ => {}
+        }
+        if let Some(addr) = self
+            .listeners
+            .get("proxy")
+            .filter(|addr| !addr.ip().is_loopback())
+        {
+            warnings.push(format!(
+                "proxy credentials are sent as cleartext Basic auth to {addr}, \
+                 which accepts connections beyond loopback"
+            ));
+        }
+        if config.admin_token.is_none()
+            && let Some(addr) = self
+                .listeners
+                .get("admin")
+                .filter(|addr| !addr.ip().is_loopback())
+        {
+            warnings.push(format!(
+                "the admin API on {addr} has no PROXY_ADMIN_TOKEN and refuses every non-loopback client"
+            ));
+        }
+        warnings
+    }
+
+    pub(crate) fn to_json(&self, config: &Config) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_hash": option_env!("PROCENT_GIT_HASH").unwrap_or("unknown"),
+            "build_timestamp": option_env!("PROCENT_BUILD_TIME").and_then(|time| time.parse::<u64>().ok()),
+            "features": features(),
+            "started_at": self.started_at,
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "listeners": self.listeners,
+            "auth": self.auth,
+            "config": effective_config(config),
+        })
+    }
+}
+
+fn features() -> Vec<&'static str> {
+    [
+        ("chaos", cfg!(feature = "chaos")),
+        ("geoip", cfg!(feature = "geoip")),
+        ("ldap", cfg!(feature = "ldap")),
+        ("tls-intercept", cfg!(feature = "tls-intercept")),
+        ("tls-origin", cfg!(feature = "tls-origin")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+fn redact(secret: Option<&String>) -> Option<&'static str> {
+    secret.is_some().then_some(REDACTED)
+}
+
+const fn store_kind(store: &StoreConfig) -> &'static str {
+    match store {
+        StoreConfig::Memory => "memory",
+        StoreConfig::File(_) => "file",
+        StoreConfig::Redis(_) => "redis",
+        StoreConfig::Coordinator(_) => "coordinator",
+    }
+}
+
+fn effective_config(config: &Config) -> Value {
+    let store = match &config.store {
+        StoreConfig::Memory => json!({ "kind": "memory" }),
+        StoreConfig::File(path) => json!({ "kind": "file", "path": path }),
+        StoreConfig::Redis(url) => json!({ "kind": "redis", "url": redact(Some(url)) }),
+        StoreConfig::Coordinator(coordinator) => json!({
+            "kind": "coordinator",
+            "url": coordinator.url,
+            "token": redact(coordinator.token.as_ref()),
+            "chunk": coordinator.chunk,
+        }),
+    };
+    let upstreams: BTreeMap<&String, Value> = config
+        .upstreams
+        .iter()
+        .map(|(name, upstream)| {
+            let credentials = redact(upstream.credentials.as_ref());
+            (
+                name,
+                json!({ "addr": upstream.addr, "credentials": credentials }),
+            )
+        })
+        .collect();
+    let plans: BTreeMap<&String, String> = config
+        .plans
+        .iter()
+        .map(|(name, limits)| (name, format!("{limits:?}")))
+        .collect();
+    json!({
+        "host": config.host,
+        "port": config.port,
+        "admin_addr": config.admin_addr,
+        "admin_token": redact(config.admin_token.as_ref()),
+        "max_connections": config.max_connections,
+        "max_connections_per_ip": config.max_connections_per_ip,
+        "header_timeout": config.header_timeout,
+        "stall_timeout": config.stall_timeout,
+        "tunnel_buffer": config.tunnel_buffer,
+        "forward_http": config.forward_http,
+        "forward_pool_per_origin": config.forward_pool.per_origin,
+        "forward_cache_bytes": config.forward_cache.max_bytes,
+        "enforcement": format!("{:?}", config.enforcement),
+        "stealth": format!("{:?}", config.stealth),
+        "maintenance": config.maintenance.is_some(),
+        "chaos": config.chaos,
+        "session_ttl": config.session_ttl,
+        "plans": plans,
+        "users_file": config.users_file,
+        "users_key": redact(config.users_key.as_ref()),
+        "users_validation": format!("{:?}", config.users_validation),
+        "schedule_enforcement": format!("{:?}", config.schedule_enforcement),
+        "store": store,
+        "ledger_path": config.ledger_path,
+        "webhooks": config.webhooks.urls.len(),
+        "webhook_secret": redact(config.webhooks.secret.as_ref()),
+        "acl_allow": config.acl.allow,
+        "acl_deny": config.acl.deny,
+        "category_file": config.categories.file,
+        "category_url": config.categories.url,
+        "allow_ip_targets": config.allow_ip_targets,
+        "geoip_db": config.geoip_db,
+        "upstreams": upstreams,
+        "transparent": config.transparent.as_ref().map(|transparent| &transparent.addr),
+        "intercept_ca": config.intercept.as_ref().map(|intercept| &intercept.ca_cert),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::build_config;
+
+    #[test]
+    fn reports_listeners_and_redacts_secrets() {
+        let mut config = build_config();
+        config.admin_token = Some("s3cret".to_string());
+        config.store = StoreConfig::Redis("redis://:hunter2@cache:6379".to_string());
+        let listener: SocketAddr = ([127, 0, 0, 1], 9090).into();
+        let info = RuntimeInfo::new([("proxy", listener)], "built-in").to_json(&config);
+
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["listeners"]["proxy"], "127.0.0.1:9090");
+        assert_eq!(info["config"]["admin_token"], REDACTED);
+        assert_eq!(info["config"]["store"]["url"], REDACTED);
+        let text = info.to_string();
+        assert!(!text.contains("s3cret") && !text.contains("hunter2"));
+    }
+
+    #[test]
+    fn warns_about_insecure_combinations() {
+        let mut config = build_config();
+        let loopback: SocketAddr = ([127, 0, 0, 1], 9090).into();
+        let public: SocketAddr = ([0, 0, 0, 0], 9091).into();
+
+        let warnings =
+            RuntimeInfo::new([("proxy", loopback)], "built-in").security_warnings(&config);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("built-in users"));
+
+        let runtime = RuntimeInfo::new([("proxy", public), ("admin", public)], "users_file");
+        let warnings = runtime.security_warnings(&config);
+        assert_eq!(warnings.len(), 3);
+        assert!(warnings[0].contains("plaintext passwords"));
+        assert!(warnings[1].contains("cleartext Basic auth"));
+        assert!(warnings[2].contains("admin API"));
+
+        config.users_key = Some("key".to_string());
+        config.admin_token = Some("token".to_string());
+        let runtime = RuntimeInfo::new([("proxy", loopback), ("admin", public)], "users_file");
+        assert!(runtime.security_warnings(&config).is_empty());
+        assert!(
+            RuntimeInfo::new([], "custom")
+                .security_warnings(&config)
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use crate::hooks::{TunnelOpenContext, Verdict};
+use anyhow::Result;
+#[cfg(feature = "tls-intercept")]
+use rcgen::{CertificateParams, Issuer, KeyPair};
+#[cfg(feature = "tls-intercept")]
+use std::collections::HashMap;
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+#[cfg(feature = "tls-intercept")]
+use std::sync::{Mutex, PoisonError};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "tls-intercept")]
+use tokio_rustls::TlsAcceptor;
+#[cfg(feature = "tls-intercept")]
+use tokio_rustls::rustls::ServerConfig;
+#[cfg(feature = "tls-intercept")]
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+#[cfg(feature = "tls-intercept")]
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+#[cfg(feature = "tls-intercept")]
+const MAX_CACHED_HOSTS: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterceptConfig {
+    pub ca_cert: PathBuf,
+    pub ca_key: PathBuf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+pub trait Inspector: Send + Sync {
+    fn inspect(&self, tunnel: &TunnelOpenContext, direction: Direction, data: &[u8]) -> Verdict;
+}
+
+pub struct PassThrough;
+
+impl Inspector for PassThrough {
+    fn inspect(&self, _tunnel: &TunnelOpenContext, _direction: Direction, _data: &[u8]) -> Verdict {
+        Verdict::Allow
+    }
+}
+
+pub(crate) struct Interceptor {
+    #[cfg(feature = "tls-intercept")]
+    issuer: Issuer<'static, KeyPair>,
+    #[cfg(feature = "tls-intercept")]
+    ca: CertificateDer<'static>,
+    #[cfg(feature = "tls-intercept")]
+    leaf_key: KeyPair,
+    #[cfg(feature = "tls-intercept")]
+    hosts: Mutex<HashMap<String, Arc<ServerConfig>>>,
+    inspector: Arc<dyn Inspector>,
+}
+
+impl Interceptor {
+    #[cfg(feature = "tls-intercept")]
+    pub(crate) fn new(config: &InterceptConfig, inspector: Arc<dyn Inspector>) -> Result<Self> {
+        use anyhow::Context as _;
+
+        let ca_pem = std::fs::read_to_string(&config.ca_cert).with_context(|| {
+            format!("Cannot read interception CA `{}`", config.ca_cert.display())
+        })?;
+        let key_pem = std::fs::read_to_string(&config.ca_key).with_context(|| {
+            format!(
+                "Cannot read interception CA key `{}`",
+                config.ca_key.display()
+            )
+        })?;
+        Ok(Self {
+            issuer: Issuer::from_ca_cert_pem(&ca_pem, KeyPair::from_pem(&key_pem)?)?,
+            ca: CertificateDer::from_pem_slice(ca_pem.as_bytes())?,
+            leaf_key: KeyPair::generate()?,
+            hosts: Mutex::new(HashMap::new()),
+            inspector,
+        })
+    }
+
+    #[cfg(not(feature = "tls-intercept"))]
+    pub(crate) fn new(_config: &InterceptConfig, _inspector: Arc<dyn Inspector>) -> Result<Self> {
+        anyhow::bail!("TLS interception configured but the `tls-intercept` feature is disabled")
+    }
+
+    #[cfg(feature = "tls-intercept")]
+    fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(config) = hosts.get(host) {
+            return Ok(config.clone());
+        }
+        let leaf = CertificateParams::new(vec![host.to_string()])?
+            .signed_by(&self.leaf_key, &self.issuer)?;
+        let key =
+            PrivateKeyDer::try_from(self.leaf_key.serialize_der()).map_err(anyhow::Error::msg)?;
+        let config = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![leaf.der().clone(), self.ca.clone()], key)?,
+        );
+        if hosts.len() >= MAX_CACHED_HOSTS {
+            hosts.clear();
+        }
+        hosts.insert(host.to_string(), config.clone());
+        Ok(config)
+    }
+
+    #[cfg(feature = "tls-intercept")]
+    pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        tunnel: &Arc<TunnelOpenContext>,
+    ) -> io::Result<Inspected<tokio_rustls::server::TlsStream<S>>> {
+        let config = self.server_config(&tunnel.host).map_err(io::Error::other)?;
+        let stream = TlsAcceptor::from(config).accept(stream).await?;
+        Ok(self.inspect(stream, tunnel, Direction::Ingress))
+    }
+
+    #[cfg(not(feature = "tls-intercept"))]
+    #[allow(clippy::unused_async)]
+    pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        _stream: S,
+        _tunnel: &Arc<TunnelOpenContext>,
+    ) -> io::Result<Inspected<S>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS interception needs the `tls-intercept` feature",
+        ))
+    }
+
+    pub(crate) fn inspect<S>(
+        &self,
+        stream: S,
+        tunnel: &Arc<TunnelOpenContext>,
+        direction: Direction,
+    ) -> Inspected<S> {
+        Inspected {
+            inner: stream,
+            inspector: self.inspector.clone(),
+            tunnel: tunnel.clone(),
+            direction,
+        }
+    }
+}
+
+pub(crate) struct Inspected<S> {
+    inner: S,
+    inspector: Arc<dyn Inspector>,
+    tunnel: Arc<TunnelOpenContext>,
+    direction: Direction,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inspected<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if read.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        match this.inspector.inspect(&this.tunnel, this.direction, read) {
+            Verdict::Allow => Poll::Ready(Ok(())),
+            Verdict::Reject(code) => {
+                buf.set_filled(before);
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::PermissionDenied, code)))
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inspected<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+pub(crate) struct Replay<S> {
+    head: Cursor<Vec<u8>>,
+    inner: S,
+}
+
+impl<S> Replay<S> {
+    pub(crate) const fn new(head: Vec<u8>, inner: S) -> Self {
+        Self {
+            head: Cursor::new(head),
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.head.position() < this.head.get_ref().len() as u64 {
+            return Pin::new(&mut this.head).poll_read(cx, buf);
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
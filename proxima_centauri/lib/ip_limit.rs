@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+pub(crate) struct IpLimiter {
+    max: AtomicUsize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+pub(crate) struct IpGuard {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+impl IpLimiter {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max: AtomicUsize::new(max),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    pub(crate) fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<IpGuard, usize> {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = active.entry(ip).or_default();
+        let max = self.max();
+        if max != 0 && *count >= max {
+            return Err(*count);
+        }
+        *count += 1;
+        drop(active);
+        Ok(IpGuard {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = active.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&ip);
+            }
+        }
+    }
+}
+
+impl Drop for IpGuard {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_connections_per_ip_and_releases_on_drop() {
+        let limiter = Arc::new(IpLimiter::new(2));
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let first = limiter.try_acquire(ip).unwrap();
+        let _second = limiter.try_acquire(ip).unwrap();
+
+        assert_eq!(limiter.try_acquire(ip).err(), Some(2));
+        assert!(limiter.try_acquire(IpAddr::from([10, 0, 0, 2])).is_ok());
+
+        drop(first);
+        assert!(limiter.try_acquire(ip).is_ok());
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let limiter = Arc::new(IpLimiter::new(0));
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let guards = (0..100)
+            .map(|_| limiter.try_acquire(ip))
+            .collect::<Result<Vec<_>, _>>();
+
+        assert!(guards.is_ok());
+        assert_eq!(limiter.active.lock().unwrap()[&ip], 100);
+    }
+}
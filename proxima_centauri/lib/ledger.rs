@@ -0,0 +1,409 @@
+use crate::tunnel::CloseReason;
+use anyhow::{Context as _, Result};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read as _;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const DAY: u64 = 86_400;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LedgerRotation {
+    pub daily: bool,
+    pub retention_days: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct UsageRecord {
+    pub(crate) connection_id: u64,
+    pub(crate) session_id: u64,
+    pub(crate) user: String,
+    pub(crate) ingress: u64,
+    pub(crate) egress: u64,
+    pub(crate) started_at: u64,
+    pub(crate) ended_at: u64,
+    #[serde(default)]
+    pub(crate) close_reason: Option<CloseReason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sni: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dial_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) first_byte_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct QuotaGrant {
+    pub(crate) user: String,
+    pub(crate) granted_bytes: u64,
+    pub(crate) granted_at: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LedgerEntry {
+    Usage(UsageRecord),
+    Grant(QuotaGrant),
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct UsageRollup {
+    pub(crate) tunnels: u64,
+    pub(crate) ingress: u64,
+    pub(crate) egress: u64,
+    pub(crate) granted: u64,
+    pub(crate) first_at: u64,
+    pub(crate) last_at: u64,
+}
+
+struct Segment {
+    file: File,
+    day: u64,
+}
+
+pub(crate) struct Ledger {
+    path: PathBuf,
+    rotation: LedgerRotation,
+    segment: Mutex<Segment>,
+}
+
+impl Ledger {
+    pub(crate) async fn open(path: PathBuf, rotation: LedgerRotation) -> Result<Self> {
+        let file = open_segment(&path).await?;
+        let modified = file.metadata().await?.modified()?;
+        let day = modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() / DAY);
+        Ok(Self {
+            path,
+            rotation,
+            segment: Mutex::new(Segment { file, day }),
+        })
+    }
+
+    pub(crate) async fn append(&self, record: &UsageRecord) -> Result<()> {
+        self.write(serde_json::to_vec(record)?, record.ended_at)
+            .await
+    }
+
+    pub(crate) async fn append_grant(&self, grant: &QuotaGrant) -> Result<()> {
+        self.write(serde_json::to_vec(grant)?, grant.granted_at)
+            .await
+    }
+
+    async fn write(&self, mut line: Vec<u8>, at: u64) -> Result<()> {
+        line.push(b'\n');
+        let mut segment = self.segment.lock().await;
+        let day = at / DAY;
+        if self.rotation.daily
+            && day > segment.day
+            && let Err(err) = self.rotate(&mut segment, day).await
+        {
+            warn!(error = format!("{err:#}"), "Usage ledger rotation failed");
+        }
+        segment.file.write_all(&line).await?;
+        segment.file.sync_data().await?;
+        Ok(())
+    }
+
+    async fn rotate(&self, segment: &mut Segment, day: u64) -> Result<()> {
+        let archive = self.archive_path(&civil_date(segment.day));
+        let plain = archive.with_extension("");
+        tokio::fs::rename(&self.path, &plain).await?;
+        segment.file = open_segment(&self.path).await?;
+        segment.day = day;
+        let compressed = archive.clone();
+        tokio::task::spawn_blocking(move || compress(&plain, &compressed)).await??;
+        info!(archive = %archive.display(), "Usage ledger rotated");
+        if self.rotation.retention_days > 0 {
+            let cutoff = civil_date(day.saturating_sub(self.rotation.retention_days));
+            for (date, path) in self.archives().await? {
+                if date < cutoff {
+                    tokio::fs::remove_file(&path).await?;
+                    info!(archive = %path.display(), "Usage ledger archive expired");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn archive_path(&self, date: &str) -> PathBuf {
+        let (stem, extension) = self.name_parts();
+        self.path
+            .with_file_name(format!("{stem}.{date}{extension}.gz"))
+    }
+
+    fn name_parts(&self) -> (String, String) {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = self
+            .path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+        (stem, extension)
+    }
+
+    async fn archives(&self) -> Result<Vec<(String, PathBuf)>> {
+        let (stem, extension) = self.name_parts();
+        let prefix = format!("{stem}.");
+        let dir = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let mut archives = BTreeMap::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(rest) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            if let Some(date) = rest
+                .strip_suffix(&format!("{extension}.gz"))
+                .filter(|date| date.len() == 10)
+            {
+                archives
+                    .entry(date.to_string())
+                    .or_insert_with(|| entry.path());
+            } else if let Some(date) = rest
+                .strip_suffix(&extension)
+                .filter(|date| date.len() == 10)
+            {
+                archives.insert(date.to_string(), entry.path());
+            }
+        }
+        Ok(archives.into_iter().collect())
+    }
+
+    pub(crate) async fn rollup(&self) -> Result<BTreeMap<String, UsageRollup>> {
+        let mut segments = Vec::new();
+        for (_, path) in self.archives().await? {
+            let segment = if path.extension().is_some_and(|extension| extension == "gz") {
+                tokio::task::spawn_blocking(move || decompress(&path)).await??
+            } else {
+                tokio::fs::read_to_string(&path).await?
+            };
+            segments.push(segment);
+        }
+        segments.push(tokio::fs::read_to_string(&self.path).await?);
+        let mut rollup: BTreeMap<String, UsageRollup> = BTreeMap::new();
+        let mut torn = 0;
+        let entries = segments.iter().flat_map(|segment| {
+            let lines: Vec<&str> = segment.lines().filter(|line| !line.is_empty()).collect();
+            let last = lines.len().saturating_sub(1);
+            lines
+                .into_iter()
+                .enumerate()
+                .map(move |(index, line)| (index == last, line))
+        });
+        for (trailing, line) in entries {
+            let entry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(_) if trailing => {
+                    torn += 1;
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let record = match entry {
+                LedgerEntry::Usage(record) => record,
+                LedgerEntry::Grant(grant) => {
+                    rollup.entry(grant.user).or_default().granted += grant.granted_bytes;
+                    continue;
+                }
+            };
+            let totals = rollup.entry(record.user).or_default();
+            if totals.tunnels == 0 || record.started_at < totals.first_at {
+                totals.first_at = record.started_at;
+            }
+            totals.last_at = totals.last_at.max(record.ended_at);
+            totals.tunnels += 1;
+            totals.ingress += record.ingress;
+            totals.egress += record.egress;
+        }
+        if torn > 0 {
+            warn!(
+                skipped = torn,
+                "Usage ledger rollup skipped torn trailing lines"
+            );
+        }
+        let target = self.path.with_extension("rollup.json");
+        let temp = self.path.with_extension("rollup.tmp");
+        tokio::fs::write(&temp, serde_json::to_vec_pretty(&rollup)?).await?;
+        tokio::fs::rename(&temp, &target).await?;
+        Ok(rollup)
+    }
+}
+
+async fn open_segment(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Cannot open usage ledger {}", path.display()))
+}
+
+fn compress(plain: &Path, archive: &Path) -> Result<()> {
+    let mut input = std::fs::File::open(plain)?;
+    let mut encoder = GzEncoder::new(std::fs::File::create(archive)?, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(plain)?;
+    Ok(())
+}
+
+fn decompress(archive: &Path) -> Result<String> {
+    let mut content = String::new();
+    GzDecoder::new(std::fs::File::open(archive)?).read_to_string(&mut content)?;
+    Ok(content)
+}
+
+fn civil_date(day: u64) -> String {
+    let z = day.cast_signed() + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(connection_id: u64, user: &str, bytes: u64, at: u64) -> UsageRecord {
+        UsageRecord {
+            connection_id,
+            session_id: 1,
+            user: user.to_string(),
+            ingress: bytes,
+            egress: bytes * 2,
+            started_at: at,
+            ended_at: at + 5,
+            close_reason: None,
+            sni: None,
+            dial_ms: None,
+            first_byte_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn appends_records_and_rolls_them_up() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("procent-ledger-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join("usage.jsonl");
+
+        let ledger = Ledger::open(path.clone(), LedgerRotation::default()).await?;
+        ledger.append(&record(1, "alice", 100, 1_000)).await?;
+        ledger.append(&record(2, "bob", 10, 1_010)).await?;
+        drop(ledger);
+        let ledger = Ledger::open(path.clone(), LedgerRotation::default()).await?;
+        ledger.append(&record(3, "alice", 50, 900)).await?;
+        ledger
+            .append_grant(&QuotaGrant {
+                user: "alice".to_string(),
+                granted_bytes: 5_000,
+                granted_at: 1_020,
+            })
+            .await?;
+
+        let rollup = ledger.rollup().await?;
+        let written = tokio::fs::read_to_string(dir.join("usage.rollup.json")).await?;
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        assert_eq!(
+            rollup["alice"],
+            UsageRollup {
+                tunnels: 2,
+                ingress: 150,
+                egress: 300,
+                granted: 5_000,
+                first_at: 900,
+                last_at: 1_005,
+            }
+        );
+        assert_eq!(rollup["bob"].tunnels, 1);
+        assert!(written.contains("\"alice\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotates_daily_and_expires_old_archives() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("procent-rotation-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join("usage.jsonl");
+        let rotation = LedgerRotation {
+            daily: true,
+            retention_days: 2,
+        };
+        let today = crate::clock::unix_now() / DAY;
+
+        let ledger = Ledger::open(path.clone(), rotation).await?;
+        ledger.append(&record(1, "alice", 100, today * DAY)).await?;
+        ledger
+            .append(&record(2, "alice", 10, (today + 1) * DAY))
+            .await?;
+        ledger
+            .append(&record(3, "bob", 1, (today + 3) * DAY))
+            .await?;
+
+        let expired = dir.join(format!("usage.{}.jsonl.gz", civil_date(today)));
+        let kept = dir.join(format!("usage.{}.jsonl.gz", civil_date(today + 1)));
+        let rollup = ledger.rollup().await?;
+        let (expired_exists, kept_exists) = (expired.exists(), kept.exists());
+        let archived = decompress(&kept)?;
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        assert!(!expired_exists);
+        assert!(kept_exists);
+        assert!(archived.contains("\"connection_id\":2"));
+        assert_eq!(rollup["alice"].tunnels, 1);
+        assert_eq!(rollup["bob"].tunnels, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn formats_civil_dates() {
+        assert_eq!(civil_date(0), "1970-01-01");
+        assert_eq!(civil_date(19_723), "2024-01-01");
+        assert_eq!(civil_date(20_513), "2026-03-01");
+    }
+
+    #[tokio::test]
+    async fn rollup_survives_torn_lines_and_unfinished_rotations() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("procent-torn-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join("usage.jsonl");
+        let archived = serde_json::to_string(&record(1, "alice", 100, 0))?;
+        tokio::fs::write(
+            dir.join("usage.2024-01-01.jsonl"),
+            format!("{archived}\n{{\"connection_id\":3,\"us"),
+        )
+        .await?;
+
+        let ledger = Ledger::open(path.clone(), LedgerRotation::default()).await?;
+        ledger.append(&record(2, "alice", 10, 10)).await?;
+        let rollup = ledger.rollup().await;
+        tokio::fs::remove_dir_all(&dir).await?;
+
+        assert_eq!(rollup?["alice"].tunnels, 2);
+        Ok(())
+    }
+}
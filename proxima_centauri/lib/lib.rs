@@ -1,13 +1,104 @@
+mod acl;
+mod admin;
+mod anomaly;
 mod auth;
+mod auth_audit;
+mod bandwidth;
+mod category;
+mod chaos;
+mod clock;
 mod config;
+mod connections;
+mod context;
+mod coordinator;
+mod dial;
+mod egress;
+mod error;
+mod events;
+mod geoip;
 mod handler;
+mod hooks;
 mod http_utils;
-mod server;
+mod info;
+mod intercept;
+mod ip_limit;
+mod ledger;
+mod logging;
+mod maintenance;
+mod metrics;
+mod origin;
+mod pool;
+mod rdns;
 mod registry;
+mod reporter;
+mod response_cache;
+mod routing;
+mod schedule;
+mod secrets;
+mod server;
+mod session;
+mod signals;
+mod sni;
+mod spill;
+mod stats;
+mod store;
+mod systemd;
+mod transparent;
 mod tunnel;
+mod validate;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_support;
+mod webhook;
 
 #[cfg(test)]
 mod tests;
-mod context;
 
-pub use server::Server;
+pub use acl::AclConfig;
+pub use anomaly::AnomalyConfig;
+#[cfg(feature = "ldap")]
+pub use auth::{LdapAuthProvider, LdapConfig};
+pub use auth::{
+    AuthProvider, CachedAuthProvider, Credential, Database, FailoverAuthProvider, UserFilter,
+    UserPage, UserRecord, UserStatus, UsernameTaken, UsersFile, encrypt_users, load_users,
+    parse_users,
+};
+pub use category::{Categorizer, CategoryConfig};
+pub use chaos::ChaosConfig;
+pub use clock::{Clock, SystemClock};
+pub use config::{
+    Config, Enforcement, ListenerConfig, ScheduleEnforcement, StealthMode, UsersValidation,
+    build_config, init,
+};
+pub use coordinator::{CoordinatorConfig, QuotaCoordinator};
+pub use dial::{KeepaliveConfig, OutboundBinding, OutboundConfig, SocketOptions};
+pub use error::{BoxError, ProxyError};
+pub use egress::{EgressPoolConfig, EgressUsage, RotationStrategy};
+pub use events::WebhookConfig;
+pub use geoip::GeoPolicy;
+pub use hooks::{
+    AcceptContext, AuthContext, Hooks, TunnelCloseContext, TunnelOpenContext, Verdict,
+};
+pub use http_utils::request::HeaderPolicy;
+pub use http_utils::response::ConnectHeaders;
+pub use intercept::{Direction, InterceptConfig, Inspector, PassThrough};
+pub use ledger::LedgerRotation;
+pub use pool::ForwardPoolConfig;
+pub use response_cache::ForwardCacheConfig;
+pub use registry::{LimitError, LimitValue, Limits, Registry, RegistryError};
+pub use reporter::{StatsReportConfig, StatsSink};
+pub use routing::{DIRECT, Route, TLS, UpstreamProxy, parse_routes};
+pub use schedule::Schedule;
+pub use server::{ReloadHandle, Server, ServerBuilder};
+pub use session::Session;
+pub use signals::install_signal_handlers;
+pub use sni::{SniMode, SniPolicy};
+pub use stats::{
+    DestinationStats, HistogramStats, StatsHandle, StatsSnapshot, TenantStats, TrafficStats,
+    UserStats,
+};
+pub use store::{RegistryStore, StoreConfig};
+pub use systemd::listen_fds;
+pub use transparent::{TransparentConfig, TransparentMode};
+pub use tunnel::CloseReason;
+pub use validate::Validation;
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,63 @@
+use anyhow::{Result, anyhow};
+use std::sync::OnceLock;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Registry, fmt, reload};
+
+const DEFAULT_FILTER: &str = "info";
+
+static FILTER: OnceLock<reload::Handle<Targets, Registry>> = OnceLock::new();
+
+pub(crate) fn install() {
+    let filter = dotenv::var("PROXY_LOG")
+        .ok()
+        .and_then(|filter| filter.parse().ok())
+        .unwrap_or_else(|| Targets::new().with_default(tracing::Level::INFO));
+    let (layer, handle) = reload::Layer::new(filter);
+    if tracing_subscriber::registry()
+        .with(layer)
+        .with(fmt::layer())
+        .try_init()
+        .is_ok()
+    {
+        let _ = FILTER.set(handle);
+    }
+}
+
+pub(crate) fn current_filter() -> Option<String> {
+    FILTER.get()?.with_current(ToString::to_string).ok()
+}
+
+pub(crate) fn set_filter(filter: &str) -> Result<String> {
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| anyhow!("The log filter is not managed by procent"))?;
+    let filter = if filter.trim().is_empty() {
+        DEFAULT_FILTER
+    } else {
+        filter.trim()
+    };
+    let targets: Targets = filter
+        .parse()
+        .map_err(|err| anyhow!("Invalid log filter `{filter}`: {err}"))?;
+    let applied = targets.to_string();
+    handle.reload(targets)?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_can_be_changed_at_runtime() -> Result<()> {
+        install();
+        let applied = set_filter("warn,proxima_centauri::handler=trace")?;
+        assert!(applied.contains("proxima_centauri::handler=trace"));
+        assert_eq!(current_filter(), Some(applied));
+        assert!(set_filter("handler=loud").is_err());
+        assert_eq!(set_filter(" ")?, "info");
+        Ok(())
+    }
+}
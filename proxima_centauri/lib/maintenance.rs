@@ -0,0 +1,38 @@
+use std::sync::{Mutex, PoisonError};
+
+const DEFAULT_RETRY_AFTER: u64 = 300;
+
+#[derive(Default)]
+pub(crate) struct Maintenance {
+    retry_after: Mutex<Option<u64>>,
+}
+
+impl Maintenance {
+    pub(crate) const fn new(retry_after: Option<u64>) -> Self {
+        Self {
+            retry_after: Mutex::new(retry_after),
+        }
+    }
+
+    pub(crate) fn retry_after(&self) -> Option<u64> {
+        *self
+            .retry_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn set(&self, retry_after: Option<u64>) {
+        *self
+            .retry_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = retry_after;
+    }
+
+    pub(crate) fn enable(&self, retry_after: Option<u64>) -> u64 {
+        let retry_after = retry_after
+            .or_else(|| self.retry_after())
+            .unwrap_or(DEFAULT_RETRY_AFTER);
+        self.set(Some(retry_after));
+        retry_after
+    }
+}
@@ -0,0 +1,232 @@
+use crate::tunnel::CloseReason;
+use serde_json::{Value, json};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const LATENCY_BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+    sum_ms: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, elapsed: Duration) {
+        let ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        if let Some(index) = LATENCY_BUCKETS_MS.iter().position(|bound| ms <= *bound) {
+            Metrics::inc(&self.buckets[index]);
+        }
+        Metrics::add(&self.sum_ms, ms);
+        Metrics::inc(&self.count);
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let mut cumulative = 0;
+        let mut buckets: serde_json::Map<String, Value> = LATENCY_BUCKETS_MS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (bound.to_string(), json!(cumulative))
+            })
+            .collect();
+        let count = self.count.load(Ordering::Relaxed);
+        buckets.insert("+Inf".to_string(), json!(count));
+        json!({
+            "buckets": buckets,
+            "sum": self.sum_ms.load(Ordering::Relaxed),
+            "count": count,
+        })
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) requests: AtomicU64,
+    pub(crate) tunnels: AtomicU64,
+    pub(crate) request_headers: AtomicU64,
+    pub(crate) request_header_bytes: AtomicU64,
+    pub(crate) header_timeouts: AtomicU64,
+    pub(crate) uri_too_long: AtomicU64,
+    pub(crate) headers_too_large: AtomicU64,
+    pub(crate) geoip_denied: AtomicU64,
+    pub(crate) acl_denied: AtomicU64,
+    pub(crate) shadow_violations: AtomicU64,
+    pub(crate) ip_target_denied: AtomicU64,
+    pub(crate) ip_limit_rejections: AtomicU64,
+    pub(crate) soft_limit_warnings: AtomicU64,
+    pub(crate) traffic_anomalies: AtomicU64,
+    pub(crate) sni_mismatches: AtomicU64,
+    pub(crate) auth_failures: AtomicU64,
+    pub(crate) auth_lockouts: AtomicU64,
+    pub(crate) auth_locked_rejections: AtomicU64,
+    pub(crate) auth_tarpitted: AtomicU64,
+    pub(crate) tunnel_close_reasons: [AtomicU64; CloseReason::ALL.len()],
+    pub(crate) connections_active: AtomicU64,
+    pub(crate) connections_peak: AtomicU64,
+    pub(crate) connections_shed: AtomicU64,
+    pub(crate) maintenance_rejections: AtomicU64,
+    pub(crate) hook_rejections: AtomicU64,
+    pub(crate) dial_latency: Histogram,
+    pub(crate) first_byte_latency: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn next_tunnel_id(&self) -> u64 {
+        self.tunnels.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        let active = self.connections_active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.connections_peak.fetch_max(active, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn tunnel_closed(&self, reason: CloseReason) {
+        Self::inc(&self.tunnel_close_reasons[reason as usize]);
+    }
+
+    pub(crate) fn close_reasons(&self) -> Vec<(&'static str, u64)> {
+        CloseReason::ALL
+            .iter()
+            .map(|reason| {
+                (
+                    reason.as_str(),
+                    self.tunnel_close_reasons[*reason as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("requests_total", self.requests.load(Ordering::Relaxed)),
+            ("tunnels_total", self.tunnels.load(Ordering::Relaxed)),
+            (
+                "request_headers_total",
+                self.request_headers.load(Ordering::Relaxed),
+            ),
+            (
+                "request_header_bytes_total",
+                self.request_header_bytes.load(Ordering::Relaxed),
+            ),
+            (
+                "header_timeouts_total",
+                self.header_timeouts.load(Ordering::Relaxed),
+            ),
+            ("uri_too_long_total", self.uri_too_long.load(Ordering::Relaxed)),
+            (
+                "headers_too_large_total",
+                self.headers_too_large.load(Ordering::Relaxed),
+            ),
+            ("geoip_denied_total", self.geoip_denied.load(Ordering::Relaxed)),
+            ("acl_denied_total", self.acl_denied.load(Ordering::Relaxed)),
+            (
+                "shadow_violations_total",
+                self.shadow_violations.load(Ordering::Relaxed),
+            ),
+            (
+                "ip_target_denied_total",
+                self.ip_target_denied.load(Ordering::Relaxed),
+            ),
+            (
+                "ip_limit_rejections_total",
+                self.ip_limit_rejections.load(Ordering::Relaxed),
+            ),
+            (
+                "soft_limit_warnings_total",
+                self.soft_limit_warnings.load(Ordering::Relaxed),
+            ),
+            (
+                "traffic_anomalies_total",
+                self.traffic_anomalies.load(Ordering::Relaxed),
+            ),
+            (
+                "sni_mismatches_total",
+                self.sni_mismatches.load(Ordering::Relaxed),
+            ),
+            (
+                "auth_failures_total",
+                self.auth_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "auth_lockouts_total",
+                self.auth_lockouts.load(Ordering::Relaxed),
+            ),
+            (
+                "auth_locked_rejections_total",
+                self.auth_locked_rejections.load(Ordering::Relaxed),
+            ),
+            (
+                "auth_tarpitted_total",
+                self.auth_tarpitted.load(Ordering::Relaxed),
+            ),
+            (
+                "connections_active",
+                self.connections_active.load(Ordering::Relaxed),
+            ),
+            (
+                "connections_peak",
+                self.connections_peak.load(Ordering::Relaxed),
+            ),
+            (
+                "connections_shed_total",
+                self.connections_shed.load(Ordering::Relaxed),
+            ),
+            (
+                "maintenance_rejections_total",
+                self.maintenance_rejections.load(Ordering::Relaxed),
+            ),
+            (
+                "hook_rejections_total",
+                self.hook_rejections.load(Ordering::Relaxed),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_active_and_peak_connections() {
+        let metrics = Metrics::default();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.connection_opened();
+
+        assert_eq!(metrics.connections_active.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.connections_peak.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn latency_histogram_is_cumulative() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(30));
+
+        let exported = histogram.to_json();
+        assert_eq!(exported["buckets"]["5"], 1);
+        assert_eq!(exported["buckets"]["50"], 2);
+        assert_eq!(exported["buckets"]["10000"], 2);
+        assert_eq!(exported["buckets"]["+Inf"], 3);
+        assert_eq!(exported["sum"], 30_043);
+        assert_eq!(exported["count"], 3);
+    }
+}
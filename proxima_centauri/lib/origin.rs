@@ -0,0 +1,150 @@
+use anyhow::Result;
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "tls-origin")]
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+#[cfg(feature = "tls-origin")]
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+#[cfg(feature = "tls-origin")]
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+#[cfg(feature = "tls-origin")]
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+pub(crate) enum OriginStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls-origin")]
+    Tls(Box<TlsStream<TcpStream>>),
+    Cached(Cursor<Vec<u8>>),
+    Recording(Box<Self>, Vec<u8>, usize),
+}
+
+impl OriginStream {
+    pub(crate) fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Plain(stream) => Some(stream),
+            #[cfg(feature = "tls-origin")]
+            Self::Tls(stream) => Some(stream.get_ref().0),
+            Self::Cached(_) => None,
+            Self::Recording(inner, ..) => inner.tcp(),
+        }
+    }
+}
+
+impl AsyncRead for OriginStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls-origin")]
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Cached(response) => Pin::new(response).poll_read(cx, buf),
+            Self::Recording(stream, recorded, limit) => {
+                let before = buf.filled().len();
+                ready!(Pin::new(stream.as_mut()).poll_read(cx, buf))?;
+                let read = &buf.filled()[before..];
+                if recorded.len() + read.len() > *limit {
+                    *limit = 0;
+                    recorded.clear();
+                } else {
+                    recorded.extend_from_slice(read);
+                }
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for OriginStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls-origin")]
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Cached(_) => Poll::Ready(Ok(buf.len())),
+            Self::Recording(stream, ..) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls-origin")]
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Cached(_) => Poll::Ready(Ok(())),
+            Self::Recording(stream, ..) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls-origin")]
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Cached(_) => Poll::Ready(Ok(())),
+            Self::Recording(stream, ..) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+pub(crate) struct OriginTls {
+    #[cfg(feature = "tls-origin")]
+    connector: TlsConnector,
+}
+
+impl OriginTls {
+    #[cfg(feature = "tls-origin")]
+    pub(crate) fn new(extra_ca: Option<&str>) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(path) = extra_ca {
+            for cert in CertificateDer::pem_file_iter(path)? {
+                roots.add(cert?)?;
+            }
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            connector: TlsConnector::from(std::sync::Arc::new(config)),
+        })
+    }
+
+    #[cfg(not(feature = "tls-origin"))]
+    pub(crate) fn new(extra_ca: Option<&str>) -> Result<Self> {
+        if extra_ca.is_some() {
+            anyhow::bail!("Origin CA bundle configured but the `tls-origin` feature is disabled");
+        }
+        Ok(Self {})
+    }
+
+    #[cfg(feature = "tls-origin")]
+    pub(crate) async fn connect(&self, host: &str, stream: TcpStream) -> io::Result<OriginStream> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let stream = self.connector.connect(name, stream).await?;
+        Ok(OriginStream::Tls(Box::new(stream)))
+    }
+
+    #[cfg(not(feature = "tls-origin"))]
+    #[allow(clippy::unused_async, clippy::unused_self)]
+    pub(crate) async fn connect(
+        &self,
+        _host: &str,
+        _stream: TcpStream,
+    ) -> io::Result<OriginStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS origination needs the `tls-origin` feature",
+        ))
+    }
+}
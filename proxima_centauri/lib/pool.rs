@@ -0,0 +1,172 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ForwardPoolConfig {
+    pub per_origin: usize,
+    pub max_idle: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for ForwardPoolConfig {
+    fn default() -> Self {
+        Self {
+            per_origin: 0,
+            max_idle: 64,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Idle {
+    stream: TcpStream,
+    since: Instant,
+}
+
+pub(crate) struct OriginPool {
+    config: ForwardPoolConfig,
+    idle: Mutex<HashMap<String, VecDeque<Idle>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    returned: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl OriginPool {
+    pub(crate) fn new(config: ForwardPoolConfig) -> Option<Self> {
+        (config.per_origin > 0 && config.max_idle > 0).then(|| Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            returned: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn take(&self, origin: &str) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        let connections = idle.get_mut(origin);
+        let mut found = None;
+        if let Some(connections) = connections {
+            while let Some(connection) = connections.pop_back() {
+                if connection.since.elapsed() < self.config.idle_timeout
+                    && is_open(&connection.stream)
+                {
+                    found = Some(connection.stream);
+                    break;
+                }
+                self.discarded.fetch_add(1, Ordering::Relaxed);
+            }
+            if connections.is_empty() {
+                idle.remove(origin);
+            }
+        }
+        let counter = if found.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub(crate) fn put(&self, origin: &str, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        let timeout = self.config.idle_timeout;
+        idle.retain(|_, connections| {
+            connections.retain(|connection| connection.since.elapsed() < timeout);
+            !connections.is_empty()
+        });
+        let total: usize = idle.values().map(VecDeque::len).sum();
+        let connections = idle.entry(origin.to_string()).or_default();
+        if total >= self.config.max_idle || connections.len() >= self.config.per_origin {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        connections.push_back(Idle {
+            stream,
+            since: Instant::now(),
+        });
+        self.returned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn counters(&self) -> Vec<(&'static str, u64)> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(VecDeque::len)
+            .sum::<usize>();
+        vec![
+            ("forward_pool_hits_total", self.hits.load(Ordering::Relaxed)),
+            (
+                "forward_pool_misses_total",
+                self.misses.load(Ordering::Relaxed),
+            ),
+            (
+                "forward_pool_returned_total",
+                self.returned.load(Ordering::Relaxed),
+            ),
+            (
+                "forward_pool_discarded_total",
+                self.discarded.load(Ordering::Relaxed),
+            ),
+            ("forward_pool_idle", idle as u64),
+        ]
+    }
+}
+
+fn is_open(stream: &TcpStream) -> bool {
+    let mut probe = [0u8; 1];
+    matches!(stream.try_read(&mut probe), Err(err) if err.kind() == io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    async fn connection(listener: &TcpListener) -> Result<(TcpStream, TcpStream)> {
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        Ok((client, server))
+    }
+
+    #[tokio::test]
+    async fn reuses_open_connections_within_bounds() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let pool = OriginPool::new(ForwardPoolConfig {
+            per_origin: 1,
+            ..ForwardPoolConfig::default()
+        })
+        .unwrap();
+        let (first, _first_server) = connection(&listener).await?;
+        let (second, _second_server) = connection(&listener).await?;
+        let (closed, closed_server) = connection(&listener).await?;
+
+        assert!(pool.take("http://a:80").is_none());
+        pool.put("http://a:80", first);
+        pool.put("http://a:80", second);
+        assert!(pool.take("http://a:80").is_some());
+        assert!(pool.take("http://a:80").is_none());
+
+        pool.put("http://b:80", closed);
+        drop(closed_server);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.take("http://b:80").is_none());
+
+        let counters = pool.counters();
+        assert_eq!(counters[0], ("forward_pool_hits_total", 1));
+        assert_eq!(counters[1], ("forward_pool_misses_total", 3));
+        assert_eq!(counters[3], ("forward_pool_discarded_total", 2));
+        Ok(())
+    }
+}
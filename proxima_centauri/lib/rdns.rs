@@ -0,0 +1,193 @@
+use anyhow::{Context as _, Result, anyhow, bail};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RDNS_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+const MAX_POINTERS: usize = 16;
+
+pub(crate) async fn reverse_lookup(ip: IpAddr) -> Result<Option<String>> {
+    let conf = tokio::fs::read_to_string(RESOLV_CONF).await?;
+    let nameserver = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .ok_or_else(|| anyhow!("No nameserver in {RESOLV_CONF}"))?;
+    reverse_lookup_via(SocketAddr::new(nameserver, 53), ip).await
+}
+
+async fn reverse_lookup_via(nameserver: SocketAddr, ip: IpAddr) -> Result<Option<String>> {
+    let mut id = [0u8; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| anyhow!("Failed to generate DNS query id"))?;
+    let id = u16::from_be_bytes(id);
+    let local: IpAddr = if nameserver.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket
+        .send_to(&encode_query(id, &ptr_name(ip)), nameserver)
+        .await?;
+    let mut packet = [0u8; 512];
+    let size = timeout(RDNS_TIMEOUT, socket.recv(&mut packet))
+        .await
+        .context("Reverse DNS lookup timed out")??;
+    parse_ptr_answer(&packet[..size], id)
+}
+
+fn ptr_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                let _ = write!(name, "{:x}.{:x}.", byte & 0x0f, byte >> 4);
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+fn encode_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(u8::try_from(label.len()).unwrap_or(u8::MAX));
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16> {
+    packet
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| anyhow!("Truncated DNS packet"))
+}
+
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = *packet.get(offset).context("Truncated DNS name")?;
+            if len & 0xc0 == 0xc0 {
+                end.get_or_insert(offset + 2);
+                offset = usize::from(read_u16(packet, offset)? & 0x3fff);
+                break;
+            }
+            if len == 0 {
+                return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            let label = packet
+                .get(offset + 1..offset + 1 + usize::from(len))
+                .context("Truncated DNS label")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + usize::from(len);
+        }
+    }
+    bail!("Too many DNS name pointers")
+}
+
+fn parse_ptr_answer(packet: &[u8], id: u16) -> Result<Option<String>> {
+    if read_u16(packet, 0)? != id {
+        bail!("DNS response id mismatch");
+    }
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x000f != 0 {
+        return Ok(None);
+    }
+    let answers = read_u16(packet, 6)?;
+    let (_, mut offset) = read_name(packet, 12)?;
+    offset += 4;
+    for _ in 0..answers {
+        let (_, after_name) = read_name(packet, offset)?;
+        let kind = read_u16(packet, after_name)?;
+        let len = usize::from(read_u16(packet, after_name + 8)?);
+        let data = after_name + 10;
+        if kind == TYPE_PTR {
+            return Ok(Some(read_name(packet, data)?.0));
+        }
+        offset = data + len;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ptr_response(id: u16, name: &str, target: &str) -> Vec<u8> {
+        let mut packet = encode_query(id, name);
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[7] = 1;
+        packet.extend_from_slice(&[0xc0, 12]);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&300u32.to_be_bytes());
+        let rdata = encode_query(0, target)[12..target.len() + 14].to_vec();
+        packet.extend_from_slice(&u16::try_from(rdata.len()).unwrap().to_be_bytes());
+        packet.extend_from_slice(&rdata);
+        packet
+    }
+
+    #[test]
+    fn builds_reverse_names() {
+        assert_eq!(
+            ptr_name(IpAddr::from([192, 0, 2, 10])),
+            "10.2.0.192.in-addr.arpa"
+        );
+        assert!(
+            ptr_name("2001:db8::1".parse().unwrap())
+                .starts_with("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2")
+        );
+    }
+
+    #[test]
+    fn parses_ptr_answer_with_compression() -> Result<()> {
+        let packet = ptr_response(7, "10.2.0.192.in-addr.arpa", "host.example.com");
+
+        assert_eq!(
+            parse_ptr_answer(&packet, 7)?,
+            Some("host.example.com".to_string())
+        );
+        assert!(parse_ptr_answer(&packet, 8).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn queries_nameserver_over_udp() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = server.local_addr()?;
+        let responder = tokio::spawn(async move {
+            let mut query = [0u8; 512];
+            let (_, peer) = server.recv_from(&mut query).await?;
+            let id = u16::from_be_bytes([query[0], query[1]]);
+            let response = ptr_response(id, "1.0.0.127.in-addr.arpa", "localhost");
+            server.send_to(&response, peer).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let name = reverse_lookup_via(addr, IpAddr::from([127, 0, 0, 1])).await?;
+        responder.await??;
+        assert_eq!(name.as_deref(), Some("localhost"));
+        Ok(())
+    }
+}
@@ -1,44 +1,396 @@
-use std::collections::HashMap;
+use crate::clock::{self, Clock};
+use crate::session::{Session, Sessions};
+use crate::stats::{DestinationStats, HistogramStats, TenantStats, TrafficStats, UserStats};
+use crate::store::RegistryStore;
+use crate::tunnel::CloseReason;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::{Debug, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tokio::time::Instant;
 
-#[derive(Default)]
+const TOP_DESTINATIONS: usize = 16;
+const TUNNEL_BYTES_BUCKETS: [u64; 8] = [
+    1 << 10,
+    16 << 10,
+    256 << 10,
+    1 << 20,
+    16 << 20,
+    256 << 20,
+    1 << 30,
+    4 << 30,
+];
+const TUNNEL_MS_BUCKETS: [u64; 8] = [
+    100, 1_000, 10_000, 60_000, 300_000, 900_000, 1_800_000, 3_600_000,
+];
+
+pub(crate) const QUOTA_CHUNK: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct TrafficCounters {
+    ingress: AtomicU64,
+    egress: AtomicU64,
+    leased: AtomicU64,
+    ceiling: AtomicU64,
+    parent: Option<Arc<Self>>,
+}
+
+impl Default for TrafficCounters {
+    fn default() -> Self {
+        Self {
+            ingress: AtomicU64::default(),
+            egress: AtomicU64::default(),
+            leased: AtomicU64::default(),
+            ceiling: AtomicU64::new(u64::MAX),
+            parent: None,
+        }
+    }
+}
+
+impl TrafficCounters {
+    pub(crate) fn within(parent: Option<Arc<Self>>) -> Self {
+        Self {
+            parent,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn add_ingress(&self, value: u64) {
+        self.ingress.fetch_add(value, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.add_ingress(value);
+        }
+    }
+
+    pub(crate) fn add_egress(&self, value: u64) {
+        self.egress.fetch_add(value, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.add_egress(value);
+        }
+    }
+
+    pub(crate) fn ingress(&self) -> u128 {
+        u128::from(self.ingress.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn egress(&self) -> u128 {
+        u128::from(self.egress.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn total(&self) -> u128 {
+        self.ingress() + self.egress()
+    }
+
+    pub(crate) fn ceiling(&self) -> u128 {
+        u128::from(self.ceiling.load(Ordering::Acquire))
+    }
+
+    pub(crate) fn set_ceiling(&self, ceiling: Option<u128>) {
+        let ceiling = ceiling.map_or(u64::MAX, |ceiling| {
+            u64::try_from(ceiling).unwrap_or(u64::MAX)
+        });
+        self.ceiling.store(ceiling, Ordering::Release);
+    }
+
+    fn reset(&self) {
+        self.ingress.store(0, Ordering::Relaxed);
+        self.egress.store(0, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct QuotaLease {
+    counters: Arc<TrafficCounters>,
+    limit: u128,
+    chunk: u64,
+    balance: AtomicU64,
+    renewal: Option<(Arc<dyn RegistryStore>, String, Limits)>,
+}
+
+impl QuotaLease {
+    pub(crate) const fn new(counters: Arc<TrafficCounters>, limit: u128, chunk: u64) -> Self {
+        Self {
+            counters,
+            limit,
+            chunk,
+            balance: AtomicU64::new(0),
+            renewal: None,
+        }
+    }
+
+    pub(crate) fn renewed_from(
+        mut self,
+        store: Arc<dyn RegistryStore>,
+        user: &str,
+        limits: Limits,
+    ) -> Self {
+        self.renewal = Some((store, user.to_string(), limits));
+        self
+    }
+
+    pub(crate) async fn renew(&self) -> bool {
+        match &self.renewal {
+            Some((store, user, limits)) => matches!(store.extend(user, *limits).await, Ok(true)),
+            None => false,
+        }
+    }
+
+    pub(crate) fn reserve(&self, size: u64) -> u64 {
+        loop {
+            let balance = self.balance.load(Ordering::Acquire);
+            if balance < size && self.lease(size - balance) {
+                continue;
+            }
+            let granted = balance.min(size);
+            if self
+                .balance
+                .compare_exchange(
+                    balance,
+                    balance - granted,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                return granted;
+            }
+        }
+    }
+
+    pub(crate) fn settle(&self, size: u64) {
+        self.counters.leased.fetch_sub(size, Ordering::AcqRel);
+    }
+
+    fn lease(&self, needed: u64) -> bool {
+        let leased = &self.counters.leased;
+        loop {
+            let current = leased.load(Ordering::Acquire);
+            let available = self
+                .limit
+                .min(self.counters.ceiling())
+                .saturating_sub(self.counters.total() + u128::from(current));
+            let grant = u64::try_from(available.min(u128::from(self.chunk.max(needed))))
+                .unwrap_or(u64::MAX);
+            if grant == 0 {
+                return false;
+            }
+            if leased
+                .compare_exchange(
+                    current,
+                    current + grant,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                self.balance.fetch_add(grant, Ordering::AcqRel);
+                return true;
+            }
+        }
+    }
+}
+
+impl Drop for QuotaLease {
+    fn drop(&mut self) {
+        let balance = *self.balance.get_mut();
+        self.settle(balance);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Distribution {
+    bounds: &'static [u64],
+    counts: Vec<u64>,
+    sum: u128,
+    count: u64,
+    max: u64,
+}
+
+impl Distribution {
+    pub(crate) fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0,
+            count: 0,
+            max: 0,
+        }
+    }
+
+    pub(crate) fn observe(&mut self, value: u64) {
+        let index = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[index] += 1;
+        self.sum += u128::from(value);
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub(crate) fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+
+    fn percentile(&self, percent: u64) -> Option<u64> {
+        let rank = (self.count * percent).div_ceil(100).max(1);
+        let mut cumulative = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                return Some(
+                    self.bounds
+                        .get(index)
+                        .map_or(self.max, |bound| (*bound).min(self.max)),
+                );
+            }
+        }
+        None
+    }
+
+    pub(crate) fn stats(&self) -> HistogramStats {
+        let mut cumulative = 0;
+        let mut buckets: BTreeMap<String, u64> = self
+            .bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(bound, count)| {
+                cumulative += count;
+                (bound.to_string(), cumulative)
+            })
+            .collect();
+        buckets.insert("+Inf".to_string(), self.count);
+        HistogramStats {
+            buckets,
+            sum: self.sum,
+            count: self.count,
+            p50: self.percentile(50),
+            p90: self.percentile(90),
+            p99: self.percentile(99),
+        }
+    }
+}
+
 pub(crate) struct StatsTable {
-    ingress_traffic: u128,
-    egress: u128,
+    traffic: Arc<TrafficCounters>,
     concurrency: u16,
+    tunnel_bytes: Distribution,
+    tunnel_duration_ms: Distribution,
+}
+
+impl Default for StatsTable {
+    fn default() -> Self {
+        Self {
+            traffic: Arc::default(),
+            concurrency: 0,
+            tunnel_bytes: Distribution::new(&TUNNEL_BYTES_BUCKETS),
+            tunnel_duration_ms: Distribution::new(&TUNNEL_MS_BUCKETS),
+        }
+    }
 }
 
 impl StatsTable {
-    pub(crate) const fn total_traffic(&self) -> u128 {
-        self.ingress_traffic + self.egress
+    pub(crate) fn total_traffic(&self) -> u128 {
+        self.traffic.total()
     }
 }
 
-enum LimitValue<T> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitValue<T> {
     Unrestricted,
     Restricted(T),
 }
-pub(crate) struct Limits {
+
+impl<T: Copy> LimitValue<T> {
+    pub(crate) const fn restricted(self) -> Option<T> {
+        match self {
+            Self::Unrestricted => None,
+            Self::Restricted(value) => Some(value),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
     concurrency: LimitValue<u16>,
     traffic: LimitValue<u128>,
+    lifetime: LimitValue<Duration>,
+    bandwidth: LimitValue<u64>,
 }
 impl Default for Limits {
     fn default() -> Self {
         Self {
             concurrency: LimitValue::Unrestricted,
             traffic: LimitValue::Unrestricted,
+            lifetime: LimitValue::Unrestricted,
+            bandwidth: LimitValue::Unrestricted,
         }
     }
 }
 
 impl Limits {
+    pub const fn new(concurrency: LimitValue<u16>, traffic: LimitValue<u128>) -> Self {
+        Self {
+            concurrency,
+            traffic,
+            lifetime: LimitValue::Unrestricted,
+            bandwidth: LimitValue::Unrestricted,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_lifetime(self, lifetime: Duration) -> Self {
+        Self {
+            lifetime: LimitValue::Restricted(lifetime),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub const fn with_bandwidth(self, bytes_per_second: u64) -> Self {
+        Self {
+            bandwidth: LimitValue::Restricted(bytes_per_second),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub(crate) const fn topped_up(self, bytes: u128) -> Self {
+        match self.traffic {
+            LimitValue::Restricted(traffic) => Self {
+                traffic: LimitValue::Restricted(traffic.saturating_add(bytes)),
+                ..self
+            },
+            LimitValue::Unrestricted => self,
+        }
+    }
+
+    pub(crate) const fn concurrency(&self) -> LimitValue<u16> {
+        self.concurrency
+    }
+
+    pub(crate) const fn traffic(&self) -> LimitValue<u128> {
+        self.traffic
+    }
+
+    pub(crate) const fn lifetime(&self) -> LimitValue<Duration> {
+        self.lifetime
+    }
+
+    pub(crate) const fn bandwidth(&self) -> LimitValue<u64> {
+        self.bandwidth
+    }
+
     #[allow(dead_code)]
     pub(crate) const fn with_low_concurrency() -> Self {
         Self {
             concurrency: LimitValue::Restricted(2),
             traffic: LimitValue::Unrestricted,
+            lifetime: LimitValue::Unrestricted,
+            bandwidth: LimitValue::Unrestricted,
         }
     }
 
@@ -47,16 +399,62 @@ impl Limits {
         Self {
             concurrency: LimitValue::Unrestricted,
             traffic: LimitValue::Restricted(10_000),
+            lifetime: LimitValue::Unrestricted,
+            bandwidth: LimitValue::Unrestricted,
         }
     }
 
-    pub(crate) const fn with_low_limits() -> Self {
+    pub const fn with_low_limits() -> Self {
         Self {
             concurrency: LimitValue::Restricted(2),
             traffic: LimitValue::Restricted(10_000),
+            lifetime: LimitValue::Unrestricted,
+            bandwidth: LimitValue::Unrestricted,
         }
     }
 }
+
+impl<T: FromStr> FromStr for LimitValue<T> {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "*" | "unlimited" => Ok(Self::Unrestricted),
+            limit => limit
+                .parse()
+                .map(Self::Restricted)
+                .map_err(|_| anyhow::anyhow!("Invalid limit `{limit}`")),
+        }
+    }
+}
+
+impl FromStr for Limits {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.split(':');
+        let (Some(concurrency), Some(traffic), lifetime, bandwidth, None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            anyhow::bail!(
+                "Expected `concurrency:traffic[:lifetime[:bandwidth]]`, got `{value}`"
+            );
+        };
+        let mut limits = Self::new(concurrency.parse()?, traffic.parse()?);
+        let lifetime = lifetime.map(str::parse::<LimitValue<u64>>).transpose()?;
+        if let Some(LimitValue::Restricted(seconds)) = lifetime {
+            limits = limits.with_lifetime(Duration::from_secs(seconds));
+        }
+        if let Some(bandwidth) = bandwidth {
+            limits.bandwidth = bandwidth.parse()?;
+        }
+        Ok(limits)
+    }
+}
 pub(crate) struct Limiter {
     limits: Limits,
 }
@@ -64,7 +462,7 @@ impl Limiter {
     pub(crate) const fn new(limits: Limits) -> Self {
         Self { limits }
     }
-    pub(crate) const fn is_limit_exceed(&self, stats: &StatsTable) -> Result<(), LimitError> {
+    pub(crate) fn is_limit_exceed(&self, stats: &StatsTable) -> Result<(), LimitError> {
         if self.is_concurrency_limit_exceed(stats.concurrency) {
             return Err(LimitError::ConcurrencyLimitExceed(stats.concurrency));
         }
@@ -77,7 +475,7 @@ impl Limiter {
     const fn is_traffic_limit_exceed(&self, total_traffic: u128) -> bool {
         match self.limits.traffic {
             LimitValue::Unrestricted => false,
-            LimitValue::Restricted(value) => value < total_traffic,
+            LimitValue::Restricted(value) => value <= total_traffic,
         }
     }
     const fn is_concurrency_limit_exceed(&self, concurrency: u16) -> bool {
@@ -88,58 +486,262 @@ impl Limiter {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SoftLimitWarning {
+    pub(crate) used: u128,
+    pub(crate) limit: u128,
+    pub(crate) percent: u8,
+}
+
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Traffic {
+    ingress: u128,
+    egress: u128,
+}
+
+impl Traffic {
+    const fn total(self) -> u128 {
+        self.ingress + self.egress
+    }
+}
+
 pub(crate) struct UserContext {
     limiter: Limiter,
     stats_table: StatsTable,
+    countries: HashMap<String, Traffic>,
+    destinations: HashMap<String, Traffic>,
+    close_reasons: HashMap<CloseReason, u64>,
+    tunnels: HashMap<u64, Instant>,
+    traffic_warned: bool,
     last_update_at: Instant,
+    clock: Arc<dyn Clock>,
 }
 impl UserContext {
-    pub(crate) fn new(limits: Limits) -> Self {
+    pub(crate) fn new(limits: Limits, clock: Arc<dyn Clock>) -> Self {
         Self {
             limiter: Limiter::new(limits),
             stats_table: StatsTable::default(),
-            last_update_at: Instant::now(),
+            countries: HashMap::new(),
+            destinations: HashMap::new(),
+            close_reasons: HashMap::new(),
+            tunnels: HashMap::new(),
+            traffic_warned: false,
+            last_update_at: clock.now(),
+            clock,
+        }
+    }
+
+    pub(crate) fn cross_traffic_threshold(&mut self, percent: u8) -> Option<SoftLimitWarning> {
+        let limit = self.limiter.limits.traffic().restricted()?;
+        let used = self.stats_table.total_traffic();
+        let threshold = limit.saturating_mul(u128::from(percent));
+        if self.traffic_warned || used.saturating_mul(100) < threshold {
+            return None;
         }
+        self.traffic_warned = true;
+        Some(SoftLimitWarning {
+            used,
+            limit,
+            percent,
+        })
+    }
+    const fn top_up(&mut self, bytes: u128) {
+        self.limiter.limits = self.limiter.limits.topped_up(bytes);
+        self.traffic_warned = false;
     }
+
+    fn reset_traffic(&mut self) {
+        self.stats_table.traffic.reset();
+        self.traffic_warned = false;
+    }
+
     pub(crate) fn add_ingress_traffic(&mut self, traffic_value: u128) {
-        self.stats_table.ingress_traffic += traffic_value;
-        self.last_update_at = Instant::now();
+        let value = u64::try_from(traffic_value).unwrap_or(u64::MAX);
+        self.stats_table.traffic.add_ingress(value);
+        self.last_update_at = self.clock.now();
     }
     pub(crate) fn add_egress_traffic(&mut self, traffic_value: u128) {
-        self.stats_table.egress += traffic_value;
-        self.last_update_at = Instant::now();
+        let value = u64::try_from(traffic_value).unwrap_or(u64::MAX);
+        self.stats_table.traffic.add_egress(value);
+        self.last_update_at = self.clock.now();
+    }
+
+    pub(crate) fn add_country_traffic(&mut self, country: &str, ingress: u128, egress: u128) {
+        let traffic = self.countries.entry(country.to_string()).or_default();
+        traffic.ingress += ingress;
+        traffic.egress += egress;
+    }
+
+    pub(crate) fn add_destination_traffic(&mut self, host: &str, ingress: u128, egress: u128) {
+        if !self.destinations.contains_key(host) && self.destinations.len() >= TOP_DESTINATIONS {
+            let smallest = self
+                .destinations
+                .iter()
+                .min_by_key(|(_, traffic)| traffic.total())
+                .map(|(host, _)| host.clone());
+            if let Some(smallest) = smallest {
+                self.destinations.remove(&smallest);
+            }
+        }
+        let traffic = self.destinations.entry(host.to_string()).or_default();
+        traffic.ingress += ingress;
+        traffic.egress += egress;
+    }
+
+    pub(crate) fn record_close(&mut self, reason: CloseReason) {
+        *self.close_reasons.entry(reason).or_default() += 1;
+    }
+
+    pub(crate) fn record_tunnel(&mut self, bytes: u64, duration: Duration) {
+        self.stats_table.tunnel_bytes.observe(bytes);
+        self.stats_table
+            .tunnel_duration_ms
+            .observe(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX));
+    }
+
+    pub(crate) fn stats(&self, user: &str) -> UserStats {
+        UserStats {
+            user: user.to_string(),
+            traffic: TrafficStats {
+                ingress: self.stats_table.traffic.ingress(),
+                egress: self.stats_table.traffic.egress(),
+            },
+            concurrency: self.stats_table.concurrency,
+            countries: self
+                .countries
+                .iter()
+                .map(|(country, traffic)| {
+                    let traffic = TrafficStats {
+                        ingress: traffic.ingress,
+                        egress: traffic.egress,
+                    };
+                    (country.clone(), traffic)
+                })
+                .collect(),
+            destinations: self.top_destinations(),
+            tunnels_closed: self
+                .close_reasons
+                .iter()
+                .map(|(reason, count)| (reason.as_str(), *count))
+                .collect(),
+            tunnel_bytes: self.stats_table.tunnel_bytes.stats(),
+            tunnel_duration_ms: self.stats_table.tunnel_duration_ms.stats(),
+        }
+    }
+
+    fn top_destinations(&self) -> Vec<DestinationStats> {
+        let mut destinations: Vec<_> = self
+            .destinations
+            .iter()
+            .map(|(host, traffic)| DestinationStats {
+                host: host.clone(),
+                traffic: TrafficStats {
+                    ingress: traffic.ingress,
+                    egress: traffic.egress,
+                },
+            })
+            .collect();
+        destinations.sort_by(|a, b| {
+            let total = |stats: &DestinationStats| stats.traffic.ingress + stats.traffic.egress;
+            total(b).cmp(&total(a)).then_with(|| a.host.cmp(&b.host))
+        });
+        destinations
     }
 
     pub(crate) fn inc_concurrency(&mut self) {
         self.stats_table.concurrency += 1;
-        self.last_update_at = Instant::now();
+        self.last_update_at = self.clock.now();
     }
     pub(crate) fn dec_concurrency(&mut self) {
         self.stats_table.concurrency -= 1;
-        self.last_update_at = Instant::now();
+        self.last_update_at = self.clock.now();
     }
 }
-pub(crate) struct Registry {
+struct Tenant {
+    limiter: Limiter,
+    users: BTreeSet<String>,
+}
+
+pub struct Registry {
     inner: HashMap<String, UserContext>,
+    tenants: HashMap<String, Tenant>,
+    grants: HashMap<String, u128>,
+    sessions: Sessions,
+    traffic_window: u64,
+    window: u64,
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Error, Debug)]
-pub(crate) enum LimitError {
+pub enum LimitError {
     #[error("Concurrency limit exceed")]
     ConcurrencyLimitExceed(u16),
     #[error("Traffic limit exceed")]
     TrafficLimitExceed(u128),
+    #[error("Tenant concurrency limit exceed")]
+    TenantConcurrencyLimitExceed(u16, u16),
+    #[error("Tenant traffic limit exceed")]
+    TenantTrafficLimitExceed(u128, u128),
+    #[error("Outside of the allowed schedule")]
+    OutsideSchedule,
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error(transparent)]
+    Limit(#[from] LimitError),
+    #[error("User `{0}` is not registered")]
+    UnknownUser(String),
+}
+
+impl LimitError {
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::ConcurrencyLimitExceed(_) => "concurrency_limit_exceeded",
+            Self::TrafficLimitExceed(_) => "traffic_quota_exceeded",
+            Self::TenantConcurrencyLimitExceed(..) => "tenant_concurrency_limit_exceeded",
+            Self::TenantTrafficLimitExceed(..) => "tenant_traffic_quota_exceeded",
+            Self::OutsideSchedule => "outside_schedule",
+        }
+    }
 }
 
 
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registry {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             inner: HashMap::new(),
+            tenants: HashMap::new(),
+            grants: HashMap::new(),
+            sessions: Sessions::default(),
+            traffic_window: 0,
+            window: 0,
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            sessions: Sessions::with_clock(clock.clone()),
+            clock,
+            ..Self::new()
         }
     }
     pub(crate) fn create_user(&mut self, user: &str, limits: Limits)  {
-        self.inner.entry(user.to_string()).or_insert_with(|| UserContext::new(limits));
+        self.inner.entry(user.to_string()).or_insert_with(|| UserContext::new(limits, self.clock.clone()));
+    }
+
+    pub(crate) fn set_limits(&mut self, user: &str, limits: Limits) {
+        self.inner
+            .entry(user.to_string())
+            .and_modify(|ctx| ctx.limiter = Limiter::new(limits))
+            .or_insert_with(|| UserContext::new(limits, self.clock.clone()));
     }
 
     pub(crate) fn add_ingress_traffic(&mut self, user: &str, traffic_value: u128) {
@@ -153,6 +755,79 @@ impl Registry {
             .and_modify(|ctx| ctx.add_egress_traffic(traffic_value));
     }
 
+    pub(crate) fn add_country_traffic(
+        &mut self,
+        user: &str,
+        country: &str,
+        ingress: u128,
+        egress: u128,
+    ) {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.add_country_traffic(country, ingress, egress);
+        }
+    }
+
+    pub(crate) fn add_destination_traffic(
+        &mut self,
+        user: &str,
+        host: &str,
+        ingress: u128,
+        egress: u128,
+    ) {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.add_destination_traffic(host, ingress, egress);
+        }
+    }
+
+    pub(crate) fn record_close(&mut self, user: &str, reason: CloseReason) {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.record_close(reason);
+        }
+    }
+
+    pub(crate) fn record_tunnel(&mut self, user: &str, bytes: u64, duration: Duration) {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.record_tunnel(bytes, duration);
+        }
+    }
+
+    pub(crate) fn tunnel_histograms(&self) -> (HistogramStats, HistogramStats) {
+        let mut bytes = Distribution::new(&TUNNEL_BYTES_BUCKETS);
+        let mut duration = Distribution::new(&TUNNEL_MS_BUCKETS);
+        for ctx in self.inner.values() {
+            bytes.merge(&ctx.stats_table.tunnel_bytes);
+            duration.merge(&ctx.stats_table.tunnel_duration_ms);
+        }
+        (bytes.stats(), duration.stats())
+    }
+
+    pub(crate) fn traffic_counters(&self, user: &str) -> Option<Arc<TrafficCounters>> {
+        self.inner
+            .get(user)
+            .map(|ctx| ctx.stats_table.traffic.clone())
+    }
+
+    pub(crate) fn cross_traffic_threshold(
+        &mut self,
+        user: &str,
+        percent: u8,
+    ) -> Option<SoftLimitWarning> {
+        self.inner.get_mut(user)?.cross_traffic_threshold(percent)
+    }
+
+    pub(crate) fn top_up(&mut self, user: &str, bytes: u128) -> u128 {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.top_up(bytes);
+        }
+        let granted = self.grants.entry(user.to_string()).or_default();
+        *granted = granted.saturating_add(bytes);
+        *granted
+    }
+
+    pub(crate) fn granted(&self, user: &str) -> u128 {
+        self.grants.get(user).copied().unwrap_or_default()
+    }
+
     pub(crate) fn inc_concurrency(&mut self, user: &str) {
         self.inner
             .entry(user.to_string())
@@ -164,13 +839,198 @@ impl Registry {
             .and_modify(UserContext::dec_concurrency);
     }
 
+    pub(crate) fn open_tunnel(&mut self, user: &str, tunnel_id: u64) {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.tunnels.insert(tunnel_id, self.clock.now());
+        }
+    }
+
+    pub(crate) fn close_tunnel(&mut self, user: &str, tunnel_id: u64) {
+        if let Some(ctx) = self.inner.get_mut(user) {
+            ctx.tunnels.remove(&tunnel_id);
+        }
+    }
+
+    pub(crate) fn oldest_tunnel(&self, user: &str) -> Option<Instant> {
+        self.inner.get(user)?.tunnels.values().min().copied()
+    }
+
+    pub(crate) fn oldest_tenant_tunnel(&self, user: &str) -> Option<Instant> {
+        let tenant = self
+            .tenants
+            .values()
+            .find(|tenant| tenant.users.contains(user))?;
+        tenant
+            .users
+            .iter()
+            .filter_map(|user| self.oldest_tunnel(user))
+            .min()
+    }
+
+    pub(crate) fn set_traffic_window(&mut self, seconds: u64) {
+        self.traffic_window = seconds;
+        self.window = self.current_window();
+    }
+
+    pub(crate) const fn window(&self) -> u64 {
+        self.window
+    }
+
+    pub(crate) fn traffic_reset_at(&self) -> Option<u64> {
+        if self.traffic_window == 0 {
+            return None;
+        }
+        let next = self.current_window().checked_add(1)?;
+        next.checked_mul(self.traffic_window)
+    }
+
+    pub(crate) fn roll_traffic_window(&mut self) -> bool {
+        let window = self.current_window();
+        if window == self.window {
+            return false;
+        }
+        self.window = window;
+        self.inner.values_mut().for_each(UserContext::reset_traffic);
+        true
+    }
+
+    fn current_window(&self) -> u64 {
+        self.clock
+            .unix_now()
+            .checked_div(self.traffic_window)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn open_session(&mut self, user: &str, client_ip: IpAddr, ttl: Duration) -> u64 {
+        self.sessions.attach(user, client_ip, ttl)
+    }
+
+    pub(crate) fn close_session(&mut self, session_id: u64, ingress: u128, egress: u128) {
+        self.sessions.detach(session_id, ingress, egress);
+    }
+
+    pub(crate) fn evict_expired_sessions(&mut self, ttl: Duration) -> Vec<u64> {
+        self.sessions.evict_expired(ttl)
+    }
+
+    pub(crate) fn sessions(&self) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.iter().cloned().collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    pub(crate) fn has_user(&self, user: &str) -> bool {
+        self.inner.contains_key(user)
+    }
+
+    pub(crate) fn traffic(&self) -> Vec<(String, u128, u128)> {
+        self.inner
+            .iter()
+            .map(|(user, ctx)| {
+                let traffic = &ctx.stats_table.traffic;
+                (user.clone(), traffic.ingress(), traffic.egress())
+            })
+            .collect()
+    }
+
+    pub(crate) fn user_stats(&self) -> Vec<UserStats> {
+        let mut users: Vec<UserStats> = self
+            .inner
+            .iter()
+            .map(|(user, ctx)| ctx.stats(user))
+            .collect();
+        users.sort_by(|a, b| a.user.cmp(&b.user));
+        users
+    }
+
+    pub(crate) fn stats_of(&self, user: &str) -> Option<UserStats> {
+        self.inner.get(user).map(|ctx| ctx.stats(user))
+    }
+
     pub(crate) fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
 
-    pub(crate) fn check_limits(&self, user: &str) -> Result<(), LimitError> {
-        let stats = self.inner.get(user).unwrap();
-        stats.limiter.is_limit_exceed(&stats.stats_table)
+    pub(crate) fn check_limits(&self, user: &str) -> Result<(), RegistryError> {
+        let stats = self
+            .inner
+            .get(user)
+            .ok_or_else(|| RegistryError::UnknownUser(user.to_string()))?;
+        Ok(stats.limiter.is_limit_exceed(&stats.stats_table)?)
+    }
+
+    pub(crate) fn join_tenant(&mut self, user: &str, tenant: &str, limits: Limits) {
+        for (name, other) in &mut self.tenants {
+            if name != tenant {
+                other.users.remove(user);
+            }
+        }
+        let entry = self
+            .tenants
+            .entry(tenant.to_string())
+            .or_insert_with(|| Tenant {
+                limiter: Limiter::new(limits),
+                users: BTreeSet::new(),
+            });
+        entry.limiter = Limiter::new(limits);
+        entry.users.insert(user.to_string());
+    }
+
+    pub(crate) fn check_tenant_limits(&self, user: &str) -> Result<(), LimitError> {
+        let Some(tenant) = self.tenants.values().find(|tenant| tenant.users.contains(user)) else {
+            return Ok(());
+        };
+        let (concurrency, traffic) = self.tenant_usage(tenant);
+        let limits = tenant.limiter.limits;
+        if let LimitValue::Restricted(limit) = limits.concurrency
+            && tenant.limiter.is_concurrency_limit_exceed(concurrency)
+        {
+            return Err(LimitError::TenantConcurrencyLimitExceed(concurrency, limit));
+        }
+        if let LimitValue::Restricted(limit) = limits.traffic
+            && tenant.limiter.is_traffic_limit_exceed(traffic.total())
+        {
+            return Err(LimitError::TenantTrafficLimitExceed(traffic.total(), limit));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn tenant_stats(&self) -> Vec<TenantStats> {
+        let mut tenants: Vec<TenantStats> = self
+            .tenants
+            .iter()
+            .map(|(name, tenant)| {
+                let (concurrency, traffic) = self.tenant_usage(tenant);
+                TenantStats {
+                    tenant: name.clone(),
+                    users: tenant.users.iter().cloned().collect(),
+                    concurrency,
+                    traffic: TrafficStats {
+                        ingress: traffic.ingress,
+                        egress: traffic.egress,
+                    },
+                }
+            })
+            .collect();
+        tenants.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        tenants
+    }
+
+    fn tenant_usage(&self, tenant: &Tenant) -> (u16, Traffic) {
+        tenant
+            .users
+            .iter()
+            .filter_map(|user| self.inner.get(user))
+            .fold((0, Traffic::default()), |(concurrency, traffic), ctx| {
+                let stats = &ctx.stats_table;
+                (
+                    concurrency.saturating_add(stats.concurrency),
+                    Traffic {
+                        ingress: traffic.ingress + stats.traffic.ingress(),
+                        egress: traffic.egress + stats.traffic.egress(),
+                    },
+                )
+            })
     }
 }
 
@@ -180,9 +1040,34 @@ impl Display for Registry {
             writeln!(
                 f,
                 "User `{}` stats. ingress: {}, egress: {}",
-                user, ctx.stats_table.ingress_traffic, ctx.stats_table.egress
+                user,
+                ctx.stats_table.traffic.ingress(),
+                ctx.stats_table.traffic.egress()
             )
             .expect("TODO: panic message");
+            for (reason, count) in &ctx.close_reasons {
+                writeln!(f, "    closed by `{}`: {count}", reason.as_str())?;
+            }
+            for (country, traffic) in &ctx.countries {
+                writeln!(
+                    f,
+                    "    country `{country}`. ingress: {}, egress: {}",
+                    traffic.ingress, traffic.egress
+                )?;
+            }
+        }
+        for tenant in self.tenant_stats() {
+            writeln!(
+                f,
+                "Tenant `{}` stats. users: {}, ingress: {}, egress: {}",
+                tenant.tenant,
+                tenant.users.len(),
+                tenant.traffic.ingress,
+                tenant.traffic.egress
+            )?;
+        }
+        for session in self.sessions.iter() {
+            writeln!(f, "{session}")?;
         }
         Ok(())
     }
@@ -194,7 +1079,9 @@ impl Debug for Registry {
             writeln!(
                 f,
                 "User `{}` stats. ingress: {}, egress: {}",
-                user, ctx.stats_table.ingress_traffic, ctx.stats_table.egress
+                user,
+                ctx.stats_table.traffic.ingress(),
+                ctx.stats_table.traffic.egress()
             )
             .expect("TODO: panic message");
         }
@@ -210,13 +1097,25 @@ mod tests {
         Limits {
             concurrency: LimitValue::Restricted(max),
             traffic: LimitValue::Unrestricted,
+            ..Limits::default()
         }
     }
 
+    fn stats_table(concurrency: u16, ingress: u64, egress: u64) -> StatsTable {
+        let stats = StatsTable {
+            concurrency,
+            ..Default::default()
+        };
+        stats.traffic.add_ingress(ingress);
+        stats.traffic.add_egress(egress);
+        stats
+    }
+
     fn limits_with_traffic(max: u128) -> Limits {
         Limits {
             concurrency: LimitValue::Unrestricted,
             traffic: LimitValue::Restricted(max),
+            ..Limits::default()
         }
     }
 
@@ -246,11 +1145,7 @@ mod tests {
     #[test]
     fn limiter_allows_when_under_traffic_limit() {
         let limiter = Limiter::new(limits_with_traffic(10_000));
-        let stats = StatsTable {
-            ingress_traffic: 5_000,
-            egress: 4_000,
-            ..Default::default()
-        };
+        let stats = stats_table(0, 5_000, 4_000);
 
         assert!(limiter.is_limit_exceed(&stats).is_ok());
     }
@@ -258,24 +1153,19 @@ mod tests {
     #[test]
     fn limiter_denies_when_traffic_limit_exceeded() {
         let limiter = Limiter::new(limits_with_traffic(10_000));
-        let stats = StatsTable {
-            ingress_traffic: 6_000,
-            egress: 5_000,
-            ..Default::default()
-        };
+        let stats = stats_table(0, 6_000, 5_000);
 
         let result = limiter.is_limit_exceed(&stats);
         assert!(matches!(result, Err(LimitError::TrafficLimitExceed(11_000))));
+
+        let exhausted = stats_table(0, 6_000, 4_000);
+        assert!(limiter.is_limit_exceed(&exhausted).is_err());
     }
 
     #[test]
     fn limiter_allows_unrestricted() {
         let limiter = Limiter::new(Limits::default());
-        let stats = StatsTable {
-            concurrency: 100,
-            ingress_traffic: 1_000_000,
-            egress: 1_000_000,
-        };
+        let stats = stats_table(100, 1_000_000, 1_000_000);
 
         assert!(limiter.is_limit_exceed(&stats).is_ok());
     }
@@ -285,18 +1175,61 @@ mod tests {
         let limits = Limits {
             concurrency: LimitValue::Restricted(1),
             traffic: LimitValue::Restricted(100),
+            ..Limits::default()
         };
         let limiter = Limiter::new(limits);
-        let stats = StatsTable {
-            concurrency: 5,
-            ingress_traffic: 500,
-            egress: 500,
-        };
+        let stats = stats_table(5, 500, 500);
 
         let result = limiter.is_limit_exceed(&stats);
         assert!(matches!(result, Err(LimitError::ConcurrencyLimitExceed(_))));
     }
 
+    #[test]
+    fn quota_leases_never_commit_more_than_the_limit() {
+        let counters = Arc::new(TrafficCounters::default());
+        let first = QuotaLease::new(counters.clone(), 100, 40);
+        let second = QuotaLease::new(counters.clone(), 100, 40);
+
+        assert_eq!(first.reserve(30), 30);
+        assert_eq!(second.reserve(30), 30);
+        for lease in [&first, &second] {
+            counters.add_ingress(30);
+            lease.settle(30);
+        }
+        assert_eq!(first.reserve(30), 30);
+        assert_eq!(second.reserve(30), 10);
+        assert_eq!(second.reserve(30), 0);
+
+        counters.add_ingress(40);
+        first.settle(30);
+        second.settle(10);
+        assert_eq!(counters.total(), 100);
+        drop((first, second));
+        assert_eq!(counters.leased.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn parses_limits_from_plan_definitions() {
+        assert_eq!(
+            "4:1048576".parse::<Limits>().unwrap(),
+            Limits::new(LimitValue::Restricted(4), LimitValue::Restricted(1_048_576))
+        );
+        assert_eq!("*:unlimited".parse::<Limits>().unwrap(), Limits::default());
+        assert!("4".parse::<Limits>().is_err());
+        assert!("many:*".parse::<Limits>().is_err());
+        assert_eq!(
+            "2:*:600".parse::<Limits>().unwrap(),
+            Limits::new(LimitValue::Restricted(2), LimitValue::Unrestricted)
+                .with_lifetime(Duration::from_mins(10))
+        );
+        assert_eq!("*:*:*".parse::<Limits>().unwrap(), Limits::default());
+        assert_eq!(
+            "*:*:*:1048576".parse::<Limits>().unwrap(),
+            Limits::default().with_bandwidth(1_048_576)
+        );
+        assert!("1:2:3:4:5".parse::<Limits>().is_err());
+    }
+
     #[test]
     fn users_statistic_create_user_does_not_overwrite() {
         let mut stats = Registry::new();
@@ -311,7 +1244,49 @@ mod tests {
 
         stats.add_ingress_traffic("alice", 600);
         let result = stats.check_limits("alice");
-        assert!(matches!(result, Err(LimitError::TrafficLimitExceed(1100))));
+        assert!(matches!(
+            result,
+            Err(RegistryError::Limit(LimitError::TrafficLimitExceed(1100)))
+        ));
+    }
+
+    #[test]
+    fn top_up_raises_the_live_traffic_limit() {
+        let mut stats = Registry::new();
+        stats.create_user("dave", limits_with_traffic(1000));
+        stats.add_ingress_traffic("dave", 1000);
+        assert!(stats.check_limits("dave").is_err());
+
+        assert_eq!(stats.top_up("dave", 500), 500);
+        assert!(stats.check_limits("dave").is_ok());
+        assert_eq!(stats.top_up("dave", 250), 750);
+        assert_eq!(stats.granted("dave"), 750);
+        assert_eq!(stats.granted("erin"), 0);
+        assert_eq!(
+            Limits::default().topped_up(500).traffic(),
+            LimitValue::Unrestricted
+        );
+    }
+
+    #[test]
+    fn traffic_threshold_warns_once() {
+        let mut stats = Registry::new();
+        stats.create_user("carol", limits_with_traffic(1000));
+
+        stats.add_egress_traffic("carol", 700);
+        assert_eq!(stats.cross_traffic_threshold("carol", 80), None);
+
+        stats.add_egress_traffic("carol", 150);
+        assert_eq!(
+            stats.cross_traffic_threshold("carol", 80),
+            Some(SoftLimitWarning {
+                used: 850,
+                limit: 1000,
+                percent: 80,
+            })
+        );
+        stats.add_egress_traffic("carol", 100);
+        assert_eq!(stats.cross_traffic_threshold("carol", 80), None);
     }
 
     #[test]
@@ -326,10 +1301,151 @@ mod tests {
         stats.inc_concurrency("bob");
         assert!(matches!(
             stats.check_limits("bob"),
-            Err(LimitError::ConcurrencyLimitExceed(3))
+            Err(RegistryError::Limit(LimitError::ConcurrencyLimitExceed(3)))
         ));
 
         stats.dec_concurrency("bob");
         assert!(stats.check_limits("bob").is_ok());
     }
+
+    #[test]
+    fn limit_check_of_a_missing_user_is_an_error() {
+        let mut stats = Registry::new();
+        stats.inc_concurrency("ghost");
+
+        assert!(matches!(
+            stats.check_limits("ghost"),
+            Err(RegistryError::UnknownUser(user)) if user == "ghost"
+        ));
+    }
+
+    #[test]
+    fn tenant_traffic_cap_spans_its_users() {
+        let mut registry = Registry::new();
+        let cap = limits_with_traffic(1000);
+        for user in ["alice", "bob"] {
+            registry.create_user(user, Limits::default());
+            registry.join_tenant(user, "acme", cap);
+        }
+        registry.add_ingress_traffic("alice", 600);
+        assert!(registry.check_tenant_limits("bob").is_ok());
+
+        registry.add_egress_traffic("bob", 400);
+        assert!(matches!(
+            registry.check_tenant_limits("bob"),
+            Err(LimitError::TenantTrafficLimitExceed(1000, 1000))
+        ));
+        assert_eq!(registry.tenant_stats()[0].users, ["alice", "bob"]);
+
+        registry.join_tenant("bob", "globex", cap);
+        assert!(registry.check_tenant_limits("bob").is_ok());
+    }
+
+    #[test]
+    fn counts_close_reasons_per_user() {
+        let mut registry = Registry::default();
+        registry.create_user("alice", limits_with_concurrency(1));
+        registry.record_close("alice", CloseReason::ClientClosed);
+        registry.record_close("alice", CloseReason::ClientClosed);
+        registry.record_close("alice", CloseReason::IdleTimeout);
+        registry.record_close("nobody", CloseReason::IoError);
+
+        let reasons = &registry.inner["alice"].close_reasons;
+        assert_eq!(reasons[&CloseReason::ClientClosed], 2);
+        assert_eq!(reasons[&CloseReason::IdleTimeout], 1);
+        assert!(format!("{registry}").contains("closed by `idle_timeout`: 1"));
+    }
+
+    #[test]
+    fn tunnel_histograms_report_buckets_and_percentiles() {
+        let mut registry = Registry::default();
+        registry.create_user("alice", limits_with_concurrency(1));
+        registry.create_user("bob", limits_with_concurrency(1));
+        for _ in 0..98 {
+            registry.record_tunnel("alice", 500, Duration::from_millis(50));
+        }
+        registry.record_tunnel("alice", 2 << 20, Duration::from_secs(30));
+        registry.record_tunnel("bob", 8 << 30, Duration::from_hours(2));
+
+        let alice = registry.stats_of("alice").unwrap();
+        assert_eq!(alice.tunnel_bytes.count, 99);
+        assert_eq!(alice.tunnel_bytes.buckets["1024"], 98);
+        assert_eq!(alice.tunnel_bytes.buckets["+Inf"], 99);
+        assert_eq!(alice.tunnel_bytes.p50, Some(1024));
+        assert_eq!(alice.tunnel_bytes.p99, Some(2 << 20));
+        assert_eq!(alice.tunnel_duration_ms.p90, Some(100));
+
+        let (bytes, duration) = registry.tunnel_histograms();
+        assert_eq!(bytes.count, 100);
+        assert_eq!(bytes.sum, 98 * 500 + (2 << 20) + (8 << 30));
+        assert_eq!(bytes.p99, Some(16 << 20));
+        assert_eq!(duration.buckets["3600000"], 99);
+        assert_eq!(duration.buckets["+Inf"], 100);
+    }
+
+    #[test]
+    fn keeps_a_bounded_table_of_top_destinations() {
+        let mut registry = Registry::default();
+        registry.create_user("alice", limits_with_concurrency(1));
+        registry.add_destination_traffic("alice", "big.example", 5000, 5000);
+        registry.add_destination_traffic("alice", "big.example", 100, 0);
+        for index in 0..TOP_DESTINATIONS {
+            registry.add_destination_traffic("alice", &format!("host{index}.example"), 10, 10);
+        }
+
+        let destinations = registry.stats_of("alice").unwrap().destinations;
+        assert_eq!(destinations.len(), TOP_DESTINATIONS);
+        assert_eq!(destinations[0].host, "big.example");
+        assert_eq!(destinations[0].traffic.ingress, 5100);
+    }
+
+    #[test]
+    fn user_activity_is_stamped_by_the_registry_clock() {
+        let clock = crate::clock::MockClock::new();
+        let mut registry = Registry::with_clock(clock.clone());
+        registry.create_user("alice", limits_with_concurrency(1));
+        let created = registry.inner["alice"].last_update_at;
+
+        clock.advance(Duration::from_secs(90));
+        registry.add_ingress_traffic("alice", 10);
+
+        assert_eq!(created + Duration::from_secs(90), clock.now());
+        assert_eq!(registry.inner["alice"].last_update_at, clock.now());
+    }
+
+    #[test]
+    fn traffic_window_resets_usage_at_the_boundary() {
+        let clock = crate::clock::MockClock::starting_at(1_000);
+        let mut registry = Registry::with_clock(clock.clone());
+        registry.set_traffic_window(3600);
+        registry.create_user("alice", limits_with_traffic(100));
+        registry.add_ingress_traffic("alice", 100);
+
+        assert!(registry.check_limits("alice").is_err());
+        assert_eq!(registry.traffic_reset_at(), Some(3600));
+        assert!(!registry.roll_traffic_window());
+
+        clock.advance(Duration::from_secs(2600));
+        assert!(registry.roll_traffic_window());
+        assert!(registry.check_limits("alice").is_ok());
+        assert_eq!(registry.traffic_reset_at(), Some(7200));
+    }
+
+    #[test]
+    fn oldest_tunnel_tracks_open_tunnels() {
+        let clock = crate::clock::MockClock::new();
+        let mut registry = Registry::with_clock(clock.clone());
+        registry.create_user("alice", limits_with_concurrency(2));
+        let first = clock.now();
+        registry.open_tunnel("alice", 1);
+        clock.advance(Duration::from_secs(5));
+        registry.open_tunnel("alice", 2);
+
+        assert_eq!(registry.oldest_tunnel("alice"), Some(first));
+        registry.close_tunnel("alice", 1);
+        assert_eq!(registry.oldest_tunnel("alice"), Some(clock.now()));
+        registry.close_tunnel("alice", 2);
+        assert_eq!(registry.oldest_tunnel("alice"), None);
+        assert_eq!(Registry::new().traffic_reset_at(), None);
+    }
 }
@@ -0,0 +1,218 @@
+use crate::clock::unix_now;
+use crate::registry::Registry;
+use crate::stats::UserStats;
+use anyhow::{Result, bail};
+use std::fmt::Write as _;
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket, lookup_host};
+use tracing::{info, warn};
+
+const STATSD_DATAGRAM: usize = 512;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StatsSink {
+    Statsd(String),
+    Graphite(String),
+}
+
+impl FromStr for StatsSink {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = value.strip_prefix("statsd://") {
+            return Ok(Self::Statsd(addr.to_string()));
+        }
+        if let Some(addr) = value.strip_prefix("graphite://") {
+            return Ok(Self::Graphite(addr.to_string()));
+        }
+        bail!("Expected `statsd://host:port` or `graphite://host:port`, got `{value}`")
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsReportConfig {
+    pub sink: Option<StatsSink>,
+    pub prefix: String,
+    pub dump: bool,
+}
+
+pub(crate) struct Gauge {
+    name: String,
+    value: u128,
+}
+
+pub(crate) fn collect(registry: &Registry, config: &StatsReportConfig) -> Vec<Gauge> {
+    if registry.is_empty() {
+        return Vec::new();
+    }
+    if config.dump {
+        info!(stats = format!("{registry}"));
+    }
+    emit(&registry.user_stats(), &config.prefix)
+}
+
+pub(crate) async fn push(sink: &StatsSink, gauges: &[Gauge]) {
+    if gauges.is_empty() {
+        return;
+    }
+    if let Err(err) = send(sink, gauges).await {
+        warn!(error = format!("{err:#}"), "Statistics push failed");
+    }
+}
+
+fn emit(users: &[UserStats], prefix: &str) -> Vec<Gauge> {
+    let mut gauges = Vec::new();
+    let (mut ingress, mut egress, mut concurrency) = (0, 0, 0);
+    for stats in users {
+        info!(
+            user = stats.user,
+            ingress = format!("{}", stats.traffic.ingress),
+            egress = format!("{}", stats.traffic.egress),
+            concurrency = stats.concurrency,
+            "User statistics"
+        );
+        ingress += stats.traffic.ingress;
+        egress += stats.traffic.egress;
+        concurrency += u128::from(stats.concurrency);
+        let user = metric_segment(&stats.user);
+        for (name, value) in [
+            ("ingress", stats.traffic.ingress),
+            ("egress", stats.traffic.egress),
+            ("concurrency", u128::from(stats.concurrency)),
+        ] {
+            gauges.push(Gauge {
+                name: format!("{prefix}.users.{user}.{name}"),
+                value,
+            });
+        }
+    }
+    info!(
+        users = users.len(),
+        ingress = format!("{ingress}"),
+        egress = format!("{egress}"),
+        concurrency = format!("{concurrency}"),
+        "Total statistics"
+    );
+    for (name, value) in [
+        ("users", users.len() as u128),
+        ("ingress", ingress),
+        ("egress", egress),
+        ("concurrency", concurrency),
+    ] {
+        gauges.push(Gauge {
+            name: format!("{prefix}.total.{name}"),
+            value,
+        });
+    }
+    gauges
+}
+
+fn metric_segment(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+async fn send(sink: &StatsSink, gauges: &[Gauge]) -> Result<()> {
+    match sink {
+        StatsSink::Statsd(addr) => {
+            let Some(target) = lookup_host(addr).await?.next() else {
+                bail!("StatsD address `{addr}` did not resolve");
+            };
+            let local = if target.is_ipv6() {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            };
+            let socket = UdpSocket::bind(local).await?;
+            for datagram in statsd_datagrams(gauges) {
+                socket.send_to(datagram.as_bytes(), target).await?;
+            }
+        }
+        StatsSink::Graphite(addr) => {
+            let timestamp = unix_now();
+            let mut payload = String::new();
+            for gauge in gauges {
+                writeln!(payload, "{} {} {timestamp}", gauge.name, gauge.value)?;
+            }
+            let mut stream = TcpStream::connect(addr).await?;
+            stream.write_all(payload.as_bytes()).await?;
+            stream.shutdown().await?;
+        }
+    }
+    Ok(())
+}
+
+fn statsd_datagrams(gauges: &[Gauge]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for gauge in gauges {
+        let line = format!("{}:{}|g", gauge.name, gauge.value);
+        if !current.is_empty() && current.len() + 1 + line.len() > STATSD_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{HistogramStats, TrafficStats};
+    use std::collections::BTreeMap;
+
+    fn user(name: &str, ingress: u128, egress: u128) -> UserStats {
+        UserStats {
+            user: name.to_string(),
+            traffic: TrafficStats { ingress, egress },
+            concurrency: 1,
+            countries: BTreeMap::new(),
+            destinations: Vec::new(),
+            tunnels_closed: BTreeMap::new(),
+            tunnel_bytes: HistogramStats::default(),
+            tunnel_duration_ms: HistogramStats::default(),
+        }
+    }
+
+    #[test]
+    fn emits_user_and_total_gauges() {
+        let gauges = emit(&[user("alice", 10, 20), user("bob.smith", 1, 2)], "procent");
+        let lines: Vec<_> = statsd_datagrams(&gauges)
+            .iter()
+            .flat_map(|datagram| datagram.lines().map(str::to_string).collect::<Vec<_>>())
+            .collect();
+
+        assert!(lines.contains(&"procent.users.alice.ingress:10|g".to_string()));
+        assert!(lines.contains(&"procent.users.bob_smith.egress:2|g".to_string()));
+        assert!(lines.contains(&"procent.total.ingress:11|g".to_string()));
+        assert!(lines.contains(&"procent.total.concurrency:2|g".to_string()));
+        assert!(lines.contains(&"procent.total.users:2|g".to_string()));
+    }
+
+    #[test]
+    fn parses_sinks() {
+        assert_eq!(
+            "statsd://127.0.0.1:8125".parse::<StatsSink>().unwrap(),
+            StatsSink::Statsd("127.0.0.1:8125".to_string())
+        );
+        assert_eq!(
+            "graphite://graphite:2003".parse::<StatsSink>().unwrap(),
+            StatsSink::Graphite("graphite:2003".to_string())
+        );
+        assert!("udp://127.0.0.1:8125".parse::<StatsSink>().is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use crate::http_utils::framing::ResponseFraming;
+use crate::http_utils::headers;
+use httparse::{EMPTY_HEADER, Request, Response, Status};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ForwardCacheConfig {
+    pub max_bytes: usize,
+    pub max_entry: usize,
+}
+
+impl Default for ForwardCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 0,
+            max_entry: 1 << 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CacheLookup {
+    key: String,
+    if_none_match: Option<String>,
+}
+
+impl CacheLookup {
+    pub(crate) fn of(
+        request: &Request<'_, '_>,
+        authority: &str,
+        origin_path: &str,
+    ) -> Option<Self> {
+        if request.method != Some("GET")
+            || ["Authorization", "Cookie", "Range"]
+                .iter()
+                .any(|name| headers::find(request.headers, name).is_some())
+        {
+            return None;
+        }
+        let control = headers::find_str(request.headers, "Cache-Control").unwrap_or_default();
+        if directives(control).any(|(name, _)| name == "no-store" || name == "no-cache") {
+            return None;
+        }
+        Some(Self {
+            key: format!("{}{origin_path}", authority.to_ascii_lowercase()),
+            if_none_match: headers::find_str(request.headers, "If-None-Match").map(str::to_string),
+        })
+    }
+}
+
+struct Entry {
+    head: Vec<u8>,
+    body: Vec<u8>,
+    etag: Option<String>,
+    stored: Instant,
+    age: u64,
+    fresh_for: Duration,
+    used: u64,
+}
+
+impl Entry {
+    const fn size(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    fn age(&self) -> u64 {
+        self.age + self.stored.elapsed().as_secs()
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    tick: u64,
+}
+
+pub(crate) struct ResponseCache {
+    config: ForwardCacheConfig,
+    store: Mutex<Store>,
+    hits: AtomicU64,
+    revalidated: AtomicU64,
+    misses: AtomicU64,
+    stored: AtomicU64,
+    evicted: AtomicU64,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(config: ForwardCacheConfig) -> Option<Self> {
+        (config.max_bytes > 0 && config.max_entry > 0).then(|| Self {
+            config,
+            store: Mutex::new(Store::default()),
+            hits: AtomicU64::new(0),
+            revalidated: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stored: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) const fn max_entry(&self) -> usize {
+        self.config.max_entry
+    }
+
+    pub(crate) fn fetch(&self, lookup: &CacheLookup) -> Option<Vec<u8>> {
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        store.tick += 1;
+        let tick = store.tick;
+        let fresh = store
+            .entries
+            .get(&lookup.key)
+            .map(|entry| entry.stored.elapsed() < entry.fresh_for);
+        if fresh == Some(false)
+            && let Some(entry) = store.entries.remove(&lookup.key)
+        {
+            store.bytes -= entry.size();
+        }
+        let Some(entry) = store.entries.get_mut(&lookup.key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        entry.used = tick;
+        let age = format!("Age: {}\r\n", entry.age());
+        let validated = entry
+            .etag
+            .as_deref()
+            .zip(lookup.if_none_match.as_deref())
+            .is_some_and(|(etag, candidates)| etag_matches(etag, candidates));
+        if validated {
+            self.revalidated.fetch_add(1, Ordering::Relaxed);
+            let etag = entry.etag.as_deref().unwrap_or_default();
+            return Some(
+                format!("HTTP/1.1 304 Not Modified\r\nETag: {etag}\r\n{age}\r\n").into_bytes(),
+            );
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some([&entry.head, age.as_bytes(), b"\r\n", &entry.body].concat())
+    }
+
+    pub(crate) fn store(&self, lookup: &CacheLookup, recorded: &[u8]) {
+        let mut framing = ResponseFraming::new(false);
+        let used = framing.feed(recorded);
+        if !framing.is_complete() || used > self.config.max_entry {
+            return;
+        }
+        let Some(entry) = cacheable(&recorded[..used]) else {
+            return;
+        };
+        let mut store = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        store.tick += 1;
+        let size = entry.size();
+        let entry = Entry {
+            used: store.tick,
+            ..entry
+        };
+        if let Some(previous) = store.entries.insert(lookup.key.clone(), entry) {
+            store.bytes -= previous.size();
+        }
+        store.bytes += size;
+        self.stored.fetch_add(1, Ordering::Relaxed);
+        while store.bytes > self.config.max_bytes {
+            let Some(oldest) = store
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = store.entries.remove(&oldest) {
+                store.bytes -= entry.size();
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub(crate) fn counters(&self) -> Vec<(&'static str, u64)> {
+        let bytes = self
+            .store
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bytes;
+        vec![
+            (
+                "forward_cache_hits_total",
+                self.hits.load(Ordering::Relaxed),
+            ),
+            (
+                "forward_cache_revalidated_total",
+                self.revalidated.load(Ordering::Relaxed),
+            ),
+            (
+                "forward_cache_misses_total",
+                self.misses.load(Ordering::Relaxed),
+            ),
+            (
+                "forward_cache_stored_total",
+                self.stored.load(Ordering::Relaxed),
+            ),
+            (
+                "forward_cache_evicted_total",
+                self.evicted.load(Ordering::Relaxed),
+            ),
+            ("forward_cache_bytes", bytes as u64),
+        ]
+    }
+}
+
+fn cacheable(response: &[u8]) -> Option<Entry> {
+    let mut headers = [EMPTY_HEADER; 64];
+    let mut parsed = Response::new(&mut headers);
+    let Ok(Status::Complete(offset)) = parsed.parse(response) else {
+        return None;
+    };
+    if parsed.code != Some(200)
+        || headers::find(parsed.headers, "Set-Cookie").is_some()
+        || headers::find(parsed.headers, "Vary").is_some()
+    {
+        return None;
+    }
+    let control = headers::find_str(parsed.headers, "Cache-Control").unwrap_or_default();
+    let mut max_age: Option<u64> = None;
+    for (name, value) in directives(control) {
+        match name.as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "s-maxage" => max_age = value.and_then(|value| value.parse().ok()),
+            "max-age" if max_age.is_none() => max_age = value.and_then(|value| value.parse().ok()),
+            _ => {}
+        }
+    }
+    let age: u64 = headers::find_str(parsed.headers, "Age")
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0);
+    let fresh_for = max_age.filter(|max_age| *max_age > age)? - age;
+    let status_line = response.split(|byte| *byte == b'\n').next()?;
+    let mut head = status_line.to_vec();
+    head.push(b'\n');
+    for header in parsed.headers.iter() {
+        if !header.name.eq_ignore_ascii_case("Age") {
+            head.extend_from_slice(header.name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(header.value);
+            head.extend_from_slice(b"\r\n");
+        }
+    }
+    Some(Entry {
+        head,
+        body: response[offset..].to_vec(),
+        etag: headers::find_str(parsed.headers, "ETag").map(str::to_string),
+        stored: Instant::now(),
+        age,
+        fresh_for: Duration::from_secs(fresh_for),
+        used: 0,
+    })
+}
+
+fn directives(value: &str) -> impl Iterator<Item = (String, Option<&str>)> {
+    value.split(',').filter_map(|directive| {
+        let (name, value) = directive
+            .split_once('=')
+            .map_or((directive, None), |(name, value)| (name, Some(value)));
+        let name = name.trim().to_ascii_lowercase();
+        (!name.is_empty()).then(|| (name, value.map(|value| value.trim().trim_matches('"'))))
+    })
+}
+
+fn etag_matches(etag: &str, candidates: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    candidates
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || weak(candidate) == weak(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(raw: &[u8]) -> Option<CacheLookup> {
+        let mut headers = [EMPTY_HEADER; 16];
+        let mut request = Request::new(&mut headers);
+        request.parse(raw).ok()?;
+        CacheLookup::of(&request, "Example.com:80", "/app.js")
+    }
+
+    fn cache(max_bytes: usize) -> ResponseCache {
+        ResponseCache::new(ForwardCacheConfig {
+            max_bytes,
+            ..ForwardCacheConfig::default()
+        })
+        .unwrap()
+    }
+
+    const FRESH: &[u8] = b"HTTP/1.1 200 OK\r\nCache-Control: public, max-age=60\r\nAge: 5\r\n\
+                           ETag: \"v1\"\r\nContent-Length: 5\r\n\r\nhelloEXTRA";
+
+    #[test]
+    fn serves_fresh_responses_and_answers_matching_etags() {
+        let cache = cache(1 << 20);
+        let plain = lookup(b"GET /app.js HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        assert!(cache.fetch(&plain).is_none());
+        cache.store(&plain, FRESH);
+
+        let hit = String::from_utf8(cache.fetch(&plain).unwrap()).unwrap();
+        assert!(hit.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(hit.contains("\r\nAge: 5\r\n\r\nhello"));
+        assert!(!hit.contains("EXTRA"));
+
+        let conditional =
+            lookup(b"GET /app.js HTTP/1.1\r\nIf-None-Match: W/\"v1\"\r\n\r\n").unwrap();
+        let not_modified = String::from_utf8(cache.fetch(&conditional).unwrap()).unwrap();
+        assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n"));
+
+        let counters = cache.counters();
+        assert_eq!(counters[0], ("forward_cache_hits_total", 1));
+        assert_eq!(counters[1], ("forward_cache_revalidated_total", 1));
+        assert_eq!(counters[2], ("forward_cache_misses_total", 1));
+    }
+
+    #[test]
+    fn skips_uncacheable_requests_and_responses() {
+        assert!(lookup(b"POST /app.js HTTP/1.1\r\n\r\n").is_none());
+        assert!(lookup(b"GET /app.js HTTP/1.1\r\nAuthorization: Basic x\r\n\r\n").is_none());
+        assert!(lookup(b"GET /app.js HTTP/1.1\r\nCache-Control: no-cache\r\n\r\n").is_none());
+
+        let cache = cache(1 << 20);
+        let plain = lookup(b"GET /app.js HTTP/1.1\r\n\r\n").unwrap();
+        for response in [
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..],
+            b"HTTP/1.1 200 OK\r\nCache-Control: private, max-age=60\r\nContent-Length: 2\r\n\r\nok",
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nVary: Cookie\r\nContent-Length: 2\r\n\r\nok",
+            b"HTTP/1.1 404 Not Found\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\n\r\nok",
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 9\r\n\r\ncut",
+        ] {
+            cache.store(&plain, response);
+        }
+        assert!(cache.fetch(&plain).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let cache = cache(FRESH.len() * 2);
+        let first = lookup(b"GET /app.js HTTP/1.1\r\n\r\n").unwrap();
+        let mut second = first.clone();
+        second.key.push_str("?v=2");
+        let mut third = first.clone();
+        third.key.push_str("?v=3");
+        cache.store(&first, FRESH);
+        cache.store(&second, FRESH);
+        assert!(cache.fetch(&first).is_some());
+        cache.store(&third, FRESH);
+
+        assert!(cache.fetch(&first).is_some());
+        assert!(cache.fetch(&second).is_none());
+        assert_eq!(cache.counters()[4], ("forward_cache_evicted_total", 1));
+    }
+}
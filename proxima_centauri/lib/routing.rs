@@ -0,0 +1,179 @@
+use anyhow::{Result, anyhow, bail};
+use base64::Engine as _;
+use base64::engine::general_purpose;
+use httparse::{EMPTY_HEADER, Response, Status};
+use std::fmt::Write as _;
+use std::io;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const DIRECT: &str = "direct";
+pub const TLS: &str = "tls";
+
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub hosts: String,
+    pub upstream: String,
+}
+
+impl Route {
+    pub fn new(hosts: impl Into<String>, upstream: impl Into<String>) -> Self {
+        Self {
+            hosts: hosts.into(),
+            upstream: upstream.into(),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let pattern = self.hosts.to_ascii_lowercase();
+        match pattern.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => suffix.starts_with('.') && host.ends_with(suffix),
+            None => host == pattern,
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let Some((hosts, upstream)) = value.split_once('=') else {
+            bail!("Invalid route `{value}`, expected `hosts=upstream`");
+        };
+        let (hosts, upstream) = (hosts.trim(), upstream.trim());
+        if hosts.is_empty() || upstream.is_empty() {
+            bail!("Invalid route `{value}`, expected `hosts=upstream`");
+        }
+        Ok(Self::new(hosts, upstream))
+    }
+}
+
+pub fn parse_routes(value: &str) -> Result<Vec<Route>> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|route| !route.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+pub(crate) fn select<'a>(routes: &'a [Route], host: &str) -> Option<&'a Route> {
+    routes.iter().find(|route| route.matches(host))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamProxy {
+    pub addr: String,
+    pub credentials: Option<String>,
+}
+
+impl FromStr for UpstreamProxy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (credentials, addr) = match value.rsplit_once('@') {
+            Some((credentials, addr)) => (Some(credentials.to_string()), addr),
+            None => (None, value),
+        };
+        addr.rsplit_once(':')
+            .filter(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+            .ok_or_else(|| anyhow!("Invalid upstream proxy `{addr}`, expected `host:port`"))?;
+        Ok(Self {
+            addr: addr.to_string(),
+            credentials,
+        })
+    }
+}
+
+impl UpstreamProxy {
+    pub(crate) async fn connect(&self, authority: &str) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some(credentials) = &self.credentials {
+            let token = general_purpose::STANDARD.encode(credentials);
+            let _ = write!(request, "Proxy-Authorization: Basic {token}\r\n");
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        let code = read_status(&mut stream).await?;
+        if code != 200 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Upstream proxy {} answered {code}", self.addr),
+            ));
+        }
+        Ok(stream)
+    }
+}
+
+async fn read_status(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD || stream.read(&mut byte).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.push(byte[0]);
+    }
+    let mut headers = [EMPTY_HEADER; 32];
+    let mut response = Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(Status::Complete(_)) => response
+            .code
+            .ok_or_else(|| io::ErrorKind::InvalidData.into()),
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn first_matching_route_wins() -> Result<()> {
+        let routes = parse_routes("*.internal=corp; git.example.com=direct; *=egress")?;
+        assert_eq!(
+            select(&routes, "wiki.corp.internal").unwrap().upstream,
+            "corp"
+        );
+        assert_eq!(
+            select(&routes, "GIT.example.com").unwrap().upstream,
+            "direct"
+        );
+        assert_eq!(select(&routes, "internal").unwrap().upstream, "egress");
+        assert!(select(&routes[..2], "example.org").is_none());
+        assert!(parse_routes("*.internal").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connects_through_an_upstream_proxy() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy: UpstreamProxy = format!("alice:secret@{}", listener.local_addr()?).parse()?;
+        let upstream = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = vec![0u8; 256];
+            let size = socket.read(&mut request).await?;
+            socket
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\nhello")
+                .await?;
+            anyhow::Ok(String::from_utf8_lossy(&request[..size]).into_owned())
+        });
+
+        let mut stream = proxy.connect("db.internal:5432").await?;
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).await?;
+        let request = upstream.await??;
+
+        assert_eq!(&greeting, b"hello");
+        assert!(request.starts_with("CONNECT db.internal:5432 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n"));
+        Ok(())
+    }
+}
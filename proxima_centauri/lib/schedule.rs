@@ -0,0 +1,157 @@
+use anyhow::{Context as _, Result, anyhow, bail};
+use std::fmt;
+use std::str::FromStr;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const EVERY_DAY: u8 = 0b111_1111;
+const DAY_MINUTES: u16 = 24 * 60;
+const DAY_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Window {
+    days: u8,
+    start: u16,
+    end: u16,
+}
+
+impl Window {
+    const fn on(self, day: u64) -> bool {
+        self.days & (1 << day) != 0
+    }
+
+    const fn covers(self, day: u64, minute: u16) -> bool {
+        if self.start < self.end {
+            self.on(day) && self.start <= minute && minute < self.end
+        } else {
+            (self.on(day) && minute >= self.start) || (self.on((day + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (days, hours) = match value.split_once(char::is_whitespace) {
+            Some((days, hours)) => (parse_days(days)?, hours.trim()),
+            None => (EVERY_DAY, value),
+        };
+        let (start, end) = hours
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid hours `{hours}`, expected `HH:MM-HH:MM`"))?;
+        let (start, end) = (parse_minute(start)?, parse_minute(end)?);
+        if start == end || start == DAY_MINUTES {
+            bail!("Invalid hours `{hours}`, the window is empty");
+        }
+        Ok(Self { days, start, end })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    spec: String,
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn allows(&self, unix_seconds: u64) -> bool {
+        let day = (unix_seconds / DAY_SECONDS + 3) % 7;
+        let minute = u16::try_from(unix_seconds % DAY_SECONDS / 60).unwrap_or_default();
+        self.windows.iter().any(|window| window.covers(day, minute))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let windows = value
+            .split(';')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(|window| {
+                window
+                    .parse()
+                    .with_context(|| format!("Invalid schedule window `{window}`"))
+            })
+            .collect::<Result<Vec<Window>>>()?;
+        if windows.is_empty() {
+            bail!("Schedule `{value}` has no windows");
+        }
+        Ok(Self {
+            spec: value.trim().to_string(),
+            windows,
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+fn parse_days(value: &str) -> Result<u8> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|day| day.eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| anyhow!("Unknown day `{name}`, expected one of {}", DAYS.join(", ")))
+    };
+    let (first, last) = match value.split_once('-') {
+        Some((first, last)) => (day(first)?, day(last)?),
+        None => (day(value)?, day(value)?),
+    };
+    let span = (last + 7 - first) % 7;
+    Ok((first..=first + span).fold(0, |days, day| days | (1 << (day % 7))))
+}
+
+fn parse_minute(value: &str) -> Result<u16> {
+    let invalid = || anyhow!("Invalid time `{value}`, expected `HH:MM`");
+    let (hours, minutes) = value.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u16 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 24 || minutes >= 60 || hours * 60 + minutes > DAY_MINUTES {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONDAY: u64 = 4 * DAY_SECONDS;
+
+    fn at(day: u64, hour: u64, minute: u64) -> u64 {
+        MONDAY + day * DAY_SECONDS + hour * 3600 + minute * 60
+    }
+
+    #[test]
+    fn schedules_cover_their_days_and_hours() -> Result<()> {
+        let office: Schedule = "mon-fri 08:00-20:00; sat 10:00-14:00".parse()?;
+        assert!(office.allows(at(0, 8, 0)));
+        assert!(office.allows(at(4, 19, 59)));
+        assert!(!office.allows(at(4, 20, 0)));
+        assert!(office.allows(at(5, 12, 0)));
+        assert!(!office.allows(at(6, 12, 0)));
+        assert_eq!(office.to_string(), "mon-fri 08:00-20:00; sat 10:00-14:00");
+
+        let night: Schedule = "fri-sun 22:00-06:00".parse()?;
+        assert!(night.allows(at(4, 23, 0)));
+        assert!(night.allows(at(0, 5, 59)));
+        assert!(!night.allows(at(0, 22, 0)));
+        assert!("00:00-24:00".parse::<Schedule>()?.allows(at(2, 23, 59)));
+
+        for invalid in [
+            "",
+            "mon",
+            "mon 08:00",
+            "xyz 08:00-09:00",
+            "09:00-09:00",
+            "08:60-09:00",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use crate::http_utils::request::parse_forward_target;
+use anyhow::{Context as _, Result, anyhow, bail};
+use serde_json::Value;
+use std::cell::{Cell, RefCell};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const VAULT_PREFIX: &str = "vault:";
+const VAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Vault {
+    addr: String,
+    token: String,
+}
+
+pub(crate) struct Secrets {
+    vault: Option<Vault>,
+    lease: Cell<Option<Duration>>,
+    errors: RefCell<Vec<String>>,
+}
+
+impl Secrets {
+    pub(crate) fn from_env() -> Self {
+        let mut secrets = Self::new(None);
+        let token = mounted(
+            dotenv::var("VAULT_TOKEN").ok(),
+            dotenv::var("VAULT_TOKEN_FILE").ok(),
+        );
+        match token {
+            Ok(token) => {
+                secrets.vault = token.map(|token| Vault {
+                    addr: dotenv::var("VAULT_ADDR")
+                        .unwrap_or_else(|_| String::from("http://127.0.0.1:8200")),
+                    token,
+                });
+            }
+            Err(err) => secrets.fail("VAULT_TOKEN", &err),
+        }
+        secrets
+    }
+
+    const fn new(vault: Option<Vault>) -> Self {
+        Self {
+            vault,
+            lease: Cell::new(None),
+            errors: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn var(&self, name: &str) -> Option<String> {
+        let file = dotenv::var(format!("{name}_FILE")).ok();
+        self.resolve(name, dotenv::var(name).ok(), file)
+    }
+
+    fn resolve(&self, name: &str, value: Option<String>, file: Option<String>) -> Option<String> {
+        let value = match mounted(value, file) {
+            Ok(value) => value?,
+            Err(err) => {
+                self.fail(name, &err);
+                return None;
+            }
+        };
+        let Some(reference) = value.strip_prefix(VAULT_PREFIX) else {
+            return Some(value);
+        };
+        let Some(vault) = &self.vault else {
+            self.fail(
+                name,
+                &anyhow!("`{value}` needs VAULT_TOKEN or VAULT_TOKEN_FILE"),
+            );
+            return None;
+        };
+        match read_vault(vault, reference) {
+            Ok((secret, lease)) => {
+                if let Some(lease) = lease {
+                    self.lease
+                        .set(Some(self.lease.get().map_or(lease, |old| old.min(lease))));
+                }
+                Some(secret)
+            }
+            Err(err) => {
+                self.fail(name, &err);
+                None
+            }
+        }
+    }
+
+    fn fail(&self, name: &str, err: &anyhow::Error) {
+        self.errors
+            .borrow_mut()
+            .push(format!("Secret {name} could not be resolved: {err:#}"));
+    }
+
+    pub(crate) const fn lease(&self) -> Option<Duration> {
+        self.lease.get()
+    }
+
+    pub(crate) fn errors(&self) -> Vec<String> {
+        self.errors.borrow().clone()
+    }
+}
+
+fn mounted(value: Option<String>, file: Option<String>) -> Result<Option<String>> {
+    let Some(path) = file else {
+        return Ok(value);
+    };
+    let contents =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read `{path}`"))?;
+    Ok(Some(contents.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn read_vault(vault: &Vault, reference: &str) -> Result<(String, Option<Duration>)> {
+    let Some((path, field)) = reference.split_once('#') else {
+        bail!("Vault reference `{reference}` must be `path#field`");
+    };
+    let url = format!(
+        "{}/v1/{}",
+        vault.addr.trim_end_matches('/'),
+        path.trim_matches('/')
+    );
+    let target = parse_forward_target(&url)
+        .ok_or_else(|| anyhow!("VAULT_ADDR `{}` must be an http:// URL", vault.addr))?;
+    let addr = target
+        .authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Vault address `{}` did not resolve", target.authority))?;
+    let mut stream = TcpStream::connect_timeout(&addr, VAULT_TIMEOUT)?;
+    stream.set_read_timeout(Some(VAULT_TIMEOUT))?;
+    stream.set_write_timeout(Some(VAULT_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nX-Vault-Token: {}\r\n\r\n",
+        target.origin_path, target.authority, vault.token
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("Vault sent a malformed response"))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("Vault answered `{status}` for `{path}`");
+    }
+    let body: Value = serde_json::from_str(body)?;
+    let data = &body["data"];
+    let secret = match data["data"].get(field).or_else(|| data.get(field)) {
+        Some(Value::String(secret)) => secret.clone(),
+        Some(secret) => secret.to_string(),
+        None => bail!("Vault secret `{path}` has no field `{field}`"),
+    };
+    let lease = body["lease_duration"]
+        .as_u64()
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs);
+    Ok((secret, lease))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn serve_once(reply: &'static str) -> Result<(String, thread::JoinHandle<String>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = format!("http://{}", listener.local_addr()?);
+        let handle = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let size = socket.read(&mut request).unwrap();
+            socket.write_all(reply.as_bytes()).unwrap();
+            String::from_utf8_lossy(&request[..size]).into_owned()
+        });
+        Ok((addr, handle))
+    }
+
+    #[test]
+    fn reads_mounted_files_and_plain_values() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-secret-{}", std::process::id()));
+        std::fs::write(&path, "s3cret\n")?;
+        let secrets = Secrets::new(None);
+        let file = Some(path.display().to_string());
+
+        assert_eq!(
+            secrets
+                .resolve("KEY", Some("plain".into()), file)
+                .as_deref(),
+            Some("s3cret")
+        );
+        assert_eq!(
+            secrets
+                .resolve("KEY", Some("plain".into()), None)
+                .as_deref(),
+            Some("plain")
+        );
+        assert_eq!(
+            secrets.resolve("KEY", None, Some("/nonexistent".into())),
+            None
+        );
+        assert_eq!(
+            secrets.resolve("KEY", Some("vault:kv#key".into()), None),
+            None
+        );
+        assert_eq!(secrets.errors().len(), 2);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_vault_references_and_tracks_leases() -> Result<()> {
+        let (addr, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n\
+             {\"lease_duration\":600,\"data\":{\"data\":{\"password\":\"hunter2\"}}}",
+        )?;
+        let secrets = Secrets::new(Some(Vault {
+            addr,
+            token: "root".to_string(),
+        }));
+
+        let value = Some("vault:secret/data/procent#password".to_string());
+        assert_eq!(
+            secrets.resolve("REDIS", value, None).as_deref(),
+            Some("hunter2")
+        );
+        assert_eq!(secrets.lease(), Some(Duration::from_mins(10)));
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /v1/secret/data/procent HTTP/1.0\r\n"));
+        assert!(request.contains("X-Vault-Token: root\r\n"));
+        Ok(())
+    }
+}
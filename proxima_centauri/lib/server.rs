@@ -1,64 +1,631 @@
-use crate::auth::Database;
-use crate::config::{build_config, init};
-use crate::context::{Context};
-use crate::handler::handle_connection;
+use crate::admin;
+use crate::anomaly;
+use crate::auth::{AuthProvider, Database, load_rows, screen_users};
+use crate::category::Categorizer;
+use crate::clock::{self, Clock};
+use crate::config::{Config, ListenerConfig, ScheduleEnforcement, build_config, init};
+use crate::context::{Context, SharedContext};
+use crate::dial::{set_keepalive, set_socket_options};
+use crate::error::{ProxyError, Result};
+use crate::handler::{handle_connection, shadowed};
+use crate::hooks::{HookChain, Hooks};
+use crate::http_utils::response::ProxyResponse;
+use crate::info::RuntimeInfo;
+use crate::intercept::{Inspector, PassThrough};
+use crate::metrics::Metrics;
 use crate::registry::Registry;
-use anyhow::Result;
+use crate::reporter;
+use crate::stats::{StatsHandle, StatsSnapshot};
+use crate::store::{self, RegistryStore};
+use crate::transparent::{self, TransparentListener};
+use crate::tunnel::CloseReason;
+use arc_swap::ArcSwap;
+use socket2::{Domain, Socket, Type};
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
-use tokio::time::sleep;
-use tracing::{debug, info, span, Level};
+use tokio::net::{TcpListener, TcpStream, lookup_host};
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, debug, field, info, info_span, warn};
 
-pub struct Server {}
+const LISTEN_BACKLOG: i32 = 1024;
+
+type ConfigLoader = Arc<dyn Fn() -> Config + Send + Sync>;
+
+pub struct Server {
+    ctx: Context,
+    listener: TcpListener,
+    admin_listener: Option<TcpListener>,
+    transparent_listener: Option<TransparentListener>,
+    shutdown: CancellationToken,
+    drain: CancellationToken,
+    reload: Arc<Notify>,
+    config_loader: ConfigLoader,
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Option<Config>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    registry: Option<Registry>,
+    store: Option<Arc<dyn RegistryStore>>,
+    clock: Option<Arc<dyn Clock>>,
+    hooks: Vec<Arc<dyn Hooks>>,
+    inspector: Option<Arc<dyn Inspector>>,
+    categorizer: Option<Arc<dyn Categorizer>>,
+    listener: Option<TcpListener>,
+    std_listener: Option<std::net::TcpListener>,
+    admin_listener: Option<TcpListener>,
+    shutdown: Option<CancellationToken>,
+    drain: Option<CancellationToken>,
+    config_loader: Option<ConfigLoader>,
+}
+
+#[derive(Clone)]
+pub struct ReloadHandle {
+    notify: Arc<Notify>,
+}
+
+impl ReloadHandle {
+    pub fn reload(&self) {
+        self.notify.notify_one();
+    }
+}
+
+impl ServerBuilder {
+    #[must_use]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    #[must_use]
+    pub fn auth_provider(mut self, auth: impl AuthProvider + 'static) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    #[must_use]
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    #[must_use]
+    pub fn store(mut self, store: impl RegistryStore + 'static) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    #[must_use]
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    #[must_use]
+    pub fn hook(mut self, hooks: impl Hooks + 'static) -> Self {
+        self.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    #[must_use]
+    pub fn inspector(mut self, inspector: impl Inspector + 'static) -> Self {
+        self.inspector = Some(Arc::new(inspector));
+        self
+    }
+
+    #[must_use]
+    pub fn categorizer(mut self, categorizer: impl Categorizer + 'static) -> Self {
+        self.categorizer = Some(Arc::new(categorizer));
+        self
+    }
+
+    #[must_use]
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    #[must_use]
+    pub fn std_listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.std_listener = Some(listener);
+        self
+    }
+
+    #[must_use]
+    pub fn admin_listener(mut self, listener: TcpListener) -> Self {
+        self.admin_listener = Some(listener);
+        self
+    }
+
+    #[must_use]
+    pub fn shutdown_token(mut self, token: CancellationToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    #[must_use]
+    pub fn drain_token(mut self, token: CancellationToken) -> Self {
+        self.drain = Some(token);
+        self
+    }
+
+    #[must_use]
+    pub fn config_loader(mut self, loader: impl Fn() -> Config + Send + Sync + 'static) -> Self {
+        self.config_loader = Some(Arc::new(loader));
+        self
+    }
+
+    pub async fn build(self) -> Result<Server> {
+        let config = match self.config {
+            Some(config) => config,
+            None => load_config(Arc::new(build_config)).await?,
+        };
+        let report = config.validate();
+        for warning in &report.warnings {
+            warn!("Configuration: {warning}");
+        }
+        if !report.is_ok() {
+            return Err(ProxyError::Config(format!("\n{report}")));
+        }
+        let listener = match (self.listener, self.std_listener) {
+            (Some(listener), _) => listener,
+            (None, Some(listener)) => {
+                listener
+                    .set_nonblocking(true)
+                    .map_err(ProxyError::bind("inherited listener"))?;
+                TcpListener::from_std(listener).map_err(ProxyError::bind("inherited listener"))?
+            }
+            (None, None) => bind(&config).await?,
+        };
+        let admin_listener = match (self.admin_listener, config.admin_addr.as_deref()) {
+            (Some(listener), _) => Some(listener),
+            (None, Some(addr)) => Some(
+                TcpListener::bind(addr)
+                    .await
+                    .map_err(ProxyError::bind(addr))?,
+            ),
+            (None, None) => None,
+        };
+        let transparent_listener = match &config.transparent {
+            Some(transparent) => Some(
+                TransparentListener::bind(transparent)
+                    .await
+                    .map_err(ProxyError::backend)?,
+            ),
+            None => None,
+        };
+        let auth_kind = match (&self.auth, &config.users_file) {
+            (Some(_), _) => "custom",
+            (None, Some(_)) => "users_file",
+            (None, None) => "built-in",
+        };
+        let auth = match self.auth {
+            Some(auth) => auth,
+            None => default_auth(&config)?,
+        };
+        let clock = self.clock.unwrap_or_else(clock::system);
+        let registry = self.registry.unwrap_or_else(|| Registry::with_clock(clock.clone()));
+        let registry = Arc::new(Mutex::new(registry));
+        let store = match self.store {
+            Some(store) => store,
+            None => store::open(&config.store, registry.clone()).map_err(ProxyError::backend)?,
+        };
+        let hooks = HookChain::new(self.hooks);
+        let inspector = self.inspector.unwrap_or_else(|| Arc::new(PassThrough));
+        let admin_addr = admin_listener.as_ref().map(TcpListener::local_addr);
+        let transparent_addr = transparent_listener
+            .as_ref()
+            .map(TransparentListener::local_addr);
+        let runtime = RuntimeInfo::new(
+            [
+                ("proxy", Some(listener.local_addr())),
+                ("admin", admin_addr),
+                ("transparent", transparent_addr),
+            ]
+            .into_iter()
+            .filter_map(|(name, addr)| Some((name, addr?.ok()?))),
+            auth_kind,
+        );
+        Ok(Server {
+            ctx: Context::new(
+                config,
+                auth,
+                registry,
+                store,
+                clock,
+                (hooks, inspector, self.categorizer),
+                runtime,
+            )
+            .await
+            .map_err(ProxyError::backend)?,
+            listener,
+            admin_listener,
+            transparent_listener,
+            shutdown: self.shutdown.unwrap_or_default(),
+            drain: self.drain.unwrap_or_default(),
+            reload: Arc::new(Notify::new()),
+            config_loader: self.config_loader.unwrap_or_else(|| Arc::new(build_config)),
+        })
+    }
+}
 
 impl Server {
-    pub async fn run() -> Result<()> {
-        Self::run_on_addr(None).await
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
     }
 
-    pub async fn run_on_addr(addr: Option<String>) -> Result<()> {
+    pub async fn bind(addr: Option<String>) -> Result<Self> {
         init();
-        let config = build_config();
-        let database = Database::new_persistence();
-        let registry = Registry::new();
-        let ctx = Context::new(
-            config,
-            database,
-            registry,
+        let config = load_config(Arc::new(build_config)).await?;
+        let mut builder = Self::builder();
+        if let Some(addr) = addr {
+            let listener = TcpListener::bind(&addr)
+                .await
+                .map_err(ProxyError::bind(&addr))?;
+            builder = builder.listener(listener);
+        }
+        builder.config(config).build().await
+    }
+
+    pub async fn run_on_addr(addr: Option<String>) -> Result<()> {
+        Self::bind(addr).await?.run().await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    pub fn drain_token(&self) -> CancellationToken {
+        self.drain.clone()
+    }
+
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            notify: self.reload.clone(),
+        }
+    }
+
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle::new(self.ctx.registry.clone(), self.ctx.metrics.clone())
+    }
+
+    pub async fn stats_snapshot(&self) -> StatsSnapshot {
+        self.stats_handle().snapshot().await
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let Self {
+            mut ctx,
+            listener,
+            admin_listener,
+            transparent_listener,
+            shutdown,
+            drain,
+            reload,
+            config_loader,
+        } = self;
+        tokio::spawn(
+            housekeeping(ctx.clone(), shutdown.clone()).instrument(info_span!("housekeeping")),
         );
-        let bind_addr = addr.unwrap_or_else(|| ctx.config.addr());
-        let global_span = span!(Level::TRACE, "global-log-tracer");
-        let _ = global_span.enter();
-        let ctx_copy = ctx.clone();
-        tokio::spawn(async move{
-            loop {
-                sleep(Duration::from_secs(10)).await;
-                let stats_guard = ctx_copy.registry.lock().await;
-                if !stats_guard.is_empty() {
-                    info!(stats = format!("{}", stats_guard));
+        if let Some(ledger) = ctx.ledger.clone() {
+            let interval = Duration::from_secs(ctx.config.ledger_rollup);
+            let rollup_shutdown = shutdown.clone();
+            tokio::spawn(
+                async move {
+                    loop {
+                        tokio::select! {
+                            () = rollup_shutdown.cancelled() => break,
+                            () = sleep(interval) => {}
+                        }
+                        if let Err(err) = ledger.rollup().await {
+                            warn!(error = format!("{err}"), "Usage ledger rollup failed");
+                        }
+                    }
                 }
+                .instrument(info_span!("ledger")),
+            );
+        }
+        if let Some(lease) = ctx.config.secret_lease {
+            tokio::spawn(refresh_secrets(reload.clone(), lease, shutdown.clone()));
+        }
+        if let Some(anomaly) = ctx.config.anomaly {
+            let watch = anomaly::watch(ctx.clone(), anomaly, shutdown.clone());
+            tokio::spawn(watch.instrument(info_span!("anomaly")));
+        }
+        let live: SharedContext = Arc::new(ArcSwap::from_pointee(ctx.clone()));
+        if let Some(admin_listener) = admin_listener {
+            let serve = admin::serve(admin_listener, live.clone(), shutdown.clone());
+            tokio::spawn(serve.instrument(info_span!("admin")));
+        }
+        let slots = Arc::new(Semaphore::new(slot_count(ctx.config.max_connections)));
+        let tracker = TaskTracker::new();
+        if let Some(transparent_listener) = transparent_listener {
+            let admission = (slots.clone(), tracker.clone());
+            let stop = (shutdown.clone(), drain.clone());
+            let serve = transparent::serve(transparent_listener, live.clone(), admission, stop);
+            tokio::spawn(serve.instrument(info_span!("transparent")));
+        }
+        info!("Server started on {}", listener.local_addr()?);
+        ctx.runtime.log_banner(&ctx.config);
+
+        let reload = (reload.as_ref(), &config_loader, &live);
+        let admission = (&slots, &tracker);
+        if accept_loop(&mut ctx, listener, &shutdown, &drain, reload, admission).await? {
+            info!(
+                connections = tracker.len(),
+                "Server draining, listener closed"
+            );
+            tracker.close();
+            let drain_timeout = Duration::from_secs(ctx.config.listener.drain_timeout);
+            let drained = tokio::select! {
+                () = shutdown.cancelled() => false,
+                drained = timeout(drain_timeout, tracker.wait()) => drained.is_ok(),
+            };
+            if !drained {
+                warn!(
+                    connections = tracker.len(),
+                    "Drain timeout elapsed with connections still open"
+                );
             }
-        });
-        info!("Server started on {}", bind_addr);
-        let listener = TcpListener::bind(&bind_addr).await?;
-
-        loop {
-            let (socket, socket_addr) = listener.accept().await?;
-            let socket_span = span!(
-                Level::TRACE,
-                "socket-log-tracer",
-                socket_addr = format!("{:?}", socket_addr)
+            shutdown.cancel();
+        } else {
+            info!("Server shutdown requested");
+        }
+        ctx.store.flush().await.map_err(ProxyError::backend)?;
+        Ok(())
+    }
+}
+
+fn default_auth(config: &Config) -> Result<Arc<dyn AuthProvider>> {
+    let database = match &config.users_file {
+        Some(path) => Database::with_users(
+            load_rows(path, config.users_key.as_deref())
+                .and_then(|rows| screen_users(path, rows, config))
+                .map_err(ProxyError::config)?,
+        ),
+        None => Database::new_persistence(),
+    };
+    database.set_plans(config.plans.clone());
+    Ok(Arc::new(database))
+}
+
+async fn housekeeping(ctx: Context, shutdown: CancellationToken) {
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => break,
+            () = sleep(Duration::from_secs(10)) => {}
+        }
+        if let Err(err) = ctx.store.flush().await {
+            warn!(error = format!("{err}"), "Registry store flush failed");
+        }
+        ctx.auth_audit.evict_expired();
+        if ctx.config.schedule_enforcement == ScheduleEnforcement::Tunnel {
+            enforce_schedules(&ctx).await;
+        }
+        let mut stats_guard = ctx.registry.lock().await;
+        if stats_guard.roll_traffic_window() {
+            info!("Traffic window rolled over, quotas reset");
+        }
+        let evicted =
+            stats_guard.evict_expired_sessions(Duration::from_secs(ctx.config.session_ttl));
+        let report = &ctx.config.stats_report;
+        let gauges = reporter::collect(&stats_guard, report);
+        drop(stats_guard);
+        if let Some(sink) = &report.sink {
+            reporter::push(sink, &gauges).await;
+        }
+        if let Some(pool) = &ctx.egress {
+            for session_id in evicted {
+                pool.forget_session(session_id);
+            }
+            for usage in pool.usage() {
+                info!(
+                    egress_addr = format!("{}", usage.addr),
+                    connections = usage.connections,
+                    bytes = usage.bytes
+                );
+            }
+        }
+    }
+}
+
+async fn enforce_schedules(ctx: &Context) {
+    let now = ctx.clock.unix_now();
+    for user in ctx.connections.users() {
+        match ctx.auth.schedule(&user).await {
+            Ok(Some(schedule)) if !schedule.allows(now) => {
+                if shadowed(ctx, CloseReason::OutsideSchedule.as_str()) {
+                    continue;
+                }
+                let tunnels = ctx
+                    .connections
+                    .kill_user(&user, CloseReason::OutsideSchedule);
+                info!(
+                    user = user,
+                    tunnels = tunnels,
+                    "Closing tunnels outside the user's schedule"
+                );
+            }
+            Ok(_) => {}
+            Err(err) => warn!(
+                user = user,
+                error = format!("{err}"),
+                "Cannot load the user's schedule"
+            ),
+        }
+    }
+}
+
+async fn refresh_secrets(reload: Arc<Notify>, lease: Duration, shutdown: CancellationToken) {
+    let period = (lease * 2 / 3).max(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            () = shutdown.cancelled() => break,
+            () = sleep(period) => {}
+        }
+        info!("Secret lease is expiring, reloading configuration");
+        reload.notify_one();
+    }
+}
+
+async fn load_config(loader: ConfigLoader) -> Result<Config> {
+    tokio::task::spawn_blocking(move || loader())
+        .await
+        .map_err(ProxyError::backend)
+}
+
+async fn apply_reload(
+    ctx: &mut Context,
+    loader: &ConfigLoader,
+    live: &SharedContext,
+    slots: &Arc<Semaphore>,
+) {
+    let reloaded = match load_config(loader.clone()).await {
+        Ok(config) => ctx.reloaded(config).await,
+        Err(err) => Err(err.into()),
+    };
+    match reloaded {
+        Ok(reloaded) => {
+            resize_slots(
+                slots,
+                slot_count(ctx.config.max_connections),
+                slot_count(reloaded.config.max_connections),
             );
-            let _guard = socket_span.enter();
-            debug!("Socket connection accepted {socket_addr}");
-            let ctx_copy = ctx.clone();
-            tokio::spawn(async {
-                handle_connection(
-                    socket,
-                    ctx_copy
-                )
-                .await
-            });
+            *ctx = reloaded;
+            live.store(Arc::new(ctx.clone()));
+            info!("Configuration reloaded");
         }
+        Err(err) => warn!(
+            error = format!("{err}"),
+            "Configuration reload failed, keeping previous settings"
+        ),
     }
 }
+
+async fn accept_loop(
+    ctx: &mut Context,
+    listener: TcpListener,
+    shutdown: &CancellationToken,
+    drain: &CancellationToken,
+    (reload, loader, live): (&Notify, &ConfigLoader, &SharedContext),
+    (slots, tracker): (&Arc<Semaphore>, &TaskTracker),
+) -> Result<bool> {
+    let accept_wait = Duration::from_millis(ctx.config.accept_wait);
+    loop {
+        let permit = tokio::select! {
+            () = shutdown.cancelled() => return Ok(false),
+            () = drain.cancelled() => return Ok(true),
+            () = reload.notified() => {
+                apply_reload(ctx, loader, live, slots).await;
+                continue;
+            }
+            permit = acquire_slot(slots, accept_wait) => permit,
+        };
+        let (socket, socket_addr) = tokio::select! {
+            () = shutdown.cancelled() => return Ok(false),
+            () = drain.cancelled() => return Ok(true),
+            () = reload.notified() => {
+                apply_reload(ctx, loader, live, slots).await;
+                continue;
+            }
+            accepted = listener.accept() => accepted?,
+        };
+        let Some(permit) = permit.or_else(|| slots.clone().try_acquire_owned().ok()) else {
+            shed(socket, &ctx.metrics, socket_addr);
+            continue;
+        };
+        let span = info_span!("connection", client = %socket_addr, user = field::Empty);
+        set_keepalive(&socket, ctx.config.keepalive);
+        set_socket_options(&socket, ctx.config.socket);
+        let ctx_copy = ctx.clone();
+        tracker.spawn(
+            async move {
+                debug!("Socket connection accepted");
+                ctx_copy.metrics.connection_opened();
+                let result = handle_connection(socket, ctx_copy.clone()).await;
+                ctx_copy.metrics.connection_closed();
+                drop(permit);
+                result
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn bind(config: &Config) -> Result<TcpListener> {
+    let listen = config.addr();
+    let addr = lookup_host(&listen)
+        .await
+        .map_err(ProxyError::bind(&listen))?
+        .next()
+        .ok_or_else(|| {
+            ProxyError::bind(&listen)(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "address did not resolve",
+            ))
+        })?;
+    listen_on(addr, config.listener).map_err(ProxyError::bind(&listen))
+}
+
+fn listen_on(addr: SocketAddr, config: ListenerConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        socket.set_only_v6(config.v6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn shed(socket: TcpStream, metrics: &Metrics, socket_addr: SocketAddr) {
+    Metrics::inc(&metrics.connections_shed);
+    warn!("Connection limit reached, shedding {socket_addr}");
+    if let Ok(mut socket) = socket.into_std() {
+        let _ = socket.write(&ProxyResponse::ServiceUnavailable.to_bytes());
+    }
+}
+
+const fn slot_count(max_connections: usize) -> usize {
+    match max_connections {
+        0 => Semaphore::MAX_PERMITS,
+        max => max,
+    }
+}
+
+fn resize_slots(slots: &Arc<Semaphore>, from: usize, to: usize) {
+    if to >= from {
+        slots.add_permits(to - from);
+        return;
+    }
+    let held = u32::try_from(from - to - slots.forget_permits(from - to)).unwrap_or(u32::MAX);
+    if held > 0 {
+        let slots = slots.clone();
+        tokio::spawn(async move {
+            if let Ok(permits) = slots.acquire_many_owned(held).await {
+                permits.forget();
+            }
+        });
+    }
+}
+
+async fn acquire_slot(slots: &Arc<Semaphore>, wait: Duration) -> Option<OwnedSemaphorePermit> {
+    timeout(wait, slots.clone().acquire_owned())
+        .await
+        .ok()
+        .and_then(Result::ok)
+}
@@ -0,0 +1,214 @@
+use crate::clock::{self, Clock, unix_now};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub user: String,
+    pub client_ip: IpAddr,
+    pub started_at: u64,
+    pub tunnels: u64,
+    pub active_tunnels: u16,
+    pub ingress: u128,
+    pub egress: u128,
+    #[serde(skip)]
+    last_seen_at: Instant,
+}
+
+impl Session {
+    fn new(id: u64, user: &str, client_ip: IpAddr, now: Instant) -> Self {
+        Self {
+            id,
+            user: user.to_string(),
+            client_ip,
+            started_at: unix_now(),
+            tunnels: 0,
+            active_tunnels: 0,
+            ingress: 0,
+            egress: 0,
+            last_seen_at: now,
+        }
+    }
+
+    fn is_alive(&self, now: Instant, ttl: Duration) -> bool {
+        self.active_tunnels > 0 || now.saturating_duration_since(self.last_seen_at) < ttl
+    }
+}
+
+impl Display for Session {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Session `{}` of `{}` from {}. tunnels: {} (active {}), ingress: {}, egress: {}",
+            self.id,
+            self.user,
+            self.client_ip,
+            self.tunnels,
+            self.active_tunnels,
+            self.ingress,
+            self.egress
+        )
+    }
+}
+
+pub(crate) struct Sessions {
+    next_id: u64,
+    inner: HashMap<u64, Session>,
+    by_client: HashMap<(String, IpAddr), u64>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::with_clock(clock::system())
+    }
+}
+
+impl Sessions {
+    pub(crate) fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            next_id: 0,
+            inner: HashMap::new(),
+            by_client: HashMap::new(),
+            clock,
+        }
+    }
+
+    pub(crate) fn attach(&mut self, user: &str, client_ip: IpAddr, ttl: Duration) -> u64 {
+        let key = (user.to_string(), client_ip);
+        let now = self.clock.now();
+        let existing = self
+            .by_client
+            .get(&key)
+            .and_then(|id| self.inner.get_mut(id))
+            .filter(|session| session.is_alive(now, ttl));
+
+        let session = if let Some(session) = existing {
+            session
+        } else {
+            self.next_id += 1;
+            let id = self.next_id;
+            self.by_client.insert(key, id);
+            self.inner
+                .entry(id)
+                .or_insert_with(|| Session::new(id, user, client_ip, now))
+        };
+        session.tunnels += 1;
+        session.active_tunnels += 1;
+        session.last_seen_at = now;
+        session.id
+    }
+
+    pub(crate) fn detach(&mut self, id: u64, ingress: u128, egress: u128) {
+        if let Some(session) = self.inner.get_mut(&id) {
+            session.ingress += ingress;
+            session.egress += egress;
+            session.active_tunnels = session.active_tunnels.saturating_sub(1);
+            session.last_seen_at = self.clock.now();
+        }
+    }
+
+    pub(crate) fn evict_expired(&mut self, ttl: Duration) -> Vec<u64> {
+        let now = self.clock.now();
+        let mut evicted = Vec::new();
+        self.inner.retain(|id, session| {
+            let alive = session.is_alive(now, ttl);
+            if !alive {
+                evicted.push(*id);
+            }
+            alive
+        });
+        self.by_client.retain(|_, id| self.inner.contains_key(id));
+        evicted
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.inner.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn tunnels_within_ttl_share_session() {
+        let mut sessions = Sessions::default();
+        let ttl = Duration::from_mins(5);
+
+        let first = sessions.attach("alice", CLIENT, ttl);
+        sessions.detach(first, 100, 200);
+        let second = sessions.attach("alice", CLIENT, ttl);
+
+        assert_eq!(first, second);
+        let session = sessions.iter().next().unwrap();
+        assert_eq!(session.tunnels, 2);
+        assert_eq!(session.ingress, 100);
+        assert_eq!(session.egress, 200);
+    }
+
+    #[test]
+    fn different_client_ip_gets_new_session() {
+        let mut sessions = Sessions::default();
+        let ttl = Duration::from_mins(5);
+
+        let first = sessions.attach("alice", CLIENT, ttl);
+        let second = sessions.attach("alice", OTHER_CLIENT, ttl);
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn idle_session_expires_after_ttl() {
+        let mut sessions = Sessions::default();
+
+        let first = sessions.attach("alice", CLIENT, Duration::ZERO);
+        sessions.detach(first, 0, 0);
+        let second = sessions.attach("alice", CLIENT, Duration::ZERO);
+        assert_ne!(first, second);
+
+        sessions.detach(second, 0, 0);
+        sessions.evict_expired(Duration::ZERO);
+        assert_eq!(sessions.iter().count(), 0);
+    }
+
+    #[test]
+    fn active_session_is_not_evicted() {
+        let mut sessions = Sessions::default();
+
+        sessions.attach("alice", CLIENT, Duration::ZERO);
+        sessions.evict_expired(Duration::ZERO);
+
+        assert_eq!(sessions.iter().count(), 1);
+    }
+
+    #[test]
+    fn idle_session_is_evicted_once_the_clock_passes_its_ttl() {
+        let clock = MockClock::new();
+        let mut sessions = Sessions::with_clock(clock.clone());
+        let ttl = Duration::from_mins(5);
+
+        let id = sessions.attach("alice", CLIENT, ttl);
+        clock.advance(Duration::from_hours(1));
+        assert!(sessions.evict_expired(ttl).is_empty());
+
+        sessions.detach(id, 0, 0);
+        clock.advance(Duration::from_secs(299));
+        assert!(sessions.evict_expired(ttl).is_empty());
+        assert_eq!(sessions.attach("alice", CLIENT, ttl), id);
+        sessions.detach(id, 0, 0);
+
+        clock.advance(ttl);
+        assert_eq!(sessions.evict_expired(ttl), [id]);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::server::Server;
+use anyhow::Result;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{info, warn};
+
+pub fn install_signal_handlers(server: &Server) -> Result<()> {
+    let shutdown = server.shutdown_token();
+    let drain = server.drain_token();
+    let reload = server.reload_handle();
+    let stats = server.stats_handle();
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut dump = signal(SignalKind::user_defined1())?;
+    let mut upgrade = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = hangup.recv() => {
+                    info!("SIGHUP received, reloading configuration");
+                    reload.reload();
+                }
+                Some(()) = terminate.recv() => {
+                    if drain.is_cancelled() {
+                        warn!("SIGTERM received while draining, shutting down");
+                        shutdown.cancel();
+                    } else {
+                        info!("SIGTERM received, draining connections");
+                        drain.cancel();
+                    }
+                }
+                Some(()) = upgrade.recv() => {
+                    info!("SIGUSR2 received, handing over to the new process");
+                    drain.cancel();
+                }
+                Some(()) = dump.recv() => match serde_json::to_string(&stats.snapshot().await) {
+                    Ok(snapshot) => info!(snapshot = snapshot, "Registry snapshot"),
+                    Err(err) => warn!(error = format!("{err}"), "Registry snapshot failed"),
+                },
+                () = shutdown.cancelled() => break,
+            }
+        }
+    });
+    Ok(())
+}
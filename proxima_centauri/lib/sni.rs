@@ -0,0 +1,223 @@
+use anyhow::{Result, bail};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, timeout_at};
+
+const RECORD_HEADER: usize = 5;
+const MAX_RECORD: usize = 16 * 1024;
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME: u16 = 0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SniMode {
+    #[default]
+    Off,
+    Log,
+    Enforce,
+}
+
+impl FromStr for SniMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "off" => Ok(Self::Off),
+            "log" => Ok(Self::Log),
+            "enforce" => Ok(Self::Enforce),
+            _ => bail!("Unknown SNI mode `{value}`, expected off, log or enforce"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SniPolicy {
+    pub mode: SniMode,
+    pub timeout: Duration,
+}
+
+impl Default for SniPolicy {
+    fn default() -> Self {
+        Self {
+            mode: SniMode::Off,
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ClientHello {
+    Incomplete,
+    NotTls,
+    Tls(Option<String>),
+}
+
+pub(crate) async fn read_client_hello(
+    source: &mut TcpStream,
+    mut data: Vec<u8>,
+    wait: Duration,
+) -> std::io::Result<(Vec<u8>, ClientHello)> {
+    let deadline = Instant::now() + wait;
+    let mut chunk = [0u8; 4096];
+    loop {
+        let hello = parse_client_hello(&data);
+        if hello != ClientHello::Incomplete || data.len() >= RECORD_HEADER + MAX_RECORD {
+            return Ok((data, hello));
+        }
+        match timeout_at(deadline, source.read(&mut chunk)).await {
+            Ok(Ok(0)) | Err(_) => return Ok((data, hello)),
+            Ok(Ok(size)) => data.extend_from_slice(&chunk[..size]),
+            Ok(Err(err)) => return Err(err),
+        }
+    }
+}
+
+pub(crate) fn parse_client_hello(data: &[u8]) -> ClientHello {
+    match data {
+        [] => return ClientHello::Incomplete,
+        [first, ..] if *first != HANDSHAKE => return ClientHello::NotTls,
+        [_, major, ..] if *major != 3 => return ClientHello::NotTls,
+        _ if data.len() < RECORD_HEADER => return ClientHello::Incomplete,
+        _ => {}
+    }
+    let length = usize::from(u16::from_be_bytes([data[3], data[4]]));
+    let Some(record) = data.get(RECORD_HEADER..RECORD_HEADER + length) else {
+        return ClientHello::Incomplete;
+    };
+    if record.first() != Some(&CLIENT_HELLO) {
+        return ClientHello::NotTls;
+    }
+    ClientHello::Tls(server_name(record))
+}
+
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut hello = Cursor(record.get(4..)?);
+    hello.take(2 + 32)?;
+    let session = hello.u8()?;
+    hello.take(usize::from(session))?;
+    let suites = hello.u16()?;
+    hello.take(usize::from(suites))?;
+    let compression = hello.u8()?;
+    hello.take(usize::from(compression))?;
+    let length = hello.u16()?;
+    let mut extensions = Cursor(hello.take(usize::from(length))?);
+    while let (Some(kind), Some(length)) = (extensions.u16(), extensions.u16()) {
+        let mut body = Cursor(extensions.take(usize::from(length))?);
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let list = body.u16()?;
+        let mut names = Cursor(body.take(usize::from(list))?);
+        while let Some(name_type) = names.u8() {
+            let length = names.u16()?;
+            let name = names.take(usize::from(length))?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+        }
+    }
+    None
+}
+
+pub(crate) fn matches_host(host: &str, sni: &str) -> bool {
+    host.trim_end_matches('.')
+        .eq_ignore_ascii_case(sni.trim_end_matches('.'))
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    const fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn client_hello(sni: Option<&str>) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(name) = sni {
+        let name = name.as_bytes();
+        let length = u16::try_from(name.len()).unwrap_or(u16::MAX);
+        extensions.extend_from_slice(&SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(length + 5).to_be_bytes());
+        extensions.extend_from_slice(&(length + 3).to_be_bytes());
+        extensions.push(0);
+        extensions.extend_from_slice(&length.to_be_bytes());
+        extensions.extend_from_slice(name);
+    }
+    extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+    let mut hello = vec![0x03, 0x03];
+    hello.extend_from_slice(&[0x11; 32]);
+    hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    hello.extend_from_slice(&u16::try_from(extensions.len()).unwrap_or(0).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+    let mut record = vec![CLIENT_HELLO];
+    record.extend_from_slice(&u32::try_from(hello.len()).unwrap_or(0).to_be_bytes()[1..]);
+    record.extend_from_slice(&hello);
+    let mut data = vec![HANDSHAKE, 0x03, 0x01];
+    data.extend_from_slice(&u16::try_from(record.len()).unwrap_or(0).to_be_bytes());
+    data.extend_from_slice(&record);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_server_name_from_client_hello() {
+        let hello = client_hello(Some("Example.COM"));
+        assert_eq!(
+            parse_client_hello(&hello),
+            ClientHello::Tls(Some("example.com".to_string()))
+        );
+        assert_eq!(
+            parse_client_hello(&client_hello(None)),
+            ClientHello::Tls(None)
+        );
+    }
+
+    #[test]
+    fn waits_for_whole_record_and_rejects_plaintext() {
+        let hello = client_hello(Some("example.com"));
+        assert_eq!(parse_client_hello(&hello[..3]), ClientHello::Incomplete);
+        assert_eq!(parse_client_hello(&hello[..40]), ClientHello::Incomplete);
+        assert_eq!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ClientHello::NotTls
+        );
+        assert_eq!(parse_client_hello(b"SSH-2.0-OpenSSH"), ClientHello::NotTls);
+    }
+
+    #[test]
+    fn truncated_extensions_yield_no_name() {
+        let mut hello = client_hello(Some("example.com"));
+        let cut = hello.len() - 12;
+        hello.truncate(cut);
+        let length = u16::try_from(cut - RECORD_HEADER).unwrap_or(0);
+        hello[3..5].copy_from_slice(&length.to_be_bytes());
+        assert_eq!(parse_client_hello(&hello), ClientHello::Tls(None));
+    }
+
+    #[test]
+    fn host_comparison_ignores_case_and_trailing_dot() {
+        assert!(matches_host("Example.com.", "example.com"));
+        assert!(!matches_host("example.com", "evil.example.com"));
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SpilledEvent {
+    pub(crate) url: String,
+    pub(crate) payload: Value,
+}
+
+pub(crate) struct SpillQueue {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl SpillQueue {
+    pub(crate) const fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self { path, max_bytes }
+    }
+
+    pub(crate) async fn push(&self, event: &SpilledEvent) -> Result<bool> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let size = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err.into()),
+        };
+        if size + line.len() as u64 > self.max_bytes {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .with_context(|| format!("Cannot open spill queue {}", self.path.display()))?;
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+        Ok(true)
+    }
+
+    pub(crate) async fn drain(&self) -> Result<Vec<SpilledEvent>> {
+        let content = match fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        fs::remove_file(&self.path).await?;
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn spills_up_to_the_size_bound_and_drains_in_order() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-spill-{}.jsonl", std::process::id()));
+        let queue = SpillQueue::new(path.clone(), 120);
+        let event = |id: u64| SpilledEvent {
+            url: "http://billing.internal/hooks".to_string(),
+            payload: json!({ "event": "tunnel_closed", "session_id": id }),
+        };
+
+        assert!(queue.push(&event(1)).await?);
+        assert!(!queue.push(&event(2)).await?);
+        assert_eq!(queue.drain().await?, vec![event(1)]);
+        assert!(!path.exists());
+        assert!(queue.drain().await?.is_empty());
+        Ok(())
+    }
+}
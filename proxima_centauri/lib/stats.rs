@@ -0,0 +1,85 @@
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use crate::session::Session;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficStats {
+    pub ingress: u128,
+    pub egress: u128,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DestinationStats {
+    pub host: String,
+    pub traffic: TrafficStats,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HistogramStats {
+    pub buckets: BTreeMap<String, u64>,
+    pub sum: u128,
+    pub count: u64,
+    pub p50: Option<u64>,
+    pub p90: Option<u64>,
+    pub p99: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UserStats {
+    pub user: String,
+    pub traffic: TrafficStats,
+    pub concurrency: u16,
+    pub countries: BTreeMap<String, TrafficStats>,
+    pub destinations: Vec<DestinationStats>,
+    pub tunnels_closed: BTreeMap<&'static str, u64>,
+    pub tunnel_bytes: HistogramStats,
+    pub tunnel_duration_ms: HistogramStats,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TenantStats {
+    pub tenant: String,
+    pub users: Vec<String>,
+    pub concurrency: u16,
+    pub traffic: TrafficStats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub users: Vec<UserStats>,
+    pub tenants: Vec<TenantStats>,
+    pub sessions: Vec<Session>,
+    pub counters: BTreeMap<&'static str, u64>,
+    pub tunnels_closed: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Clone)]
+pub struct StatsHandle {
+    registry: Arc<Mutex<Registry>>,
+    metrics: Arc<Metrics>,
+}
+
+impl StatsHandle {
+    pub(crate) const fn new(registry: Arc<Mutex<Registry>>, metrics: Arc<Metrics>) -> Self {
+        Self { registry, metrics }
+    }
+
+    pub async fn snapshot(&self) -> StatsSnapshot {
+        let registry = self.registry.lock().await;
+        let users = registry.user_stats();
+        let tenants = registry.tenant_stats();
+        let sessions = registry.sessions();
+        drop(registry);
+        StatsSnapshot {
+            users,
+            tenants,
+            sessions,
+            counters: self.metrics.counters().into_iter().collect(),
+            tunnels_closed: self.metrics.close_reasons().into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,920 @@
+use crate::coordinator::{CoordinatorConfig, CoordinatorStore};
+use crate::error::ProxyError;
+use crate::registry::{LimitError, Limits, Registry, RegistryError, TrafficCounters};
+use anyhow::{Context as _, Result, anyhow, bail};
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tracing::warn;
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+const SLOT_TTL: Duration = Duration::from_mins(1);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum StoreConfig {
+    #[default]
+    Memory,
+    File(PathBuf),
+    Redis(String),
+    Coordinator(CoordinatorConfig),
+}
+
+#[async_trait]
+pub trait RegistryStore: Send + Sync {
+    async fn try_acquire(&self, user: &str, limits: Limits) -> Result<()>;
+
+    async fn release(&self, user: &str) -> Result<()>;
+
+    async fn add_traffic(&self, user: &str, ingress: u128, egress: u128) -> Result<()>;
+
+    async fn extend(&self, _user: &str, _limits: Limits) -> Result<bool> {
+        Ok(false)
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn reload(&self, _config: &StoreConfig) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) fn open(
+    config: &StoreConfig,
+    registry: Arc<Mutex<Registry>>,
+) -> Result<Arc<dyn RegistryStore>> {
+    Ok(match config {
+        StoreConfig::Memory => Arc::new(MemoryStore::new(registry)),
+        StoreConfig::File(path) => Arc::new(FileStore::open(path.clone(), registry)?),
+        StoreConfig::Redis(url) => Arc::new(RedisStore::new(url)?),
+        StoreConfig::Coordinator(coordinator) => Arc::new(CoordinatorStore::new(
+            coordinator.clone(),
+            MemoryStore::new(registry),
+        )),
+    })
+}
+
+fn acquire(registry: &mut Registry, user: &str) -> Result<()> {
+    registry.roll_traffic_window();
+    registry.inc_concurrency(user);
+    match registry.check_limits(user) {
+        Ok(()) => Ok(()),
+        Err(RegistryError::Limit(err)) => {
+            registry.dec_concurrency(user);
+            Err(err.into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(crate) struct ConcurrencyGuard {
+    store: Arc<dyn RegistryStore>,
+    user: Option<String>,
+}
+
+impl ConcurrencyGuard {
+    pub(crate) async fn acquire(
+        store: Arc<dyn RegistryStore>,
+        user: &str,
+        limits: Limits,
+    ) -> crate::error::Result<Self> {
+        store.try_acquire(user, limits).await.map_err(|err| {
+            match err.downcast::<LimitError>() {
+                Ok(err) => ProxyError::LimitExceeded(err),
+                Err(err) => ProxyError::backend(err),
+            }
+        })?;
+        Ok(Self {
+            store,
+            user: Some(user.to_string()),
+        })
+    }
+
+    pub(crate) async fn release(mut self) -> crate::error::Result<()> {
+        match self.user.take() {
+            Some(user) => self.store.release(&user).await.map_err(ProxyError::backend),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ConcurrencyGuard {
+    fn drop(&mut self) {
+        let Some(user) = self.user.take() else {
+            return;
+        };
+        let store = self.store.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(err) = store.release(&user).await {
+                    warn!(
+                        user = user,
+                        error = format!("{err}"),
+                        "Concurrency release failed"
+                    );
+                }
+            });
+        }
+    }
+}
+
+pub(crate) struct MemoryStore {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl MemoryStore {
+    pub(crate) const fn new(registry: Arc<Mutex<Registry>>) -> Self {
+        Self { registry }
+    }
+
+    pub(crate) async fn traffic_counters(&self, user: &str) -> Option<Arc<TrafficCounters>> {
+        self.registry.lock().await.traffic_counters(user)
+    }
+}
+
+#[async_trait]
+impl RegistryStore for MemoryStore {
+    async fn try_acquire(&self, user: &str, limits: Limits) -> Result<()> {
+        let mut registry = self.registry.lock().await;
+        registry.set_limits(user, limits);
+        acquire(&mut registry, user)
+    }
+
+    async fn release(&self, user: &str) -> Result<()> {
+        self.registry.lock().await.dec_concurrency(user);
+        Ok(())
+    }
+
+    async fn add_traffic(&self, user: &str, ingress: u128, egress: u128) -> Result<()> {
+        let mut registry = self.registry.lock().await;
+        registry.add_ingress_traffic(user, ingress);
+        registry.add_egress_traffic(user, egress);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct TrafficSnapshot {
+    ingress: u128,
+    egress: u128,
+    #[serde(default)]
+    window: u64,
+}
+
+pub(crate) struct FileStore {
+    memory: MemoryStore,
+    path: PathBuf,
+    restored: Mutex<HashMap<String, TrafficSnapshot>>,
+}
+
+impl FileStore {
+    fn open(path: PathBuf, registry: Arc<Mutex<Registry>>) -> Result<Self> {
+        let restored = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .with_context(|| format!("Invalid registry snapshot {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            memory: MemoryStore { registry },
+            path,
+            restored: Mutex::new(restored),
+        })
+    }
+}
+
+#[async_trait]
+impl RegistryStore for FileStore {
+    async fn try_acquire(&self, user: &str, limits: Limits) -> Result<()> {
+        let mut registry = self.memory.registry.lock().await;
+        if !registry.has_user(user) {
+            registry.create_user(user, limits);
+            let snapshot = self.restored.lock().await.remove(user);
+            if let Some(snapshot) = snapshot.filter(|snapshot| snapshot.window == registry.window())
+            {
+                registry.add_ingress_traffic(user, snapshot.ingress);
+                registry.add_egress_traffic(user, snapshot.egress);
+            }
+        }
+        registry.set_limits(user, limits);
+        acquire(&mut registry, user)
+    }
+
+    async fn release(&self, user: &str) -> Result<()> {
+        self.memory.release(user).await
+    }
+
+    async fn add_traffic(&self, user: &str, ingress: u128, egress: u128) -> Result<()> {
+        self.memory.add_traffic(user, ingress, egress).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let mut snapshot = self.restored.lock().await.clone();
+        let registry = self.memory.registry.lock().await;
+        let (window, traffic) = (registry.window(), registry.traffic());
+        drop(registry);
+        for (user, ingress, egress) in traffic {
+            snapshot.insert(
+                user,
+                TrafficSnapshot {
+                    ingress,
+                    egress,
+                    window,
+                },
+            );
+        }
+        let temp = self.path.with_extension("tmp");
+        tokio::fs::write(&temp, serde_json::to_vec(&snapshot)?).await?;
+        tokio::fs::rename(&temp, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct RedisTarget {
+    pub(crate) addr: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl RedisTarget {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let authority = url
+            .strip_prefix("redis://")
+            .ok_or_else(|| anyhow!("must start with redis://"))?;
+        let authority = authority.split('/').next().unwrap_or_default();
+        let (userinfo, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let (username, password) = match userinfo.map(|userinfo| userinfo.split_once(':')) {
+            Some(Some((username, password))) => (username, Some(password.to_string())),
+            Some(None) => bail!("credentials must be `user:password@` or `:password@`"),
+            None => ("", None),
+        };
+        if host.is_empty() {
+            bail!("has no host");
+        }
+        Ok(Self {
+            addr: if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{host}:6379")
+            },
+            username: Some(username.to_string()).filter(|username| !username.is_empty()),
+            password: password.filter(|password| !password.is_empty()),
+        })
+    }
+
+    async fn connect(&self) -> Result<BufStream<TcpStream>> {
+        let mut stream = BufStream::new(TcpStream::connect(&self.addr).await?);
+        if let Some(password) = &self.password {
+            let mut args = vec!["AUTH"];
+            args.extend(self.username.as_deref());
+            args.push(password);
+            match exchange(&mut stream, &args).await? {
+                Reply::Status(_) => {}
+                reply => bail!("Unexpected Redis AUTH reply {reply:?}"),
+            }
+        }
+        Ok(stream)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Self>),
+}
+
+pub(crate) struct RedisStore {
+    target: ArcSwap<RedisTarget>,
+    instance: String,
+    held: std::sync::Mutex<HashMap<String, u16>>,
+    connection: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RedisStore {
+    fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            target: ArcSwap::from_pointee(RedisTarget::parse(url)?),
+            instance: instance_id(),
+            held: std::sync::Mutex::default(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn command(&self, args: &[&str]) -> Result<Reply> {
+        let mut connection = self.connection.lock().await;
+        let reply = timeout(REDIS_TIMEOUT, async {
+            if connection.is_none() {
+                *connection = Some(self.target.load().connect().await?);
+            }
+            let stream = connection
+                .as_mut()
+                .context("Redis connection is not established")?;
+            exchange(stream, args).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Redis did not answer within {REDIS_TIMEOUT:?}")));
+        if reply.is_err() {
+            *connection = None;
+        }
+        reply
+    }
+
+    async fn integer(&self, args: &[&str]) -> Result<i64> {
+        match self.command(args).await? {
+            Reply::Integer(value) => Ok(value),
+            reply => bail!("Expected an integer from Redis, got {reply:?}"),
+        }
+    }
+
+    async fn strings(&self, args: &[&str]) -> Result<Vec<Option<String>>> {
+        match self.command(args).await? {
+            Reply::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Reply::Bulk(value) => Ok(value),
+                    reply => bail!("Expected a string from Redis, got {reply:?}"),
+                })
+                .collect(),
+            reply => bail!("Expected an array from Redis, got {reply:?}"),
+        }
+    }
+
+    fn key(user: &str, counter: &str) -> String {
+        format!("procent:{user}:{counter}")
+    }
+
+    fn slot_key(user: &str, instance: &str) -> String {
+        Self::key(user, &format!("concurrency:{instance}"))
+    }
+
+    async fn keep_alive(&self, user: &str) -> Result<()> {
+        let ttl = SLOT_TTL.as_secs().to_string();
+        self.integer(&["EXPIRE", &Self::slot_key(user, &self.instance), &ttl])
+            .await?;
+        self.integer(&["EXPIRE", &Self::key(user, "instances"), &ttl])
+            .await?;
+        Ok(())
+    }
+
+    async fn concurrency(&self, user: &str) -> Result<u16> {
+        let instances_key = Self::key(user, "instances");
+        let instances = self.strings(&["SMEMBERS", &instances_key]).await?;
+        let instances: Vec<String> = instances.into_iter().flatten().collect();
+        let keys: Vec<String> = instances
+            .iter()
+            .map(|instance| Self::slot_key(user, instance))
+            .collect();
+        let mut args = vec!["MGET"];
+        args.extend(keys.iter().map(String::as_str));
+        let counts = self.strings(&args).await?;
+        let mut total: i64 = 0;
+        for (instance, count) in instances.iter().zip(counts) {
+            match count {
+                Some(count) => total = total.saturating_add(count.parse::<i64>()?.max(0)),
+                None => {
+                    self.integer(&["SREM", &instances_key, instance]).await?;
+                }
+            }
+        }
+        Ok(u16::try_from(total).unwrap_or(u16::MAX))
+    }
+
+    fn hold(&self, user: &str, delta: i32) {
+        let mut held = self.held.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = held.entry(user.to_string()).or_default();
+        *count = count.saturating_add_signed(i16::try_from(delta).unwrap_or_default());
+        if *count == 0 {
+            held.remove(user);
+        }
+    }
+}
+
+#[async_trait]
+impl RegistryStore for RedisStore {
+    async fn try_acquire(&self, user: &str, limits: Limits) -> Result<()> {
+        let slot_key = Self::slot_key(user, &self.instance);
+        self.integer(&["SADD", &Self::key(user, "instances"), &self.instance])
+            .await?;
+        self.integer(&["INCR", &slot_key]).await?;
+        self.hold(user, 1);
+        let usage = async {
+            self.keep_alive(user).await?;
+            let concurrency = self.concurrency(user).await?;
+            let ingress = self
+                .integer(&["INCRBY", &Self::key(user, "ingress"), "0"])
+                .await?;
+            let egress = self
+                .integer(&["INCRBY", &Self::key(user, "egress"), "0"])
+                .await?;
+            let traffic = u128::try_from(ingress.saturating_add(egress)).unwrap_or_default();
+            Ok::<_, anyhow::Error>((concurrency, traffic))
+        };
+        let (concurrency, traffic) = match usage.await {
+            Ok(usage) => usage,
+            Err(err) => {
+                let _ = self.release(user).await;
+                return Err(err);
+            }
+        };
+
+        let exceeded = if limits
+            .concurrency()
+            .restricted()
+            .is_some_and(|max| max < concurrency)
+        {
+            Some(LimitError::ConcurrencyLimitExceed(concurrency))
+        } else if limits
+            .traffic()
+            .restricted()
+            .is_some_and(|max| max <= traffic)
+        {
+            Some(LimitError::TrafficLimitExceed(traffic))
+        } else {
+            None
+        };
+        if let Some(err) = exceeded {
+            self.release(user).await?;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    async fn release(&self, user: &str) -> Result<()> {
+        self.hold(user, -1);
+        self.integer(&["DECR", &Self::slot_key(user, &self.instance)])
+            .await?;
+        Ok(())
+    }
+
+    async fn add_traffic(&self, user: &str, ingress: u128, egress: u128) -> Result<()> {
+        for (counter, value) in [("ingress", ingress), ("egress", egress)] {
+            let value = i64::try_from(value).unwrap_or(i64::MAX).to_string();
+            self.integer(&["INCRBY", &Self::key(user, counter), &value])
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let users: Vec<String> = self
+            .held
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .cloned()
+            .collect();
+        for user in users {
+            self.keep_alive(&user).await?;
+        }
+        Ok(())
+    }
+
+    async fn reload(&self, config: &StoreConfig) -> Result<()> {
+        let StoreConfig::Redis(url) = config else {
+            return Ok(());
+        };
+        let target = RedisTarget::parse(url)?;
+        if *self.target.load_full() != target {
+            let mut connection = self.connection.lock().await;
+            self.target.store(Arc::new(target));
+            *connection = None;
+        }
+        Ok(())
+    }
+}
+
+fn instance_id() -> String {
+    let mut bytes = [0u8; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        bytes = std::process::id()
+            .to_be_bytes()
+            .repeat(2)
+            .try_into()
+            .unwrap_or_default();
+    }
+    bytes.iter().fold(String::new(), |mut id, byte| {
+        let _ = write!(id, "{byte:02x}");
+        id
+    })
+}
+
+async fn exchange(stream: &mut BufStream<TcpStream>, args: &[&str]) -> Result<Reply> {
+    stream.write_all(&encode_command(args)).await?;
+    stream.flush().await?;
+    let line = read_line(stream).await?;
+    let Some(count) = line.strip_prefix('*') else {
+        return read_scalar(stream, &line).await;
+    };
+    let count: i64 = count.parse()?;
+    let mut items = Vec::new();
+    for _ in 0..count.max(0) {
+        let line = read_line(stream).await?;
+        items.push(read_scalar(stream, &line).await?);
+    }
+    Ok(Reply::Array(items))
+}
+
+async fn read_line(stream: &mut BufStream<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        bail!("Redis closed the connection");
+    }
+    Ok(line.trim_end_matches("\r\n").to_string())
+}
+
+async fn read_scalar(stream: &mut BufStream<TcpStream>, line: &str) -> Result<Reply> {
+    let Some(("$", length)) = line.split_at_checked(1) else {
+        return parse_scalar(line);
+    };
+    let Ok(length) = usize::try_from(length.parse::<i64>()?) else {
+        return Ok(Reply::Bulk(None));
+    };
+    let mut data = vec![0u8; length + 2];
+    stream.read_exact(&mut data).await?;
+    data.truncate(length);
+    Ok(Reply::Bulk(Some(String::from_utf8(data)?)))
+}
+
+fn encode_command(args: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        let _ = write!(command, "${}\r\n{arg}\r\n", arg.len());
+    }
+    command.into_bytes()
+}
+
+fn parse_scalar(line: &str) -> Result<Reply> {
+    let line = line.trim_end_matches("\r\n");
+    match line.split_at_checked(1) {
+        Some(("+", status)) => Ok(Reply::Status(status.to_string())),
+        Some((":", value)) => Ok(Reply::Integer(value.parse()?)),
+        Some(("-", error)) => bail!("Redis error: {error}"),
+        _ => bail!("Unexpected Redis reply `{line}`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthProvider, Database, UserRecord};
+    use crate::registry::LimitValue;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    fn limits(concurrency: u16, traffic: u128) -> Limits {
+        Limits::new(
+            LimitValue::Restricted(concurrency),
+            LimitValue::Restricted(traffic),
+        )
+    }
+
+    fn limit_error(result: Result<()>) -> LimitError {
+        result.unwrap_err().downcast::<LimitError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_store_enforces_and_releases_concurrency() -> Result<()> {
+        let store = MemoryStore {
+            registry: Arc::new(Mutex::new(Registry::new())),
+        };
+
+        store.try_acquire("alice", limits(1, 100)).await?;
+        assert!(matches!(
+            limit_error(store.try_acquire("alice", limits(1, 100)).await),
+            LimitError::ConcurrencyLimitExceed(2)
+        ));
+        store.release("alice").await?;
+        store.try_acquire("alice", limits(1, 100)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn plan_changes_are_enforced_on_the_next_acquire() -> Result<()> {
+        let mut database = Database::new_persistence();
+        database.insert(UserRecord {
+            plan: Some("pro".to_string()),
+            ..UserRecord::new("subscriber", "secret")
+        });
+        database.insert(UserRecord {
+            plan: Some("retired".to_string()),
+            ..UserRecord::new("legacy", "secret")
+        });
+        database.set_plans(HashMap::from([("pro".to_string(), limits(1, 100))]));
+        let store = MemoryStore {
+            registry: Arc::new(Mutex::new(Registry::new())),
+        };
+        assert_eq!(database.limits("legacy").await?, Limits::with_low_limits());
+
+        let plan = database.limits("subscriber").await?;
+        store.try_acquire("subscriber", plan).await?;
+        assert!(matches!(
+            limit_error(store.try_acquire("subscriber", plan).await),
+            LimitError::ConcurrencyLimitExceed(2)
+        ));
+
+        database.set_plans(HashMap::from([("pro".to_string(), limits(2, 100))]));
+        let upgraded = database.limits("subscriber").await?;
+        store.try_acquire("subscriber", upgraded).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_guard_releases_concurrency() -> Result<()> {
+        let store: Arc<dyn RegistryStore> = Arc::new(MemoryStore {
+            registry: Arc::new(Mutex::new(Registry::new())),
+        });
+
+        let guard = ConcurrencyGuard::acquire(store.clone(), "dave", limits(1, 100)).await?;
+        assert!(matches!(
+            ConcurrencyGuard::acquire(store.clone(), "dave", limits(1, 100)).await,
+            Err(ProxyError::LimitExceeded(
+                LimitError::ConcurrencyLimitExceed(_)
+            ))
+        ));
+        guard.release().await?;
+
+        let guard = ConcurrencyGuard::acquire(store.clone(), "dave", limits(1, 100)).await?;
+        drop(guard);
+        tokio::task::yield_now().await;
+        ConcurrencyGuard::acquire(store, "dave", limits(1, 100)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_store_restores_traffic_from_snapshot() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-store-{}.json", std::process::id()));
+        let store = FileStore::open(path.clone(), Arc::new(Mutex::new(Registry::new())))?;
+        store.try_acquire("bob", limits(5, 100)).await?;
+        store.add_traffic("bob", 60, 50).await?;
+        store.release("bob").await?;
+        store.flush().await?;
+
+        let restored = FileStore::open(path.clone(), Arc::new(Mutex::new(Registry::new())))?;
+        let result = restored.try_acquire("bob", limits(5, 100)).await;
+        std::fs::remove_file(&path)?;
+
+        assert!(matches!(
+            limit_error(result),
+            LimitError::TrafficLimitExceed(110)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_store_drops_traffic_from_an_earlier_window() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("procent-window-{}.json", std::process::id()));
+        let clock = crate::clock::MockClock::starting_at(3_000);
+        let windowed = |clock: Arc<crate::clock::MockClock>| {
+            let mut registry = Registry::with_clock(clock);
+            registry.set_traffic_window(3600);
+            Arc::new(Mutex::new(registry))
+        };
+        let store = FileStore::open(path.clone(), windowed(clock.clone()))?;
+        store.try_acquire("bob", limits(5, 100)).await?;
+        store.add_traffic("bob", 60, 50).await?;
+        store.release("bob").await?;
+        store.flush().await?;
+
+        clock.advance(Duration::from_mins(10));
+        let restored = FileStore::open(path.clone(), windowed(clock))?;
+        let result = restored.try_acquire("bob", limits(5, 100)).await;
+        std::fs::remove_file(&path)?;
+
+        assert!(result.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn encodes_commands_and_parses_replies() -> Result<()> {
+        assert_eq!(
+            encode_command(&["INCRBY", "key", "10"]),
+            b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$2\r\n10\r\n"
+        );
+        assert_eq!(parse_scalar(":42\r\n")?, Reply::Integer(42));
+        assert_eq!(parse_scalar("+OK\r\n")?, Reply::Status("OK".to_string()));
+        assert!(parse_scalar("-ERR wrong type\r\n").is_err());
+        Ok(())
+    }
+
+    type FakeRedis = Arc<std::sync::Mutex<HashMap<String, Vec<String>>>>;
+
+    fn fake_reply(
+        state: &FakeRedis,
+        args: &[&str],
+        authorized: &mut bool,
+        password: Option<&str>,
+    ) -> String {
+        if args[0] == "AUTH" {
+            *authorized = args.last().copied() == password;
+            return if *authorized {
+                "+OK\r\n"
+            } else {
+                "-WRONGPASS invalid password\r\n"
+            }
+            .to_string();
+        }
+        if !*authorized {
+            return "-NOAUTH Authentication required\r\n".to_string();
+        }
+        let mut state = state.lock().unwrap();
+        if state.contains_key(&format!("fail:{}", args[0])) {
+            return format!("-ERR {} failed\r\n", args[0]);
+        }
+        let integer = |value: &Vec<String>| {
+            value
+                .first()
+                .and_then(|value| value.parse::<i64>().ok())
+                .unwrap_or_default()
+        };
+        match args[0] {
+            "INCR" | "DECR" | "INCRBY" => {
+                let delta = match args[0] {
+                    "INCR" => 1,
+                    "DECR" => -1,
+                    _ => args[2].parse().unwrap(),
+                };
+                let value = state.entry(args[1].to_string()).or_default();
+                let next = integer(value) + delta;
+                *value = vec![next.to_string()];
+                format!(":{next}\r\n")
+            }
+            "EXPIRE" => format!(":{}\r\n", i32::from(state.contains_key(args[1]))),
+            "SADD" => {
+                let members = state.entry(args[1].to_string()).or_default();
+                let added = !members.iter().any(|member| member == args[2]);
+                if added {
+                    members.push(args[2].to_string());
+                }
+                format!(":{}\r\n", i32::from(added))
+            }
+            "SREM" => {
+                let members = state.entry(args[1].to_string()).or_default();
+                members.retain(|member| member != args[2]);
+                ":1\r\n".to_string()
+            }
+            "SMEMBERS" | "MGET" => {
+                let values: Vec<Option<String>> = if args[0] == "SMEMBERS" {
+                    state
+                        .get(args[1])
+                        .cloned()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(Some)
+                        .collect()
+                } else {
+                    args[1..]
+                        .iter()
+                        .map(|key| state.get(*key).map(|value| integer(value).to_string()))
+                        .collect()
+                };
+                values
+                    .iter()
+                    .fold(format!("*{}\r\n", values.len()), |mut reply, value| {
+                        let _ = match value {
+                            Some(value) => write!(reply, "${}\r\n{value}\r\n", value.len()),
+                            None => write!(reply, "$-1\r\n"),
+                        };
+                        reply
+                    })
+            }
+            command => format!("-ERR unknown command `{command}`\r\n"),
+        }
+    }
+
+    async fn fake_redis(password: Option<&'static str>) -> Result<(SocketAddr, FakeRedis)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = FakeRedis::default();
+        let shared = state.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let state = shared.clone();
+                tokio::spawn(async move {
+                    let mut authorized = password.is_none();
+                    let mut buffer = vec![0u8; 4096];
+                    loop {
+                        let size = socket.read(&mut buffer).await?;
+                        if size == 0 {
+                            return Ok::<_, anyhow::Error>(());
+                        }
+                        let request = String::from_utf8_lossy(&buffer[..size]).into_owned();
+                        let args: Vec<&str> = request
+                            .split("\r\n")
+                            .filter(|part| !part.is_empty() && !part.starts_with(['*', '$']))
+                            .collect();
+                        let reply = fake_reply(&state, &args, &mut authorized, password);
+                        socket.write_all(reply.as_bytes()).await?;
+                    }
+                });
+            }
+        });
+        Ok((addr, state))
+    }
+
+    #[tokio::test]
+    async fn redis_store_talks_resp() -> Result<()> {
+        let (addr, _) = fake_redis(None).await?;
+        let store = RedisStore::new(&format!("redis://{addr}"))?;
+        store.try_acquire("carol", limits(1, 100)).await?;
+        assert!(matches!(
+            limit_error(store.try_acquire("carol", limits(1, 100)).await),
+            LimitError::ConcurrencyLimitExceed(2)
+        ));
+        store.add_traffic("carol", 80, 30).await?;
+        store.release("carol").await?;
+        assert!(matches!(
+            limit_error(store.try_acquire("carol", limits(1, 100)).await),
+            LimitError::TrafficLimitExceed(110)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redis_store_authenticates_from_the_url() -> Result<()> {
+        let (addr, _) = fake_redis(Some("hunter2")).await?;
+        let anonymous = RedisStore::new(&format!("redis://{addr}"))?;
+        assert!(anonymous.try_acquire("erin", limits(1, 100)).await.is_err());
+        let wrong = RedisStore::new(&format!("redis://:guess@{addr}"))?;
+        assert!(wrong.try_acquire("erin", limits(1, 100)).await.is_err());
+        let store = RedisStore::new(&format!("redis://default:hunter2@{addr}/0"))?;
+        store.try_acquire("erin", limits(1, 100)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redis_slots_of_a_vanished_instance_expire() -> Result<()> {
+        let (addr, state) = fake_redis(None).await?;
+        let url = format!("redis://{addr}");
+        let crashed = RedisStore::new(&url)?;
+        let survivor = RedisStore::new(&url)?;
+        crashed.try_acquire("frank", limits(1, 100)).await?;
+        assert!(matches!(
+            limit_error(survivor.try_acquire("frank", limits(1, 100)).await),
+            LimitError::ConcurrencyLimitExceed(2)
+        ));
+
+        state
+            .lock()
+            .unwrap()
+            .remove(&RedisStore::slot_key("frank", &crashed.instance));
+        survivor.try_acquire("frank", limits(1, 100)).await?;
+        assert_eq!(
+            state.lock().unwrap()[&RedisStore::key("frank", "instances")],
+            [survivor.instance.as_str()]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redis_slot_is_released_when_acquire_fails() -> Result<()> {
+        let (addr, state) = fake_redis(None).await?;
+        let store = RedisStore::new(&format!("redis://{addr}"))?;
+        state
+            .lock()
+            .unwrap()
+            .insert("fail:MGET".to_string(), Vec::new());
+        assert!(store.try_acquire("grace", limits(1, 100)).await.is_err());
+        assert_eq!(
+            state.lock().unwrap()[&RedisStore::slot_key("grace", &store.instance)],
+            ["0"]
+        );
+        assert!(store.held.lock().unwrap().is_empty());
+
+        state.lock().unwrap().remove("fail:MGET");
+        store.try_acquire("grace", limits(1, 100)).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn parses_redis_urls() -> Result<()> {
+        let target = RedisTarget::parse("redis://user:p@ss@cache/2")?;
+        assert_eq!(target.addr, "cache:6379");
+        assert_eq!(target.username.as_deref(), Some("user"));
+        assert_eq!(target.password.as_deref(), Some("p@ss"));
+        let target = RedisTarget::parse("redis://:secret@10.0.0.1:6380")?;
+        assert_eq!(
+            (target.addr.as_str(), target.username),
+            ("10.0.0.1:6380", None)
+        );
+        assert!(RedisTarget::parse("redis://secret@cache").is_err());
+        assert!(RedisTarget::parse("rediss://cache").is_err());
+        Ok(())
+    }
+}
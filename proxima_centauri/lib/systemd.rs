@@ -0,0 +1,61 @@
+use anyhow::{Context as _, Result, bail};
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+static ADOPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn listen_fds() -> Result<Vec<TcpListener>> {
+    let count = passed_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )?;
+    if count == 0 || ADOPTED.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(adopt)
+        .collect()
+}
+
+fn passed_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<RawFd> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0);
+    };
+    if pid.parse::<u32>().context("Invalid LISTEN_PID")? != own_pid {
+        return Ok(0);
+    }
+    let count: RawFd = fds.parse().context("Invalid LISTEN_FDS")?;
+    if count < 0 {
+        bail!("Invalid LISTEN_FDS `{count}`");
+    }
+    Ok(count)
+}
+
+#[allow(unsafe_code)]
+fn adopt(fd: RawFd) -> Result<TcpListener> {
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener
+        .local_addr()
+        .with_context(|| format!("Inherited fd {fd} is not a TCP listener"))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_fds_only_for_own_pid() -> Result<()> {
+        assert_eq!(passed_fds(None, None, 42)?, 0);
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42)?, 2);
+        assert_eq!(passed_fds(Some("7"), Some("2"), 42)?, 0);
+        assert!(passed_fds(Some("42"), Some("-1"), 42).is_err());
+        assert!(passed_fds(Some("pid"), Some("1"), 42).is_err());
+        Ok(())
+    }
+}
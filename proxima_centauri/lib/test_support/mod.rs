@@ -0,0 +1,170 @@
+pub use crate::clock::MockClock;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector, client::TlsStream};
+
+const CERT_PEM: &[u8] = include_bytes!("cert.pem");
+const KEY_PEM: &[u8] = include_bytes!("key.pem");
+
+pub struct MockTargetServer {
+    addr: SocketAddr,
+    accepted: Arc<AtomicUsize>,
+    handle: JoinHandle<()>,
+}
+
+impl MockTargetServer {
+    async fn spawn<F, Fut>(serve: F) -> Result<Self>
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(serve(socket));
+            }
+        });
+        Ok(Self {
+            addr,
+            accepted,
+            handle,
+        })
+    }
+
+    pub async fn start_echo() -> Result<Self> {
+        Self::spawn(|mut socket| async move { echo(&mut socket).await }).await
+    }
+
+    pub async fn start_sender(bytes_to_send: usize) -> Result<Self> {
+        Self::spawn(move |mut socket| async move {
+            let _ = socket.write_all(&vec![0x41u8; bytes_to_send]).await;
+            let _ = socket.shutdown().await;
+        })
+        .await
+    }
+
+    pub async fn start_slow(chunk: usize, chunks: usize, delay: Duration) -> Result<Self> {
+        Self::spawn(move |mut socket| async move {
+            for _ in 0..chunks {
+                sleep(delay).await;
+                if socket.write_all(&vec![0x42u8; chunk]).await.is_err() {
+                    return;
+                }
+            }
+            let _ = socket.shutdown().await;
+        })
+        .await
+    }
+
+    pub async fn start_abrupt_close() -> Result<Self> {
+        Self::spawn(|socket| async move { drop(socket) }).await
+    }
+
+    pub async fn start_websocket_echo() -> Result<Self> {
+        Self::spawn(|mut socket| async move {
+            let mut buf = [0u8; 1024];
+            let Ok(n) = socket.read(&mut buf).await else {
+                return;
+            };
+            let head = String::from_utf8_lossy(&buf[..n]);
+            if !head.starts_with("GET /chat HTTP/1.1\r\n")
+                || !head.contains("Upgrade: websocket")
+                || head.contains("Proxy-Authorization")
+            {
+                let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+                return;
+            }
+            let _ = socket
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\n\
+                      Upgrade: websocket\r\n\
+                      Connection: Upgrade\r\n\r\n",
+                )
+                .await;
+            echo(&mut socket).await;
+        })
+        .await
+    }
+
+    pub async fn start_tls_echo() -> Result<Self> {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certificate()?],
+                PrivateKeyDer::from_pem_slice(KEY_PEM)?,
+            )?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        Self::spawn(move |socket| {
+            let acceptor = acceptor.clone();
+            async move {
+                if let Ok(mut stream) = acceptor.accept(socket).await {
+                    echo(&mut stream).await;
+                }
+            }
+        })
+        .await
+    }
+
+    pub const fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockTargetServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn echo(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) {
+    let mut buf = [0u8; 1024];
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn certificate() -> Result<CertificateDer<'static>> {
+    Ok(CertificateDer::from_pem_slice(CERT_PEM)?)
+}
+
+pub fn tls_client_config() -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate()?)?;
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+pub async fn tls_connect(stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(tls_client_config()?);
+    Ok(connector
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?)
+}
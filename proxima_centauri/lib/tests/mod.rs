@@ -1,2 +1,4 @@
+#[allow(clippy::use_self)]
 mod common;
+#[allow(clippy::use_self, clippy::used_underscore_binding)]
 mod integration_tests;
@@ -0,0 +1,261 @@
+use crate::acl::Cidr;
+use crate::context::{Context, SharedContext};
+use crate::dial::{set_keepalive, set_socket_options};
+use crate::handler::handle_transparent;
+use crate::metrics::Metrics;
+use anyhow::{Context as _, Result, bail};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, lookup_host};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, debug, info, info_span, warn};
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransparentMode {
+    #[default]
+    Redirect,
+    Tproxy,
+}
+
+impl FromStr for TransparentMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "redirect" => Ok(Self::Redirect),
+            "tproxy" => Ok(Self::Tproxy),
+            _ => bail!("Unknown transparent mode `{value}`, expected redirect or tproxy"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransparentConfig {
+    pub addr: String,
+    pub mode: TransparentMode,
+    pub clients: Vec<String>,
+}
+
+pub(crate) struct ClientMap(Vec<(Cidr, String)>);
+
+impl ClientMap {
+    pub(crate) fn compile(clients: &[String]) -> Result<Self> {
+        clients
+            .iter()
+            .map(|client| {
+                let (network, user) = client
+                    .split_once('=')
+                    .filter(|(_, user)| !user.trim().is_empty())
+                    .with_context(|| {
+                        format!("Transparent client `{client}` must be `cidr=user`")
+                    })?;
+                let network = Cidr::parse(network.trim())
+                    .with_context(|| format!("Invalid transparent client network `{network}`"))?;
+                Ok((network, user.trim().to_string()))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    fn user_for(&self, ip: IpAddr) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(network, _)| network.contains(ip))
+            .map(|(_, user)| user.as_str())
+    }
+}
+
+pub(crate) struct TransparentListener {
+    listener: TcpListener,
+    mode: TransparentMode,
+    clients: ClientMap,
+}
+
+impl TransparentListener {
+    pub(crate) async fn bind(config: &TransparentConfig) -> Result<Self> {
+        let clients = ClientMap::compile(&config.clients)?;
+        let addr = lookup_host(&config.addr)
+            .await?
+            .next()
+            .with_context(|| format!("Cannot resolve transparent address {}", config.addr))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if config.mode == TransparentMode::Tproxy {
+            set_ip_transparent(&socket, addr)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        Ok(Self {
+            listener: TcpListener::from_std(socket.into())?,
+            mode: config.mode,
+            clients,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+pub(crate) async fn serve(
+    transparent: TransparentListener,
+    live: SharedContext,
+    (slots, tracker): (Arc<Semaphore>, TaskTracker),
+    (shutdown, drain): (CancellationToken, CancellationToken),
+) {
+    let Ok(listen_addr) = transparent.listener.local_addr() else {
+        return;
+    };
+    info!(
+        mode = format!("{:?}", transparent.mode),
+        "Transparent listener started on {listen_addr}"
+    );
+    loop {
+        let (socket, peer) = tokio::select! {
+            () = shutdown.cancelled() => return,
+            () = drain.cancelled() => return,
+            accepted = transparent.listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(error = format!("{err}"), "Transparent accept failed");
+                    continue;
+                }
+            },
+        };
+        let ctx = Context::clone(&live.load());
+        let Ok(permit) = slots.clone().try_acquire_owned() else {
+            Metrics::inc(&ctx.metrics.connections_shed);
+            warn!("Connection limit reached, shedding transparent client {peer}");
+            continue;
+        };
+        set_keepalive(&socket, ctx.config.keepalive);
+        set_socket_options(&socket, ctx.config.socket);
+        let target = match original_destination(&socket, transparent.mode) {
+            Ok(target) if !is_listener(target, listen_addr) => target,
+            Ok(_) => {
+                debug!("Dropping connection from {peer} that was not redirected");
+                continue;
+            }
+            Err(err) => {
+                debug!(
+                    error = format!("{err}"),
+                    "No original destination for {peer}"
+                );
+                continue;
+            }
+        };
+        let Some(user) = transparent.clients.user_for(peer.ip()) else {
+            warn!(
+                client_ip = format!("{}", peer.ip()),
+                "Transparent client is not mapped to a user"
+            );
+            continue;
+        };
+        let user = user.to_string();
+        let span = info_span!("connection", client = %peer, user = %user);
+        tracker.spawn(
+            async move {
+                ctx.metrics.connection_opened();
+                if let Err(err) = handle_transparent(socket, ctx.clone(), user, target).await {
+                    debug!(error = format!("{err}"), "Transparent tunnel failed");
+                }
+                ctx.metrics.connection_closed();
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+fn is_listener(target: SocketAddr, listen_addr: SocketAddr) -> bool {
+    target.port() == listen_addr.port()
+        && (listen_addr.ip().is_unspecified() || target.ip() == listen_addr.ip())
+}
+
+fn original_destination(stream: &TcpStream, mode: TransparentMode) -> io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    match mode {
+        TransparentMode::Tproxy => Ok(local),
+        TransparentMode::Redirect => redirected_destination(stream, local),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn redirected_destination(stream: &TcpStream, local: SocketAddr) -> io::Result<SocketAddr> {
+    let socket = SockRef::from(stream);
+    let original = if local.is_ipv4() {
+        socket.original_dst_v4()?
+    } else {
+        socket.original_dst_v6()?
+    };
+    original
+        .as_socket()
+        .ok_or_else(|| io::Error::other("Original destination is not an IP address"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn redirected_destination(_stream: &TcpStream, _local: SocketAddr) -> io::Result<SocketAddr> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn set_ip_transparent(socket: &Socket, addr: SocketAddr) -> Result<()> {
+    if !addr.is_ipv4() {
+        bail!("TPROXY mode supports IPv4 listen addresses only");
+    }
+    socket
+        .set_ip_transparent_v4(true)
+        .context("Cannot set IP_TRANSPARENT, TPROXY mode needs CAP_NET_ADMIN")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ip_transparent(_socket: &Socket, _addr: SocketAddr) -> Result<()> {
+    bail!("TPROXY mode is only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_clients_to_users_in_order() -> Result<()> {
+        let clients = ClientMap::compile(&[
+            "10.0.0.5=alice".to_string(),
+            "10.0.0.0/24 = office".to_string(),
+            "fd00::/8=lab".to_string(),
+        ])?;
+        assert_eq!(clients.user_for("10.0.0.5".parse()?), Some("alice"));
+        assert_eq!(clients.user_for("10.0.0.9".parse()?), Some("office"));
+        assert_eq!(clients.user_for("fd00::1".parse()?), Some("lab"));
+        assert_eq!(clients.user_for("192.168.1.1".parse()?), None);
+
+        assert!(ClientMap::compile(&["10.0.0.0/24".to_string()]).is_err());
+        assert!(ClientMap::compile(&["10.0.0.0/33=bob".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn recognises_connections_to_the_listener_itself() -> Result<()> {
+        let any: SocketAddr = "0.0.0.0:9091".parse()?;
+        assert!(is_listener("127.0.0.1:9091".parse()?, any));
+        assert!(!is_listener("93.184.216.34:443".parse()?, any));
+        assert!(!is_listener(
+            "127.0.0.2:9091".parse()?,
+            "127.0.0.1:9091".parse()?
+        ));
+        assert_eq!(
+            "tproxy".parse::<TransparentMode>()?,
+            TransparentMode::Tproxy
+        );
+        assert!("nat".parse::<TransparentMode>().is_err());
+        Ok(())
+    }
+}
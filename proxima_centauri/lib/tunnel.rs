@@ -1,25 +1,648 @@
-use crate::http_utils::response::ProxyResponse;
-use anyhow::{Result, bail};
+use crate::bandwidth::TokenBucket;
+use crate::error::Result;
+use crate::http_utils::framing::ResponseFraming;
+use crate::registry::{QuotaLease, TrafficCounters};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
 use std::time::Duration;
-use tokio::io::{AsyncWriteExt, copy_bidirectional};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, split};
 use tokio::net::TcpStream;
-use tokio::time::timeout;
-pub async fn connect_target(
+use tokio::time::{Instant, sleep_until, timeout};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    ClientClosed,
+    TargetClosed,
+    IdleTimeout,
+    QuotaExceeded,
+    AdminKick,
+    MaxLifetime,
+    SniMismatch,
+    IoError,
+    WriteStalled,
+    OutsideSchedule,
+    ChaosReset,
+    InspectorBlocked,
+    CategoryDenied,
+    ClientReset,
+}
+
+impl CloseReason {
+    pub const ALL: [Self; 14] = [
+        Self::ClientClosed,
+        Self::TargetClosed,
+        Self::IdleTimeout,
+        Self::QuotaExceeded,
+        Self::AdminKick,
+        Self::MaxLifetime,
+        Self::SniMismatch,
+        Self::IoError,
+        Self::WriteStalled,
+        Self::OutsideSchedule,
+        Self::ChaosReset,
+        Self::InspectorBlocked,
+        Self::CategoryDenied,
+        Self::ClientReset,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientClosed => "client_closed",
+            Self::TargetClosed => "target_closed",
+            Self::IdleTimeout => "idle_timeout",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::AdminKick => "admin_kick",
+            Self::MaxLifetime => "max_lifetime",
+            Self::SniMismatch => "sni_mismatch",
+            Self::IoError => "io_error",
+            Self::WriteStalled => "write_stalled",
+            Self::OutsideSchedule => "outside_schedule",
+            Self::ChaosReset => "chaos_reset",
+            Self::InspectorBlocked => "inspector_blocked",
+            Self::CategoryDenied => "category_denied",
+            Self::ClientReset => "client_reset",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RelayPolicy {
+    pub(crate) idle: Duration,
+    pub(crate) lifetime: Option<Duration>,
+    pub(crate) stall: Option<Duration>,
+    pub(crate) buffer: usize,
+}
+
+pub(crate) struct RelayOutcome {
+    pub(crate) ingress: u64,
+    pub(crate) egress: u64,
+    pub(crate) reason: CloseReason,
+    pub(crate) first_byte: Option<Duration>,
+    pub(crate) duration: Duration,
+    pub(crate) reusable: bool,
+}
+
+impl RelayOutcome {
+    pub(crate) const fn empty(reason: CloseReason) -> Self {
+        Self {
+            ingress: 0,
+            egress: 0,
+            reason,
+            first_byte: None,
+            duration: Duration::ZERO,
+            reusable: false,
+        }
+    }
+}
+
+pub(crate) async fn forward_request(
+    source: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    target: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    head: &[u8],
+    policy: RelayPolicy,
+    live: Option<&TrafficCounters>,
+    quota: Option<&QuotaLease>,
+    bandwidth: Option<&TokenBucket>,
+) -> Result<RelayOutcome> {
+    target.write_all(head).await?;
+    if let Some(live) = live {
+        live.add_ingress(head.len() as u64);
+    }
+    let mut outcome = relay(source, target, policy, live, quota, bandwidth).await;
+    outcome.ingress += head.len() as u64;
+    Ok(outcome)
+}
+
+async fn relay(
+    source: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    target: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    policy: RelayPolicy,
+    live: Option<&TrafficCounters>,
+    quota: Option<&QuotaLease>,
+    bandwidth: Option<&TokenBucket>,
+) -> RelayOutcome {
+    let started = Instant::now();
+    let activity = AtomicU64::new(0);
+    let ingress = AtomicU64::new(0);
+    let egress = AtomicU64::new(0);
+    let first_byte = OnceLock::new();
+    let (mut source_read, mut source_write) = split(source);
+    let (mut target_read, mut target_write) = split(target);
+    let upstream = pipe(
+        &mut source_read,
+        &mut target_write,
+        |size| {
+            ingress.fetch_add(size, Ordering::Relaxed);
+            if let Some(live) = live {
+                live.add_ingress(size);
+            }
+        },
+        (quota, bandwidth),
+        policy,
+        &activity,
+        started,
+    );
+    let downstream = pipe(
+        &mut target_read,
+        &mut source_write,
+        |size| {
+            if egress.fetch_add(size, Ordering::Relaxed) == 0 {
+                let _ = first_byte.set(started.elapsed());
+            }
+            if let Some(live) = live {
+                live.add_egress(size);
+            }
+        },
+        (quota, bandwidth),
+        policy,
+        &activity,
+        started,
+    );
+
+    let reason = tokio::select! {
+        reason = closed_first(upstream, downstream) => reason,
+        () = idle_expired(&activity, started, policy.idle) => CloseReason::IdleTimeout,
+        () = lifetime_expired(started, policy.lifetime) => CloseReason::MaxLifetime,
+    };
+    if matches!(
+        reason,
+        CloseReason::MaxLifetime | CloseReason::QuotaExceeded
+    ) {
+        let _ = source_write.shutdown().await;
+        let _ = target_write.shutdown().await;
+    }
+    RelayOutcome {
+        ingress: ingress.load(Ordering::Relaxed),
+        egress: egress.load(Ordering::Relaxed),
+        reason,
+        first_byte: first_byte.into_inner(),
+        duration: started.elapsed(),
+        reusable: false,
+    }
+}
+
+pub(crate) async fn exchange(
     source: &mut TcpStream,
-    target: &mut TcpStream,
-    timeout_sec: Duration,
-) -> Result<(u64, u64)> {
-    source
-        .write_all(ProxyResponse::ConnectionEstablished.as_bytes())
-        .await?;
+    target: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    (head, head_request, upload): (&[u8], bool, ResponseFraming),
+    policy: RelayPolicy,
+    live: Option<&TrafficCounters>,
+    quota: Option<&QuotaLease>,
+    bandwidth: Option<&TokenBucket>,
+) -> Result<RelayOutcome> {
+    let started = Instant::now();
+    target.write_all(head).await?;
+    if let Some(live) = live {
+        live.add_ingress(head.len() as u64);
+    }
+    let activity = AtomicU64::new(0);
+    let ingress = AtomicU64::new(head.len() as u64);
+    let egress = AtomicU64::new(0);
+    let first_byte = OnceLock::new();
+    let (mut source_read, mut source_write) = source.split();
+    let (mut target_read, target_write) = split(target);
+    let mut origin = KeepOpen(target_write);
+    let mut request = Framed {
+        inner: &mut source_read,
+        framing: upload,
+    };
+    let mut response = Framed {
+        inner: &mut target_read,
+        framing: ResponseFraming::new(head_request),
+    };
+    let upstream = pipe(
+        &mut request,
+        &mut origin,
+        |size| {
+            ingress.fetch_add(size, Ordering::Relaxed);
+            if let Some(live) = live {
+                live.add_ingress(size);
+            }
+        },
+        (quota, bandwidth),
+        policy,
+        &activity,
+        started,
+    );
+    let downstream = pipe(
+        &mut response,
+        &mut source_write,
+        |size| {
+            if egress.fetch_add(size, Ordering::Relaxed) == 0 {
+                let _ = first_byte.set(started.elapsed());
+            }
+            if let Some(live) = live {
+                live.add_egress(size);
+            }
+        },
+        (quota, bandwidth),
+        policy,
+        &activity,
+        started,
+    );
+    let reason = tokio::select! {
+        reason = answered(upstream, downstream) => reason,
+        () = idle_expired(&activity, started, policy.idle) => CloseReason::IdleTimeout,
+        () = lifetime_expired(started, policy.lifetime) => CloseReason::MaxLifetime,
+    };
+    Ok(RelayOutcome {
+        ingress: ingress.load(Ordering::Relaxed),
+        egress: egress.load(Ordering::Relaxed),
+        reason,
+        first_byte: first_byte.into_inner(),
+        duration: started.elapsed(),
+        reusable: reason == CloseReason::TargetClosed
+            && request.framing.is_complete()
+            && response.framing.is_reusable(),
+    })
+}
+
+async fn answered(
+    upstream: impl Future<Output = io::Result<()>>,
+    downstream: impl Future<Output = io::Result<()>>,
+) -> CloseReason {
+    tokio::pin!(upstream, downstream);
+    let result = tokio::select! {
+        result = &mut downstream => result,
+        result = &mut upstream => match result {
+            Ok(()) => downstream.await,
+            Err(err) => Err(err),
+        },
+    };
+    match result {
+        Ok(()) => CloseReason::TargetClosed,
+        Err(err) if err.kind() == io::ErrorKind::QuotaExceeded => CloseReason::QuotaExceeded,
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => CloseReason::WriteStalled,
+        Err(_) => CloseReason::IoError,
+    }
+}
+
+struct KeepOpen<T>(T);
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for KeepOpen<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+}
+
+struct Framed<'a, T> {
+    inner: &'a mut T,
+    framing: ResponseFraming,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Framed<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.framing.is_complete() {
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut *self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - before;
+        if read == 0 {
+            self.framing.closed();
+            return Poll::Ready(Ok(()));
+        }
+        let used = self.framing.feed(&buf.filled()[before..]);
+        if used < read {
+            self.framing.overrun();
+            buf.set_filled(before + used);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn closed_first(
+    upstream: impl Future<Output = io::Result<()>>,
+    downstream: impl Future<Output = io::Result<()>>,
+) -> CloseReason {
+    tokio::pin!(upstream, downstream);
+    let (result, reason) = tokio::select! {
+        result = &mut upstream => (result, CloseReason::ClientClosed),
+        result = &mut downstream => (result, CloseReason::TargetClosed),
+    };
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::QuotaExceeded => {
+            return CloseReason::QuotaExceeded;
+        }
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => return CloseReason::WriteStalled,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            return CloseReason::InspectorBlocked;
+        }
+        Err(err) if client_reset(&err, reason) => return CloseReason::ClientReset,
+        Err(_) => return CloseReason::IoError,
+        Ok(()) => {}
+    }
+    let _ = match reason {
+        CloseReason::ClientClosed => downstream.await,
+        _ => upstream.await,
+    };
+    reason
+}
+
+fn client_reset(err: &io::Error, side: CloseReason) -> bool {
+    match side {
+        CloseReason::ClientClosed => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+        ),
+        _ => err.kind() == io::ErrorKind::BrokenPipe,
+    }
+}
+
+async fn pipe(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    transferred: impl Fn(u64),
+    (quota, bandwidth): (Option<&QuotaLease>, Option<&TokenBucket>),
+    policy: RelayPolicy,
+    activity: &AtomicU64,
+    started: Instant,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; policy.buffer];
+    loop {
+        let size = reader.read(&mut buf).await?;
+        if size == 0 {
+            return writer.shutdown().await;
+        }
+        let mut allowed = quota.map_or(size, |quota| {
+            usize::try_from(quota.reserve(size as u64)).unwrap_or(size)
+        });
+        if let Some(quota) = quota
+            && allowed < size
+            && quota.renew().await
+        {
+            let missing = size - allowed;
+            allowed += usize::try_from(quota.reserve(missing as u64)).unwrap_or(missing);
+        }
+        if let Some(bandwidth) = bandwidth {
+            bandwidth
+                .take(allowed as u64, || touch(activity, started))
+                .await;
+        }
+        let written = write_within(writer, &buf[..allowed], policy.stall).await;
+        if written.is_ok() {
+            transferred(allowed as u64);
+        }
+        if let Some(quota) = quota {
+            quota.settle(allowed as u64);
+        }
+        written?;
+        if allowed < size {
+            return Err(io::ErrorKind::QuotaExceeded.into());
+        }
+        touch(activity, started);
+    }
+}
+
+async fn write_within(
+    writer: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+    stall: Option<Duration>,
+) -> io::Result<()> {
+    match stall {
+        Some(stall) => timeout(stall, writer.write_all(data))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => writer.write_all(data).await,
+    }
+    .map_err(|err| match err.kind() {
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => {
+            io::ErrorKind::BrokenPipe.into()
+        }
+        _ => err,
+    })
+}
+
+fn touch(activity: &AtomicU64, started: Instant) {
+    let elapsed = u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX);
+    activity.store(elapsed, Ordering::Relaxed);
+}
 
-    match timeout(timeout_sec, copy_bidirectional(source, target)).await {
-        Ok(result) => {
-            let (st, ts) = result?;
-            Ok((st, ts))
+async fn idle_expired(activity: &AtomicU64, started: Instant, idle_timeout: Duration) {
+    loop {
+        let last = Duration::from_millis(activity.load(Ordering::Relaxed));
+        let deadline = started + last + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
         }
-        Err(err) => {
-            bail!(err)
+        sleep_until(deadline).await;
+    }
+}
+
+async fn lifetime_expired(started: Instant, lifetime: Option<Duration>) {
+    match lifetime {
+        Some(lifetime) => sleep_until(started + lifetime).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tokio::net::TcpListener;
+
+    async fn pair() -> Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        Ok((client, server))
+    }
+
+    const fn policy(idle_ms: u64) -> RelayPolicy {
+        RelayPolicy {
+            idle: Duration::from_millis(idle_ms),
+            lifetime: None,
+            stall: None,
+            buffer: 8192,
         }
     }
+
+    #[tokio::test]
+    async fn reports_which_side_closed_first() -> Result<()> {
+        let (mut client, mut source) = pair().await?;
+        let (mut target, mut remote) = pair().await?;
+        let relay = tokio::spawn(async move {
+            relay(&mut source, &mut target, policy(5_000), None, None, None).await
+        });
+
+        client.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await?;
+        remote.write_all(b"hi").await?;
+        drop(remote);
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        drop(client);
+
+        let outcome = relay.await?;
+        assert_eq!(outcome.reason, CloseReason::TargetClosed);
+        assert_eq!((outcome.ingress, outcome.egress), (5, 2));
+        assert!(outcome.first_byte.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn half_closed_target_still_receives_client_data() -> Result<()> {
+        let (mut client, mut source) = pair().await?;
+        let (mut target, mut remote) = pair().await?;
+        let relay = tokio::spawn(async move {
+            relay(&mut source, &mut target, policy(5_000), None, None, None).await
+        });
+
+        remote.write_all(b"220 ready").await?;
+        remote.shutdown().await?;
+        let mut greeting = Vec::new();
+        client.read_to_end(&mut greeting).await?;
+        assert_eq!(greeting, b"220 ready");
+
+        client.write_all(b"QUIT").await?;
+        let mut command = [0u8; 4];
+        remote.read_exact(&mut command).await?;
+        assert_eq!(&command, b"QUIT");
+        client.shutdown().await?;
+        assert_eq!(remote.read(&mut command).await?, 0);
+
+        let outcome = relay.await?;
+        assert_eq!(outcome.reason, CloseReason::TargetClosed);
+        assert_eq!((outcome.ingress, outcome.egress), (4, 9));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn live_counters_grow_during_transfer() -> Result<()> {
+        let (mut client, mut source) = pair().await?;
+        let (mut target, mut remote) = pair().await?;
+        let live = std::sync::Arc::new(TrafficCounters::default());
+        let counters = live.clone();
+        let relay = tokio::spawn(async move {
+            relay(
+                &mut source,
+                &mut target,
+                policy(5_000),
+                Some(&counters),
+                None,
+                None,
+            )
+            .await
+        });
+
+        client.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await?;
+        remote.write_all(b"hi").await?;
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await?;
+        assert_eq!((live.ingress(), live.egress()), (5, 2));
+
+        drop((client, remote));
+        relay.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_tunnels_time_out() -> Result<()> {
+        let (_client, mut source) = pair().await?;
+        let (mut target, _remote) = pair().await?;
+
+        let outcome = relay(&mut source, &mut target, policy(50), None, None, None).await;
+        assert_eq!(outcome.reason, CloseReason::IdleTimeout);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tunnels_are_half_closed_after_max_lifetime() -> Result<()> {
+        let (mut client, mut source) = pair().await?;
+        let (mut target, mut remote) = pair().await?;
+        let relay = tokio::spawn(async move {
+            let policy = RelayPolicy {
+                lifetime: Some(Duration::from_millis(50)),
+                ..policy(5_000)
+            };
+            relay(&mut source, &mut target, policy, None, None, None).await
+        });
+
+        client.write_all(b"hello").await?;
+        let outcome = relay.await?;
+        assert_eq!(outcome.reason, CloseReason::MaxLifetime);
+
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        remote.read_to_end(&mut rest).await?;
+        assert_eq!(rest, b"hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stalled_client_closes_tunnel() -> Result<()> {
+        let (_client, mut source) = pair().await?;
+        let (mut target, mut remote) = pair().await?;
+        tokio::spawn(async move {
+            let chunk = vec![0u8; 64 * 1024];
+            while remote.write_all(&chunk).await.is_ok() {}
+        });
+        let policy = RelayPolicy {
+            stall: Some(Duration::from_millis(100)),
+            ..policy(5_000)
+        };
+
+        let outcome = relay(&mut source, &mut target, policy, None, None, None).await;
+        assert_eq!(outcome.reason, CloseReason::WriteStalled);
+        assert!(outcome.egress > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exhausted_quota_closes_tunnel() -> Result<()> {
+        let (mut client, mut source) = pair().await?;
+        let (mut target, mut remote) = pair().await?;
+        let live = std::sync::Arc::new(TrafficCounters::default());
+        let quota = QuotaLease::new(live.clone(), 8, 4);
+        let relay = tokio::spawn(async move {
+            let outcome = relay(
+                &mut source,
+                &mut target,
+                policy(5_000),
+                Some(&live),
+                Some(&quota),
+                None,
+            )
+            .await;
+            (outcome, live.total())
+        });
+
+        client.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await?;
+        remote.write_all(b"world").await?;
+
+        let (outcome, total) = relay.await?;
+        assert_eq!(outcome.reason, CloseReason::QuotaExceeded);
+        assert_eq!(total, 8);
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        assert_eq!(rest, b"wor");
+        Ok(())
+    }
 }
@@ -0,0 +1,411 @@
+use crate::acl::Acl;
+use crate::auth::{cipher, load_rows, user_problems};
+use crate::category::Categories;
+use crate::config::{Config, Enforcement, UsersValidation};
+use crate::geoip::GeoIp;
+use crate::http_utils::request::{is_header_name, parse_forward_target};
+use crate::intercept::{Interceptor, PassThrough};
+use crate::store::{RedisTarget, StoreConfig};
+use crate::transparent::ClientMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Validation {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Validation {
+    pub const fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+}
+
+impl Display for Validation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "error: {error}")?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "warning: {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Validation {
+        let mut report = Validation::default();
+        self.check_addresses(&mut report);
+        self.check_policies(&mut report);
+        self.check_limits(&mut report);
+        self.check_paths(&mut report);
+        report.errors.extend(self.plan_errors.iter().cloned());
+        report.errors.extend(self.secret_errors.iter().cloned());
+        report
+    }
+
+    pub async fn check_backends(&self) -> Validation {
+        let mut report = self.validate();
+        self.check_users_file(&mut report);
+        if let StoreConfig::Redis(url) = &self.store
+            && let Ok(RedisTarget { addr, .. }) = RedisTarget::parse(url)
+        {
+            match timeout(BACKEND_TIMEOUT, TcpStream::connect(&addr)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => {
+                    report.error(format!("Redis store at {addr} is unreachable: {err}"));
+                }
+                Err(_) => report.error(format!("Redis store at {addr} did not answer in time")),
+            }
+        }
+        report
+    }
+
+    fn check_addresses(&self, report: &mut Validation) {
+        if self.port.parse::<u16>().is_err() {
+            report.error(format!("PROXY_PORT `{}` is not a port number", self.port));
+        }
+        if self.host.is_empty() || self.host.contains(char::is_whitespace) {
+            report.error(format!("PROXY_HOST `{}` is not a valid host", self.host));
+        }
+        if let Some(addr) = &self.admin_addr {
+            if !addr
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+            {
+                report.error(format!("PROXY_ADMIN_ADDR `{addr}` must be `host:port`"));
+            }
+            if self.admin_token.is_none() {
+                report.warn("The admin API is enabled without PROXY_ADMIN_TOKEN".to_string());
+            }
+            if *addr == self.addr() {
+                report.error(
+                    "PROXY_ADMIN_ADDR must differ from the proxy listen address".to_string(),
+                );
+            }
+        }
+        for url in self.webhooks.urls.iter().chain(&self.warn_webhook) {
+            if parse_forward_target(url).is_none() {
+                report.error(format!(
+                    "Webhook URL `{url}` must be an absolute http:// URL"
+                ));
+            }
+        }
+        if let Some(transparent) = &self.transparent {
+            if transparent.addr == self.addr() {
+                report.error(
+                    "PROXY_TRANSPARENT_ADDR must differ from the proxy listen address".to_string(),
+                );
+            }
+            if let Err(err) = ClientMap::compile(&transparent.clients) {
+                report.error(format!("{err:#}"));
+            } else if transparent.clients.is_empty() {
+                report.warn(
+                    "PROXY_TRANSPARENT_CLIENTS is empty, transparent connections will be dropped"
+                        .to_string(),
+                );
+            }
+        }
+        if let Some(addr) = self.outbound.default.addr
+            && !self.egress_pool.addrs.is_empty()
+        {
+            report.warn(format!(
+                "PROXY_OUTBOUND_ADDR {addr} is ignored while PROXY_EGRESS_POOL is set"
+            ));
+        }
+    }
+
+    fn check_policies(&self, report: &mut Validation) {
+        let acl = Acl::compile(&self.acl);
+        if let Err(err) = &acl {
+            report.error(format!("Invalid ACL rule: {err}"));
+        }
+        let sourced = self.categories.file.is_some() || self.categories.url.is_some();
+        if acl.is_ok_and(|acl| acl.uses_categories()) && !sourced {
+            report.warn(
+                "ACL category rules are set without PROXY_CATEGORY_FILE or PROXY_CATEGORY_URL"
+                    .to_string(),
+            );
+        }
+        if self.categories.file.is_some() && self.categories.url.is_some() {
+            report
+                .warn("PROXY_CATEGORY_URL is ignored while PROXY_CATEGORY_FILE is set".to_string());
+        }
+        if let Err(err) = Categories::new(&self.categories, None) {
+            report.error(format!("{err}"));
+        }
+        if let Err(err) = self.chaos.check() {
+            report.error(format!("{err}"));
+        } else if self.chaos.is_enabled() {
+            report.warn("Chaos faults are enabled, do not run this in production".to_string());
+        }
+        if let Some(intercept) = &self.intercept {
+            match Interceptor::new(intercept, Arc::new(PassThrough)) {
+                Ok(_) => report.warn(
+                    "TLS interception is enabled, flagged users' tunnels will be decrypted"
+                        .to_string(),
+                ),
+                Err(err) => report.error(format!("{err}")),
+            }
+        }
+        if let Some(path) = &self.geoip_db
+            && let Err(err) = GeoIp::open(path, self.geoip_policy.clone())
+        {
+            report.error(format!("Cannot load GeoIP database `{path}`: {err}"));
+        }
+        if self.geoip_db.is_none()
+            && (!self.geoip_policy.allow.is_empty() || !self.geoip_policy.deny.is_empty())
+        {
+            report.warn("GeoIP allow/deny lists are set but PROXY_GEOIP_DB is not".to_string());
+        }
+        for (name, _) in &self.forward_headers.inject {
+            if !is_header_name(name) {
+                report.error(format!(
+                    "PROXY_HEADERS_ADD header name `{name}` is not valid"
+                ));
+            }
+        }
+        if let Some(name) = &self.connect_headers.request_id
+            && !is_header_name(name)
+        {
+            report.error(format!(
+                "PROXY_CONNECT_REQUEST_ID header name `{name}` is not valid"
+            ));
+        }
+        if self
+            .connect_headers
+            .server
+            .as_deref()
+            .is_some_and(|server| server.contains(['\r', '\n']))
+        {
+            report.error("PROXY_CONNECT_SERVER must not contain line breaks".to_string());
+        }
+        if let Some(ip) = self
+            .acl
+            .allow
+            .iter()
+            .find_map(|rule| rule.parse::<IpAddr>().ok())
+            .filter(|_| !self.allow_ip_targets)
+        {
+            report.warn(format!(
+                "ACL allows IP {ip}, but IP literal targets are forbidden by PROXY_ALLOW_IP_TARGETS"
+            ));
+        }
+    }
+
+    fn check_limits(&self, report: &mut Validation) {
+        if self.header_timeout == 0 {
+            report.error("PROXY_HEADER_TIMEOUT must be at least 1 second".to_string());
+        }
+        if let Some(percent) = self.traffic_warn_percent
+            && !(1..=100).contains(&percent)
+        {
+            report.error(format!(
+                "PROXY_TRAFFIC_WARN_PERCENT {percent} must be between 1 and 100"
+            ));
+        }
+        if self.traffic_window != 0
+            && matches!(
+                self.store,
+                StoreConfig::Redis(_) | StoreConfig::Coordinator(_)
+            )
+        {
+            report
+                .error("PROXY_TRAFFIC_WINDOW needs the memory or file registry store".to_string());
+        }
+        if self.max_connections != 0 && self.max_connections_per_ip > self.max_connections {
+            report.warn(format!(
+                "PROXY_MAX_CONNECTIONS_PER_IP {} exceeds PROXY_MAX_CONNECTIONS {}",
+                self.max_connections_per_ip, self.max_connections
+            ));
+        }
+        let mut plans: Vec<_> = self.plans.iter().collect();
+        plans.sort_by_key(|(name, _)| name.as_str());
+        for (name, limits) in plans {
+            match limits.concurrency().restricted() {
+                Some(0) => report.warn(format!("Plan `{name}` allows no concurrent tunnels")),
+                Some(concurrency)
+                    if self.max_connections != 0
+                        && usize::from(concurrency) > self.max_connections =>
+                {
+                    report.warn(format!(
+                        "Plan `{name}` allows {concurrency} tunnels, more than PROXY_MAX_CONNECTIONS {}",
+                        self.max_connections
+                    ));
+                }
+                _ => {}
+            }
+            if limits.traffic().restricted() == Some(0) {
+                report.warn(format!("Plan `{name}` has a zero traffic quota"));
+            }
+            if limits.lifetime().restricted() == Some(Duration::ZERO) {
+                report.warn(format!("Plan `{name}` closes tunnels immediately"));
+            }
+            if limits.bandwidth().restricted() == Some(0) {
+                report.warn(format!("Plan `{name}` has a zero bandwidth cap"));
+            }
+        }
+        if let Some(anomaly) = &self.anomaly {
+            if anomaly.multiplier <= 1.0 {
+                report.error(format!(
+                    "PROXY_ANOMALY_MULTIPLIER {} must be greater than 1",
+                    anomaly.multiplier
+                ));
+            }
+            if anomaly.interval.is_zero() || anomaly.window == 0 {
+                report.error(
+                    "PROXY_ANOMALY_INTERVAL and PROXY_ANOMALY_WINDOW must be at least 1"
+                        .to_string(),
+                );
+            }
+        }
+        if self.enforcement == Enforcement::Shadow {
+            report.warn(
+                "PROXY_ENFORCEMENT is shadow, ACL and limit violations are only logged".to_string(),
+            );
+        }
+        if self.auth_max_failures != 0 && self.auth_lockout == 0 {
+            report.warn(
+                "PROXY_AUTH_LOCKOUT is 0, failed logins are counted but never locked".to_string(),
+            );
+        }
+    }
+
+    fn check_paths(&self, report: &mut Validation) {
+        let mut paths = Vec::new();
+        if let StoreConfig::File(path) = &self.store {
+            paths.push(("PROXY_STORE_PATH", path.as_path()));
+        }
+        if let Some(path) = &self.ledger_path {
+            paths.push(("PROXY_LEDGER_PATH", path.as_path()));
+        }
+        for (name, path) in paths {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            if !parent.is_dir() {
+                report.error(format!(
+                    "{name} `{}`: directory `{}` does not exist",
+                    path.display(),
+                    parent.display()
+                ));
+            }
+        }
+        if let Some(path) = &self.users_file
+            && !path.is_file()
+        {
+            report.error(format!(
+                "PROXY_USERS_FILE `{}` does not exist",
+                path.display()
+            ));
+        }
+        if let Some(Err(err)) = self.users_key.as_deref().map(cipher) {
+            report.error(format!("{err}"));
+        }
+        if let StoreConfig::Redis(url) = &self.store
+            && let Err(err) = RedisTarget::parse(url)
+        {
+            report.error(format!("PROXY_REDIS_URL {err}"));
+        }
+        if let StoreConfig::Coordinator(coordinator) = &self.store
+            && parse_forward_target(&coordinator.url).is_none()
+        {
+            report.error(format!(
+                "PROXY_COORDINATOR_URL `{}` must be an http:// URL",
+                coordinator.url
+            ));
+        }
+    }
+
+    fn check_users_file(&self, report: &mut Validation) {
+        let Some(path) = self.users_file.as_deref().filter(|path| path.is_file()) else {
+            return;
+        };
+        let rows = match load_rows(path, self.users_key.as_deref()) {
+            Ok(rows) => rows,
+            Err(err) => return report.error(format!("{err:#}")),
+        };
+        for (_, problem) in user_problems(&rows, self) {
+            if self.users_validation == UsersValidation::Strict {
+                report.error(problem);
+            } else {
+                report.warn(format!("{problem}, the row will be skipped"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::build_config;
+    use crate::registry::{LimitValue, Limits};
+
+    #[test]
+    fn default_config_is_valid() {
+        let report = build_config().validate();
+        assert!(report.is_ok(), "{report}");
+    }
+
+    #[test]
+    fn reports_actionable_errors_and_warnings() {
+        let mut config = build_config();
+        config.port = "http".to_string();
+        config.header_timeout = 0;
+        config.traffic_warn_percent = Some(150);
+        config.acl.deny = vec!["10.0.0.0/99".to_string()];
+        config.store = StoreConfig::File("/nonexistent/dir/registry.json".into());
+        config.max_connections = 8;
+        config.plans.insert(
+            "bulk".to_string(),
+            Limits::new(LimitValue::Restricted(16), LimitValue::Unrestricted),
+        );
+
+        let report = config.validate();
+        assert_eq!(report.errors.len(), 5, "{report}");
+        assert!(report.errors[0].contains("PROXY_PORT `http`"));
+        assert!(
+            report
+                .errors
+                .iter()
+                .any(|error| error.contains("/nonexistent/dir"))
+        );
+        assert!(
+            report
+                .warnings
+                .iter()
+                .any(|warning| warning.contains("Plan `bulk` allows 16 tunnels"))
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_redis_fails_backend_check() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map(|addr| addr.port())
+            .unwrap();
+        let mut config = build_config();
+        config.store = StoreConfig::Redis(format!("redis://127.0.0.1:{port}"));
+
+        let report = config.check_backends().await;
+        assert!(report.errors[0].contains("unreachable"), "{report}");
+    }
+}
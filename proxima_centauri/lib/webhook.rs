@@ -0,0 +1,87 @@
+use crate::http_utils::request::parse_forward_target;
+use anyhow::{Result, anyhow, bail};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) async fn post_json(url: &str, body: &[u8], signature: Option<&str>) -> Result<()> {
+    let target =
+        parse_forward_target(url).ok_or_else(|| anyhow!("Unsupported webhook URL `{url}`"))?;
+    let signature = signature
+        .map(|signature| format!("X-Procent-Signature: {signature}\r\n"))
+        .unwrap_or_default();
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\n{signature}Connection: close\r\n\r\n",
+        target.origin_path,
+        target.authority,
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+
+    let status = timeout(WEBHOOK_TIMEOUT, async {
+        let mut stream = TcpStream::connect(&target.authority).await?;
+        stream.write_all(&request).await?;
+        let mut response = [0u8; 64];
+        let size = stream.read(&mut response).await?;
+        Ok::<_, anyhow::Error>(String::from_utf8_lossy(&response[..size]).into_owned())
+    })
+    .await??;
+
+    if !status.starts_with("HTTP/1.1 2") && !status.starts_with("HTTP/1.0 2") {
+        bail!(
+            "Webhook `{url}` responded with `{}`",
+            status.lines().next().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn accept_once(listener: TcpListener, reply: &'static [u8]) -> Result<String> {
+        let (mut socket, _) = listener.accept().await?;
+        let mut request = vec![0u8; 1024];
+        let size = socket.read(&mut request).await?;
+        socket.write_all(reply).await?;
+        Ok(String::from_utf8_lossy(&request[..size]).into_owned())
+    }
+
+    #[tokio::test]
+    async fn posts_json_payload() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hooks/proxy", listener.local_addr()?);
+        let server = tokio::spawn(accept_once(listener, b"HTTP/1.1 204 No Content\r\n\r\n"));
+
+        post_json(&url, br#"{"event":"test"}"#, None).await?;
+
+        let request = server.await??;
+        assert!(request.starts_with("POST /hooks/proxy HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json"));
+        assert!(request.ends_with(r#"{"event":"test"}"#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_status() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/", listener.local_addr()?);
+        let server = tokio::spawn(accept_once(listener, b"HTTP/1.1 500 Oops\r\n\r\n"));
+
+        assert!(post_json(&url, b"{}", None).await.is_err());
+        server.await??;
+        assert!(
+            post_json("https://example.com/", b"{}", None)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}